@@ -1,65 +1,52 @@
-use okta_jwt_verifier::{DefaultClaims, Verifier};
+use okta_jwt_verifier::{
+    Config, DefaultClaims, DefaultResponseMapper, MatchedKey, RawClaims,
+    TideAuthentication, Verifier,
+};
 use serde_json::json;
 use std::env;
-use tide::{http::mime::JSON, Request, Response, Result, Server, StatusCode};
-use tide_http_auth::{
-    Authentication, BearerAuthRequest, BearerAuthScheme, Storage,
-};
+use tide::{http::mime::JSON, Request, Response, Result, StatusCode};
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum Authenticated {
-    User,
-}
+const REALM: &str = "api";
 
-#[async_trait::async_trait]
-impl Storage<Authenticated, BearerAuthRequest> for State {
-    async fn get_user(
-        &self,
-        req: BearerAuthRequest,
-    ) -> tide::Result<Option<Authenticated>> {
-        let issuer = env::var("ISSUER")
-            .expect("You need to provide the ISSUER env variable!");
-        let _tokendata = Verifier::new(&issuer)
-            .await?
-            .verify::<DefaultClaims>(&req.token)
-            .await?;
-        Ok(Some(Authenticated::User))
-    }
-}
-
-#[derive(Clone)]
-pub struct State {}
-
-pub async fn protected(req: Request<State>) -> tide::Result {
-    if let Some(_authenticated) = req.ext::<Authenticated>() {
-        Ok(Response::builder(StatusCode::Ok)
-            .body(json!({
-                "message": "Here I am!"
-            }))
-            .content_type(JSON)
-            .build())
-    } else {
-        Ok(Response::builder(StatusCode::Forbidden)
-            .body(json!({
-                "message": "Unauthenticated"
-            }))
-            .content_type(JSON)
-            .build())
-    }
+pub async fn protected(req: Request<()>) -> tide::Result {
+    let claims = req.ext::<DefaultClaims>();
+    let raw = req.ext::<RawClaims>();
+    let kid = req.ext::<MatchedKey>();
+    Ok(Response::builder(StatusCode::Ok)
+        .body(json!({
+            "message": "Here I am!",
+            "sub": claims.map(|claims| claims.sub.clone()),
+            "scp": raw.map(|RawClaims(raw)| raw["scp"].clone()),
+            "kid": kid.map(|MatchedKey(kid)| kid.clone()),
+        }))
+        .content_type(JSON)
+        .build())
 }
 
 #[async_std::main]
 async fn main() -> Result<()> {
-    let state = State {};
+    let issuer = env::var("ISSUER")
+        .expect("You need to provide the ISSUER env variable!");
+    // The handler reads the scopes from the raw claims
+    let config = Config { retain_raw_claims: true, ..Config::default() };
+    let verifier = Verifier::new_with_config(&issuer, config).await?;
     tide::log::start();
-    let mut app = Server::with_state(state);
-    app.with(Authentication::new(BearerAuthScheme::default()));
+    let mut app = tide::new();
     app.at("/").get(|_| async {
         Ok(json!({
             "message": "Hello World!"
         }))
     });
-    app.at("/protected").get(protected);
+    // Rejected requests are answered by the ResponseMapper, by default with
+    // the status and WWW-Authenticate and Retry-After headers from
+    // ErrorResponse so that an Okta outage isn't reported as a bad token
+    let mut protected_routes = tide::new();
+    protected_routes.with(
+        TideAuthentication::<DefaultClaims>::new(verifier)
+            .with_mapper(DefaultResponseMapper::new(Some(REALM))),
+    );
+    protected_routes.at("/").get(protected);
+    app.at("/protected").nest(protected_routes);
 
     app.listen("0.0.0.0:8080").await?;
     Ok(())
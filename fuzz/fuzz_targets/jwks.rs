@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use okta_jwt_verifier::fuzzing;
+
+fuzz_target!(|body: &[u8]| {
+    fuzzing::parse_jwks(body);
+});
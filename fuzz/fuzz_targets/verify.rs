@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use okta_jwt_verifier::{fuzzing, DefaultClaims};
+
+// Public half of the key used by the regression tests under tests/
+const JWKS: &str = r#"{"keys":[{"kty":"RSA","alg":"RS256","kid":"12345","use":"sig","e":"AQAB","n":"yqq0N5u8Jvl-BLH2VMP_NAv_zY9T8mSq0V2Gk5Ql5H1a-4qi3viorUXG3AvIEEccpLsW85ps5-I9itp74jllRjA5HG5smbb-Oym0m2Hovfj6qP_1m1drQg8oth6tNmupNqVzlGGWZLsSCBLuMa3pFaPhoxl9lGU3XJIQ1_evMkOb98I3hHb4ELn3WGtNlAVkbP20R8sSii_zFjPqrG_NbSPLyAl1ctbG2d8RllQF1uRIqYQj85yx73hqQCMpYWU3d9QzpkLf_C35_79qNnSKa3t0cyDKinOY7JGIwh8DWAa4pfEzgg56yLcilYSSohXeaQV0nR8-rm9J8GUYXjPK7w"}]}"#;
+
+fuzz_target!(|token: &str| {
+    let verifier = fuzzing::verifier("https://fuzz.example", JWKS);
+    let _ = futures_executor::block_on(verifier.verify::<DefaultClaims>(token));
+});
@@ -0,0 +1,419 @@
+use std::fmt::Write;
+use std::ops::Not;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{token_scopes, Error, Verifier};
+
+/// Describes an authorization rule evaluated against the claims of a
+/// verified token, see [`Verifier::require_rule`]. Rules compose, so that
+/// e.g. two groups or a scope can be required:
+///
+/// ```
+/// use okta_jwt_verifier::Rule;
+///
+/// let rule = Rule::any_of([
+///     Rule::all_of([Rule::group("Billing"), Rule::group("Managers")]),
+///     Rule::scope("billing:admin"),
+/// ]);
+///```
+///
+/// A missing claim fails every rule but [`Rule::Not`]. Rules are evaluated
+/// without recursion, so deeply nested rules don't exhaust the stack, and
+/// cloning a rule shares its nested rules rather than copying them.
+/// Dropping, comparing, or printing a rule still visits every level.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// The groups claim holds the group.
+    Group(String),
+    /// The scp claim, or the scope claim, grants the scope.
+    Scope(String),
+    /// The claim equals the value, or holds it when the claim is an array.
+    Claim(String, Value),
+    /// Every one of the rules holds, which is the case when there are none.
+    AllOf(Arc<[Rule]>),
+    /// At least one of the rules holds.
+    AnyOf(Arc<[Rule]>),
+    /// The rule doesn't hold.
+    Not(Arc<Rule>),
+}
+
+impl Rule {
+    /// `group` requires the group.
+    pub fn group(group: &str) -> Self {
+        Self::Group(group.to_string())
+    }
+
+    /// `scope` requires the scope.
+    pub fn scope(scope: &str) -> Self {
+        Self::Scope(scope.to_string())
+    }
+
+    /// `claim` requires the claim to equal or hold the value.
+    pub fn claim(name: &str, value: impl Into<Value>) -> Self {
+        Self::Claim(name.to_string(), value.into())
+    }
+
+    /// `all_of` requires every one of the rules.
+    pub fn all_of(rules: impl IntoIterator<Item = Rule>) -> Self {
+        Self::AllOf(rules.into_iter().collect())
+    }
+
+    /// `any_of` requires at least one of the rules.
+    pub fn any_of(rules: impl IntoIterator<Item = Rule>) -> Self {
+        Self::AnyOf(rules.into_iter().collect())
+    }
+
+    /// `holds` evaluates the rule against the claims of a token.
+    pub fn holds(&self, claims: &Value) -> bool {
+        Evaluation::new(self, claims).holds(0)
+    }
+
+    // The nested rules
+    fn children(&self) -> &[Rule] {
+        match self {
+            Self::AllOf(rules) | Self::AnyOf(rules) => rules,
+            Self::Not(rule) => std::slice::from_ref(rule),
+            _ => &[],
+        }
+    }
+
+    // Whether a rule without nested rules holds
+    fn leaf_holds(&self, claims: &Value) -> bool {
+        match self {
+            Self::Group(group) => {
+                claims.get("groups").and_then(Value::as_array).is_some_and(
+                    |groups| groups.iter().any(|g| g.as_str() == Some(group)),
+                )
+            }
+            Self::Scope(scope) => {
+                token_scopes(claims).contains(&scope.as_str())
+            }
+            Self::Claim(name, value) => match claims.get(name) {
+                Some(Value::Array(values)) if !value.is_array() => {
+                    values.contains(value)
+                }
+                Some(claim) => claim == value,
+                None => false,
+            },
+            _ => unreachable!("only called for rules without nested rules"),
+        }
+    }
+
+    // A single line naming the rule without its nested rules
+    fn summary(&self) -> String {
+        let mut summary = String::new();
+        let mut rule = self;
+        while let Self::Not(negated) = rule {
+            summary.push_str("not ");
+            rule = negated;
+        }
+        let _ = match rule {
+            Self::Group(group) => write!(summary, "group {group}"),
+            Self::Scope(scope) => write!(summary, "scope {scope}"),
+            Self::Claim(name, value) => {
+                write!(summary, "claim {name} = {value}")
+            }
+            Self::AllOf(rules) => write!(summary, "all of {}", count(rules)),
+            Self::AnyOf(rules) => write!(summary, "any of {}", count(rules)),
+            Self::Not(_) => unreachable!("negations were skipped"),
+        };
+        summary
+    }
+}
+
+impl Not for Rule {
+    type Output = Rule;
+
+    /// Requires the rule not to hold.
+    fn not(self) -> Rule {
+        Rule::Not(Arc::new(self))
+    }
+}
+
+fn count(rules: &[Rule]) -> String {
+    match rules.len() {
+        1 => "1 rule".to_string(),
+        n => format!("{n} rules"),
+    }
+}
+
+// The rules of a tree in breadth-first order, nested rules always come
+// after the rule holding them so that evaluating from the back sees the
+// outcome of every nested rule first
+struct Evaluation<'a> {
+    rules: Vec<&'a Rule>,
+    // The index of the first nested rule of every rule
+    first_child: Vec<usize>,
+    holds: Vec<bool>,
+}
+
+impl<'a> Evaluation<'a> {
+    fn new(rule: &'a Rule, claims: &Value) -> Self {
+        let mut rules = vec![rule];
+        let mut first_child = Vec::new();
+        let mut i = 0;
+        while i < rules.len() {
+            first_child.push(rules.len());
+            rules.extend(rules[i].children());
+            i += 1;
+        }
+        let mut holds = vec![false; rules.len()];
+        for i in (0..rules.len()).rev() {
+            let children =
+                first_child[i]..first_child[i] + rules[i].children().len();
+            holds[i] = match rules[i] {
+                Rule::AllOf(_) => children.into_iter().all(|c| holds[c]),
+                Rule::AnyOf(_) => children.into_iter().any(|c| holds[c]),
+                Rule::Not(_) => !holds[first_child[i]],
+                leaf => leaf.leaf_holds(claims),
+            };
+        }
+        Self { rules, first_child, holds }
+    }
+
+    fn holds(&self, i: usize) -> bool {
+        self.holds[i]
+    }
+
+    // Explains why a rule that doesn't hold failed: the first failed rule
+    // of an all of, every rule of an any of, and the rule a not negated
+    fn explain(&self, i: usize) -> String {
+        enum Step {
+            Explain(usize),
+            Text(&'static str),
+        }
+        let mut explanation = String::new();
+        let mut steps = vec![Step::Explain(i)];
+        while let Some(step) = steps.pop() {
+            let i = match step {
+                Step::Text(text) => {
+                    explanation.push_str(text);
+                    continue;
+                }
+                Step::Explain(i) => i,
+            };
+            let children = self.first_child[i]
+                ..self.first_child[i] + self.rules[i].children().len();
+            match self.rules[i] {
+                Rule::Group(group) => {
+                    let _ = write!(explanation, "missing group {group}");
+                }
+                Rule::Scope(scope) => {
+                    let _ = write!(explanation, "missing scope {scope}");
+                }
+                Rule::Claim(name, value) => {
+                    let _ = write!(explanation, "claim {name} is not {value}");
+                }
+                Rule::AllOf(_) => {
+                    if let Some(c) =
+                        children.into_iter().find(|&c| !self.holds(c))
+                    {
+                        steps.push(Step::Explain(c));
+                    }
+                }
+                Rule::AnyOf(_) => {
+                    explanation.push_str("none of (");
+                    steps.push(Step::Text(")"));
+                    for (n, c) in children.into_iter().enumerate().rev() {
+                        steps.push(Step::Explain(c));
+                        if n > 0 {
+                            steps.push(Step::Text(" | "));
+                        }
+                    }
+                }
+                Rule::Not(rule) => {
+                    let _ = write!(explanation, "{} holds", rule.summary());
+                }
+            }
+        }
+        explanation
+    }
+}
+
+impl Verifier {
+    /// `require_rule` requires the claims of every token to satisfy the
+    /// rule, evaluated once the token was validated. Tokens that don't are
+    /// rejected with [`Error::RuleNotSatisfied`], explaining which part of
+    /// the rule failed. Every rule added this way has to hold.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{DefaultClaims, Rule, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     Verifier::new(&issuer)
+    ///         .await?
+    ///         .require_rule(Rule::any_of([
+    ///             Rule::all_of([Rule::group("Billing"), Rule::group("Managers")]),
+    ///             Rule::scope("billing:admin"),
+    ///         ]))
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn require_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    // Checks the claims against the rules added with require_rule
+    pub(crate) fn check_rules(&self, claims: &Value) -> Result<()> {
+        for rule in &self.rules {
+            let evaluation = Evaluation::new(rule, claims);
+            if !evaluation.holds(0) {
+                bail!(Error::RuleNotSatisfied {
+                    explanation: evaluation.explain(0),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jwt_simple::prelude::*;
+    use serde_json::json;
+
+    use crate::test_support::*;
+
+    fn explain(rule: &Rule, claims: &Value) -> Option<String> {
+        let evaluation = Evaluation::new(rule, claims);
+        (!evaluation.holds(0)).then(|| evaluation.explain(0))
+    }
+
+    #[test]
+    fn nested_rules_explain_the_failed_branch() {
+        let rule = Rule::any_of([
+            Rule::all_of([Rule::group("Billing"), Rule::group("Managers")]),
+            Rule::scope("billing:admin"),
+        ]);
+        let manager = json!({ "groups": ["Billing", "Managers"] });
+        assert!(rule.holds(&manager));
+        let admin = json!({ "scp": ["billing:admin"], "groups": ["Billing"] });
+        assert!(rule.holds(&admin));
+        let billing = json!({ "scp": ["billing:read"], "groups": ["Billing"] });
+        assert_eq!(
+            explain(&rule, &billing).as_deref(),
+            Some("none of (missing group Managers | missing scope billing:admin)")
+        );
+        assert!(Rule::all_of([]).holds(&billing));
+        assert!(!Rule::any_of([]).holds(&billing));
+    }
+
+    #[test]
+    fn not_negates_and_missing_claims_fail() {
+        let claims = json!({
+            "groups": ["Contractors"],
+            "department": "sales",
+            "regions": ["eu", "us"],
+        });
+        let rule = !Rule::group("Contractors");
+        assert!(!rule.holds(&claims));
+        assert_eq!(
+            explain(&rule, &claims).as_deref(),
+            Some("group Contractors holds")
+        );
+        assert!(Rule::claim("department", "sales").holds(&claims));
+        assert!(Rule::claim("regions", "eu").holds(&claims));
+        assert!(Rule::claim("regions", json!(["eu", "us"])).holds(&claims));
+        assert!(!Rule::claim("regions", "apac").holds(&claims));
+
+        // Missing claims fail their rule, so only their negation holds
+        let empty = json!({});
+        for rule in [
+            Rule::group("Contractors"),
+            Rule::scope("openid"),
+            Rule::claim("department", "sales"),
+        ] {
+            assert!(!rule.holds(&empty));
+            assert!((!rule).holds(&empty));
+        }
+        assert_eq!(
+            explain(&Rule::claim("department", "sales"), &empty).as_deref(),
+            Some(r#"claim department is not "sales""#)
+        );
+    }
+
+    // Dropping recurses once per level, take the levels apart one at a time
+    fn dismantle(rule: Rule) {
+        let mut next = Some(rule);
+        while let Some(rule) = next.take() {
+            next = match rule {
+                Rule::AllOf(mut rules) | Rule::AnyOf(mut rules) => {
+                    Arc::get_mut(&mut rules)
+                        .and_then(|rules| rules.first_mut())
+                        .map(|rule| std::mem::replace(rule, Rule::all_of([])))
+                }
+                Rule::Not(rule) => Arc::into_inner(rule),
+                _ => None,
+            };
+        }
+    }
+
+    #[test]
+    fn deeply_nested_rules_dont_exhaust_the_stack() {
+        let mut rule = Rule::group("Everyone");
+        for _ in 0..200_000 {
+            rule = Rule::all_of([!rule]);
+        }
+        let claims = json!({ "groups": ["Everyone"] });
+        assert!(rule.holds(&claims));
+        assert_eq!(explain(&rule, &claims), None);
+        let rule = !rule;
+        assert_eq!(
+            explain(&rule, &claims).as_deref(),
+            Some("all of 1 rule holds")
+        );
+        // Clones share the nested rules
+        let clone = rule.clone();
+        assert_eq!(clone.summary(), rule.summary());
+        drop(clone);
+        dismantle(rule);
+    }
+
+    #[async_test]
+    async fn require_rule_rejects_unauthorized_tokens() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let token = |groups: &[&str]| {
+            sign(
+                Claims::with_custom_claims(
+                    json!({ "groups": groups }),
+                    Duration::from_hours(2),
+                )
+                .with_issuer(server.url()),
+            )
+        };
+        let verifier = Verifier::new(&server.url())
+            .await?
+            .require_rule(Rule::group("Billing"))
+            .require_rule(!Rule::group("Contractors"));
+        verifier.verify::<Value>(&token(&["Billing"])).await?;
+
+        let err = verifier
+            .verify::<Value>(&token(&["Billing", "Contractors"]))
+            .await
+            .unwrap_err();
+        let error = err.downcast_ref::<Error>().unwrap();
+        assert_eq!(
+            error,
+            &Error::RuleNotSatisfied {
+                explanation: "group Contractors holds".to_string()
+            }
+        );
+        assert_eq!(error.status_hint(), 403);
+        Ok(())
+    }
+}
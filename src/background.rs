@@ -0,0 +1,178 @@
+// Keeps the keys of a Verifier fresh from a spawned task, see
+// Config::background_refresh
+
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use crate::refresh::{self, RefreshDecision, RefreshTrigger};
+use crate::retry;
+use crate::{logging, runtime, Config, KeyStore};
+
+// The delay before retrying a failed refresh, doubled with every
+// further failure up to the refresh interval
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Shared by a Verifier and its clones. The task waits on a lock that is
+// held by this handle, so dropping the last clone wakes the task up and
+// stops it right away rather than after the next interval.
+#[derive(Debug)]
+pub(crate) struct BackgroundRefresh {
+    _running: async_lock::MutexGuardArc<()>,
+}
+
+impl BackgroundRefresh {
+    pub(crate) fn spawn(
+        keys: &Arc<KeyStore>,
+        issuer: &str,
+        config: &Config,
+        interval: Duration,
+    ) -> Self {
+        let stop = Arc::new(async_lock::Mutex::new(()));
+        let running = stop.try_lock_arc().expect("a new lock is unlocked");
+        runtime::spawn(run(
+            Arc::downgrade(keys),
+            issuer.to_string(),
+            config.clone(),
+            interval,
+            stop,
+        ));
+        Self { _running: running }
+    }
+}
+
+// Only a weak reference to the keys is held so that the task doesn't keep
+// them alive
+async fn run(
+    keys: Weak<KeyStore>,
+    issuer: String,
+    config: Config,
+    interval: Duration,
+    stop: Arc<async_lock::Mutex<()>>,
+) {
+    let mut failures = 0;
+    let mut delay = interval;
+    while runtime::timeout(delay, stop.lock()).await.is_none() {
+        let Some(keys) = keys.upgrade() else { return };
+        let context = keys.refresh_context(RefreshTrigger::Periodic);
+        if refresh::decide(&config, context) == RefreshDecision::Skip {
+            delay = interval;
+            continue;
+        }
+        let seen = keys.generation();
+        match keys.refresh_since(&issuer, &config, seen).await {
+            Ok(()) => {
+                failures = 0;
+                delay = interval;
+            }
+            Err(e) => {
+                // A rate limit or open circuit is waited out rather than
+                // retried sooner
+                let reset = retry::until_reset(&e).unwrap_or_default();
+                delay =
+                    retry_delay(failures, interval).max(reset.min(interval));
+                failures += 1;
+                logging::warn!(
+                    "Refreshing keys in the background failed, retrying in {delay:?}: {e}"
+                );
+            }
+        }
+    }
+}
+
+// The delay before the next attempt after the given number of consecutive
+// failures
+fn retry_delay(failures: u32, interval: Duration) -> Duration {
+    RETRY_DELAY.saturating_mul(1 << failures.min(16)).min(interval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::test_support::*;
+    use crate::{DefaultClaims, Verifier, ORG_ENDPOINT};
+
+    fn config(interval: u64) -> Config {
+        Config {
+            background_refresh: Some(Duration::from_millis(interval)),
+            refetch_on_kid_miss: false,
+            ..Config::default()
+        }
+    }
+
+    #[async_test]
+    async fn replaces_the_keys_periodically() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk()]);
+        let verifier =
+            Verifier::new_with_config(&server.url(), config(50)).await?;
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        assert!(verifier.verify::<DefaultClaims>(&rotated).await.is_err());
+
+        m.remove();
+        mock_keys(&mut server, vec![rotated_jwk()]);
+        sleep(Duration::from_millis(300)).await;
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+        assert!(verifier.key_generation() > 0);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn stops_once_the_last_clone_is_dropped() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body_from_request(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                keys_body(vec![jwk()]).into()
+            })
+            .create();
+        let verifier =
+            Verifier::new_with_config(&server.url(), config(50)).await?;
+        let clone = verifier.clone();
+        drop(verifier);
+        sleep(Duration::from_millis(200)).await;
+        assert!(requests.load(Ordering::SeqCst) > 1);
+
+        let keys = Arc::downgrade(&clone.keys);
+        drop(clone);
+        sleep(Duration::from_millis(50)).await;
+        let stopped_at = requests.load(Ordering::SeqCst);
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(requests.load(Ordering::SeqCst), stopped_at);
+        assert!(keys.upgrade().is_none());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn keeps_the_keys_when_a_refresh_fails() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk()]);
+        let verifier =
+            Verifier::new_with_config(&server.url(), config(50)).await?;
+        m.remove();
+        server.mock("GET", ORG_ENDPOINT).with_status(503).create();
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(verifier.key_generation(), 0);
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn backs_off_after_failures() {
+        let hour = Duration::from_secs(3600);
+        let delays: Vec<_> =
+            (0..4).map(|failures| retry_delay(failures, hour)).collect();
+        assert_eq!(delays, [1, 2, 4, 8].map(Duration::from_secs));
+        assert_eq!(retry_delay(12, hour), hour);
+        assert_eq!(retry_delay(u32::MAX, hour), hour);
+        let short = Duration::from_millis(50);
+        assert_eq!(retry_delay(0, short), short);
+    }
+}
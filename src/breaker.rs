@@ -0,0 +1,263 @@
+// Stops retrieving the keys for a while after repeated failures, see
+// Config::circuit_breaker. The state is part of the KeyStore and thus shared
+// by a Verifier and its clones. Once the circuit is open retrievals fail
+// right away until it half-opens, then the next retrieval is a probe that
+// closes the circuit on success and opens it again on failure. Retrievals
+// are serialized by the refresh lock, so callers arriving during the probe
+// wait for it and share its outcome.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Result};
+
+use crate::{logging, Error};
+
+/// Describes when retrieving the keys is suspended after repeated
+/// failures, see [`Config::circuit_breaker`](crate::Config::circuit_breaker)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitBreaker {
+    /// The number of consecutive failed retrievals, each including its
+    /// retries, that opens the circuit. Failures classified as fatal by
+    /// the [`Config::retry_classifier`](crate::Config::retry_classifier)
+    /// aren't counted. By default 5.
+    pub failure_threshold: u32,
+    /// How long retrievals fail right away with
+    /// [`Error::KeySourceUnavailable`] once the circuit opened, after
+    /// which a single retrieval probes the keys endpoint again. Longer
+    /// than a year counts as a year. By default 30 seconds.
+    pub open_for: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self { failure_threshold: 5, open_for: Duration::from_secs(30) }
+    }
+}
+
+// Caps CircuitBreaker::open_for so that the end of the suspension can
+// always be represented
+const LONGEST_OPEN: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+// The consecutive failures and, while the circuit is open, until when
+#[derive(Debug, Default)]
+pub(crate) struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl BreakerState {
+    // Fails while the circuit is open
+    pub(crate) fn check(&self) -> Result<()> {
+        let Some(until) = self.open_until else { return Ok(()) };
+        let now = Instant::now();
+        if until <= now {
+            return Ok(());
+        }
+        bail!(Error::KeySourceUnavailable {
+            failures: self.failures,
+            retry_at: SystemTime::now() + (until - now),
+        })
+    }
+
+    // Records the outcome of a retrieval, a failed probe opens the circuit
+    // again right away
+    pub(crate) fn record(&mut self, breaker: &CircuitBreaker, ok: bool) {
+        if ok {
+            *self = Self::default();
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= breaker.failure_threshold {
+            if self.open_until.is_none() {
+                logging::warn!(
+                    "Suspending key retrievals for {:?} after {} failures",
+                    breaker.open_for,
+                    self.failures
+                );
+            }
+            let now = Instant::now();
+            self.open_until =
+                now.checked_add(breaker.open_for.min(LONGEST_OPEN));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::test_support::*;
+    use crate::{
+        Config, DefaultClaims, DefaultClassifier, FetchRetry, RetryClassifier,
+        RetryDecision, Verifier, ORG_ENDPOINT,
+    };
+
+    fn unavailable(result: Result<()>) -> Option<u32> {
+        match result.unwrap_err().downcast_ref::<Error>() {
+            Some(Error::KeySourceUnavailable { failures, .. }) => {
+                Some(*failures)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn opens_after_the_threshold_and_closes_on_success() {
+        let breaker = CircuitBreaker {
+            failure_threshold: 2,
+            open_for: Duration::from_secs(60),
+        };
+        let mut state = BreakerState::default();
+        state.record(&breaker, false);
+        assert!(state.check().is_ok());
+        state.record(&breaker, false);
+        assert_eq!(unavailable(state.check()), Some(2));
+        state.record(&breaker, true);
+        assert!(state.check().is_ok());
+        state.record(&breaker, false);
+        assert!(state.check().is_ok());
+    }
+
+    #[test]
+    fn caps_how_long_the_circuit_stays_open() {
+        let breaker =
+            CircuitBreaker { failure_threshold: 1, open_for: Duration::MAX };
+        let mut state = BreakerState::default();
+        state.record(&breaker, false);
+        let e = state.check().unwrap_err();
+        let Some(Error::KeySourceUnavailable { retry_at, .. }) =
+            e.downcast_ref::<Error>()
+        else {
+            panic!("{e}");
+        };
+        let wait = retry_at.duration_since(SystemTime::now()).unwrap();
+        assert!(wait <= LONGEST_OPEN && wait > LONGEST_OPEN / 2, "{wait:?}");
+    }
+
+    #[test]
+    fn half_opens_after_the_cool_off() {
+        let breaker =
+            CircuitBreaker { failure_threshold: 1, open_for: Duration::ZERO };
+        let mut state = BreakerState::default();
+        state.record(&breaker, false);
+        assert!(state.check().is_ok());
+        // The failed probe opens the circuit again
+        let breaker =
+            CircuitBreaker { open_for: Duration::from_secs(60), ..breaker };
+        state.record(&breaker, false);
+        assert_eq!(unavailable(state.check()), Some(2));
+    }
+
+    #[async_test]
+    async fn clones_share_the_open_circuit() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk()]);
+        let config = Config {
+            fetch_retry: FetchRetry::disabled(),
+            circuit_breaker: Some(CircuitBreaker {
+                failure_threshold: 2,
+                open_for: Duration::from_millis(300),
+            }),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        m.remove();
+        let down = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(503)
+            .expect(2)
+            .create();
+        let clone = verifier.clone();
+        for _ in 0..2 {
+            assert!(verifier.refresh_keys().await.is_err());
+        }
+        let err = clone.refresh_keys().await.unwrap_err();
+        assert!(err.downcast_ref::<Error>().is_some_and(Error::is_retryable));
+        assert_eq!(unavailable(Err(err)), Some(2));
+        down.assert();
+        // The current keys are still served, a token needing new keys
+        // reports the open circuit
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        let err = verifier.verify::<DefaultClaims>(&rotated).await;
+        assert_eq!(unavailable(err.map(drop)), Some(2));
+
+        down.remove();
+        let up = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(2)
+            .create();
+        sleep(Duration::from_millis(400)).await;
+        clone.refresh_keys().await?;
+        verifier.refresh_keys().await?;
+        up.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn only_counts_failures_that_arent_fatal() -> Result<()> {
+        // Retries 418 on top of the failures retried by default
+        #[derive(Debug)]
+        struct Teapot;
+
+        impl RetryClassifier for Teapot {
+            fn classify(&self, error: &Error) -> RetryDecision {
+                match error {
+                    Error::KeysStatus { status: 418, .. } => {
+                        RetryDecision::Retryable
+                    }
+                    _ => DefaultClassifier.classify(error),
+                }
+            }
+        }
+
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk()]);
+        let config = Config {
+            fetch_retry: FetchRetry::disabled(),
+            circuit_breaker: Some(CircuitBreaker {
+                failure_threshold: 2,
+                open_for: Duration::from_secs(60),
+            }),
+            retry_classifier: Some(Arc::new(Teapot)),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        m.remove();
+
+        let gone = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(410)
+            .expect(3)
+            .create();
+        for _ in 0..3 {
+            let err = verifier.refresh_keys().await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::KeysStatus { status: 410, .. })
+            ));
+        }
+        gone.assert();
+        gone.remove();
+
+        let teapot = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(418)
+            .expect(2)
+            .create();
+        for _ in 0..2 {
+            assert!(verifier.refresh_keys().await.is_err());
+        }
+        assert_eq!(
+            unavailable(verifier.refresh_keys().await.map(|_| ())),
+            Some(2)
+        );
+        teapot.assert();
+        Ok(())
+    }
+}
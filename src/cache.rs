@@ -0,0 +1,955 @@
+#[cfg(feature = "cache-reqwest")]
+pub use http_cache_reqwest::{
+    CacheManager, CacheMode, HttpCacheOptions, HttpResponse,
+};
+pub use http_cache_semantics::CachePolicy;
+#[cfg(all(feature = "cache-surf", not(feature = "cache-reqwest")))]
+pub use http_cache_surf::{
+    CacheManager, CacheMode, HttpCacheOptions, HttpResponse,
+};
+
+#[cfg(feature = "cache-reqwest")]
+use http_cache_reqwest::HttpCache;
+#[cfg(all(feature = "cache-surf", not(feature = "cache-reqwest")))]
+use http_cache_surf::HttpCache;
+
+#[cfg(feature = "cache-memory")]
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+#[cfg(feature = "cache-memory")]
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::Result;
+
+use crate::fetch::CACHING;
+#[cfg(not(feature = "cache-memory"))]
+use crate::{runtime, snapshot};
+use crate::{Config, Error};
+
+// Where DiskManager stores the cache by default, relative to the working
+// directory
+const DEFAULT_DIR: &str = "./http-cacache";
+
+// Starts every cache key, telling the entries of the crate apart from
+// others in a shared store
+const NAMESPACE: &str = "okta-jwt-verifier";
+
+/// Configures the disk cache of the `cache-surf` and `cache-reqwest`
+/// features, or the memory cache along with `cache-memory`, see
+/// [`Config::cache`]. The default follows the caching headers of the keys
+/// endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct CacheConfig {
+    /// How the cache is consulted, by default [`CacheMode::Default`],
+    /// which revalidates stale keys with the keys endpoint. Modes that
+    /// ignore staleness, such as [`CacheMode::ForceCache`], also answer
+    /// the retrievals made for an unknown kid from the cache, so a
+    /// rotation is only picked up once the cached keys are gone.
+    pub mode: CacheMode,
+    /// Overrides the freshness rules and the cache key, by default
+    /// nothing is overridden. A `cache_key` is still scoped to the issuer,
+    /// see [`cache_key`].
+    pub options: HttpCacheOptions,
+    /// The directory the cache is stored in, e.g. the one writable volume
+    /// of a read-only container. It's created when missing, and a
+    /// directory that can't be created or written to fails the
+    /// construction of the Verifier with [`Error::CacheUnavailable`]. By
+    /// default `http-cacache` in the working directory. Unused with the
+    /// `cache-memory` feature, which never writes to disk, and with a
+    /// [`CacheStore::Custom`] store.
+    pub dir: Option<PathBuf>,
+    /// Where the cached responses are kept, by default
+    /// [`CacheStore::BuiltIn`].
+    pub store: CacheStore,
+    /// The responses kept by the `cache-memory` feature. By default an
+    /// empty [`MemoryStore`] of its own for every Config, shared by its
+    /// clones.
+    #[cfg(feature = "cache-memory")]
+    pub memory: MemoryStore,
+}
+
+impl CacheConfig {
+    fn dir(&self) -> &Path {
+        self.dir.as_deref().unwrap_or(Path::new(DEFAULT_DIR))
+    }
+}
+
+/// Where the cache of the `cache-surf` and `cache-reqwest` features keeps
+/// the responses of the keys endpoint, see [`CacheConfig::store`]. Without
+/// either feature nothing is cached and there is no store to pick, the
+/// keys are only revalidated in memory.
+#[derive(Clone, Default)]
+pub enum CacheStore {
+    /// The store of the crate, on disk in [`CacheConfig::dir`], or in
+    /// memory with the `cache-memory` feature.
+    #[default]
+    BuiltIn,
+    /// A [`CacheManager`] supplied by the application, e.g. one writing to
+    /// a shared cache layer. [`CacheConfig::mode`] and
+    /// [`CacheConfig::options`] still apply, while [`CacheConfig::dir`]
+    /// and the `cache-memory` feature are ignored. The manager is shared
+    /// by every retrieval, including those of clones of the Verifier.
+    Custom(Arc<dyn CacheManager>),
+}
+
+impl CacheStore {
+    /// `custom` wraps the given manager in a [`CacheStore::Custom`].
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{CacheConfig, CacheStore, Config};
+    /// # use okta_jwt_verifier::{CacheManager, CachePolicy, HttpResponse};
+    /// # type Result<T> =
+    /// #     std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+    /// # struct CompanyCache;
+    /// # #[async_trait::async_trait]
+    /// # impl CacheManager for CompanyCache {
+    /// #     async fn get(
+    /// #         &self,
+    /// #         _: &str,
+    /// #     ) -> Result<Option<(HttpResponse, CachePolicy)>> {
+    /// #         Ok(None)
+    /// #     }
+    /// #     async fn put(
+    /// #         &self,
+    /// #         _: String,
+    /// #         res: HttpResponse,
+    /// #         _: CachePolicy,
+    /// #     ) -> Result<HttpResponse> {
+    /// #         Ok(res)
+    /// #     }
+    /// #     async fn delete(&self, _: &str) -> Result<()> {
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    ///
+    /// let config = Config {
+    ///     cache: CacheConfig {
+    ///         store: CacheStore::custom(CompanyCache),
+    ///         ..CacheConfig::default()
+    ///     },
+    ///     ..Config::default()
+    /// };
+    /// ```
+    pub fn custom(manager: impl CacheManager) -> Self {
+        Self::Custom(Arc::new(manager))
+    }
+}
+
+impl fmt::Debug for CacheStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BuiltIn => f.write_str("BuiltIn"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+// Creates the cache directory if needed and makes sure it can be written
+// to, rather than letting every retrieval fail on it later. Nothing is
+// cached when cache-surf is left without its client.
+pub(crate) fn prepare(config: &Config) -> Result<()> {
+    if !CACHING
+        || cfg!(feature = "cache-memory")
+        || matches!(config.cache.store, CacheStore::Custom(_))
+    {
+        return Ok(());
+    }
+    let dir = config.cache.dir();
+    let unavailable = |e: std::io::Error| Error::CacheUnavailable {
+        path: dir.display().to_string(),
+        reason: e.to_string(),
+    };
+    std::fs::create_dir_all(dir).map_err(unavailable)?;
+    let probe = dir.join(format!(".write-check-{}", std::process::id()));
+    std::fs::write(&probe, b"").map_err(unavailable)?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+/// `cache_key` returns the key the response of the url is cached under
+/// when it's requested for the issuer, `okta-jwt-verifier:{issuer}:GET:{url}`
+/// with the full url, query included. Keys are scoped to the issuer so
+/// that issuers sharing a keys url, e.g. through an absolute
+/// [`Config::keys_endpoint`](crate::Config::keys_endpoint), never answer
+/// each other's retrievals from the cache. Requests that aren't made for
+/// an issuer, such as those of a
+/// [`DenylistSource::Url`](crate::DenylistSource::Url), are cached under
+/// `okta-jwt-verifier:GET:{url}`. A `cache_key` of
+/// [`CacheConfig::options`] replaces the `GET:{url}` part.
+///
+/// ```
+/// use okta_jwt_verifier::cache_key;
+///
+/// let issuer = "https://your.okta.com";
+/// let url = "https://your.okta.com/oauth2/v1/keys";
+/// assert_eq!(
+///     cache_key(issuer, url),
+///     format!("okta-jwt-verifier:{issuer}:GET:{url}")
+/// );
+/// ```
+pub fn cache_key(issuer: &str, url: &str) -> String {
+    scoped_key(Some(issuer), &format!("GET:{url}"))
+}
+
+fn scoped_key(issuer: Option<&str>, key: &str) -> String {
+    match issuer {
+        Some(issuer) => format!("{NAMESPACE}:{issuer}:{key}"),
+        None => format!("{NAMESPACE}:{key}"),
+    }
+}
+
+// The cache middleware of the client, as configured, caching the
+// responses under the keys of the issuer
+pub(crate) fn http_cache(
+    config: &Config,
+    issuer: Option<&str>,
+) -> HttpCache<Manager> {
+    let manager = match &config.cache.store {
+        CacheStore::BuiltIn => Manager::BuiltIn(built_in(config)),
+        CacheStore::Custom(manager) => Manager::Custom(manager.clone()),
+    };
+    let mut options = config.cache.options.clone();
+    let custom = options.cache_key.take();
+    let issuer = issuer.map(str::to_string);
+    options.cache_key = Some(Arc::new(move |parts| {
+        key_of(custom.as_deref(), issuer.as_deref(), parts)
+    }));
+    HttpCache { mode: config.cache.mode, manager, options }
+}
+
+// The key of the request, given by the cache_key of the options if any,
+// scoped to the issuer
+fn key_of<K>(
+    custom: Option<&K>,
+    issuer: Option<&str>,
+    parts: &http::request::Parts,
+) -> String
+where
+    K: Fn(&http::request::Parts) -> String + ?Sized,
+{
+    let key = match custom {
+        Some(custom) => custom(parts),
+        None => format!("{}:{}", parts.method, parts.uri),
+    };
+    scoped_key(issuer, &key)
+}
+
+// The key the response of the url is cached under for the issuer by
+// http_cache, with the cache_key of the options applied
+pub(crate) fn configured_key(
+    config: &Config,
+    issuer: &str,
+    url: &str,
+) -> Result<String> {
+    let (parts, ()) = http::Request::get(url).body(())?.into_parts();
+    let custom = config.cache.options.cache_key.as_deref();
+    Ok(key_of(custom, Some(issuer), &parts))
+}
+
+// Deletes the response of the url cached for the issuer from the store in
+// use, under the key http_cache gives it, so that the next retrieval
+// reaches the url
+pub(crate) async fn clear(
+    config: &Config,
+    issuer: &str,
+    url: &str,
+) -> Result<()> {
+    let key = configured_key(config, issuer, url)?;
+    let cache = http_cache(config, Some(issuer));
+    cache.manager.delete(&key).await.map_err(|e| anyhow::anyhow!(e))
+}
+
+#[cfg(not(feature = "cache-memory"))]
+type BuiltIn = DiskManager;
+
+#[cfg(not(feature = "cache-memory"))]
+fn built_in(config: &Config) -> BuiltIn {
+    DiskManager { path: config.cache.dir().to_path_buf() }
+}
+
+#[cfg(feature = "cache-memory")]
+type BuiltIn = MemoryManager;
+
+#[cfg(feature = "cache-memory")]
+fn built_in(config: &Config) -> BuiltIn {
+    MemoryManager(config.cache.memory.clone())
+}
+
+// The manager handed to the cache middleware, one of the crate or the one
+// of the application
+#[derive(Clone)]
+pub(crate) enum Manager {
+    BuiltIn(BuiltIn),
+    Custom(Arc<dyn CacheManager>),
+}
+
+// The results of a CacheManager, whose errors are boxed
+type ManagerResult<T> =
+    std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[async_trait::async_trait]
+impl CacheManager for Manager {
+    async fn get(
+        &self,
+        cache_key: &str,
+    ) -> ManagerResult<Option<(HttpResponse, CachePolicy)>> {
+        match self {
+            Self::BuiltIn(manager) => manager.get(cache_key).await,
+            Self::Custom(manager) => manager.get(cache_key).await,
+        }
+    }
+
+    async fn put(
+        &self,
+        cache_key: String,
+        response: HttpResponse,
+        policy: CachePolicy,
+    ) -> ManagerResult<HttpResponse> {
+        match self {
+            Self::BuiltIn(manager) => {
+                manager.put(cache_key, response, policy).await
+            }
+            Self::Custom(manager) => {
+                manager.put(cache_key, response, policy).await
+            }
+        }
+    }
+
+    async fn delete(&self, cache_key: &str) -> ManagerResult<()> {
+        match self {
+            Self::BuiltIn(manager) => manager.delete(cache_key).await,
+            Self::Custom(manager) => manager.delete(cache_key).await,
+        }
+    }
+}
+
+// Keeps the cache on disk in the directory, a file per entry written with
+// blocking calls off the task, so that it works with the runtime of either
+// client
+#[cfg(not(feature = "cache-memory"))]
+#[derive(Debug, Clone)]
+pub(crate) struct DiskManager {
+    path: PathBuf,
+}
+
+// An entry of the disk cache
+#[cfg(not(feature = "cache-memory"))]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Stored {
+    response: HttpResponse,
+    policy: CachePolicy,
+}
+
+#[cfg(not(feature = "cache-memory"))]
+#[async_trait::async_trait]
+impl CacheManager for DiskManager {
+    async fn get(
+        &self,
+        cache_key: &str,
+    ) -> ManagerResult<Option<(HttpResponse, CachePolicy)>> {
+        let path = snapshot::path_of(&self.path, cache_key);
+        let bytes = match runtime::unblock(move || std::fs::read(path)).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // An entry this release can't read is a miss, e.g. after an upgrade
+        let stored = serde_json::from_slice::<Stored>(&bytes).ok();
+        Ok(stored.map(|Stored { response, policy }| (response, policy)))
+    }
+
+    async fn put(
+        &self,
+        cache_key: String,
+        response: HttpResponse,
+        policy: CachePolicy,
+    ) -> ManagerResult<HttpResponse> {
+        let stored = Stored { response, policy };
+        let bytes = serde_json::to_vec(&stored)?;
+        let path = snapshot::path_of(&self.path, &cache_key);
+        runtime::unblock(move || snapshot::replace(&path, &bytes))
+            .await
+            .map_err(|e| e.into_boxed_dyn_error())?;
+        Ok(stored.response)
+    }
+
+    async fn delete(&self, cache_key: &str) -> ManagerResult<()> {
+        let path = snapshot::path_of(&self.path, cache_key);
+        match runtime::unblock(move || std::fs::remove_file(path)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The responses kept in memory by the `cache-memory` feature, see
+/// [`CacheConfig::memory`]. Clones share the entries, so a Config and its
+/// clones share the cache, while separately built Configs keep theirs
+/// apart. Beyond its capacity the oldest response is evicted.
+#[cfg(feature = "cache-memory")]
+#[derive(Clone)]
+pub struct MemoryStore {
+    entries: Arc<Mutex<MemoryEntries>>,
+    capacity: usize,
+}
+
+// The responses along with their policies, keyed by the cache key, each
+// numbered in the order it was stored
+#[cfg(feature = "cache-memory")]
+#[derive(Default)]
+struct MemoryEntries {
+    responses: BTreeMap<String, (u64, HttpResponse, CachePolicy)>,
+    stored: u64,
+}
+
+// Enough for the keys and fallback urls of a few hundred issuers
+#[cfg(feature = "cache-memory")]
+const DEFAULT_MEMORY_CAPACITY: usize = 1024;
+
+#[cfg(feature = "cache-memory")]
+impl MemoryStore {
+    /// `with_capacity` constructs an empty store keeping at most the given
+    /// number of responses.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { entries: Arc::default(), capacity }
+    }
+
+    /// The number of responses currently kept.
+    pub fn len(&self) -> usize {
+        self.lock().responses.len()
+    }
+
+    /// Whether no response is kept.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryEntries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(feature = "cache-memory")]
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_MEMORY_CAPACITY)
+    }
+}
+
+#[cfg(feature = "cache-memory")]
+impl fmt::Debug for MemoryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStore")
+            .field("len", &self.len())
+            .field("capacity", &self.capacity)
+            .finish()
+    }
+}
+
+// Keeps the cache in memory, e.g. on read-only filesystems, with the same
+// freshness rules as the disk cache since those are applied by HttpCache
+#[cfg(feature = "cache-memory")]
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryManager(MemoryStore);
+
+#[cfg(feature = "cache-memory")]
+#[async_trait::async_trait]
+impl CacheManager for MemoryManager {
+    async fn get(
+        &self,
+        cache_key: &str,
+    ) -> ManagerResult<Option<(HttpResponse, CachePolicy)>> {
+        let entries = self.0.lock();
+        let entry = entries.responses.get(cache_key);
+        Ok(entry
+            .map(|(_, response, policy)| (response.clone(), policy.clone())))
+    }
+
+    async fn put(
+        &self,
+        cache_key: String,
+        response: HttpResponse,
+        policy: CachePolicy,
+    ) -> ManagerResult<HttpResponse> {
+        let mut entries = self.0.lock();
+        entries.stored += 1;
+        let stored = entries.stored;
+        let responses = &mut entries.responses;
+        responses.insert(cache_key, (stored, response.clone(), policy));
+        while responses.len() > self.0.capacity {
+            let oldest = responses
+                .iter()
+                .min_by_key(|(_, (stored, ..))| *stored)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => responses.remove(&oldest),
+                None => break,
+            };
+        }
+        Ok(response)
+    }
+
+    async fn delete(&self, cache_key: &str) -> ManagerResult<()> {
+        self.0.lock().responses.remove(cache_key);
+        Ok(())
+    }
+}
+
+// Nothing is cached when cache-surf is left without its client
+#[cfg(all(
+    test,
+    any(
+        feature = "cache-reqwest",
+        all(feature = "cache-surf", not(feature = "client-reqwest"))
+    )
+))]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use crate::test_support::*;
+    use crate::Verifier;
+
+    // A manager of the application, counting what the crate asks of it
+    #[derive(Clone, Default)]
+    struct CountingManager(Arc<Counts>);
+
+    #[derive(Default)]
+    struct Counts {
+        entries: Mutex<HashMap<String, (HttpResponse, CachePolicy)>>,
+        puts: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl CacheManager for CountingManager {
+        async fn get(
+            &self,
+            cache_key: &str,
+        ) -> ManagerResult<Option<(HttpResponse, CachePolicy)>> {
+            Ok(self.0.entries.lock().unwrap().get(cache_key).cloned())
+        }
+
+        async fn put(
+            &self,
+            cache_key: String,
+            response: HttpResponse,
+            policy: CachePolicy,
+        ) -> ManagerResult<HttpResponse> {
+            self.0.puts.fetch_add(1, Ordering::SeqCst);
+            self.0
+                .entries
+                .lock()
+                .unwrap()
+                .insert(cache_key, (response.clone(), policy));
+            Ok(response)
+        }
+
+        async fn delete(&self, cache_key: &str) -> ManagerResult<()> {
+            self.0.entries.lock().unwrap().remove(cache_key);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn honors_the_configured_mode_and_options() {
+        let cache = http_cache(&Config::default(), None);
+        assert_eq!(cache.mode, CacheMode::Default);
+        assert!(cache.options.cache_options.is_none());
+
+        let config = Config {
+            cache: CacheConfig {
+                mode: CacheMode::ForceCache,
+                options: HttpCacheOptions {
+                    cache_key: Some(Arc::new(|parts| {
+                        format!("keys:{}", parts.uri)
+                    })),
+                    ..HttpCacheOptions::default()
+                },
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let cache = http_cache(&config, Some("https://your.okta.com"));
+        assert_eq!(cache.mode, CacheMode::ForceCache);
+        let (parts, _) = http::Request::get("https://your.okta.com/v1/keys")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let key = cache.options.cache_key.unwrap();
+        assert_eq!(
+            key(&parts),
+            "okta-jwt-verifier:https://your.okta.com:keys:\
+             https://your.okta.com/v1/keys"
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "cache-memory"))]
+    fn sets_up_the_cache_directory_up_front() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let dir = root.path().join("volume").join("keys");
+        let config = Config {
+            cache: CacheConfig { dir: Some(dir.clone()), ..Default::default() },
+            ..Config::default()
+        };
+        Verifier::lazy_with_config("https://your.okta.com", config.clone())?;
+        assert!(dir.is_dir());
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+        let Manager::BuiltIn(manager) = http_cache(&config, None).manager
+        else {
+            panic!("the built-in store is the default");
+        };
+        assert_eq!(manager.path, dir);
+
+        // A file where the directory should be
+        let file = root.path().join("file");
+        std::fs::write(&file, "")?;
+        let config = Config {
+            cache: CacheConfig { dir: Some(file), ..Default::default() },
+            ..Config::default()
+        };
+        let e = Verifier::lazy_with_config("https://your.okta.com", config)
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(Error::CacheUnavailable { .. })
+        ));
+        assert_eq!(e.downcast_ref::<Error>().unwrap().status_hint(), 500);
+        Ok(())
+    }
+
+    #[cfg(feature = "cache-memory")]
+    #[async_test]
+    async fn caches_the_keys_in_memory() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", "/memory/v1/keys")
+            .with_header("Cache-Control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        // Nothing can be written where the disk cache would be
+        let root = tempfile::tempdir()?;
+        let file = root.path().join("file");
+        std::fs::write(&file, "")?;
+        // The cache outlives the mock server, whose port may be reused
+        let config = Config {
+            keys_endpoint: Some("/memory/v1/keys".into()),
+            cache: CacheConfig {
+                dir: Some(file.clone()),
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        verifier.refresh_keys().await?;
+        verifier.refresh_keys().await?;
+        keys.assert();
+        assert!(file.is_file());
+        Ok(())
+    }
+
+    #[cfg(feature = "cache-memory")]
+    #[async_test]
+    async fn bounds_the_memory_cache_of_each_config() -> Result<()> {
+        let mut servers = Vec::new();
+        for _ in 0..2 {
+            let mut server = mockito::Server::new_async().await;
+            server
+                .mock("GET", "/bounded/v1/keys")
+                .with_header("Cache-Control", "max-age=300")
+                .with_body(keys_body(vec![jwk()]))
+                .create();
+            servers.push(server);
+        }
+        let memory = MemoryStore::with_capacity(1);
+        let config = Config {
+            keys_endpoint: Some("/bounded/v1/keys".into()),
+            cache: CacheConfig { memory: memory.clone(), ..Default::default() },
+            ..Config::default()
+        };
+        let other = Config::default();
+        for server in &servers {
+            Verifier::new_with_config(&server.url(), config.clone()).await?;
+            assert_eq!(memory.len(), 1);
+        }
+        assert!(other.cache.memory.is_empty());
+        Ok(())
+    }
+
+    #[cfg(feature = "cache-memory")]
+    #[async_test]
+    async fn skips_the_memory_cache_for_uncacheable_keys() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", "/no-store/v1/keys")
+            .with_header("Cache-Control", "no-store")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(2)
+            .create();
+        let config = Config {
+            keys_endpoint: Some("/no-store/v1/keys".into()),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        verifier.refresh_keys().await?;
+        keys.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn caches_the_keys_with_a_custom_manager() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", "/custom/v1/keys")
+            .with_header("Cache-Control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let manager = CountingManager::default();
+        // The directory of the built-in store is neither checked nor used
+        let root = tempfile::tempdir()?;
+        let file = root.path().join("file");
+        std::fs::write(&file, "")?;
+        let config = Config {
+            keys_endpoint: Some("/custom/v1/keys".into()),
+            cache: CacheConfig {
+                dir: Some(file),
+                store: CacheStore::custom(manager.clone()),
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        assert_eq!(format!("{:?}", config.cache.store), "Custom");
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        verifier.clone().refresh_keys().await?;
+        keys.assert();
+        assert_eq!(manager.0.puts.load(Ordering::SeqCst), 1);
+        assert_eq!(manager.0.entries.lock().unwrap().len(), 1);
+        verifier.verify::<crate::DefaultClaims>(&token(&server.url())).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn keeps_the_entries_of_each_client_id_apart() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = |server: &mut mockito::ServerGuard, client_id, jwk| {
+            server
+                .mock("GET", "/oauth2/v1/keys")
+                .match_query(mockito::Matcher::UrlEncoded(
+                    "client_id".into(),
+                    String::from(client_id),
+                ))
+                .with_header("Cache-Control", "max-age=300")
+                .with_body(keys_body(vec![jwk]))
+                .expect(1)
+                .create()
+        };
+        let first_keys = keys(&mut server, "first", jwk());
+        let second_keys = keys(&mut server, "second", rotated_jwk());
+        let manager = CountingManager::default();
+        // Two apps of the org authorization server in one process
+        let config = |client_id: &str| Config {
+            keys_client_id: Some(client_id.into()),
+            cache: CacheConfig {
+                store: CacheStore::custom(manager.clone()),
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let issuer = server.url();
+        let first = Verifier::new_with_config(&issuer, config("first")).await?;
+        let second =
+            Verifier::new_with_config(&issuer, config("second")).await?;
+        first_keys.assert();
+        second_keys.assert();
+        first.verify::<crate::DefaultClaims>(&token(&issuer)).await?;
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&issuer));
+        second.verify::<crate::DefaultClaims>(&rotated).await?;
+
+        assert_ne!(first.cache_key()?, second.cache_key()?);
+        assert!(first.cache_key()?.ends_with("?client_id=first"));
+        let mut keys: Vec<_> =
+            manager.0.entries.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, [first.cache_key()?, second.cache_key()?]);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn keeps_the_entries_of_each_issuer_apart() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let first_keys = server
+            .mock("GET", "/shared/keys?client_id=app")
+            .with_header("Cache-Control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let manager = CountingManager::default();
+        // Two authorization servers whose keys are served at the same url
+        let url = format!("{}/shared/keys", server.url());
+        let config = Config {
+            keys_endpoint: Some(url.clone()),
+            allow_absolute_keys_endpoint: true,
+            keys_client_id: Some("app".into()),
+            cache: CacheConfig {
+                store: CacheStore::custom(manager.clone()),
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let first = format!("{}/oauth2/first", server.url());
+        let second = format!("{}/oauth2/second", server.url());
+        let verifier =
+            Verifier::new_with_config(&first, config.clone()).await?;
+        first_keys.assert();
+
+        first_keys.remove();
+        let second_keys = server
+            .mock("GET", "/shared/keys?client_id=app")
+            .with_header("Cache-Control", "max-age=300")
+            .with_body(keys_body(vec![rotated_jwk()]))
+            .expect(1)
+            .create();
+        let other = Verifier::new_with_config(&second, config).await?;
+        second_keys.assert();
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&second));
+        other.verify::<crate::DefaultClaims>(&rotated).await?;
+        assert!(other
+            .verify::<crate::DefaultClaims>(&token(&second))
+            .await
+            .is_err());
+        // The first issuer still answers from its own entry
+        verifier.refresh_keys().await?;
+        verifier.verify::<crate::DefaultClaims>(&token(&first)).await?;
+        second_keys.assert();
+
+        let url = format!("{url}?client_id=app");
+        let mut keys: Vec<_> =
+            manager.0.entries.lock().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, [cache_key(&first, &url), cache_key(&second, &url)]);
+        assert_eq!(verifier.cache_key()?, cache_key(&first, &url));
+
+        // Purging one issuer leaves the other alone
+        manager.delete(&verifier.cache_key()?).await.unwrap();
+        let left: Vec<_> =
+            manager.0.entries.lock().unwrap().keys().cloned().collect();
+        assert_eq!(left, [cache_key(&second, &url)]);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn clears_the_cached_keys() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", "/clear/v1/keys")
+            .with_header("Cache-Control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let dir = tempfile::tempdir()?;
+        let config = Config {
+            keys_endpoint: Some("/clear/v1/keys".into()),
+            cache: CacheConfig {
+                dir: Some(dir.path().to_path_buf()),
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        // Answered from the cache
+        verifier.refresh_keys().await?;
+        keys.assert();
+
+        keys.remove();
+        let rotated = server
+            .mock("GET", "/clear/v1/keys")
+            .with_header("Cache-Control", "max-age=300")
+            .with_body(keys_body(vec![rotated_jwk()]))
+            .expect(1)
+            .create();
+        verifier.clear_cache().await?;
+        verifier.refresh_keys().await?;
+        rotated.assert();
+        let token =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        verifier.verify::<crate::DefaultClaims>(&token).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn clears_the_keys_under_the_configured_cache_key() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/custom/v1/keys")
+            .with_header("Cache-Control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .create();
+        let manager = CountingManager::default();
+        let config = Config {
+            keys_endpoint: Some("/custom/v1/keys".into()),
+            cache: CacheConfig {
+                store: CacheStore::custom(manager.clone()),
+                options: HttpCacheOptions {
+                    cache_key: Some(Arc::new(|parts| {
+                        format!("keys:{}", parts.uri)
+                    })),
+                    ..HttpCacheOptions::default()
+                },
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        let keys: Vec<_> =
+            manager.0.entries.lock().unwrap().keys().cloned().collect();
+        assert_eq!(keys, [verifier.cache_key()?]);
+        assert!(keys[0].ends_with(&format!(":keys:{}", verifier.keys_url()?)));
+
+        verifier.clear_cache().await?;
+        assert!(manager.0.entries.lock().unwrap().is_empty());
+        // Nothing left to delete
+        verifier.clear_cache().await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn clears_the_keys_of_the_fallback_urls() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/down/v1/keys").with_status(500).create();
+        server
+            .mock("GET", "/mirror/keys")
+            .with_header("Cache-Control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .create();
+        let manager = CountingManager::default();
+        let config = Config {
+            keys_endpoint: Some("/down/v1/keys".into()),
+            fallback_keys_urls: vec!["/mirror/keys".into()],
+            cache: CacheConfig {
+                store: CacheStore::custom(manager.clone()),
+                ..CacheConfig::default()
+            },
+            ..Config::default()
+        };
+        let issuer = server.url();
+        Verifier::new_with_config(&issuer, config.clone()).await?;
+        let mirror = cache_key(&issuer, &format!("{issuer}/mirror/keys"));
+        assert!(manager.0.entries.lock().unwrap().contains_key(&mirror));
+
+        let verifier = Verifier::lazy_with_config(&issuer, config)?;
+        verifier.clear_cache().await?;
+        assert!(manager.0.entries.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
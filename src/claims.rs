@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Describes the default claims inside a decoded token
+///
+/// Claims Okta adds over time, such as `idp`, become new fields, so the
+/// struct is non-exhaustive: outside of the crate it's obtained by
+/// deserializing a token rather than by a struct literal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct DefaultClaims {
+    /// The Issuer Identifier of the response.
+    /// This value is the unique identifier for the Authorization Server instance.
+    pub iss: String,
+    /// The subject of the token.
+    pub sub: String,
+    /// Array of scopes that are granted to this access token.
+    pub scp: Option<Vec<String>>,
+    /// Client ID of the client that requested the access token.
+    pub cid: Option<String>,
+    /// A unique identifier for the user.
+    /// It isn't included in the access token if there is no user bound to it.
+    pub uid: Option<String>,
+    /// The time the access token expires, represented in Unix time (seconds).
+    pub exp: u64,
+    /// The time the access token was issued, represented in Unix time (seconds).
+    pub iat: u64,
+    /// The groups the user belongs to.
+    /// Only included if a groups claim is configured on the authorization server.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+    /// The id of the identity provider that authenticated the user, e.g.
+    /// a federated SAML or OIDC provider, or the Okta org itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idp: Option<String>,
+    /// Any other claims in the token, such as `aud`, `jti`, or custom claims.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>,
+}
+
+/// Helpers for inspecting the claims Okta includes in its tokens.
+///
+/// Absent claims are treated as empty, and all comparisons are case
+/// sensitive. Implement it for a custom claims struct to gain the
+/// same helpers.
+///
+/// ```no_run
+/// use okta_jwt_verifier::{DefaultClaims, OktaClaims, Verifier};
+///
+/// #[async_std::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let token = "token";
+///     let issuer = "https://your.domain/oauth2/default";
+///
+///     let claims = Verifier::new(&issuer)
+///         .await?
+///         .verify::<DefaultClaims>(&token)
+///         .await?
+///         .claims;
+///     if claims.has_scope("admin") {
+///         // ...
+///     }
+///     Ok(())
+/// }
+///```
+pub trait OktaClaims {
+    /// The scopes granted to the token.
+    fn scopes(&self) -> &[String];
+
+    /// The groups the user belongs to.
+    fn groups(&self) -> &[String] {
+        &[]
+    }
+
+    /// The id of the identity provider that authenticated the user.
+    fn idp(&self) -> Option<&str> {
+        None
+    }
+
+    /// Claims not covered by a dedicated accessor.
+    fn extra_claims(&self) -> Option<&HashMap<String, Value>> {
+        None
+    }
+
+    /// Whether the given scope was granted.
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scopes().iter().any(|s| s == scope)
+    }
+
+    /// Whether every one of the given scopes was granted.
+    fn has_all_scopes(&self, scopes: &[&str]) -> bool {
+        scopes.iter().all(|scope| self.has_scope(scope))
+    }
+
+    /// Whether at least one of the given scopes was granted.
+    fn has_any_scope(&self, scopes: &[&str]) -> bool {
+        scopes.iter().any(|scope| self.has_scope(scope))
+    }
+
+    /// Whether the user belongs to the given group.
+    fn has_group(&self, group: &str) -> bool {
+        self.groups().iter().any(|g| g == group)
+    }
+
+    /// Looks up one of the extra claims by name.
+    fn claim(&self, name: &str) -> Option<&Value> {
+        self.extra_claims().and_then(|extra| extra.get(name))
+    }
+
+    /// Whether the named extra claim is present.
+    fn has_claim(&self, name: &str) -> bool {
+        self.claim(name).is_some()
+    }
+
+    /// The number of extra claims present.
+    fn claim_count(&self) -> usize {
+        self.extra_claims().map_or(0, HashMap::len)
+    }
+}
+
+impl OktaClaims for DefaultClaims {
+    fn scopes(&self) -> &[String] {
+        self.scp.as_deref().unwrap_or_default()
+    }
+
+    fn groups(&self) -> &[String] {
+        self.groups.as_deref().unwrap_or_default()
+    }
+
+    fn idp(&self) -> Option<&str> {
+        self.idp.as_deref()
+    }
+
+    fn extra_claims(&self) -> Option<&HashMap<String, Value>> {
+        Some(&self.extra)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn claims(value: Value) -> DefaultClaims {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn minimal() -> Value {
+        json!({"iss": "https://issuer", "sub": "user", "exp": 2, "iat": 1})
+    }
+
+    #[test]
+    fn absent_claims_are_empty() {
+        let claims = claims(minimal());
+        assert!(!claims.has_scope("openid"));
+        assert!(claims.has_all_scopes(&[]));
+        assert!(!claims.has_any_scope(&["openid"]));
+        assert!(!claims.has_group("Everyone"));
+        assert!(!claims.has_claim("email"));
+        assert_eq!(claims.claim_count(), 0);
+        assert_eq!(claims.idp(), None);
+    }
+
+    #[test]
+    fn idp_has_a_dedicated_accessor() {
+        let mut value = minimal();
+        value["idp"] = json!("0oa1partneridp");
+        value["external_id"] = json!("abc");
+        let claims = claims(value);
+        assert_eq!(claims.idp(), Some("0oa1partneridp"));
+        assert!(!claims.has_claim("idp"));
+        assert!(claims.has_claim("external_id"));
+    }
+
+    #[test]
+    fn scopes_and_groups_are_matched_exactly() {
+        let mut value = minimal();
+        value["scp"] = json!(["openid", "Admin"]);
+        value["groups"] = json!(["Everyone"]);
+        let claims = claims(value);
+        assert!(claims.has_scope("openid"));
+        assert!(claims.has_scope("Admin"));
+        assert!(!claims.has_scope("admin"));
+        assert!(claims.has_all_scopes(&["openid", "Admin"]));
+        assert!(!claims.has_all_scopes(&["openid", "profile"]));
+        assert!(claims.has_any_scope(&["profile", "openid"]));
+        assert!(claims.has_group("Everyone"));
+        assert!(!claims.has_group("everyone"));
+    }
+
+    #[test]
+    fn extra_claims_are_collected() {
+        let mut value = minimal();
+        value["aud"] = json!("api://default");
+        value["email"] = json!("user@example.com");
+        let claims = claims(value);
+        assert_eq!(claims.claim_count(), 2);
+        assert!(claims.has_claim("email"));
+        assert!(!claims.has_claim("Email"));
+        assert_eq!(claims.claim("aud"), Some(&json!("api://default")));
+        // dedicated fields are not duplicated into the extras
+        assert!(!claims.has_claim("sub"));
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::Config;
+
+/// Tells the current time to the checks the crate applies to the claims
+/// itself, see [`Config::clock`]. Implemented for closures returning a
+/// `SystemTime`.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> SystemTime;
+}
+
+impl<F: Fn() -> SystemTime + Send + Sync> Clock for F {
+    fn now(&self) -> SystemTime {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+// The current time in seconds since the epoch, as told by the configured
+// clock or the system
+pub(crate) fn unix_now(config: &Config) -> u64 {
+    let now = config.clock.as_ref().map_or_else(SystemTime::now, |c| c.now());
+    now.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,39 @@
+use subtle::ConstantTimeEq;
+
+/// `secure_compare` tells whether two values are equal in a time that
+/// doesn't depend on where they differ, e.g. for comparing a nonce or a
+/// hash in a validation hook. The crate compares certificate thumbprints
+/// this way. Values of different lengths are unequal right away, so only
+/// their length isn't kept secret.
+///
+/// ```
+/// use okta_jwt_verifier::secure_compare;
+///
+/// assert!(secure_compare("n-0S6_WzA2Mj", "n-0S6_WzA2Mj"));
+/// assert!(!secure_compare("n-0S6_WzA2Mj", "n-0S6_WzA2Mk"));
+/// ```
+pub fn secure_compare(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    a.as_ref().ct_eq(b.as_ref()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_like_equality() {
+        let cases: [(&str, &str); 6] = [
+            ("", ""),
+            ("nonce", "nonce"),
+            ("nonce", "nonc3"),
+            ("nonce", "nonce-longer"),
+            ("nonce", ""),
+            ("NONCE", "nonce"),
+        ];
+        for (a, b) in cases {
+            assert_eq!(secure_compare(a, b), a == b, "{a} {b}");
+            assert_eq!(secure_compare(b, a), a == b, "{b} {a}");
+        }
+        assert!(secure_compare(vec![0xab; 32], [0xab; 32]));
+    }
+}
@@ -0,0 +1,177 @@
+// Adapters for code written against the `key` and `token` modules and the
+// `verify` function of earlier releases, built on top of the current
+// internals. `key::get` and `token::decode` keep the signatures built around
+// the `Keys { jwks, max_age }` shape, while the `JWK`, `JWKS`, `key_id` and
+// `verify` items of the 0.3 releases are kept alongside them. Only compiled
+// with the `compat` feature and deprecated from the start, so that every
+// remaining use shows up as a warning while migrating.
+
+/// Retrieving the keys of an issuer, see [`Verifier`](crate::Verifier)
+/// for the replacement.
+pub mod key {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use jsonwebtoken::jwk::JwkSet;
+    use serde::{Deserialize, Serialize};
+
+    use crate::{Config, DEFAULT_ENDPOINT};
+
+    /// The keys of an issuer as retrieved from its keys endpoint
+    #[deprecated(note = "use Verifier, which retrieves and caches the keys")]
+    #[derive(Debug, Clone)]
+    pub struct Keys {
+        /// The retrieved JWKS document.
+        pub jwks: JwkSet,
+        /// How long the keys may be cached, the max-age of the
+        /// Cache-Control header sent along with them.
+        pub max_age: Option<Duration>,
+    }
+
+    /// Describes the key retrieved from upstream
+    #[deprecated(note = "use Verifier, which retrieves and caches the keys")]
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct JWK {
+        /// The "kty" (key type) parameter identifies the cryptographic
+        /// algorithm family used with the key, such as "RSA" or "EC".
+        pub kty: String,
+        /// The "alg" (algorithm) parameter identifies the algorithm
+        /// intended for use with the key.
+        pub alg: String,
+        /// The "kid" (key ID) parameter is used to match a specific key.
+        pub kid: String,
+        /// The "use" (public key use) parameter identifies the intended use
+        /// of the public key.
+        #[serde(rename = "use")]
+        pub uses: String,
+        /// RSA public exponent is used on signed / encoded data to decode
+        /// the original value
+        pub e: String,
+        /// RSA modulus is the product of two prime numbers used to generate
+        /// the key pair
+        pub n: String,
+    }
+
+    /// Container for keys
+    #[deprecated(note = "use Verifier, which retrieves and caches the keys")]
+    #[allow(deprecated)]
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct JWKS {
+        inner: HashMap<String, JWK>,
+    }
+
+    #[allow(deprecated)]
+    impl JWKS {
+        /// Attempts to retrieve a key by given id
+        pub fn where_id(&self, kid: &str) -> Option<&JWK> {
+            self.inner.get(kid)
+        }
+    }
+
+    // Keys lacking one of the fields of a JWK, e.g. without an alg, can't
+    // be looked up by their id
+    #[allow(deprecated)]
+    impl From<&Keys> for JWKS {
+        fn from(keys: &Keys) -> Self {
+            let inner = keys
+                .jwks
+                .keys
+                .iter()
+                .filter_map(|key| serde_json::to_value(key).ok())
+                .filter_map(|key| serde_json::from_value::<JWK>(key).ok())
+                .map(|jwk| (jwk.kid.clone(), jwk))
+                .collect();
+            Self { inner }
+        }
+    }
+
+    /// `get` retrieves the keys of the issuer from the keys endpoint, a
+    /// path appended to the issuer that defaults to `/v1/keys`.
+    #[deprecated(note = "use Verifier::new or Verifier::new_with_config")]
+    #[allow(deprecated)]
+    pub async fn get(
+        issuer: &str,
+        keys_endpoint: Option<&str>,
+    ) -> Result<Keys> {
+        let config = Config {
+            keys_endpoint: Some(
+                keys_endpoint.unwrap_or(DEFAULT_ENDPOINT).into(),
+            ),
+            ..Config::default()
+        };
+        let (jwks, fetch) =
+            crate::get(issuer, &config, None, &Default::default()).await?;
+        Ok(Keys {
+            jwks: serde_json::from_value(serde_json::to_value(jwks)?)?,
+            max_age: fetch.max_age,
+        })
+    }
+}
+
+/// Decoding tokens with keys retrieved by [`key::get`], see
+/// [`Verifier::verify`](crate::Verifier::verify) for the replacement.
+pub mod token {
+    use std::sync::Arc;
+
+    use anyhow::{bail, Result};
+    use jsonwebtoken::{Header, TokenData};
+    use serde::de::DeserializeOwned;
+
+    #[allow(deprecated)]
+    use super::key::Keys;
+    use crate::{Config, Never, Verifier};
+
+    pub use crate::DefaultClaims;
+
+    /// Attempts to retrieve a key id for a given token
+    #[deprecated(note = "use inspect::header, which reads the whole header")]
+    pub fn key_id(token: &str) -> Result<String> {
+        match jsonwebtoken::decode_header(token)? {
+            Header { kid: Some(kid), .. } => Ok(kid),
+            _ => bail!("No key id found!"),
+        }
+    }
+
+    /// `decode` verifies the token against the given keys and issuer,
+    /// without retrieving the keys again for an unknown kid.
+    #[deprecated(note = "use Verifier::verify")]
+    #[allow(deprecated)]
+    pub async fn decode<T>(
+        token: &str,
+        issuer: &str,
+        keys: &Keys,
+    ) -> Result<TokenData<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let config = Config {
+            refresh_policy: Some(Arc::new(Never)),
+            ..Config::default()
+        };
+        let jwks = serde_json::to_string(&keys.jwks)?;
+        Verifier::with_keys_and_config(issuer, &jwks, config)?
+            .verify::<T>(token)
+            .await
+    }
+}
+
+use anyhow::{bail, Result};
+use jsonwebtoken::TokenData;
+use serde::de::DeserializeOwned;
+
+/// Accepts an issuer and token, attempts key retrieval,
+/// then attempts to decode a token
+#[deprecated(note = "use Verifier::new followed by Verifier::verify")]
+#[allow(deprecated)]
+pub async fn verify<T>(issuer: &str, token: &str) -> Result<TokenData<T>>
+where
+    T: DeserializeOwned,
+{
+    let kid = token::key_id(token)?;
+    let keys = key::get(issuer, None).await?;
+    match key::JWKS::from(&keys).where_id(&kid) {
+        Some(_) => token::decode::<T>(token, issuer, &keys).await,
+        None => bail!("No matching key found!"),
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::Result;
+use jsonwebtoken::{Header, TokenData};
+use serde::{Deserialize, Serialize};
+
+use crate::inspect::{alg_name, TokenHeader};
+
+/// The header and claims of a verified token, like jsonwebtoken's
+/// [`TokenData`] but cloneable and serializable, e.g. to store it in the
+/// request extensions or hand it to a logging task. Converts from and to
+/// [`TokenData`] without losing anything.
+///
+/// ```
+/// use jsonwebtoken::{Algorithm, Header, TokenData};
+/// use okta_jwt_verifier::DecodedToken;
+///
+/// let header = Header { kid: Some("k1".into()), ..Header::new(Algorithm::RS256) };
+/// let decoded = DecodedToken::from(TokenData { header, claims: "claims" });
+/// assert_eq!(decoded.header.alg, "RS256");
+/// let token_data = TokenData::try_from(decoded.clone())?;
+/// assert_eq!(token_data.header.kid.as_deref(), Some("k1"));
+/// # Ok::<(), anyhow::Error>(())
+///```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DecodedToken<T> {
+    /// The decoded header.
+    pub header: TokenHeader,
+    /// The decoded claims.
+    pub claims: T,
+}
+
+impl From<Header> for TokenHeader {
+    fn from(header: Header) -> Self {
+        Self {
+            alg: alg_name(header.alg).to_string(),
+            kid: header.kid,
+            typ: header.typ,
+            x5t: header.x5t,
+            x5t_s256: header.x5t_s256,
+            cty: header.cty,
+            jku: header.jku,
+            jwk: header.jwk,
+            x5u: header.x5u,
+            x5c: header.x5c,
+        }
+    }
+}
+
+impl TryFrom<TokenHeader> for Header {
+    type Error = anyhow::Error;
+
+    /// Fails with [`Error::MalformedToken`](crate::Error::MalformedToken)
+    /// when jsonwebtoken doesn't know the algorithm.
+    fn try_from(header: TokenHeader) -> Result<Self> {
+        Ok(Self {
+            alg: header.algorithm()?,
+            kid: header.kid,
+            typ: header.typ,
+            x5t: header.x5t,
+            x5t_s256: header.x5t_s256,
+            cty: header.cty,
+            jku: header.jku,
+            jwk: header.jwk,
+            x5u: header.x5u,
+            x5c: header.x5c,
+        })
+    }
+}
+
+impl<T> From<TokenData<T>> for DecodedToken<T> {
+    fn from(token_data: TokenData<T>) -> Self {
+        Self { header: token_data.header.into(), claims: token_data.claims }
+    }
+}
+
+impl<T> TryFrom<DecodedToken<T>> for TokenData<T> {
+    type Error = anyhow::Error;
+
+    /// Fails with [`Error::MalformedToken`](crate::Error::MalformedToken)
+    /// when jsonwebtoken doesn't know the algorithm.
+    fn try_from(decoded: DecodedToken<T>) -> Result<Self> {
+        Ok(Self { header: decoded.header.try_into()?, claims: decoded.claims })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsonwebtoken::jwk::Jwk;
+    use jsonwebtoken::Algorithm;
+    use serde_json::{json, Value};
+
+    use crate::Error;
+
+    // A header setting every parameter
+    fn header() -> Header {
+        let jwk: Jwk = serde_json::from_value(json!({
+            "kty": "RSA",
+            "kid": "embedded",
+            "n": "AQAB",
+            "e": "AQAB",
+        }))
+        .unwrap();
+        Header {
+            typ: Some("at+jwt".to_string()),
+            alg: Algorithm::PS384,
+            cty: Some("JWT".to_string()),
+            jku: Some("https://your.domain/keys".to_string()),
+            jwk: Some(jwk),
+            kid: Some("k1".to_string()),
+            x5u: Some("https://your.domain/cert".to_string()),
+            x5c: Some(vec!["MIIC".to_string(), "MIID".to_string()]),
+            x5t: Some("sha1".to_string()),
+            x5t_s256: Some("sha256".to_string()),
+        }
+    }
+
+    #[test]
+    fn converts_without_losing_anything() -> Result<()> {
+        let claims = json!({ "sub": "jane", "scp": ["openid"] });
+        let decoded =
+            DecodedToken::from(TokenData { header: header(), claims });
+        assert_eq!(decoded.header.alg, "PS384");
+        // Both headers serialize to the same JSON, field by field
+        assert_eq!(
+            serde_json::to_value(&decoded.header)?,
+            serde_json::to_value(header())?
+        );
+        let token_data = TokenData::try_from(decoded.clone())?;
+        assert_eq!(token_data.header, header());
+        assert_eq!(token_data.claims, decoded.claims);
+        for alg in [Algorithm::RS256, Algorithm::ES256, Algorithm::EdDSA] {
+            let header = TokenHeader::from(Header::new(alg));
+            assert_eq!(Value::from(header.alg), serde_json::to_value(alg)?);
+        }
+
+        let unknown =
+            TokenHeader { alg: "none".to_string(), ..header().into() };
+        let e = Header::try_from(unknown).unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&Error::MalformedToken));
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_through_serde() -> Result<()> {
+        let decoded = DecodedToken {
+            header: header().into(),
+            claims: json!({ "sub": "jane", "aud": ["a", "b"] }),
+        };
+        let json = serde_json::to_string(&decoded)?;
+        assert_eq!(
+            serde_json::from_str::<DecodedToken<Value>>(&json)?,
+            decoded
+        );
+        let value = serde_json::to_value(&decoded)?;
+        assert_eq!(value["header"]["x5t#S256"], "sha256");
+        assert_eq!(value["claims"]["sub"], "jane");
+
+        // Unset header parameters are left out
+        let minimal: DecodedToken<Value> = DecodedToken {
+            header: Header::new(Algorithm::RS256).into(),
+            claims: json!({}),
+        };
+        assert_eq!(
+            serde_json::to_value(&minimal)?,
+            json!({ "header": { "alg": "RS256", "typ": "JWT" }, "claims": {} })
+        );
+        Ok(())
+    }
+}
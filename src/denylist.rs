@@ -0,0 +1,350 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+use crate::fetch::remote_fetch;
+use crate::{runtime, Config, Error, Verifier};
+
+/// Describes where the list of revoked token ids is published
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DenylistSource {
+    /// A local file, read again on every refresh.
+    File(PathBuf),
+    /// A url retrieved with the same client settings as the keys.
+    Url(String),
+}
+
+// The revoked token ids and when they were last loaded
+#[derive(Debug, Default)]
+struct Loaded {
+    jtis: HashSet<String>,
+    at: Option<Instant>,
+}
+
+// A list of revoked token ids, loaded on first use and again
+// whenever it is older than the refresh interval
+#[derive(Debug)]
+pub(crate) struct Denylist {
+    source: DenylistSource,
+    refresh: Duration,
+    loaded: RwLock<Loaded>,
+    // Held while reloading, so concurrent verifications share one reload
+    reloading: async_lock::Mutex<()>,
+}
+
+impl Denylist {
+    pub(crate) fn new(source: DenylistSource, refresh: Duration) -> Self {
+        Self {
+            source,
+            refresh,
+            loaded: RwLock::new(Loaded::default()),
+            reloading: async_lock::Mutex::new(()),
+        }
+    }
+
+    // Whether the given token id is revoked, the list is reloaded first if
+    // it's due. A failed reload keeps the previous list until the next
+    // refresh, but the very first load has to succeed.
+    pub(crate) async fn contains(
+        &self,
+        jti: &str,
+        config: &Config,
+    ) -> Result<bool> {
+        let since = self.read().at;
+        if since.map_or(true, |at| at.elapsed() >= self.refresh) {
+            let _reloading = self.reloading.lock().await;
+            // Another verification may have reloaded while this one waited
+            if self.read().at == since {
+                self.reload(config).await?;
+            }
+        }
+        Ok(self.read().jtis.contains(jti))
+    }
+
+    async fn reload(&self, config: &Config) -> Result<()> {
+        match self.load(config).await {
+            Ok(jtis) => {
+                *self.write() = Loaded { jtis, at: Some(Instant::now()) }
+            }
+            Err(e) => {
+                let mut loaded = self.write();
+                if loaded.at.is_none() {
+                    return Err(e);
+                }
+                loaded.at = Some(Instant::now());
+            }
+        }
+        Ok(())
+    }
+
+    // Any failure other than the typed ones of a remote fetch is reported
+    // as Error::DenylistUnavailable, so that it isn't taken for a bad token
+    async fn load(&self, config: &Config) -> Result<HashSet<String>> {
+        let body = match &self.source {
+            DenylistSource::File(path) => {
+                let path = path.clone();
+                runtime::unblock(move || std::fs::read(path))
+                    .await
+                    .map_err(|e| self.unavailable(e))?
+            }
+            DenylistSource::Url(url) => {
+                remote_fetch(url, None, config, None).await?.body
+            }
+        };
+        parse(&body).map_err(|e| self.unavailable(e))
+    }
+
+    fn unavailable(&self, reason: impl std::fmt::Display) -> anyhow::Error {
+        let source = match &self.source {
+            DenylistSource::File(path) => path.display().to_string(),
+            DenylistSource::Url(url) => url.clone(),
+        };
+        Error::DenylistUnavailable { source, reason: reason.to_string() }.into()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Loaded> {
+        self.loaded.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Loaded> {
+        self.loaded.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Accepts either a JSON array of strings or one id per line,
+// blank lines and lines starting with # are ignored
+fn parse(body: &[u8]) -> Result<HashSet<String>> {
+    let body = std::str::from_utf8(body)?.trim();
+    if body.starts_with('[') {
+        let jtis: Vec<String> = serde_json::from_str(body)?;
+        return Ok(jtis.into_iter().collect());
+    }
+    Ok(body
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+impl Verifier {
+    /// `with_jti_denylist` rejects tokens whose jti claim appears in a list
+    /// of revoked token ids with [`Error::Revoked`]. The list holds either
+    /// one id per line or a JSON array, is loaded on the first verification
+    /// and again once it is older than `refresh`. If a reload fails the
+    /// previous list stays in use until the next refresh, while a failed
+    /// first load fails the verification with
+    /// [`Error::DenylistUnavailable`], a 503 rather than a bad token. Tokens
+    /// without a jti claim are accepted unless
+    /// [`Verifier::reject_missing_jti`] is enabled.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{DefaultClaims, DenylistSource, Verifier};
+    /// use std::time::Duration;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///     let source =
+    ///         DenylistSource::Url("https://your.domain/revoked.json".into());
+    ///
+    ///     Verifier::new(&issuer)
+    ///         .await?
+    ///         .with_jti_denylist(source, Duration::from_secs(60))
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn with_jti_denylist(
+        mut self,
+        source: DenylistSource,
+        refresh: Duration,
+    ) -> Self {
+        self.denylist = Some(Arc::new(Denylist::new(source, refresh)));
+        self
+    }
+
+    /// `reject_missing_jti` is for rejecting tokens without a jti claim
+    /// while a denylist is configured. By default this is set to false.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{DefaultClaims, DenylistSource, Verifier};
+    /// use std::time::Duration;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///     let source = DenylistSource::File("revoked.txt".into());
+    ///
+    ///     Verifier::new(&issuer)
+    ///         .await?
+    ///         .with_jti_denylist(source, Duration::from_secs(60))
+    ///         .reject_missing_jti(true)
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn reject_missing_jti(mut self, reject: bool) -> Self {
+        self.reject_missing_jti = reject;
+        self
+    }
+
+    // Checks the jti claim against the denylist, if one is configured
+    pub(crate) async fn check_denylist(&self, claims: &Value) -> Result<()> {
+        let Some(denylist) = &self.denylist else {
+            return Ok(());
+        };
+        match claims.get("jti").and_then(Value::as_str) {
+            Some(jti) if denylist.contains(jti, &self.config).await? => {
+                bail!(Error::Revoked)
+            }
+            None if self.reject_missing_jti => {
+                bail!(Error::MissingClaim { claim: "jti".into() })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{DefaultClaims, ErrorResponse};
+
+    #[test]
+    fn parses_lines_and_json_arrays() -> Result<()> {
+        let expected: HashSet<String> =
+            ["a".to_string(), "b".to_string()].into();
+        assert_eq!(parse(b"a\n\n# revoked after incident\n b \n")?, expected);
+        assert_eq!(parse(br#"["a", "b"]"#)?, expected);
+        assert!(parse(b"")?.is_empty());
+        assert!(parse(b"[1, 2]").is_err());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn rejects_revoked_tokens_from_a_file() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), "other\n")?;
+        let token = sign(claims(&server.url()).with_jwt_id("revoked"));
+        let verifier = Verifier::new(&server.url()).await?.with_jti_denylist(
+            DenylistSource::File(file.path().to_path_buf()),
+            Duration::ZERO,
+        );
+        verifier.verify::<DefaultClaims>(&token).await?;
+
+        std::fs::write(file.path(), "other\nrevoked\n")?;
+        let err = verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::Revoked));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn rejects_revoked_tokens_from_a_url() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let m = server
+            .mock("GET", "/revoked.json")
+            .with_status(200)
+            .with_body(r#"["revoked"]"#)
+            .expect(1)
+            .create();
+        let verifier = Verifier::new(&server.url()).await?.with_jti_denylist(
+            DenylistSource::Url(format!("{}/revoked.json", server.url())),
+            Duration::from_secs(60 * 60),
+        );
+        let revoked = sign(claims(&server.url()).with_jwt_id("revoked"));
+        let allowed = sign(claims(&server.url()).with_jwt_id("allowed"));
+        let err = verifier.verify::<DefaultClaims>(&revoked).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::Revoked));
+        verifier.verify::<DefaultClaims>(&allowed).await?;
+        m.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn concurrent_verifications_share_a_reload() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", "/revoked.json")
+            .with_status(200)
+            .with_body(r#"["revoked"]"#)
+            .expect(1)
+            .create();
+        let denylist = Denylist::new(
+            DenylistSource::Url(format!("{}/revoked.json", server.url())),
+            Duration::ZERO,
+        );
+        let config = Config::default();
+        let checks = (0..8).map(|_| denylist.contains("revoked", &config));
+        for revoked in futures::future::join_all(checks).await {
+            assert!(revoked?);
+        }
+        m.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn missing_jti_is_configurable() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let file = tempfile::NamedTempFile::new()?;
+        let verifier = Verifier::new(&server.url()).await?.with_jti_denylist(
+            DenylistSource::File(file.path().to_path_buf()),
+            Duration::ZERO,
+        );
+        let token = token(&server.url());
+        verifier.verify::<DefaultClaims>(&token).await?;
+        let err = verifier
+            .reject_missing_jti(true)
+            .verify::<DefaultClaims>(&token)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::MissingClaim { claim: "jti".into() })
+        );
+        Ok(())
+    }
+
+    #[async_test]
+    async fn first_load_must_succeed() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let dir = tempfile::tempdir()?;
+        let corrupt = dir.path().join("corrupt.json");
+        std::fs::write(&corrupt, b"[\"unterminated")?;
+        let token = sign(claims(&server.url()).with_jwt_id("any"));
+        for path in [dir.path().join("missing.txt"), corrupt] {
+            let verifier =
+                Verifier::new(&server.url()).await?.with_jti_denylist(
+                    DenylistSource::File(path.clone()),
+                    Duration::ZERO,
+                );
+            let err =
+                verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::DenylistUnavailable { source, .. })
+                    if *source == path.display().to_string()
+            ));
+            // A denylist that can't be used is an outage, not a bad token
+            let response = ErrorResponse::new(&err, None);
+            assert_eq!(response.status, 503);
+            assert_eq!(response.www_authenticate, None);
+        }
+        Ok(())
+    }
+}
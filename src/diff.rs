@@ -0,0 +1,296 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{Jwk, Jwks, Verifier, VerifierState};
+
+/// Describes a key without its material, e.g. for reporting rotations
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct KeyInfo {
+    /// The key id.
+    pub kid: String,
+    /// The key type, e.g. `RSA`.
+    pub kty: String,
+    /// The algorithm the key is intended for, e.g. `RS256`.
+    pub alg: String,
+    /// The intended use of the key, e.g. `sig`.
+    #[serde(rename = "use")]
+    pub key_use: String,
+    /// The JWK thumbprint of the key as specified by
+    /// [RFC 7638](https://www.rfc-editor.org/rfc/rfc7638), which changes
+    /// along with the key material.
+    pub thumbprint: String,
+}
+
+impl From<&Jwk> for KeyInfo {
+    fn from(jwk: &Jwk) -> Self {
+        // The required members in lexicographic order, without whitespace
+        let members = format!(
+            r#"{{"e":{},"kty":{},"n":{}}}"#,
+            serde_json::Value::from(jwk.e.as_str()),
+            serde_json::Value::from(jwk.kty.as_str()),
+            serde_json::Value::from(jwk.n.as_str()),
+        );
+        Self {
+            kid: jwk.kid.clone(),
+            kty: jwk.kty.clone(),
+            alg: jwk.alg.clone(),
+            key_use: jwk.uses.clone(),
+            thumbprint: URL_SAFE_NO_PAD.encode(Sha256::digest(members)),
+        }
+    }
+}
+
+/// Describes how one key set differs from another, see [`Jwks::diff`]
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+#[non_exhaustive]
+pub struct KeySetDiff {
+    /// The keys whose kid only the other key set holds.
+    pub added: Vec<KeyInfo>,
+    /// The keys whose kid only this key set holds.
+    pub removed: Vec<KeyInfo>,
+    /// The keys held by both key sets with the same kid but different
+    /// parameters, as this key set and the other one hold them.
+    pub changed: Vec<(KeyInfo, KeyInfo)>,
+}
+
+impl KeySetDiff {
+    /// Whether both key sets hold the same keys.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+impl Jwks {
+    /// `diff` describes how the other key set differs from this one, e.g.
+    /// a key set retrieved earlier compared to the current one. Keys that
+    /// are the same in both are left out, and keys sharing a kid are
+    /// paired up in document order.
+    ///
+    /// ```
+    /// use okta_jwt_verifier::Jwks;
+    ///
+    /// let key = |kid: &str, n: &str| {
+    ///     format!(r#"{{"kty":"RSA","alg":"RS256","kid":"{kid}","use":"sig","e":"AQAB","n":"{n}"}}"#)
+    /// };
+    /// let before: Jwks = serde_json::from_str(&format!(
+    ///     r#"{{"keys":[{},{}]}}"#,
+    ///     key("a", "AQAB"),
+    ///     key("b", "AQAB")
+    /// ))?;
+    /// let after: Jwks = serde_json::from_str(&format!(
+    ///     r#"{{"keys":[{},{}]}}"#,
+    ///     key("b", "AQAC"),
+    ///     key("c", "AQAB")
+    /// ))?;
+    ///
+    /// let diff = before.diff(&after);
+    /// assert_eq!(diff.added[0].kid, "c");
+    /// assert_eq!(diff.removed[0].kid, "a");
+    /// assert_eq!(diff.changed[0].1.kid, "b");
+    /// # Ok::<(), serde_json::Error>(())
+    ///```
+    pub fn diff(&self, other: &Jwks) -> KeySetDiff {
+        let unmatched = |keys: &[Jwk], other: &[Jwk]| -> Vec<Jwk> {
+            let mut other = other.to_vec();
+            keys.iter()
+                .filter(|key| match other.iter().position(|o| o == *key) {
+                    Some(i) => {
+                        other.remove(i);
+                        false
+                    }
+                    None => true,
+                })
+                .cloned()
+                .collect()
+        };
+        let mut before = unmatched(&self.keys, &other.keys);
+        let after = unmatched(&other.keys, &self.keys);
+        let mut diff = KeySetDiff::default();
+        for key in &after {
+            match before.iter().position(|old| old.kid == key.kid) {
+                Some(i) => {
+                    let old = before.remove(i);
+                    diff.changed.push(((&old).into(), key.into()));
+                }
+                None => diff.added.push(key.into()),
+            }
+        }
+        diff.removed = before.iter().map(KeyInfo::from).collect();
+        diff
+    }
+}
+
+impl Verifier {
+    /// `diff_since` describes how the current keys differ from those of a
+    /// snapshot taken earlier with [`Verifier::to_state`], e.g. for a job
+    /// monitoring rotations.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verifier = Verifier::new(&issuer).await?;
+    ///     let snapshot = verifier.to_state();
+    ///     verifier.refresh_keys().await?;
+    ///     let diff = verifier.diff_since(&snapshot);
+    ///     println!("added {:?}, removed {:?}", diff.added, diff.removed);
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn diff_since(&self, snapshot: &VerifierState) -> KeySetDiff {
+        let before = Jwks::from_keys(snapshot.keys().to_vec());
+        before.diff(&self.keys.load().jwks)
+    }
+
+    /// `key_ids` lists the kids of the keys currently trusted, in the
+    /// order of the JWKS document.
+    pub fn key_ids(&self) -> Vec<String> {
+        self.keys.load().jwks.keys.iter().map(|jwk| jwk.kid.clone()).collect()
+    }
+
+    /// `key` describes the currently trusted key with the given kid, e.g.
+    /// to report it next to the kid of a token that failed verification.
+    /// Only the first of several keys sharing the kid is returned.
+    pub fn key(&self, kid: &str) -> Option<KeyInfo> {
+        let keys = self.keys.load();
+        keys.jwks.keys.iter().find(|jwk| jwk.kid == kid).map(KeyInfo::from)
+    }
+
+    /// `key_infos` describes all of the keys currently trusted, in the
+    /// order of the JWKS document, e.g. for an admin endpoint.
+    ///
+    /// ```
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// let keys = r#"{"keys":[{"kty":"RSA","alg":"RS256","kid":"a","use":"sig","e":"AQAB","n":"AQAB"}]}"#;
+    /// let verifier = Verifier::with_keys("https://your.okta.com", keys)?;
+    /// assert_eq!(verifier.key_ids(), ["a"]);
+    /// assert_eq!(verifier.key("a").map(|key| key.alg), Some("RS256".into()));
+    /// assert_eq!(verifier.key_infos().len(), 1);
+    /// # Ok::<(), anyhow::Error>(())
+    ///```
+    pub fn key_infos(&self) -> Vec<KeyInfo> {
+        self.keys.load().jwks.keys.iter().map(KeyInfo::from).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+
+    use crate::test_support::*;
+
+    fn keys(keys: &[Jwk]) -> Jwks {
+        Jwks::from_keys(keys.to_vec())
+    }
+
+    fn with_kid(jwk: Jwk, kid: &str) -> Jwk {
+        Jwk { kid: kid.to_string(), ..jwk }
+    }
+
+    fn kids(infos: &[KeyInfo]) -> Vec<&str> {
+        infos.iter().map(|info| info.kid.as_str()).collect()
+    }
+
+    #[test]
+    fn same_keys_have_no_diff() {
+        let set = keys(&[jwk(), rotated_jwk()]);
+        assert!(set.diff(&set).is_empty());
+        // The order of the keys isn't a change
+        assert!(set.diff(&keys(&[rotated_jwk(), jwk()])).is_empty());
+    }
+
+    #[test]
+    fn reports_added_and_removed_kids() {
+        let before = keys(&[jwk()]);
+        let after = keys(&[jwk(), rotated_jwk()]);
+        let diff = before.diff(&after);
+        assert_eq!(kids(&diff.added), vec![ROTATED_KEY_ID]);
+        assert!(diff.removed.is_empty() && diff.changed.is_empty());
+
+        let diff = after.diff(&before);
+        assert_eq!(kids(&diff.removed), vec![ROTATED_KEY_ID]);
+        assert!(diff.added.is_empty() && diff.changed.is_empty());
+    }
+
+    #[test]
+    fn reports_changed_parameters_of_a_kid() {
+        let reused = with_kid(rotated_jwk(), KEY_ID);
+        let diff = keys(&[jwk()]).diff(&keys(std::slice::from_ref(&reused)));
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        let [(old, new)] = diff.changed.as_slice() else {
+            panic!("expected one changed key: {diff:?}");
+        };
+        assert_eq!((old.kid.as_str(), new.kid.as_str()), (KEY_ID, KEY_ID));
+        assert_ne!(old.thumbprint, new.thumbprint);
+        assert_eq!(new, &KeyInfo::from(&reused));
+
+        let resigned = Jwk { alg: "RS512".into(), ..jwk() };
+        let diff = keys(&[jwk()]).diff(&keys(&[resigned]));
+        let [(old, new)] = diff.changed.as_slice() else {
+            panic!("expected one changed key: {diff:?}");
+        };
+        assert_eq!(old.thumbprint, new.thumbprint);
+        assert_eq!(new.alg, "RS512");
+    }
+
+    #[test]
+    fn computes_the_rfc_7638_thumbprint() {
+        // The example key of RFC 7638 section 3.1
+        let jwk = Jwk {
+            kty: "RSA".into(),
+            e: "AQAB".into(),
+            n: "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw".into(),
+            ..jwk()
+        };
+        assert_eq!(
+            KeyInfo::from(&jwk).thumbprint,
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[async_test]
+    async fn diffs_against_a_snapshot() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        let snapshot = verifier.to_state();
+        assert!(verifier.diff_since(&snapshot).is_empty());
+
+        m.remove();
+        mock_keys(&mut server, vec![rotated_jwk()]);
+        verifier.refresh_keys().await?;
+        let diff = verifier.diff_since(&snapshot);
+        assert_eq!(kids(&diff.added), vec![ROTATED_KEY_ID]);
+        assert_eq!(kids(&diff.removed), vec![KEY_ID]);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn lists_the_trusted_keys() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk(), rotated_jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        assert_eq!(verifier.key_ids(), [KEY_ID, ROTATED_KEY_ID]);
+        let key = verifier.key(KEY_ID).unwrap();
+        assert_eq!(key, KeyInfo::from(&jwk()));
+        assert_eq!((key.kty.as_str(), key.key_use.as_str()), ("RSA", "sig"));
+        assert_eq!(kids(&verifier.key_infos()), [KEY_ID, ROTATED_KEY_ID]);
+
+        m.remove();
+        mock_keys(&mut server, vec![rotated_jwk()]);
+        verifier.refresh_keys().await?;
+        assert_eq!(verifier.key_ids(), [ROTATED_KEY_ID]);
+        assert_eq!(verifier.key(KEY_ID), None);
+        Ok(())
+    }
+}
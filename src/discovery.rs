@@ -0,0 +1,382 @@
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::fetch::remote_fetch;
+use crate::{endpoint_url, logging, set_client_id, Config, Error, Verifier};
+
+// Where the OpenID Connect discovery document is published, relative to
+// the issuer
+pub(crate) const DISCOVERY_PATH: &str = "/.well-known/openid-configuration";
+
+/// The fields of the OpenID Connect discovery document of the issuer that
+/// the Verifier relies on, see [`Config::discovery`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct DiscoveryInfo {
+    /// The issuer the document describes, which has to be the one of the
+    /// Verifier.
+    pub issuer: String,
+    /// The url the keys are retrieved from.
+    pub jwks_uri: String,
+    /// The algorithms the issuer signs ID tokens with, empty when the
+    /// document doesn't say.
+    #[serde(default)]
+    pub id_token_signing_alg_values_supported: Vec<String>,
+}
+
+impl DiscoveryInfo {
+    // Whether tokens signed with the algorithm, e.g. RS256, may come from
+    // the issuer. Any algorithm may when the document doesn't list them.
+    pub(crate) fn signs_with(&self, alg: &str) -> bool {
+        let supported = &self.id_token_signing_alg_values_supported;
+        supported.is_empty() || supported.iter().any(|value| value == alg)
+    }
+
+    // The jwks_uri along with the client_id query parameter, if configured
+    pub(crate) fn keys_url(&self, config: &Config) -> String {
+        let Some(client_id) = &config.keys_client_id else {
+            return self.jwks_uri.clone();
+        };
+        match url::Url::parse(&self.jwks_uri) {
+            Ok(mut url) => {
+                set_client_id(&mut url, client_id);
+                url.into()
+            }
+            Err(_) => self.jwks_uri.clone(),
+        }
+    }
+}
+
+// The discovery document and when it was retrieved, shared by a Verifier
+// and its clones. It's only replaced by retrievals of the keys, which are
+// serialized by the refresh lock of the key store.
+#[derive(Debug, Default)]
+pub(crate) struct DiscoveryCache {
+    loaded: RwLock<Option<(DiscoveryInfo, Instant)>>,
+}
+
+impl DiscoveryCache {
+    pub(crate) fn info(&self) -> Option<DiscoveryInfo> {
+        let loaded = self.loaded.read().unwrap_or_else(PoisonError::into_inner);
+        loaded.as_ref().map(|(info, _)| info.clone())
+    }
+
+    // The document, retrieved again once it's older than the ttl, along
+    // with whether it was just retrieved. A failed retrieval keeps the
+    // previous document, only the very first one has to succeed.
+    pub(crate) async fn resolve(
+        &self,
+        issuer: &str,
+        config: &Config,
+        ttl: Duration,
+    ) -> Result<(DiscoveryInfo, bool)> {
+        let current = {
+            let loaded =
+                self.loaded.read().unwrap_or_else(PoisonError::into_inner);
+            loaded.clone()
+        };
+        if let Some((info, at)) = &current {
+            if at.elapsed() < ttl {
+                return Ok((info.clone(), false));
+            }
+        }
+        match self.reload(issuer, config).await {
+            Ok(info) => Ok((info, true)),
+            Err(e) => match current {
+                Some((info, _)) => {
+                    logging::warn!(
+                        "Keeping the previous discovery document: {e:#}"
+                    );
+                    Ok((info, false))
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    // Retrieves the document regardless of its age
+    pub(crate) async fn reload(
+        &self,
+        issuer: &str,
+        config: &Config,
+    ) -> Result<DiscoveryInfo> {
+        let info = retrieve(issuer, config).await?;
+        *self.loaded.write().unwrap_or_else(PoisonError::into_inner) =
+            Some((info.clone(), Instant::now()));
+        Ok(info)
+    }
+}
+
+// Retrieves the discovery document with the client settings of the keys
+async fn retrieve(issuer: &str, config: &Config) -> Result<DiscoveryInfo> {
+    let url: String = endpoint_url(issuer, DISCOVERY_PATH, false)?.into();
+    let limit = Some(config.max_keys_bytes);
+    let fetch = || remote_fetch(&url, Some(issuer), config, limit);
+    let fetched = config.fetch_retry.run(config.classifier(), fetch).await?;
+    parse(&url, &fetched.body, issuer)
+}
+
+// Reads the document, which has to describe the issuer and name an http
+// or https jwks_uri
+fn parse(url: &str, body: &[u8], issuer: &str) -> Result<DiscoveryInfo> {
+    let invalid = |reason: String| Error::InvalidDiscovery {
+        url: url.to_string(),
+        reason,
+    };
+    let info: DiscoveryInfo =
+        serde_json::from_slice(body).map_err(|e| invalid(e.to_string()))?;
+    let expected = issuer.trim_end_matches('/');
+    if info.issuer.trim_end_matches('/') != expected {
+        bail!(Error::DiscoveryIssuerMismatch {
+            expected: expected.to_string(),
+            found: info.issuer,
+        })
+    }
+    let usable = url::Url::parse(&info.jwks_uri).is_ok_and(|jwks_uri| {
+        matches!(jwks_uri.scheme(), "https" | "http") && jwks_uri.has_host()
+    });
+    if !usable {
+        bail!(invalid(format!(
+            "jwks_uri {} is not an http or https url",
+            info.jwks_uri
+        )))
+    }
+    Ok(info)
+}
+
+impl Verifier {
+    /// `discovery_info` returns the discovery document the keys were last
+    /// retrieved with, see [`Config::discovery`]. None until it has been
+    /// retrieved, and always without discovery.
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// use okta_jwt_verifier::{Config, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///     let config = Config {
+    ///         discovery: Some(Duration::from_secs(24 * 60 * 60)),
+    ///         ..Config::default()
+    ///     };
+    ///
+    ///     let verifier = Verifier::new_with_config(&issuer, config).await?;
+    ///     if let Some(info) = verifier.discovery_info() {
+    ///         println!("keys at {}", info.jwks_uri);
+    ///     }
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn discovery_info(&self) -> Option<DiscoveryInfo> {
+        self.keys.discovery().info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::DefaultClaims;
+
+    fn document(issuer: &str, jwks_uri: &str, algs: &[&str]) -> String {
+        serde_json::json!({
+            "issuer": issuer,
+            "jwks_uri": jwks_uri,
+            "id_token_signing_alg_values_supported": algs,
+        })
+        .to_string()
+    }
+
+    fn config() -> Config {
+        Config {
+            discovery: Some(Duration::from_secs(3600)),
+            ..Config::default()
+        }
+    }
+
+    #[async_test]
+    async fn retrieves_the_keys_from_the_jwks_uri() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let issuer = server.url();
+        let discovery = server
+            .mock("GET", DISCOVERY_PATH)
+            .with_body(document(&issuer, &format!("{issuer}/keys"), &["RS256"]))
+            .expect(1)
+            .create();
+        let keys = server
+            .mock("GET", "/keys")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let verifier = Verifier::new_with_config(&issuer, config()).await?;
+        verifier.verify::<DefaultClaims>(&token(&issuer)).await?;
+        let info = verifier.discovery_info().unwrap();
+        assert_eq!(info.jwks_uri, format!("{issuer}/keys"));
+        assert_eq!(info.id_token_signing_alg_values_supported, ["RS256"]);
+        assert_eq!(verifier.keys_url()?, format!("{issuer}/keys"));
+        discovery.assert();
+        keys.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn follows_a_jwks_uri_that_moved() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let issuer = server.url();
+        let moved = document(&issuer, &format!("{issuer}/moved/keys"), &[]);
+        let discovery = server
+            .mock("GET", DISCOVERY_PATH)
+            .with_body(document(&issuer, &format!("{issuer}/keys"), &[]))
+            .expect(1)
+            .create();
+        let keys = server
+            .mock("GET", "/keys")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let verifier = Verifier::new_with_config(&issuer, config()).await?;
+        discovery.assert();
+        keys.assert();
+
+        // The old jwks_uri is gone, the document is retrieved again
+        // although it's still fresh
+        server.reset();
+        let gone = server.mock("GET", "/keys").with_status(404).create();
+        let discovery = server
+            .mock("GET", DISCOVERY_PATH)
+            .with_body(moved)
+            .expect(1)
+            .create();
+        let rotated = server
+            .mock("GET", "/moved/keys")
+            .with_body(keys_body(vec![rotated_jwk()]))
+            .expect(1)
+            .create();
+        verifier.refresh_keys().await?;
+        let token = sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&issuer));
+        verifier.verify::<DefaultClaims>(&token).await?;
+        assert_eq!(
+            verifier.fetch_metadata().unwrap().source,
+            format!("{issuer}/moved/keys")
+        );
+        assert_eq!(
+            verifier.discovery_info().unwrap().jwks_uri,
+            format!("{issuer}/moved/keys")
+        );
+        gone.assert();
+        discovery.assert();
+        rotated.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn retrieves_the_document_again_after_the_ttl() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let issuer = server.url();
+        let discovery = server
+            .mock("GET", DISCOVERY_PATH)
+            .with_body(document(&issuer, &format!("{issuer}/keys"), &[]))
+            .expect(2)
+            .create();
+        server.mock("GET", "/keys").with_body(keys_body(vec![jwk()])).create();
+        let config =
+            Config { discovery: Some(Duration::ZERO), ..Config::default() };
+        let verifier = Verifier::new_with_config(&issuer, config).await?;
+        verifier.refresh_keys().await?;
+        discovery.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn rejects_the_document_of_another_issuer() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let issuer = server.url();
+        server
+            .mock("GET", DISCOVERY_PATH)
+            .with_body(document(
+                "https://other.okta.com",
+                &format!("{issuer}/keys"),
+                &[],
+            ))
+            .create();
+        let e = Verifier::new_with_config(&issuer, config()).await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&Error::DiscoveryIssuerMismatch {
+                expected: issuer.clone(),
+                found: "https://other.okta.com".to_string(),
+            })
+        );
+        assert_eq!(e.downcast_ref::<Error>().unwrap().status_hint(), 500);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_documents_without_a_usable_jwks_uri() {
+        let issuer = "https://your.okta.com";
+        let url = format!("{issuer}{DISCOVERY_PATH}");
+        for body in [
+            "{}".to_string(),
+            document(issuer, "/v1/keys", &[]),
+            document(issuer, "file:///etc/keys", &[]),
+        ] {
+            let e = parse(&url, body.as_bytes(), issuer).unwrap_err();
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(Error::InvalidDiscovery { .. })
+            ));
+        }
+        let body = document(&format!("{issuer}/"), &format!("{issuer}/k"), &[]);
+        assert!(parse(&url, body.as_bytes(), issuer).is_ok());
+    }
+
+    #[async_test]
+    async fn narrows_the_algorithms_to_the_advertised_ones() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let issuer = server.url();
+        server
+            .mock("GET", DISCOVERY_PATH)
+            .with_body(document(&issuer, &format!("{issuer}/keys"), &["ES256"]))
+            .create();
+        server.mock("GET", "/keys").with_body(keys_body(vec![jwk()])).create();
+        let verifier = Verifier::new_with_config(&issuer, config()).await?;
+        assert!(verifier.effective_policy().algorithms.is_empty());
+        let e = verifier
+            .verify::<DefaultClaims>(&token(&issuer))
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::InvalidToken { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn adds_the_client_id_to_the_jwks_uri() {
+        let info = DiscoveryInfo {
+            issuer: "https://your.okta.com".into(),
+            jwks_uri: "https://your.okta.com/oauth2/v1/keys".into(),
+            id_token_signing_alg_values_supported: Vec::new(),
+        };
+        let config =
+            Config { keys_client_id: Some("0oa1".into()), ..Config::default() };
+        assert_eq!(
+            info.keys_url(&config),
+            "https://your.okta.com/oauth2/v1/keys?client_id=0oa1"
+        );
+        assert_eq!(info.keys_url(&Config::default()), info.jwks_uri);
+        let info = DiscoveryInfo {
+            jwks_uri: "https://your.okta.com/oauth2/v1/keys?client_id=0oa2"
+                .into(),
+            ..info
+        };
+        assert_eq!(
+            info.keys_url(&config),
+            "https://your.okta.com/oauth2/v1/keys?client_id=0oa1"
+        );
+    }
+}
@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use jsonwebtoken::TokenData;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::verify::check_token_shape;
+use crate::{
+    lock_within, unverified_claims, BoxFuture, Config, Error, Hook, Verifier,
+};
+
+// Issuers whose verifiers are kept around unless configured otherwise
+const DEFAULT_CAPACITY: usize = 100;
+
+// How long the allowlist decisions are kept unless configured otherwise
+const DEFAULT_ALLOWLIST_TTL: Duration = Duration::from_secs(5 * 60);
+
+type AllowlistHook =
+    Hook<dyn Fn(String) -> BoxFuture<Result<bool>> + Send + Sync>;
+type FactoryHook =
+    Hook<dyn Fn(String) -> BoxFuture<Result<Verifier>> + Send + Sync>;
+
+// A bounded set of verifiers keyed by issuer, the least recently
+// used one is evicted once the capacity is reached
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<String, (Verifier, u64)>,
+    tick: u64,
+}
+
+impl Lru {
+    fn get(&mut self, issuer: &str) -> Option<Verifier> {
+        self.tick += 1;
+        let (verifier, used) = self.entries.get_mut(issuer)?;
+        *used = self.tick;
+        Some(verifier.clone())
+    }
+
+    fn insert(&mut self, issuer: String, verifier: Verifier, capacity: usize) {
+        self.tick += 1;
+        if !self.entries.contains_key(&issuer) && self.entries.len() >= capacity
+        {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(issuer, _)| issuer.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(issuer, (verifier, self.tick));
+    }
+}
+
+// The allowlist decisions per issuer and when they were made, bounded like
+// the verifiers and dropping the oldest decision once full
+#[derive(Debug, Default)]
+struct Decisions(HashMap<String, (bool, Instant)>);
+
+impl Decisions {
+    fn get(&mut self, issuer: &str, ttl: Duration) -> Option<bool> {
+        match self.0.get(issuer) {
+            Some((allowed, at)) if at.elapsed() < ttl => Some(*allowed),
+            Some(_) => {
+                self.0.remove(issuer);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, issuer: String, allowed: bool, capacity: usize) {
+        if !self.0.contains_key(&issuer) && self.0.len() >= capacity {
+            let oldest = self
+                .0
+                .iter()
+                .min_by_key(|(_, (_, at))| *at)
+                .map(|(issuer, _)| issuer.clone());
+            if let Some(oldest) = oldest {
+                self.0.remove(&oldest);
+            }
+        }
+        self.0.insert(issuer, (allowed, Instant::now()));
+    }
+}
+
+/// Verifies tokens from issuers that are only known at runtime, such as
+/// one Okta org per customer.
+///
+/// The issuer is read from the unverified token and checked against an
+/// optional pattern and an async allowlist before anything is retrieved.
+/// The decisions of the allowlist are kept for a while, see
+/// [`DynamicVerifier::allowlist_ttl`].
+/// A [`Verifier`] is then built for the issuer on first use and kept in
+/// a bounded cache, the least recently used issuer being evicted first.
+/// Concurrent first uses of an issuer share a single build.
+///
+/// ```no_run
+/// use okta_jwt_verifier::{DefaultClaims, DynamicVerifier, Verifier};
+///
+/// #[async_std::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let token = "token";
+///
+///     let verifier = DynamicVerifier::new(|issuer: String| async move {
+///         // e.g. look the issuer up in the customer database
+///         Ok(issuer.starts_with("https://acme."))
+///     })
+///     .issuer_pattern("https://*.okta.com/oauth2/*")
+///     .factory(|issuer: String| async move {
+///         Ok(Verifier::new(&issuer).await?.add_audience("api://default"))
+///     });
+///     verifier.verify::<DefaultClaims>(&token).await?;
+///     Ok(())
+/// }
+///```
+#[derive(Debug, Clone)]
+pub struct DynamicVerifier {
+    allowlist: AllowlistHook,
+    factory: Option<FactoryHook>,
+    config: Config,
+    pattern: Option<String>,
+    capacity: usize,
+    wait_timeout: Option<Duration>,
+    allowlist_ttl: Duration,
+    decisions: Arc<Mutex<Decisions>>,
+    verifiers: Arc<Mutex<Lru>>,
+    // Serializes the builds per issuer
+    building: Arc<Mutex<HashMap<String, Arc<async_lock::Mutex<()>>>>>,
+}
+
+impl DynamicVerifier {
+    /// `new` constructs an instance of DynamicVerifier that only accepts
+    /// issuers for which the given allowlist resolves to `true`.
+    pub fn new<F, Fut>(allowlist: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<bool>> + Send + 'static,
+    {
+        Self {
+            allowlist: Hook(Arc::new(move |issuer| {
+                Box::pin(allowlist(issuer)) as BoxFuture<_>
+            })),
+            factory: None,
+            config: Config::default(),
+            pattern: None,
+            capacity: DEFAULT_CAPACITY,
+            wait_timeout: None,
+            allowlist_ttl: DEFAULT_ALLOWLIST_TTL,
+            decisions: Arc::default(),
+            verifiers: Arc::default(),
+            building: Arc::default(),
+        }
+    }
+
+    /// `issuer_pattern` restricts the issuers to those matching a pattern
+    /// before the allowlist is consulted. A `*` stands for one or more
+    /// letters, digits, `-` or `_`, so it can match a subdomain or an
+    /// authorization server id but never spans a `.` or `/`.
+    pub fn issuer_pattern(mut self, pattern: &str) -> Self {
+        self.pattern = Some(pattern.to_string());
+        self
+    }
+
+    /// `factory` overrides how the Verifier for an allowed issuer is built,
+    /// e.g. to apply per-issuer audiences or a [`Config`](crate::Config).
+    /// By default [`Verifier::new`] is used.
+    pub fn factory<F, Fut>(mut self, factory: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Verifier>> + Send + 'static,
+    {
+        self.factory = Some(Hook(Arc::new(move |issuer| {
+            Box::pin(factory(issuer)) as BoxFuture<_>
+        })));
+        self
+    }
+
+    /// `config` is for overriding the [`Config`] the Verifiers are built
+    /// with when no `factory` is given, by default [`Config::default`].
+    /// All of them share its [`Config::fetch_queue`], so that at most
+    /// [`Config::max_concurrent_fetches`] issuers retrieve their keys at
+    /// the same time. A `factory` shares the queue by building every
+    /// Verifier with clones of the same Config.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// `capacity` is for overriding the number of issuers whose
+    /// verifiers are cached, by default 100.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// `allowlist_ttl` is for overriding how long the decision of the
+    /// allowlist for an issuer is kept before it's consulted again, by
+    /// default 5 minutes. As many decisions as the `capacity` are kept,
+    /// and a zero ttl consults the allowlist on every verification.
+    pub fn allowlist_ttl(mut self, ttl: Duration) -> Self {
+        self.allowlist_ttl = ttl;
+        self
+    }
+
+    /// `wait_timeout` is for limiting how long to wait for the Verifier of
+    /// an issuer that is already being built by a concurrent call, after
+    /// which [`Error::WaitTimedOut`] is returned. By default callers
+    /// wait for the build to finish.
+    pub fn wait_timeout(mut self, wait_timeout: Duration) -> Self {
+        self.wait_timeout = Some(wait_timeout);
+        self
+    }
+
+    /// `verify` resolves the Verifier for the issuer of the token and uses
+    /// it to verify the token, see [`Verifier::verify`]. Issuers that don't
+    /// match the pattern or are denied by the allowlist are rejected with
+    /// [`Error::IssuerNotAllowed`] before any keys are retrieved.
+    pub async fn verify<T>(&self, token: &str) -> Result<TokenData<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let token = token.trim();
+        if token.is_empty() {
+            bail!(Error::EmptyToken)
+        }
+        self.verifier_for(token).await?.verify::<T>(token).await
+    }
+
+    // Finds or builds the verifier for the unverified issuer of the token
+    async fn verifier_for(&self, token: &str) -> Result<Verifier> {
+        check_token_shape(token, self.config.max_token_bytes)?;
+        let claims = unverified_claims(token)?;
+        let issuer = match claims.get("iss").and_then(Value::as_str) {
+            Some(issuer) => issuer.to_string(),
+            None => bail!(Error::MissingClaim { claim: "iss".into() }),
+        };
+        if let Some(pattern) = &self.pattern {
+            if !matches_pattern(pattern, &issuer) {
+                bail!(Error::IssuerNotAllowed)
+            }
+        }
+        if !self.allowed(&issuer).await? {
+            bail!(Error::IssuerNotAllowed)
+        }
+        if let Some(verifier) = self.lock().get(&issuer) {
+            return Ok(verifier);
+        }
+        let build =
+            self.lock_building().entry(issuer.clone()).or_default().clone();
+        let _guard = lock_within(&build, self.wait_timeout, &issuer).await?;
+        let _building = Building { verifier: self, issuer: &issuer };
+        // Another caller may have built it while we were waiting
+        if let Some(verifier) = self.lock().get(&issuer) {
+            return Ok(verifier);
+        }
+        let verifier = match &self.factory {
+            Some(Hook(factory)) => factory(issuer.clone()).await?,
+            None => {
+                Verifier::new_with_config(&issuer, self.config.clone()).await?
+            }
+        };
+        self.lock().insert(issuer.clone(), verifier.clone(), self.capacity);
+        Ok(verifier)
+    }
+
+    // Consults the allowlist unless a recent decision for the issuer is kept
+    async fn allowed(&self, issuer: &str) -> Result<bool> {
+        let decisions =
+            || self.decisions.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(allowed) = decisions().get(issuer, self.allowlist_ttl) {
+            return Ok(allowed);
+        }
+        let Hook(allowlist) = &self.allowlist;
+        let allowed = allowlist(issuer.to_string()).await?;
+        if !self.allowlist_ttl.is_zero() {
+            decisions().insert(issuer.to_string(), allowed, self.capacity);
+        }
+        Ok(allowed)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        self.verifiers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_building(
+        &self,
+    ) -> MutexGuard<'_, HashMap<String, Arc<async_lock::Mutex<()>>>> {
+        self.building.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Removes the build lock of the issuer once its build finished, whether the
+// Verifier was built or not
+struct Building<'a> {
+    verifier: &'a DynamicVerifier,
+    issuer: &'a str,
+}
+
+impl Drop for Building<'_> {
+    fn drop(&mut self) {
+        self.verifier.lock_building().remove(self.issuer);
+    }
+}
+
+// Matches a pattern where `*` stands for one or more characters
+// allowed in a host label or authorization server id. Every position of
+// the pattern the value could have reached so far is tracked at once, so
+// the time taken grows with the length of the pattern times the value
+// rather than exponentially with the number of `*`.
+fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let is_wild = |c: u8| c.is_ascii_alphanumeric() || c == b'-' || c == b'_';
+    let pattern = pattern.as_bytes();
+    let mut reached = vec![false; pattern.len() + 1];
+    reached[0] = true;
+    for &c in value.as_bytes() {
+        let mut next = vec![false; pattern.len() + 1];
+        for (i, &p) in pattern.iter().enumerate() {
+            if !reached[i] {
+                continue;
+            }
+            if p == b'*' && is_wild(c) {
+                // The `*` may go on matching or end with this character
+                next[i] = true;
+                next[i + 1] = true;
+            } else if p == c && p != b'*' {
+                next[i + 1] = true;
+            }
+        }
+        reached = next;
+    }
+    reached[pattern.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::test_support::*;
+    use crate::{DefaultClaims, ORG_ENDPOINT};
+
+    #[test]
+    fn matches_issuer_patterns() {
+        let pattern = "https://*.okta.com/oauth2/*";
+        assert!(matches_pattern(
+            pattern,
+            "https://acme.okta.com/oauth2/default"
+        ));
+        assert!(matches_pattern(pattern, "https://a-b.okta.com/oauth2/aus1_x"));
+        assert!(!matches_pattern(pattern, "https://.okta.com/oauth2/default"));
+        assert!(!matches_pattern(pattern, "https://acme.okta.com/oauth2/"));
+        assert!(!matches_pattern(
+            pattern,
+            "https://evil.com/.okta.com/oauth2/x"
+        ));
+        assert!(!matches_pattern(
+            pattern,
+            "https://evil.com#.okta.com/oauth2/x"
+        ));
+        assert!(!matches_pattern(
+            pattern,
+            "https://a.b.okta.com/oauth2/default"
+        ));
+        assert!(!matches_pattern(
+            pattern,
+            "https://acme.okta.com/oauth2/default/extra"
+        ));
+        assert!(matches_pattern(
+            "https://acme.okta.com",
+            "https://acme.okta.com"
+        ));
+        assert!(matches_pattern("a*b*c", "axbxbyc"));
+        assert!(!matches_pattern("a*", "a"));
+    }
+
+    #[test]
+    fn matches_many_wildcards_in_linear_time() {
+        let pattern = format!("{}.", "*".repeat(64));
+        let value = "a".repeat(4096);
+        let started = Instant::now();
+        assert!(!matches_pattern(&pattern, &value));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[async_test]
+    async fn keeps_the_allowlist_decisions() -> Result<()> {
+        let consulted = Arc::new(AtomicUsize::new(0));
+        let counter = consulted.clone();
+        let verifier = DynamicVerifier::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async { Ok(false) }
+        });
+        let token = token("https://denied.example.com");
+        for _ in 0..3 {
+            let err =
+                verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<Error>(),
+                Some(&Error::IssuerNotAllowed)
+            );
+        }
+        assert_eq!(consulted.load(Ordering::SeqCst), 1);
+
+        let verifier = verifier.allowlist_ttl(Duration::ZERO);
+        verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+        verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+        assert_eq!(consulted.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn verifies_tokens_from_allowed_issuers_only() -> Result<()> {
+        let mut first = mockito::Server::new_async().await;
+        let mut second = mockito::Server::new_async().await;
+        let mut denied = mockito::Server::new_async().await;
+        let f = first
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let s = second
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![rotated_jwk()]))
+            .expect(1)
+            .create();
+        let d = denied.mock("GET", ORG_ENDPOINT).expect(0).create();
+        let allowed = [first.url(), second.url()];
+        let verifier = DynamicVerifier::new(move |issuer: String| {
+            let allowed = allowed.contains(&issuer);
+            async move { Ok(allowed) }
+        });
+
+        for _ in 0..2 {
+            verifier.verify::<DefaultClaims>(&token(&first.url())).await?;
+            let rotated = sign_with(
+                ROTATED_KP_PEM,
+                ROTATED_KEY_ID,
+                claims(&second.url()),
+            );
+            verifier.verify::<DefaultClaims>(&rotated).await?;
+        }
+        let err = verifier
+            .verify::<DefaultClaims>(&token(&denied.url()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::IssuerNotAllowed));
+        f.assert();
+        s.assert();
+        d.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn rejects_empty_tokens_without_consulting_the_allowlist() {
+        let verifier = DynamicVerifier::new(|_| async {
+            panic!("the allowlist should not be consulted")
+        });
+        for empty in ["", " ", "\t\n"] {
+            let err =
+                verifier.verify::<DefaultClaims>(empty).await.unwrap_err();
+            assert_eq!(err.downcast_ref::<Error>(), Some(&Error::EmptyToken));
+        }
+    }
+
+    #[async_test]
+    async fn builds_verifiers_with_the_factory() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let verifier = DynamicVerifier::new(|_| async { Ok(true) }).factory(
+            |issuer: String| async move {
+                Ok(Verifier::new(&issuer).await?.add_audience("api://other"))
+            },
+        );
+        let token = sign(claims(&server.url()).with_audience("api://default"));
+        assert!(verifier.verify::<DefaultClaims>(&token).await.is_err());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn concurrent_first_uses_share_one_fetch() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body_from_request(|_| {
+                std::thread::sleep(Duration::from_millis(200));
+                keys_body(vec![jwk()]).into()
+            })
+            .expect(1)
+            .create();
+        let verifier = DynamicVerifier::new(|_| async { Ok(true) });
+        let token = token(&server.url());
+        let verifies =
+            (0..50).map(|_| verifier.verify::<DefaultClaims>(&token));
+        for result in futures::future::join_all(verifies).await {
+            result?;
+        }
+        m.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn failed_builds_release_the_issuer() -> Result<()> {
+        let verifier = DynamicVerifier::new(|_| async { Ok(true) })
+            .factory(|_| async { anyhow::bail!("no verifier") });
+        let token = token("https://acme.example.com");
+        for _ in 0..2 {
+            let err =
+                verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+            assert_eq!(err.to_string(), "no verifier");
+        }
+        assert!(verifier.lock_building().is_empty());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn waiting_for_a_build_can_time_out() -> Result<()> {
+        let verifier = DynamicVerifier::new(|_| async { Ok(true) })
+            .wait_timeout(Duration::from_millis(50))
+            .factory(|issuer: String| async move {
+                crate::runtime::sleep(Duration::from_millis(300)).await;
+                Verifier::with_keys(&issuer, &keys_body(vec![jwk()]))
+            });
+        let token = token("https://acme.example.com");
+        let (first, second) = futures::future::join(
+            verifier.verify::<DefaultClaims>(&token),
+            verifier.verify::<DefaultClaims>(&token),
+        )
+        .await;
+        first?;
+        assert_eq!(
+            second.unwrap_err().downcast_ref::<Error>(),
+            Some(&Error::WaitTimedOut {
+                url: "https://acme.example.com".into()
+            })
+        );
+        Ok(())
+    }
+
+    #[async_test]
+    async fn evicts_the_least_recently_used_issuer() -> Result<()> {
+        let mut first = mockito::Server::new_async().await;
+        let mut second = mockito::Server::new_async().await;
+        for server in [&mut first, &mut second] {
+            mock_keys(server, vec![jwk()]);
+        }
+        let built = Arc::new(AtomicUsize::new(0));
+        let counter = built.clone();
+        let verifier = DynamicVerifier::new(|_| async { Ok(true) })
+            .capacity(1)
+            .factory(move |issuer: String| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move { Verifier::new(&issuer).await }
+            });
+        for issuer in [first.url(), first.url(), second.url(), first.url()] {
+            verifier.verify::<DefaultClaims>(&token(&issuer)).await?;
+        }
+        assert_eq!(built.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,867 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use http::StatusCode;
+
+/// Describes the failures this crate can report.
+///
+/// Fallible methods return [`anyhow::Result`], the underlying `Error`
+/// can be recovered with [`anyhow::Error::downcast_ref`].
+///
+/// ```no_run
+/// use okta_jwt_verifier::{DefaultClaims, Error, Verifier};
+///
+/// #[async_std::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let token = "token";
+///     let issuer = "https://your.domain/oauth2/default";
+///
+///     let verifier = Verifier::new(&issuer).await?;
+///     if let Err(e) = verifier.verify::<DefaultClaims>(&token).await {
+///         if let Some(Error::NoMatchingKey) = e.downcast_ref::<Error>() {
+///             // the token was signed by a key we don't know about
+///         }
+///     }
+///     Ok(())
+/// }
+///```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The request doesn't carry a token.
+    MissingToken,
+    /// The token is empty or only whitespace.
+    EmptyToken,
+    /// The request carries several values for the source of the token,
+    /// e.g. two Authorization headers, see
+    /// [`Config::duplicate_authorization`](crate::Config::duplicate_authorization).
+    AmbiguousAuthorization {
+        /// Where the values were found, e.g. `Authorization header`.
+        source: String,
+        /// The number of values found.
+        count: usize,
+    },
+    /// The token is larger than the maximum accepted size.
+    TokenTooLarge {
+        /// The size of the token in bytes.
+        size: usize,
+        /// The maximum accepted size in bytes.
+        max: usize,
+    },
+    /// The claims of the token are larger than
+    /// [`Config::max_claims_bytes`](crate::Config::max_claims_bytes).
+    ClaimsTooLarge {
+        /// The size of the decoded claims in bytes.
+        size: usize,
+        /// The maximum accepted size in bytes.
+        max: usize,
+    },
+    /// The token is not made up of three dot separated segments.
+    MalformedToken,
+    /// The decoded claims of the token aren't valid UTF-8, see
+    /// [`Config::strict_payload_parsing`](
+    /// crate::Config::strict_payload_parsing).
+    PayloadNotUtf8,
+    /// A claim appears more than once in the same object of the token,
+    /// see [`Config::strict_payload_parsing`](
+    /// crate::Config::strict_payload_parsing).
+    DuplicateClaim {
+        /// The name of the repeated claim.
+        claim: String,
+    },
+    /// A timestamp claim isn't written as a plain integer, e.g. `1.7e9`,
+    /// see [`Config::strict_payload_parsing`](
+    /// crate::Config::strict_payload_parsing). A negative integer is
+    /// reported as an [`Error::InvalidToken`] instead.
+    NonCanonicalNumber {
+        /// The name of the claim.
+        claim: String,
+    },
+    /// The token has expired.
+    TokenExpired,
+    /// The iat claim of the token lies further in the future than the
+    /// leeway allows, see [`Verifier::reject_future_iat`](
+    /// crate::Verifier::reject_future_iat).
+    IssuedInFuture {
+        /// The iat claim, in seconds since the epoch.
+        iat: u64,
+        /// The leeway in seconds.
+        allowed_skew: u64,
+    },
+    /// The token failed validation, e.g. because of its signature,
+    /// issuer, or audience.
+    InvalidToken {
+        /// Why the token was rejected.
+        reason: String,
+    },
+    /// The token lacks scopes required by the Verifier.
+    InsufficientScope {
+        /// The scopes that are required, or the alternatives when
+        /// any one of them would do.
+        required: Vec<String>,
+        /// The audience whose policy required the scopes, see
+        /// [`Verifier::audience_policy`](crate::Verifier::audience_policy).
+        audience: Option<String>,
+    },
+    /// The token header does not contain a key id.
+    MissingKeyId,
+    /// None of the known keys match the key id of the token.
+    NoMatchingKey,
+    /// The keys endpoint could not be reached.
+    KeysUnreachable {
+        /// The url that was requested.
+        url: String,
+        /// The underlying transport failure.
+        reason: String,
+    },
+    /// The keys endpoint responded with an unsuccessful status.
+    KeysStatus {
+        /// The status code of the response.
+        status: u16,
+        /// The url that responded, after following any redirects.
+        url: String,
+        /// The start of the response body, which usually tells what was
+        /// wrong with the request, e.g. an unknown authorization server.
+        body: String,
+    },
+    /// A request to the keys endpoint took longer than
+    /// [`Config::fetch_timeout`](crate::Config::fetch_timeout).
+    KeysTimeout {
+        /// The url that was requested.
+        url: String,
+        /// The configured timeout.
+        timeout: Duration,
+    },
+    /// The [`Config::keys_file`](crate::Config::keys_file) couldn't be
+    /// read.
+    KeysFileUnreadable {
+        /// The path of the file.
+        path: String,
+        /// Why it couldn't be read.
+        reason: String,
+    },
+    /// The directory of the disk cache of the `cache-*` features couldn't
+    /// be created or written to.
+    CacheUnavailable {
+        /// The path of the directory.
+        path: String,
+        /// Why it can't be used.
+        reason: String,
+    },
+    /// A response, e.g. of the keys endpoint, exceeded the maximum size,
+    /// see [`Config::max_keys_bytes`](crate::Config::max_keys_bytes).
+    ResponseTooLarge {
+        /// The url that was requested.
+        url: String,
+        /// The maximum size in bytes.
+        max: usize,
+    },
+    /// The keys endpoint responded with 429 Too Many Requests, see
+    /// [`FetchRetry::max_rate_limit_wait`](crate::FetchRetry::max_rate_limit_wait).
+    KeysRateLimited {
+        /// The url that was requested.
+        url: String,
+        /// When the rate limit resets according to the `Retry-After` or
+        /// `X-Rate-Limit-Reset` header of the response, if any.
+        retry_at: Option<SystemTime>,
+    },
+    /// Retrieving the keys failed repeatedly, so no further attempt is
+    /// made for a while, see
+    /// [`Config::circuit_breaker`](crate::Config::circuit_breaker).
+    KeySourceUnavailable {
+        /// The number of consecutive failed retrievals.
+        failures: u32,
+        /// When the keys are retrieved again at the earliest.
+        retry_at: SystemTime,
+    },
+    /// Waiting for work already in progress took longer than allowed, e.g.
+    /// a retrieval of the keys limited by
+    /// [`Config::wait_timeout`](crate::Config::wait_timeout) or a build
+    /// limited by
+    /// [`DynamicVerifier::wait_timeout`](crate::DynamicVerifier::wait_timeout).
+    WaitTimedOut {
+        /// The keys url or the issuer that was waited on.
+        url: String,
+    },
+    /// A request for the keys waited longer than
+    /// [`Config::fetch_queue_timeout`](crate::Config::fetch_queue_timeout)
+    /// for other requests sharing the
+    /// [`Config::fetch_queue`](crate::Config::fetch_queue) to finish. The
+    /// keys url wasn't requested, so this doesn't count towards opening
+    /// the circuit breaker.
+    FetchQueueTimedOut {
+        /// The url that was going to be requested.
+        url: String,
+        /// The configured timeout.
+        timeout: Duration,
+    },
+    /// As many requests for the keys as
+    /// [`Config::fetch_queue_capacity`](crate::Config::fetch_queue_capacity)
+    /// were already waiting in the
+    /// [`Config::fetch_queue`](crate::Config::fetch_queue). The keys url
+    /// wasn't requested, so this doesn't count towards opening the circuit
+    /// breaker.
+    FetchQueueFull {
+        /// The url that was going to be requested.
+        url: String,
+        /// The configured capacity.
+        capacity: usize,
+    },
+    /// The jti denylist given to
+    /// [`Verifier::with_jti_denylist`](crate::Verifier::with_jti_denylist)
+    /// could not be read or parsed on its first load.
+    DenylistUnavailable {
+        /// The path or url of the denylist.
+        source: String,
+        /// Why it could not be used.
+        reason: String,
+    },
+    /// A required setting is missing from the Okta configuration.
+    MissingOktaConfig {
+        /// The dotted name of the missing key, e.g. `okta.client.orgUrl`.
+        key: String,
+    },
+    /// A setting in the Okta configuration could not be used.
+    InvalidOktaConfig {
+        /// The dotted name of the offending key, e.g. `okta.client.orgUrl`.
+        key: String,
+        /// Why the value was rejected.
+        reason: String,
+    },
+    /// The Okta configuration file given to
+    /// [`Verifier::from_okta_yaml`](crate::Verifier::from_okta_yaml)
+    /// couldn't be read.
+    OktaConfigUnreadable {
+        /// The path of the file.
+        path: String,
+        /// Why it couldn't be read.
+        reason: String,
+    },
+    /// The sub claim of the token is missing or not in the allowlist.
+    /// The values are only included when verbose errors are enabled.
+    SubjectNotAllowed {
+        /// The rejected subject.
+        subject: Option<String>,
+        /// The allowed subjects, sorted.
+        allowed: Option<Vec<String>>,
+    },
+    /// The idp claim of the token is missing or not one of the identity
+    /// providers accepted with
+    /// [`Verifier::require_idp_any`](crate::Verifier::require_idp_any).
+    IdpNotAllowed {
+        /// The rejected identity provider id.
+        idp: Option<String>,
+    },
+    /// The claims don't satisfy a rule added with
+    /// [`Verifier::require_rule`](crate::Verifier::require_rule).
+    RuleNotSatisfied {
+        /// Which part of the rule failed, e.g.
+        /// `none of (missing group Managers | missing scope billing:admin)`.
+        explanation: String,
+    },
+    /// The token id is on the jti denylist.
+    Revoked,
+    /// A claim required by the configured checks is missing.
+    MissingClaim {
+        /// The name of the missing claim.
+        claim: String,
+    },
+    /// The token names no client, while
+    /// [`Verifier::client_id_only`](crate::Verifier::client_id_only)
+    /// requires the cid or azp claim.
+    MissingClientIdClaim,
+    /// The client named by the token doesn't match the one set with
+    /// [`Verifier::client_id`](crate::Verifier::client_id) or
+    /// [`Verifier::client_id_only`](crate::Verifier::client_id_only).
+    ClientIdMismatch,
+    /// The issuer of the token is not allowed by a
+    /// [`DynamicVerifier`](crate::DynamicVerifier).
+    IssuerNotAllowed,
+    /// The leeway exceeds [`Config::leeway_threshold`](crate::Config)
+    /// while strict mode is enabled.
+    LeewayTooLarge {
+        /// The configured leeway in seconds.
+        leeway: u64,
+        /// The threshold in seconds.
+        threshold: u64,
+    },
+    /// The threshold set with
+    /// [`Verifier::audience_threshold`](crate::Verifier::audience_threshold)
+    /// is zero or exceeds the number of configured audiences.
+    InvalidAudienceThreshold {
+        /// The configured threshold.
+        threshold: usize,
+        /// The number of configured audiences.
+        audiences: usize,
+    },
+    /// The verification exceeded its time budget.
+    Timeout {
+        /// The phase that was in progress.
+        phase: TimeoutPhase,
+    },
+    /// The org url or authorization server id passed to
+    /// [`Verifier::for_org`](crate::Verifier::for_org) or
+    /// [`Verifier::for_auth_server`](crate::Verifier::for_auth_server)
+    /// can't be used to build an issuer.
+    InvalidIssuer {
+        /// The offending value.
+        url: String,
+        /// Why the value was rejected.
+        reason: String,
+    },
+    /// The keys endpoint can't be joined with the issuer, e.g. because it
+    /// names another host without
+    /// [`Config::allow_absolute_keys_endpoint`](crate::Config::allow_absolute_keys_endpoint).
+    InvalidKeysEndpoint {
+        /// The configured keys endpoint.
+        endpoint: String,
+        /// Why the value was rejected.
+        reason: String,
+    },
+    /// A key set, e.g. one passed to
+    /// [`Verifier::with_keys`](crate::Verifier::with_keys), isn't a JWKS
+    /// document or holds no key that can verify tokens.
+    InvalidKeySet {
+        /// Why the key set was rejected.
+        reason: String,
+    },
+    /// The retrieved key set holds no key that can verify tokens, e.g.
+    /// because it's empty or every key is of an unsupported type.
+    NoUsableKeys {
+        /// The number of keys in the key set.
+        received: usize,
+        /// Why each of the keys was skipped.
+        skipped: Vec<String>,
+    },
+    /// The OpenID Connect discovery document of the issuer can't be used,
+    /// e.g. because it names no `jwks_uri`, see
+    /// [`Config::discovery`](crate::Config::discovery).
+    InvalidDiscovery {
+        /// The url of the document.
+        url: String,
+        /// Why the document was rejected.
+        reason: String,
+    },
+    /// The OpenID Connect discovery document describes another issuer than
+    /// the one of the Verifier.
+    DiscoveryIssuerMismatch {
+        /// The issuer of the Verifier.
+        expected: String,
+        /// The issuer named by the document.
+        found: String,
+    },
+    /// Retrieving the keys failed at every url tried, the keys endpoint
+    /// and the [`Config::fallback_keys_urls`](crate::Config::fallback_keys_urls).
+    /// Only reported when more than one url was tried.
+    KeysEndpointsFailed {
+        /// The urls tried, in order, each with the error it failed with.
+        attempts: Vec<(String, Error)>,
+    },
+    /// A [`VerifierState`](crate::VerifierState) snapshot was written
+    /// with a format this version of the crate can't restore.
+    UnsupportedStateVersion {
+        /// The format version of the snapshot.
+        found: u32,
+        /// The format version this crate reads and writes.
+        supported: u32,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TokenTooLarge { size, max } => {
+                write!(f, "Token is too large ({size} bytes, max {max})!")
+            }
+            Error::ClaimsTooLarge { size, max } => {
+                write!(f, "Claims are too large ({size} bytes, max {max})!")
+            }
+            Error::MalformedToken => write!(f, "Token is malformed!"),
+            Error::PayloadNotUtf8 => write!(f, "Token claims are not UTF-8!"),
+            Error::DuplicateClaim { claim } => {
+                write!(f, "Token repeats the {claim} claim!")
+            }
+            Error::NonCanonicalNumber { claim } => {
+                write!(f, "Token claim {claim} is not a plain integer!")
+            }
+            Error::TokenExpired => write!(f, "Token has expired!"),
+            Error::IssuedInFuture { iat, allowed_skew } => write!(
+                f,
+                "Token was issued at {iat}, more than {allowed_skew}s in the future!"
+            ),
+            Error::InvalidToken { reason } => {
+                write!(f, "Token is invalid: {reason}!")
+            }
+            Error::InsufficientScope { required, audience } => {
+                write!(f, "Token lacks the scopes {}", required.join(", "))?;
+                match audience {
+                    Some(audience) => {
+                        write!(f, " required for audience {audience}!")
+                    }
+                    None => write!(f, "!"),
+                }
+            }
+            Error::MissingKeyId => write!(f, "No key id found!"),
+            Error::NoMatchingKey => write!(f, "No matching key found!"),
+            Error::KeysUnreachable { url, reason } => {
+                write!(f, "Unable to reach {url}: {reason}!")
+            }
+            Error::KeysFileUnreadable { path, reason } => {
+                write!(f, "Unable to read the keys file {path}: {reason}!")
+            }
+            Error::CacheUnavailable { path, reason } => write!(
+                f,
+                "Unable to use the cache directory {path}: {reason}, \
+                 see Config::cache!"
+            ),
+            Error::MissingToken => write!(f, "No token was provided!"),
+            Error::EmptyToken => write!(f, "The token is empty!"),
+            Error::AmbiguousAuthorization { source, count } => {
+                write!(f, "Found {count} values for the {source}, expected one!")
+            }
+            Error::KeysStatus { status, url, body } if body.is_empty() => {
+                write!(f, "Keys request to {url} failed with status {status}!")
+            }
+            Error::KeysStatus { status, url, body } => write!(
+                f,
+                "Keys request to {url} failed with status {status}: {body}!"
+            ),
+            Error::KeysTimeout { url, timeout } => {
+                write!(f, "Keys request to {url} timed out after {timeout:?}!")
+            }
+            Error::ResponseTooLarge { url, max } => write!(
+                f,
+                "Response from {url} is too large, the maximum is {max} bytes!"
+            ),
+            Error::KeysRateLimited { url, .. } => {
+                write!(f, "Keys request to {url} was rate limited!")
+            }
+            Error::KeySourceUnavailable { failures, .. } => write!(
+                f,
+                "Key source unavailable after {failures} failed retrievals!"
+            ),
+            Error::WaitTimedOut { url } => write!(
+                f,
+                "Timed out waiting for a request to {url} already in progress!"
+            ),
+            Error::FetchQueueTimedOut { url, timeout } => write!(
+                f,
+                "Keys request to {url} timed out after {timeout:?} waiting \
+                 for other requests!"
+            ),
+            Error::FetchQueueFull { url, capacity } => write!(
+                f,
+                "Keys request to {url} rejected with {capacity} requests \
+                 already waiting!"
+            ),
+            Error::DenylistUnavailable { source, reason } => {
+                write!(f, "Unable to load jti denylist {source}: {reason}!")
+            }
+            Error::MissingOktaConfig { key } => {
+                write!(f, "Missing Okta configuration key {key}!")
+            }
+            Error::InvalidOktaConfig { key, reason } => {
+                write!(f, "Invalid Okta configuration key {key}: {reason}!")
+            }
+            Error::OktaConfigUnreadable { path, reason } => write!(
+                f,
+                "Unable to read the Okta configuration {path}: {reason}!"
+            ),
+            Error::SubjectNotAllowed { subject, allowed } => {
+                match subject {
+                    Some(subject) => write!(f, "Subject {subject} is not allowed")?,
+                    None => write!(f, "Subject is not allowed")?,
+                }
+                match allowed {
+                    Some(allowed) => {
+                        write!(f, ", expected one of {}!", allowed.join(", "))
+                    }
+                    None => write!(f, "!"),
+                }
+            }
+            Error::IdpNotAllowed { idp: Some(idp) } => {
+                write!(f, "Identity provider {idp} is not allowed!")
+            }
+            Error::IdpNotAllowed { idp: None } => {
+                write!(f, "Token does not name an identity provider!")
+            }
+            Error::RuleNotSatisfied { explanation } => {
+                write!(f, "Authorization rule not satisfied: {explanation}!")
+            }
+            Error::Revoked => write!(f, "Token has been revoked!"),
+            Error::MissingClaim { claim } => {
+                write!(f, "Missing required claim {claim}!")
+            }
+            Error::MissingClientIdClaim => {
+                write!(f, "Token has no cid or azp claim!")
+            }
+            Error::ClientIdMismatch => write!(f, "client_id validation failed!"),
+            Error::IssuerNotAllowed => write!(f, "Issuer is not allowed!"),
+            Error::LeewayTooLarge { leeway, threshold } => write!(
+                f,
+                "Leeway of {leeway}s exceeds the threshold of {threshold}s!"
+            ),
+            Error::InvalidAudienceThreshold { threshold, audiences } => write!(
+                f,
+                "Audience threshold of {threshold} is invalid for {audiences} audiences!"
+            ),
+            Error::Timeout { phase } => {
+                write!(f, "Verification timed out during {phase}!")
+            }
+            Error::InvalidIssuer { url, reason } => {
+                write!(f, "Invalid issuer {url}: {reason}!")
+            }
+            Error::InvalidKeysEndpoint { endpoint, reason } => {
+                write!(f, "Invalid keys endpoint {endpoint}: {reason}!")
+            }
+            Error::InvalidKeySet { reason } => {
+                write!(f, "Invalid key set: {reason}!")
+            }
+            Error::NoUsableKeys { received: 0, .. } => {
+                write!(f, "No usable keys, the key set is empty!")
+            }
+            Error::NoUsableKeys { received, skipped } => write!(
+                f,
+                "No usable keys, skipped {} of {received}: {}!",
+                skipped.len(),
+                skipped.join("; ")
+            ),
+            Error::InvalidDiscovery { url, reason } => {
+                write!(f, "Invalid discovery document {url}: {reason}!")
+            }
+            Error::DiscoveryIssuerMismatch { expected, found } => write!(
+                f,
+                "The discovery document describes the issuer {found}, \
+                 expected {expected}!"
+            ),
+            Error::KeysEndpointsFailed { attempts } => {
+                write!(f, "Retrieving the keys failed at every url")?;
+                for (i, (url, error)) in attempts.iter().enumerate() {
+                    let separator = if i == 0 { ", " } else { "; " };
+                    let error = error.to_string();
+                    let error = error.trim_end_matches('!');
+                    write!(f, "{separator}{url}: {error}")?;
+                }
+                write!(f, "!")
+            }
+            Error::UnsupportedStateVersion { found, supported } => write!(
+                f,
+                "Unsupported verifier state version {found}, expected {supported}!"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Describes the phase of a verification that exceeded its time budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TimeoutPhase {
+    /// Looking up or retrieving the key that signed the token.
+    KeyFetch,
+    /// Checking the signature and claims, or deserializing the claims.
+    Decoding,
+    /// Checking the token id against the jti denylist.
+    ValidationHooks,
+}
+
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutPhase::KeyFetch => write!(f, "key fetch"),
+            TimeoutPhase::Decoding => write!(f, "decoding"),
+            TimeoutPhase::ValidationHooks => write!(f, "validation hooks"),
+        }
+    }
+}
+
+impl Error {
+    /// Builds the value of a `WWW-Authenticate` header describing this
+    /// error as specified by [RFC 6750](https://www.rfc-editor.org/rfc/rfc6750#section-3).
+    /// Failures caused by the token use the `invalid_token` error code,
+    /// missing scopes use `insufficient_scope` along with the required
+    /// scopes, and anything else only announces the Bearer scheme.
+    ///
+    /// ```
+    /// use okta_jwt_verifier::Error;
+    ///
+    /// assert_eq!(
+    ///     Error::TokenExpired.to_www_authenticate(Some("api")),
+    ///     r#"Bearer realm="api", error="invalid_token", error_description="The access token expired""#
+    /// );
+    ///```
+    pub fn to_www_authenticate(&self, realm: Option<&str>) -> String {
+        let mut params = Vec::new();
+        if let Some(realm) = realm {
+            params.push(format!("realm={}", quote(realm)));
+        }
+        if let Some((code, description)) = self.bearer_error() {
+            params.push(format!("error={}", quote(code)));
+            params.push(format!("error_description={}", quote(description)));
+        }
+        if let Error::InsufficientScope { required, .. } = self {
+            params.push(format!("scope={}", quote(&required.join(" "))));
+        }
+        if params.is_empty() {
+            return "Bearer".to_string();
+        }
+        format!("Bearer {}", params.join(", "))
+    }
+
+    /// The HTTP status that best describes this error: 400 for empty
+    /// tokens, 401 for tokens that are malformed, invalid, or expired, 403
+    /// for missing scopes and unsatisfied authorization rules, 503 when
+    /// the keys or other upstream resources are unavailable, and 500 for
+    /// configuration errors.
+    ///
+    /// ```
+    /// use okta_jwt_verifier::Error;
+    ///
+    /// assert_eq!(Error::TokenExpired.status_hint(), 401);
+    ///```
+    pub fn status_hint(&self) -> StatusCode {
+        match self {
+            // The last url tried decides, as it's the one given up on
+            Error::KeysEndpointsFailed { attempts } => attempts
+                .last()
+                .map_or(StatusCode::SERVICE_UNAVAILABLE, |(_, e)| {
+                    e.status_hint()
+                }),
+            Error::EmptyToken | Error::AmbiguousAuthorization { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            Error::InsufficientScope { .. }
+            | Error::RuleNotSatisfied { .. } => StatusCode::FORBIDDEN,
+            Error::KeysUnreachable { .. }
+            | Error::KeysFileUnreadable { .. }
+            | Error::KeysStatus { .. }
+            | Error::KeysTimeout { .. }
+            | Error::KeysRateLimited { .. }
+            | Error::KeySourceUnavailable { .. }
+            | Error::WaitTimedOut { .. }
+            | Error::FetchQueueTimedOut { .. }
+            | Error::FetchQueueFull { .. }
+            | Error::DenylistUnavailable { .. }
+            | Error::Timeout { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::MissingOktaConfig { .. }
+            | Error::InvalidOktaConfig { .. }
+            | Error::OktaConfigUnreadable { .. }
+            | Error::LeewayTooLarge { .. }
+            | Error::InvalidAudienceThreshold { .. }
+            | Error::InvalidIssuer { .. }
+            | Error::InvalidKeysEndpoint { .. }
+            | Error::CacheUnavailable { .. }
+            | Error::InvalidKeySet { .. }
+            | Error::NoUsableKeys { .. }
+            | Error::InvalidDiscovery { .. }
+            | Error::DiscoveryIssuerMismatch { .. }
+            | Error::ResponseTooLarge { .. }
+            | Error::UnsupportedStateVersion { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// A short, stable identifier of the kind of failure, e.g.
+    /// `token_expired`, suitable for metrics and logs.
+    ///
+    /// ```
+    /// use okta_jwt_verifier::Error;
+    ///
+    /// assert_eq!(Error::NoMatchingKey.code(), "no_matching_key");
+    ///```
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::MissingToken => "missing_token",
+            Error::EmptyToken => "empty_token",
+            Error::AmbiguousAuthorization { .. } => "ambiguous_authorization",
+            Error::TokenTooLarge { .. } => "token_too_large",
+            Error::ClaimsTooLarge { .. } => "claims_too_large",
+            Error::MalformedToken => "malformed_token",
+            Error::PayloadNotUtf8 => "payload_not_utf8",
+            Error::DuplicateClaim { .. } => "duplicate_claim",
+            Error::NonCanonicalNumber { .. } => "non_canonical_number",
+            Error::TokenExpired => "token_expired",
+            Error::IssuedInFuture { .. } => "issued_in_future",
+            Error::InvalidToken { .. } => "invalid_token",
+            Error::InsufficientScope { .. } => "insufficient_scope",
+            Error::MissingKeyId => "missing_key_id",
+            Error::NoMatchingKey => "no_matching_key",
+            Error::KeysUnreachable { .. } => "keys_unreachable",
+            Error::KeysFileUnreadable { .. } => "keys_file_unreadable",
+            Error::CacheUnavailable { .. } => "cache_unavailable",
+            Error::KeysStatus { .. } => "keys_status",
+            Error::KeysTimeout { .. } => "keys_timeout",
+            Error::ResponseTooLarge { .. } => "response_too_large",
+            Error::KeysRateLimited { .. } => "keys_rate_limited",
+            Error::KeySourceUnavailable { .. } => "key_source_unavailable",
+            Error::WaitTimedOut { .. } => "wait_timed_out",
+            Error::FetchQueueTimedOut { .. } => "fetch_queue_timed_out",
+            Error::FetchQueueFull { .. } => "fetch_queue_full",
+            Error::DenylistUnavailable { .. } => "denylist_unavailable",
+            Error::MissingOktaConfig { .. } => "missing_okta_config",
+            Error::InvalidOktaConfig { .. } => "invalid_okta_config",
+            Error::OktaConfigUnreadable { .. } => "okta_config_unreadable",
+            Error::SubjectNotAllowed { .. } => "subject_not_allowed",
+            Error::IdpNotAllowed { .. } => "idp_not_allowed",
+            Error::RuleNotSatisfied { .. } => "rule_not_satisfied",
+            Error::Revoked => "revoked",
+            Error::MissingClaim { .. } => "missing_claim",
+            Error::MissingClientIdClaim => "missing_client_id_claim",
+            Error::ClientIdMismatch => "client_id_mismatch",
+            Error::IssuerNotAllowed => "issuer_not_allowed",
+            Error::LeewayTooLarge { .. } => "leeway_too_large",
+            Error::InvalidAudienceThreshold { .. } => {
+                "invalid_audience_threshold"
+            }
+            Error::Timeout { .. } => "timeout",
+            Error::InvalidIssuer { .. } => "invalid_issuer",
+            Error::InvalidKeysEndpoint { .. } => "invalid_keys_endpoint",
+            Error::InvalidKeySet { .. } => "invalid_key_set",
+            Error::NoUsableKeys { .. } => "no_usable_keys",
+            Error::InvalidDiscovery { .. } => "invalid_discovery",
+            Error::DiscoveryIssuerMismatch { .. } => {
+                "discovery_issuer_mismatch"
+            }
+            Error::KeysEndpointsFailed { .. } => "keys_endpoints_failed",
+            Error::UnsupportedStateVersion { .. } => {
+                "unsupported_state_version"
+            }
+        }
+    }
+
+    /// Whether the same request may succeed when retried later, which
+    /// is the case for failures to reach upstream resources rather than
+    /// problems with the token.
+    pub fn is_retryable(&self) -> bool {
+        self.status_hint() == StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    // The RFC 6750 error code and a description that is safe to share
+    // with the client, None for failures that aren't the client's fault
+    fn bearer_error(&self) -> Option<(&'static str, &'static str)> {
+        let description = match self {
+            Error::InsufficientScope { .. } => {
+                return Some((
+                    "insufficient_scope",
+                    "The access token lacks the required scope",
+                ))
+            }
+            Error::RuleNotSatisfied { .. } => {
+                return Some((
+                    "insufficient_scope",
+                    "The access token lacks the required permissions",
+                ))
+            }
+            Error::EmptyToken => {
+                return Some(("invalid_request", "The access token is empty"))
+            }
+            Error::AmbiguousAuthorization { .. } => {
+                return Some((
+                    "invalid_request",
+                    "The request carries more than one access token",
+                ))
+            }
+            Error::TokenExpired => "The access token expired",
+            Error::IssuedInFuture { .. } => {
+                "The access token was issued in the future"
+            }
+            Error::Revoked => "The access token has been revoked",
+            Error::TokenTooLarge { .. }
+            | Error::ClaimsTooLarge { .. }
+            | Error::MalformedToken
+            | Error::PayloadNotUtf8
+            | Error::DuplicateClaim { .. }
+            | Error::NonCanonicalNumber { .. }
+            | Error::MissingKeyId => "The access token is malformed",
+            Error::NoMatchingKey
+            | Error::InvalidToken { .. }
+            | Error::SubjectNotAllowed { .. }
+            | Error::IdpNotAllowed { .. }
+            | Error::MissingClaim { .. }
+            | Error::MissingClientIdClaim
+            | Error::ClientIdMismatch
+            | Error::IssuerNotAllowed => "The access token is invalid",
+            _ => return None,
+        };
+        Some(("invalid_token", description))
+    }
+}
+
+// Formats a quoted-string, escaping quotes and backslashes
+// and dropping anything that isn't printable ASCII
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars().filter(|c| c.is_ascii() && !c.is_ascii_control()) {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn www_authenticate_for_an_expired_token() {
+        assert_eq!(
+            Error::TokenExpired.to_www_authenticate(Some("api")),
+            r#"Bearer realm="api", error="invalid_token", error_description="The access token expired""#
+        );
+    }
+
+    #[test]
+    fn www_authenticate_for_insufficient_scope() {
+        let error = Error::InsufficientScope {
+            required: vec!["orders:read".into(), "orders:write".into()],
+            audience: None,
+        };
+        assert_eq!(
+            error.to_www_authenticate(None),
+            r#"Bearer error="insufficient_scope", error_description="The access token lacks the required scope", scope="orders:read orders:write""#
+        );
+    }
+
+    #[test]
+    fn www_authenticate_for_a_missing_token() {
+        assert_eq!(Error::MissingToken.status_hint(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            Error::MissingToken.to_www_authenticate(Some("api")),
+            r#"Bearer realm="api""#
+        );
+    }
+
+    #[test]
+    fn www_authenticate_for_an_empty_token() {
+        assert_eq!(Error::EmptyToken.status_hint(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            Error::EmptyToken.to_www_authenticate(None),
+            r#"Bearer error="invalid_request", error_description="The access token is empty""#
+        );
+    }
+
+    #[test]
+    fn www_authenticate_without_error_code() {
+        let error = Error::KeysStatus {
+            status: 500,
+            url: "url".into(),
+            body: String::new(),
+        };
+        assert_eq!(error.to_www_authenticate(None), "Bearer");
+        assert_eq!(
+            error.to_www_authenticate(Some(r#"my "api"\"#)),
+            r#"Bearer realm="my \"api\"\\""#
+        );
+        assert_eq!(
+            error.to_www_authenticate(Some("caf\u{e9}\n")),
+            r#"Bearer realm="caf""#
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{clock, Error, Verifier};
+
+/// Describes how tokens without an exp claim are treated, see
+/// [`Verifier::exp_policy`]
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpPolicy {
+    /// Tokens without an exp claim are rejected.
+    #[default]
+    Require,
+    /// Tokens without an exp claim are accepted and never expire.
+    AllowMissing,
+    /// Tokens without an exp claim expire the given duration after
+    /// their iat claim, and are rejected without either claim.
+    AllowMissingWithMaxAge(Duration),
+}
+
+impl Verifier {
+    /// `exp_policy` decides how tokens without an exp claim are treated,
+    /// by default they are rejected. The exp claim is still validated
+    /// whenever present.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{DefaultClaims, ExpPolicy, Verifier};
+    /// use std::time::Duration;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///     let day = Duration::from_secs(24 * 60 * 60);
+    ///
+    ///     Verifier::new(&issuer)
+    ///         .await?
+    ///         .exp_policy(ExpPolicy::AllowMissingWithMaxAge(day))
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn exp_policy(mut self, policy: ExpPolicy) -> Self {
+        self.exp_policy = policy;
+        self.settings_changed();
+        self
+    }
+
+    /// `reject_future_iat` decides whether tokens whose iat claim lies
+    /// further in the future than the leeway are rejected with
+    /// [`Error::IssuedInFuture`], by default they are. Tokens issued a
+    /// few seconds ahead because of clock skew between Okta's servers
+    /// stay within the leeway. The time is told by [`Config::clock`](
+    /// crate::Config::clock), and tokens without an iat claim aren't
+    /// affected.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{DefaultClaims, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     Verifier::new(&issuer)
+    ///         .await?
+    ///         .reject_future_iat(false)
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn reject_future_iat(mut self, reject: bool) -> Self {
+        self.reject_future_iat = reject;
+        self
+    }
+
+    // Expires tokens without an exp claim by their age, jsonwebtoken
+    // either requires the claim or skips the check when it's absent
+    pub(crate) fn check_missing_exp(&self, claims: &Value) -> Result<()> {
+        let ExpPolicy::AllowMissingWithMaxAge(max_age) = self.exp_policy else {
+            return Ok(());
+        };
+        if !self.validate_exp || claims.get("exp").is_some() {
+            return Ok(());
+        }
+        let Some(iat) = claims.get("iat").and_then(Value::as_u64) else {
+            bail!(Error::MissingClaim { claim: "iat".to_string() })
+        };
+        let now = clock::unix_now(&self.config);
+        let expires = iat.saturating_add(max_age.as_secs());
+        if expires.saturating_add(self.leeway) < now {
+            bail!(Error::TokenExpired)
+        }
+        Ok(())
+    }
+
+    // jsonwebtoken doesn't look at iat, so whether a token issued in the
+    // future is accepted is decided here
+    pub(crate) fn check_future_iat(&self, claims: &Value) -> Result<()> {
+        if !self.reject_future_iat {
+            return Ok(());
+        }
+        let Some(iat) = claims.get("iat").and_then(Value::as_u64) else {
+            return Ok(());
+        };
+        let now = clock::unix_now(&self.config);
+        if iat > now.saturating_add(self.leeway) {
+            bail!(Error::IssuedInFuture { iat, allowed_skew: self.leeway })
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::test_support::*;
+    use crate::Config;
+
+    use jwt_simple::prelude::{Clock, JWTClaims, NoCustomClaims};
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    // A token with exp, one without, and one without exp and iat, all
+    // issued two hours ago
+    fn tokens(issuer: &str) -> [String; 3] {
+        let issued = Clock::now_since_epoch()
+            - jwt_simple::prelude::Duration::from_hours(2);
+        let with_exp = JWTClaims {
+            issued_at: Some(issued),
+            invalid_before: Some(issued),
+            ..claims(issuer)
+        };
+        let without_exp = JWTClaims { expires_at: None, ..with_exp.clone() };
+        let without_both: JWTClaims<NoCustomClaims> =
+            JWTClaims { issued_at: None, ..without_exp.clone() };
+        [sign(with_exp), sign(without_exp), sign(without_both)]
+    }
+
+    async fn outcomes(policy: ExpPolicy) -> Result<Vec<Option<Error>>> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?.exp_policy(policy);
+        let mut outcomes = Vec::new();
+        for token in tokens(&server.url()) {
+            let verified = verifier.verify::<Value>(&token).await;
+            outcomes.push(verified.err().map(|err| {
+                err.downcast_ref::<Error>().cloned().expect("crate error")
+            }));
+        }
+        Ok(outcomes)
+    }
+
+    #[async_test]
+    async fn requires_exp_by_default() -> Result<()> {
+        let [with_exp, without_exp, without_both] =
+            &outcomes(ExpPolicy::default()).await?[..]
+        else {
+            unreachable!()
+        };
+        assert_eq!(with_exp, &None);
+        for outcome in [without_exp, without_both] {
+            assert!(matches!(
+                outcome,
+                Some(Error::InvalidToken { reason })
+                    if reason == "Missing required claim: exp"
+            ));
+        }
+        Ok(())
+    }
+
+    #[async_test]
+    async fn allows_tokens_without_exp() -> Result<()> {
+        let outcomes = outcomes(ExpPolicy::AllowMissing).await?;
+        assert_eq!(outcomes, [None, None, None]);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn expires_tokens_without_exp_by_their_age() -> Result<()> {
+        let iat = Error::MissingClaim { claim: "iat".to_string() };
+        let young =
+            outcomes(ExpPolicy::AllowMissingWithMaxAge(3 * HOUR)).await?;
+        assert_eq!(young, [None, None, Some(iat.clone())]);
+
+        let old = outcomes(ExpPolicy::AllowMissingWithMaxAge(HOUR)).await?;
+        assert_eq!(old, [None, Some(Error::TokenExpired), Some(iat)]);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn rejects_tokens_issued_beyond_the_leeway() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let issuer = server.url();
+        let now = Clock::now_since_epoch();
+        let issued_in = |secs| {
+            let iat = now + jwt_simple::prelude::Duration::from_secs(secs);
+            sign(JWTClaims { issued_at: Some(iat), ..claims(&issuer) })
+        };
+        let verifier = Verifier::new(&issuer).await?.leeway(120);
+        verifier.verify::<Value>(&issued_in(5)).await?;
+        let e = verifier.verify::<Value>(&issued_in(300)).await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&Error::IssuedInFuture {
+                iat: now.as_secs() + 300,
+                allowed_skew: 120,
+            })
+        );
+
+        let lenient = verifier.clone().reject_future_iat(false);
+        lenient.verify::<Value>(&issued_in(300)).await?;
+
+        // The check asks the configured clock for the time
+        let ahead = || std::time::SystemTime::now() + HOUR;
+        let config =
+            Config { clock: Some(Arc::new(ahead)), ..Config::default() };
+        let verifier = Verifier::new_with_config(&issuer, config).await?;
+        verifier.verify::<Value>(&issued_in(300)).await?;
+        Ok(())
+    }
+}
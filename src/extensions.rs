@@ -0,0 +1,375 @@
+use anyhow::{bail, Result};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+#[cfg(any(feature = "tide", feature = "tower"))]
+use crate::{Decision, DenialResponse, ResponseMapper};
+use crate::{DecodedToken, Error, TokenExtractor, VerifiedIdentity, Verifier};
+
+/// The claims of a verified token as JSON, inserted into the request
+/// extensions next to the typed claims by [`Verifier::authenticate`] when
+/// [`Config::retain_raw_claims`] is set.
+///
+/// [`Config::retain_raw_claims`]: crate::Config::retain_raw_claims
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawClaims(pub Value);
+
+/// The claims of a verified token that [`Config::redaction`] allows to
+/// appear verbatim, leaving out the others, inserted into the request
+/// extensions by [`Verifier::authenticate`] for audit logs, which shouldn't
+/// record the claims verbatim.
+///
+/// [`Config::redaction`]: crate::Config::redaction
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditClaims(pub Value);
+
+/// The id of the key that validated the token, inserted into the request
+/// extensions by [`Verifier::authenticate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedKey(pub String);
+
+/// The request extensions of a verified token, returned by
+/// [`Verifier::authenticate_token`] for integrations whose requests
+/// aren't [`http::Request`]s, e.g. to insert each into a tide request.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Authenticated<T> {
+    /// The claims deserialized into `T`.
+    pub claims: T,
+    /// The header and claims of the token.
+    pub decoded: DecodedToken<T>,
+    /// The claims as JSON, only when
+    /// [`Config::retain_raw_claims`](crate::Config::retain_raw_claims) is
+    /// set, see [`Authenticated::claims_json`] otherwise.
+    pub raw: Option<RawClaims>,
+    /// The claims that
+    /// [`Config::redaction`](crate::Config::redaction) allows.
+    pub audit: AuditClaims,
+    /// The identity the claims describe.
+    pub identity: VerifiedIdentity,
+    /// The id of the key that validated the token.
+    pub kid: MatchedKey,
+    // The claims as JSON for hooks to borrow when the raw claims aren't
+    // retained, dropped along with the rest once inserted
+    json: Value,
+}
+
+impl<T> Authenticated<T> {
+    /// `claims_json` borrows the claims as JSON, e.g. for deciding on the
+    /// request before the extensions are inserted, whether or not the
+    /// [`RawClaims`] are retained.
+    pub fn claims_json(&self) -> &Value {
+        match &self.raw {
+            Some(RawClaims(raw)) => raw,
+            None => &self.json,
+        }
+    }
+}
+
+impl<T> Authenticated<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    /// `insert_into` inserts every extension into the given ones, the
+    /// [`RawClaims`] only if they were retained.
+    pub fn insert_into(self, extensions: &mut http::Extensions) {
+        extensions.insert(self.decoded);
+        extensions.insert(self.claims);
+        extensions.insert(self.identity);
+        if let Some(raw) = self.raw {
+            extensions.insert(raw);
+        }
+        extensions.insert(self.audit);
+        extensions.insert(self.kid);
+    }
+}
+
+impl Verifier {
+    /// `authenticate` verifies the token of a request and inserts the
+    /// claims into its extensions, so that handlers and later middleware,
+    /// such as rate limiters or audit logs, can read them without
+    /// verifying the token again. Five extensions are inserted: the
+    /// claims deserialized into `T`, the [`DecodedToken`] holding them
+    /// along with the header, the [`AuditClaims`], the [`VerifiedIdentity`],
+    /// and the [`MatchedKey`], along with the [`RawClaims`] when
+    /// [`Config::retain_raw_claims`] is set. Requests without a
+    /// token are rejected with [`Error::MissingToken`], those with a Bearer
+    /// scheme but no token with [`Error::EmptyToken`], and those carrying
+    /// several tokens in one source with [`Error::AmbiguousAuthorization`]
+    /// unless [`Config::duplicate_authorization`] or the extractor prefer
+    /// the last, see [`Verifier::token_extractor`].
+    ///
+    /// [`Config::retain_raw_claims`]: crate::Config::retain_raw_claims
+    /// [`Config::duplicate_authorization`]:
+    /// crate::Config::duplicate_authorization
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{
+    ///     DecodedToken, DefaultClaims, MatchedKey, RawClaims, TokenExtractor,
+    ///     VerifiedIdentity, Verifier,
+    /// };
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///     let verifier = Verifier::new(&issuer).await?;
+    ///     let mut req = http::Request::builder()
+    ///         .header("Authorization", "Bearer token")
+    ///         .body(())?;
+    ///
+    ///     verifier
+    ///         .authenticate::<DefaultClaims, _>(&TokenExtractor::default(), &mut req)
+    ///         .await?;
+    ///     let claims = req.extensions().get::<DefaultClaims>();
+    ///     let decoded = req.extensions().get::<DecodedToken<DefaultClaims>>();
+    ///     let raw = req.extensions().get::<RawClaims>();
+    ///     let identity = req.extensions().get::<VerifiedIdentity>();
+    ///     let kid = req.extensions().get::<MatchedKey>();
+    ///     Ok(())
+    /// }
+    ///```
+    pub async fn authenticate<T, B>(
+        &self,
+        extractor: &TokenExtractor,
+        req: &mut http::Request<B>,
+    ) -> Result<()>
+    where
+        T: DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        let duplicates = self.config.duplicate_authorization;
+        let extracted = extractor.extract_with(req, duplicates);
+        let Some(token) = self.count_empty(extracted)? else {
+            bail!(Error::MissingToken)
+        };
+        let authenticated = self.authenticate_token::<T>(&token).await?;
+        authenticated.insert_into(req.extensions_mut());
+        Ok(())
+    }
+
+    /// `authenticate_token` verifies a token and returns the extensions
+    /// [`Verifier::authenticate`] inserts, for integrations that extract
+    /// the token themselves.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{Authenticated, DefaultClaims, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///     let verifier = Verifier::new(&issuer).await?;
+    ///
+    ///     let authenticated =
+    ///         verifier.authenticate_token::<DefaultClaims>(&token).await?;
+    ///     println!("{}", authenticated.kid.0);
+    ///     Ok(())
+    /// }
+    ///```
+    pub async fn authenticate_token<T>(
+        &self,
+        token: &str,
+    ) -> Result<Authenticated<T>>
+    where
+        T: DeserializeOwned + Clone,
+    {
+        let verified = self.verify_detailed::<Value>(token).await?;
+        let DecodedToken { header, claims: json } = verified.token_data;
+        let claims = T::deserialize(&json)?;
+        let identity = VerifiedIdentity::from_claims(&json);
+        let audit = self.config.redaction.allowed_claims(&json);
+        let (raw, json) = match self.config.retain_raw_claims {
+            true => (Some(RawClaims(json)), Value::Null),
+            false => (None, json),
+        };
+        Ok(Authenticated {
+            decoded: DecodedToken { header, claims: claims.clone() },
+            claims,
+            identity,
+            audit: AuditClaims(audit),
+            raw,
+            kid: MatchedKey(verified.kid),
+            json,
+        })
+    }
+
+    // Verifies the token a middleware extracted and decides with the mapper
+    // whether the request is passed on, answering requests without a token
+    // like those with a rejected one
+    #[cfg(any(feature = "tide", feature = "tower"))]
+    pub(crate) async fn admit<T>(
+        &self,
+        token: Result<Option<String>>,
+        mapper: &dyn ResponseMapper,
+    ) -> Result<Authenticated<T>, DenialResponse>
+    where
+        T: DeserializeOwned + Clone,
+    {
+        let verified = match self.count_empty(token) {
+            Ok(Some(token)) => self.authenticate_token::<T>(&token).await,
+            Ok(None) => Err(Error::MissingToken.into()),
+            Err(e) => Err(e),
+        };
+        match verified {
+            Ok(authenticated) => {
+                match mapper.on_success(authenticated.claims_json()) {
+                    Decision::Deny(denial) => Err(denial),
+                    Decision::Allow => Ok(authenticated),
+                }
+            }
+            Err(e) => Err(mapper.on_failure(&e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{Config, DefaultClaims};
+
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, Deserialize)]
+    struct Subject {
+        sub: String,
+    }
+
+    fn request(token: Option<&str>) -> http::Request<()> {
+        let mut req = http::Request::builder();
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {token}"));
+        }
+        req.body(()).unwrap()
+    }
+
+    #[async_test]
+    async fn inserts_typed_and_raw_claims() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let config = Config { retain_raw_claims: true, ..Config::default() };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        let extractor = TokenExtractor::default();
+        let token = token(&server.url());
+
+        let mut req = request(Some(&token));
+        verifier.authenticate::<DefaultClaims, _>(&extractor, &mut req).await?;
+        let claims = req.extensions().get::<DefaultClaims>().unwrap();
+        assert_eq!(claims.sub, "test");
+        let decoded =
+            req.extensions().get::<DecodedToken<DefaultClaims>>().unwrap();
+        assert_eq!(decoded.header.kid.as_deref(), Some(KEY_ID));
+        assert_eq!(decoded.claims.sub, "test");
+        let RawClaims(raw) = req.extensions().get::<RawClaims>().unwrap();
+        assert_eq!(raw["iss"], server.url());
+        let identity = req.extensions().get::<VerifiedIdentity>().unwrap();
+        assert_eq!(identity.sub.as_deref(), Some("test"));
+        assert_eq!(
+            req.extensions().get::<MatchedKey>(),
+            Some(&MatchedKey(KEY_ID.to_string()))
+        );
+
+        let mut req = request(Some(&token));
+        verifier.authenticate::<Subject, _>(&extractor, &mut req).await?;
+        assert_eq!(req.extensions().get::<Subject>().unwrap().sub, "test");
+        assert!(req.extensions().get::<DefaultClaims>().is_none());
+        assert!(req.extensions().get::<RawClaims>().is_some());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn drops_the_raw_claims_unless_retained() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        let token = token(&server.url());
+
+        let authenticated =
+            verifier.authenticate_token::<DefaultClaims>(&token).await?;
+        assert!(authenticated.raw.is_none());
+        assert_eq!(authenticated.claims_json()["sub"], "test");
+
+        let mut req = request(Some(&token));
+        verifier
+            .authenticate::<DefaultClaims, _>(
+                &TokenExtractor::default(),
+                &mut req,
+            )
+            .await?;
+        assert!(req.extensions().get::<RawClaims>().is_none());
+        assert!(req.extensions().get::<AuditClaims>().is_some());
+        let claims = req.extensions().get::<DefaultClaims>().unwrap();
+        assert_eq!(claims.sub, "test");
+        Ok(())
+    }
+
+    #[async_test]
+    async fn audit_claims_are_redacted() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        let token = sign(
+            jwt_simple::claims::Claims::with_custom_claims(
+                serde_json::json!({ "email": "jane@example.com" }),
+                jwt_simple::prelude::Duration::from_hours(2),
+            )
+            .with_issuer(server.url())
+            .with_subject("jane"),
+        );
+        let mut req = request(Some(&token));
+        verifier
+            .authenticate::<Value, _>(&TokenExtractor::default(), &mut req)
+            .await?;
+        assert!(req.extensions().get::<RawClaims>().is_none());
+        let audit = req.extensions().get::<AuditClaims>().unwrap();
+        let AuditClaims(claims) = audit;
+        assert!(claims.get("email").is_none());
+        assert!(claims.get("sub").is_none());
+        assert_eq!(claims["iss"], server.url());
+        let output = format!("{claims} {audit:?}");
+        assert!(!output.contains("jane"));
+        assert!(output.contains(&server.url()));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn leaves_rejected_requests_untouched() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![rotated_jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        let extractor = TokenExtractor::default();
+
+        let mut req = request(None);
+        let err = verifier
+            .authenticate::<DefaultClaims, _>(&extractor, &mut req)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::MissingToken));
+
+        let mut req = request(Some(""));
+        let err = verifier
+            .authenticate::<DefaultClaims, _>(&extractor, &mut req)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::EmptyToken));
+        let status = err.downcast_ref::<Error>().unwrap().status_hint();
+        assert_eq!(status, http::StatusCode::BAD_REQUEST);
+        assert_eq!(verifier.stats().empty_tokens, 1);
+
+        let mut req = request(Some(&token(&server.url())));
+        let err = verifier
+            .authenticate::<DefaultClaims, _>(&extractor, &mut req)
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NoMatchingKey));
+        assert!(req.extensions().get::<RawClaims>().is_none());
+        assert!(req.extensions().get::<AuditClaims>().is_none());
+        assert!(req
+            .extensions()
+            .get::<DecodedToken<DefaultClaims>>()
+            .is_none());
+        assert!(req.extensions().get::<VerifiedIdentity>().is_none());
+        assert!(req.extensions().get::<MatchedKey>().is_none());
+        Ok(())
+    }
+}
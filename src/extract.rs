@@ -0,0 +1,470 @@
+use anyhow::{bail, Result};
+use http::header::{AUTHORIZATION, COOKIE};
+use http::Request;
+
+use crate::Error;
+
+/// Describes where a token can be found on a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenSource {
+    /// The `Authorization: Bearer <token>` header.
+    BearerHeader,
+    /// A cookie with the given name.
+    Cookie(String),
+    /// A query parameter with the given name, e.g. for WebSocket upgrades
+    /// where browsers can't set headers.
+    ///
+    /// Tokens in urls tend to end up in access logs, browser history, and
+    /// `Referer` headers, so prefer short lived tokens and only accept this
+    /// source on the routes that need it, see
+    /// [`TokenExtractor::allow_query`].
+    Query(String),
+}
+
+/// Decides how a request carrying several values for the same source is
+/// handled, e.g. two Authorization headers, or the same cookie twice
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateAuthorization {
+    /// Fails with [`Error::AmbiguousAuthorization`].
+    #[default]
+    Reject,
+    /// Uses the last value, e.g. behind a sidecar that appends its own
+    /// Authorization header.
+    PreferLast,
+}
+
+/// `extract_token` attempts to read a token from the given source
+/// of a request, failing with [`Error::AmbiguousAuthorization`] if the
+/// source holds more than one value. See [`Verifier::extract_token`] for
+/// handling duplicate values as configured instead.
+///
+/// [`Verifier::extract_token`]: crate::Verifier::extract_token
+///
+/// ```
+/// use okta_jwt_verifier::{extract_token, TokenSource};
+///
+/// let req = http::Request::builder()
+///     .header("Cookie", "theme=dark; access_token=abc")
+///     .body(())?;
+/// let token = extract_token(&TokenSource::Cookie("access_token".into()), &req)?;
+/// assert_eq!(token.as_deref(), Some("abc"));
+/// # Ok::<(), anyhow::Error>(())
+///```
+pub fn extract_token<B>(
+    source: &TokenSource,
+    req: &Request<B>,
+) -> Result<Option<String>> {
+    extract(source, req, DuplicateAuthorization::Reject)
+}
+
+/// `bearer_token` reads the token from the values of the Authorization
+/// headers of a request, for frameworks whose requests aren't an
+/// [`http::Request`]. Values joined into one header with commas count as
+/// separate headers. Only values with the Bearer scheme are considered, so
+/// another scheme alongside a Bearer token isn't a duplicate, while the
+/// Bearer scheme without a token fails with [`Error::EmptyToken`].
+///
+/// ```
+/// use okta_jwt_verifier::{bearer_token, DuplicateAuthorization, Error};
+///
+/// let values = ["Bearer sidecar", "Bearer client"];
+/// let err = bearer_token(values, DuplicateAuthorization::Reject).unwrap_err();
+/// assert!(matches!(
+///     err.downcast_ref::<Error>(),
+///     Some(Error::AmbiguousAuthorization { count: 2, .. })
+/// ));
+/// let token = bearer_token(values, DuplicateAuthorization::PreferLast)?;
+/// assert_eq!(token.as_deref(), Some("client"));
+/// # Ok::<(), anyhow::Error>(())
+///```
+pub fn bearer_token<'a>(
+    values: impl IntoIterator<Item = &'a str>,
+    duplicates: DuplicateAuthorization,
+) -> Result<Option<String>> {
+    let credentials = values
+        .into_iter()
+        .flat_map(credentials)
+        .filter(|credential| is_bearer(credential))
+        .collect();
+    match pick(credentials, duplicates, || "Authorization header".into())? {
+        Some(credential) => bearer(credential),
+        None => Ok(None),
+    }
+}
+
+/// Extracts a token from the first of several sources that holds one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenExtractor {
+    sources: Vec<TokenSource>,
+    allow_query: bool,
+    // Unless set, as configured for the Verifier, see extract_with
+    duplicates: Option<DuplicateAuthorization>,
+}
+
+impl Default for TokenExtractor {
+    fn default() -> Self {
+        Self::new(vec![TokenSource::BearerHeader])
+    }
+}
+
+impl TokenExtractor {
+    /// `new` constructs an instance of TokenExtractor that tries
+    /// the given sources in order.
+    ///
+    /// ```
+    /// use okta_jwt_verifier::{TokenExtractor, TokenSource};
+    ///
+    /// let extractor = TokenExtractor::new(vec![
+    ///     TokenSource::BearerHeader,
+    ///     TokenSource::Cookie("access_token".into()),
+    /// ]);
+    /// let req = http::Request::builder()
+    ///     .header("Authorization", "Bearer abc")
+    ///     .body(())?;
+    /// assert_eq!(extractor.extract(&req)?.as_deref(), Some("abc"));
+    /// # Ok::<(), anyhow::Error>(())
+    ///```
+    pub fn new(sources: Vec<TokenSource>) -> Self {
+        Self { sources, allow_query: true, duplicates: None }
+    }
+
+    /// `allow_query` is for disabling the [`TokenSource::Query`] sources,
+    /// e.g. from configuration shared by every route. By default this is
+    /// set to true.
+    pub fn allow_query(mut self, allow_query: bool) -> Self {
+        self.allow_query = allow_query;
+        self
+    }
+
+    /// `duplicate_authorization` decides how a source holding several
+    /// values is handled. By default [`Verifier::authenticate`] handles
+    /// them as configured by
+    /// [`Config::duplicate_authorization`](crate::Config::duplicate_authorization)
+    /// and [`TokenExtractor::extract`] rejects the request.
+    ///
+    /// [`Verifier::authenticate`]: crate::Verifier::authenticate
+    pub fn duplicate_authorization(
+        mut self,
+        duplicates: DuplicateAuthorization,
+    ) -> Self {
+        self.duplicates = Some(duplicates);
+        self
+    }
+
+    /// `extract` returns the token from the first source that holds one.
+    /// A source holding several values fails with
+    /// [`Error::AmbiguousAuthorization`], unless configured otherwise,
+    /// rather than falling through to the next source.
+    pub fn extract<B>(&self, req: &Request<B>) -> Result<Option<String>> {
+        self.extract_with(req, DuplicateAuthorization::default())
+    }
+
+    // Like extract, handling duplicate values as given unless the extractor
+    // was configured otherwise
+    pub(crate) fn extract_with<B>(
+        &self,
+        req: &Request<B>,
+        duplicates: DuplicateAuthorization,
+    ) -> Result<Option<String>> {
+        let duplicates = self.duplicates.unwrap_or(duplicates);
+        for source in &self.sources {
+            if !self.allow_query && matches!(source, TokenSource::Query(_)) {
+                continue;
+            }
+            if let Some(token) = extract(source, req, duplicates)? {
+                return Ok(Some(token));
+            }
+        }
+        Ok(None)
+    }
+}
+
+pub(crate) fn extract<B>(
+    source: &TokenSource,
+    req: &Request<B>,
+    duplicates: DuplicateAuthorization,
+) -> Result<Option<String>> {
+    match source {
+        TokenSource::BearerHeader => {
+            let values = req
+                .headers()
+                .get_all(AUTHORIZATION)
+                .iter()
+                .filter_map(|value| value.to_str().ok());
+            bearer_token(values, duplicates)
+        }
+        TokenSource::Cookie(name) => {
+            let values = cookie(req, name);
+            Ok(pick(values, duplicates, || format!("{name} cookie"))?
+                .and_then(non_empty))
+        }
+        TokenSource::Query(name) => {
+            let values = query(req, name);
+            let value =
+                pick(values, duplicates, || format!("{name} query parameter"))?;
+            Ok(value.and_then(non_empty))
+        }
+    }
+}
+
+// Picks the only value of a source, or the last one if preferred
+pub(crate) fn pick<T>(
+    mut values: Vec<T>,
+    duplicates: DuplicateAuthorization,
+    source: impl FnOnce() -> String,
+) -> Result<Option<T>> {
+    match (values.len(), duplicates) {
+        (0 | 1, _) | (_, DuplicateAuthorization::PreferLast) => {
+            Ok(values.pop())
+        }
+        (count, DuplicateAuthorization::Reject) => {
+            bail!(Error::AmbiguousAuthorization { source: source(), count })
+        }
+    }
+}
+
+// Splits a header value into the credentials of the Authorization headers
+// that were joined into it. Auth params, e.g. of Digest, are separated by
+// commas as well, so only a part starting with a scheme followed by a space
+// begins another credential.
+pub(crate) fn credentials(value: &str) -> Vec<&str> {
+    let mut starts = vec![0];
+    for (comma, _) in value.match_indices(',') {
+        let rest = value[comma + 1..].trim_start();
+        if let Some((scheme, _)) = rest.split_once(' ') {
+            if !scheme.is_empty() && !scheme.contains('=') {
+                starts.push(comma + 1);
+            }
+        }
+    }
+    let ends = starts[1..].iter().map(|start| start - 1).chain([value.len()]);
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| value[start..end].trim())
+        .filter(|credential| !credential.is_empty())
+        .collect()
+}
+
+// The scheme is case insensitive, see RFC 7235
+fn is_bearer(credential: &str) -> bool {
+    let scheme = credential.split(char::is_whitespace).next();
+    scheme.is_some_and(|scheme| scheme.eq_ignore_ascii_case("bearer"))
+}
+
+fn bearer(value: &str) -> Result<Option<String>> {
+    if !is_bearer(value) {
+        return Ok(None);
+    }
+    let token = value.split_once(char::is_whitespace).map_or("", |(_, t)| t);
+    match non_empty(token.trim()) {
+        Some(token) => Ok(Some(token)),
+        None => bail!(Error::EmptyToken),
+    }
+}
+
+// Cookies are `name=value` pairs separated by `;`, possibly spread
+// over several headers, with values optionally in double quotes
+fn cookie<'a, B>(req: &'a Request<B>, name: &str) -> Vec<&'a str> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .filter(|(key, _)| *key == name)
+        .map(|(_, value)| {
+            let value = value.trim();
+            value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value)
+        })
+        .collect()
+}
+
+fn query<B>(req: &Request<B>, name: &str) -> Vec<String> {
+    let Some(query) = req.uri().query() else {
+        return Vec::new();
+    };
+    url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+        .collect()
+}
+
+fn non_empty(token: impl AsRef<str>) -> Option<String> {
+    let token = token.as_ref();
+    if token.is_empty() {
+        None
+    } else {
+        Some(token.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut req = Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    // The source and count of an AmbiguousAuthorization error
+    fn ambiguous(result: Result<Option<String>>) -> Option<(String, usize)> {
+        match result.err()?.downcast_ref::<Error>()? {
+            Error::AmbiguousAuthorization { source, count } => {
+                Some((source.clone(), *count))
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn extracts_bearer_tokens() -> Result<()> {
+        let source = TokenSource::BearerHeader;
+        let req = request("/", &[("Authorization", "bearer  abc ")]);
+        assert_eq!(extract_token(&source, &req)?.as_deref(), Some("abc"));
+        let req = request("/", &[("Authorization", "Basic abc")]);
+        assert_eq!(extract_token(&source, &req)?, None);
+        let req = request("/", &[("Authorization", "Bearer\tabc")]);
+        assert_eq!(extract_token(&source, &req)?.as_deref(), Some("abc"));
+        for value in ["Bearer ", "Bearer"] {
+            let req = request("/", &[("Authorization", value)]);
+            let err = extract_token(&source, &req).unwrap_err();
+            assert_eq!(err.downcast_ref(), Some(&Error::EmptyToken));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn extracts_cookies() -> Result<()> {
+        let source = TokenSource::Cookie("token".into());
+        let req = request(
+            "/",
+            &[("Cookie", "a=1; other_token=x"), ("Cookie", "token=\"abc\"")],
+        );
+        assert_eq!(extract_token(&source, &req)?.as_deref(), Some("abc"));
+        let req = request("/", &[("Cookie", "tokens=abc")]);
+        assert_eq!(extract_token(&source, &req)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn extracts_and_decodes_query_params() -> Result<()> {
+        let source = TokenSource::Query("access%5Ftoken".into());
+        let req = request("/ws?a=1&access%5Ftoken=abc%2Edef", &[]);
+        assert_eq!(extract_token(&source, &req)?, None);
+        let source = TokenSource::Query("access_token".into());
+        assert_eq!(extract_token(&source, &req)?.as_deref(), Some("abc.def"));
+        let req = request("/ws?access_token=", &[]);
+        assert_eq!(extract_token(&source, &req)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn follows_the_configured_order() -> Result<()> {
+        let req = request(
+            "/?token=query",
+            &[("Authorization", "Bearer header"), ("Cookie", "token=cookie")],
+        );
+        let extractor = TokenExtractor::new(vec![
+            TokenSource::Query("token".into()),
+            TokenSource::Cookie("token".into()),
+            TokenSource::BearerHeader,
+        ]);
+        assert_eq!(extractor.extract(&req)?.as_deref(), Some("query"));
+        let extractor = extractor.allow_query(false);
+        assert_eq!(extractor.extract(&req)?.as_deref(), Some("cookie"));
+        let req = request("/?token=query", &[]);
+        assert_eq!(extractor.extract(&req)?, None);
+        assert_eq!(
+            TokenExtractor::default()
+                .extract(&request("/", &[("Authorization", "Bearer header")]))?
+                .as_deref(),
+            Some("header")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_duplicate_authorization_headers() -> Result<()> {
+        let source = TokenSource::BearerHeader;
+        let header = "Authorization header".to_string();
+        let req = request(
+            "/",
+            &[
+                ("Authorization", "Bearer sidecar"),
+                ("Authorization", "Bearer b"),
+            ],
+        );
+        assert_eq!(ambiguous(extract_token(&source, &req)), Some((header, 2)));
+
+        // Credentials of other schemes don't count as duplicates
+        let extractor = TokenExtractor::default();
+        for value in ["Basic a, Bearer b", "Bearer b, Basic a"] {
+            let req = request("/", &[("Authorization", value)]);
+            assert_eq!(extract_token(&source, &req)?.as_deref(), Some("b"));
+            assert_eq!(extractor.extract(&req)?.as_deref(), Some("b"));
+            let extractor = extractor
+                .clone()
+                .duplicate_authorization(DuplicateAuthorization::PreferLast);
+            assert_eq!(extractor.extract(&req)?.as_deref(), Some("b"));
+        }
+        let req = request(
+            "/",
+            &[
+                ("Authorization", "Bearer a, Basic b"),
+                ("Authorization", "Bearer c"),
+            ],
+        );
+        assert!(ambiguous(extractor.extract(&req)).is_some());
+        let extractor = TokenExtractor::default()
+            .duplicate_authorization(DuplicateAuthorization::PreferLast);
+        assert_eq!(extractor.extract(&req)?.as_deref(), Some("c"));
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_the_auth_params_of_a_single_header() -> Result<()> {
+        let value = r#"Digest realm="a b", nonce="c", Bearer d"#;
+        assert_eq!(
+            credentials(value),
+            [r#"Digest realm="a b", nonce="c""#, "Bearer d"]
+        );
+        let req = request("/", &[("Authorization", r#"Digest realm="a, b""#)]);
+        assert_eq!(extract_token(&TokenSource::BearerHeader, &req)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_duplicate_cookies_and_query_params() -> Result<()> {
+        let cookie = TokenSource::Cookie("token".into());
+        let query = TokenSource::Query("token".into());
+        let req = request(
+            "/?token=a&token=b",
+            &[("Cookie", "token=a"), ("Cookie", "token=b")],
+        );
+        assert_eq!(
+            ambiguous(extract_token(&cookie, &req)),
+            Some(("token cookie".into(), 2))
+        );
+        assert_eq!(
+            ambiguous(extract_token(&query, &req)),
+            Some(("token query parameter".into(), 2))
+        );
+
+        // An ambiguous source doesn't fall through to the next one
+        let extractor = TokenExtractor::new(vec![cookie, query]);
+        let req = request("/?token=q", &[("Cookie", "token=a; token=b")]);
+        assert!(ambiguous(extractor.extract(&req)).is_some());
+        let extractor = extractor
+            .duplicate_authorization(DuplicateAuthorization::PreferLast);
+        assert_eq!(extractor.extract(&req)?.as_deref(), Some("b"));
+        Ok(())
+    }
+}
@@ -0,0 +1,1265 @@
+// Retrieves the keys over HTTP with the client of the enabled feature, or
+// from the keys file, on behalf of the key store. Nothing here knows about
+// tokens.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+
+#[cfg(all(feature = "cache-surf", not(feature = "client-reqwest")))]
+use http_cache_surf::Cache;
+
+#[cfg(feature = "cache-reqwest")]
+use http_cache_reqwest::Cache;
+
+#[cfg(any(
+    feature = "cache-reqwest",
+    all(feature = "cache-surf", not(feature = "client-reqwest"))
+))]
+use crate::cache;
+use crate::discovery::DiscoveryCache;
+use crate::keystore::KeyState;
+#[cfg(all(
+    feature = "cache-redis",
+    any(feature = "client-reqwest", feature = "client-surf")
+))]
+use crate::redis_cache;
+#[cfg(any(feature = "client-surf", feature = "client-reqwest"))]
+use crate::retry;
+use crate::{
+    keys_urls, logging, parse_key_set, persist, queue, runtime, snapshot,
+    Config, Error, FetchMetadata, Jwks,
+};
+
+// The content types of a JWKS document, see require_json_content_type
+const JSON_MEDIA_TYPES: [&str; 2] =
+    ["application/json", "application/jwk-set+json"];
+
+// How much of an error response of the keys endpoint is kept
+#[cfg_attr(
+    not(any(feature = "client-surf", feature = "client-reqwest")),
+    allow(dead_code)
+)]
+pub(crate) const ERROR_BODY_SNIPPET_BYTES: usize = 256;
+
+// Reads the keys from the keys file off the async task, keeping the current
+// ones while its modification time and size are unchanged
+pub(crate) async fn read_keys_file(
+    path: &Path,
+    config: &Config,
+    current: Option<&KeyState>,
+) -> Result<(Jwks, FetchMetadata)> {
+    let source = path.display().to_string();
+    let previous = current
+        .and_then(|state| Some((&state.jwks, state.fetch.as_ref()?)))
+        .filter(|(_, fetch)| fetch.source == source);
+    let known = previous.and_then(|(_, fetch)| fetch.etag.clone());
+    let (path, max) = (path.to_path_buf(), config.max_keys_bytes);
+    let read = {
+        let source = source.clone();
+        runtime::unblock(move || read_file(&path, &source, known, max)).await?
+    };
+    let FileRead { tag, modified, body } = read;
+    let fetch = FetchMetadata {
+        source,
+        fetched_at: SystemTime::now(),
+        max_age: None,
+        etag: Some(tag),
+        last_modified: Some(httpdate::fmt_http_date(modified)),
+    };
+    // The file is only left unread when it matched the current keys
+    let jwks = match (body, previous) {
+        (None, Some((jwks, _))) => {
+            logging::debug!("The keys file {} is unchanged", fetch.source);
+            jwks.clone()
+        }
+        (body, _) => {
+            parse_key_set(body.as_deref().unwrap_or_default(), config)?
+        }
+    };
+    Ok((jwks, fetch))
+}
+
+// The keys file as read by read_file
+struct FileRead {
+    // The modification time to the nanosecond and the size of the file,
+    // kept as the ETag of the keys
+    tag: String,
+    modified: SystemTime,
+    // Unless the tag matched the known one
+    body: Option<Vec<u8>>,
+}
+
+// Reads the keys file unless its tag matches the known one, checking its
+// size before reading it
+fn read_file(
+    path: &Path,
+    source: &str,
+    known: Option<String>,
+    max: usize,
+) -> Result<FileRead> {
+    let unreadable = |e: std::io::Error| Error::KeysFileUnreadable {
+        path: source.to_string(),
+        reason: e.to_string(),
+    };
+    let metadata = std::fs::metadata(path).map_err(unreadable)?;
+    let modified = metadata.modified().map_err(unreadable)?;
+    let since_epoch =
+        modified.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let tag = format!(
+        "\"{}.{:09}-{}\"",
+        since_epoch.as_secs(),
+        since_epoch.subsec_nanos(),
+        metadata.len()
+    );
+    if known.as_ref() == Some(&tag) {
+        return Ok(FileRead { tag, modified, body: None });
+    }
+    let len = usize::try_from(metadata.len()).unwrap_or(usize::MAX);
+    check_size(source, len, Some(max))?;
+    let body = std::fs::read(path).map_err(unreadable)?;
+    check_size(source, body.len(), Some(max))?;
+    Ok(FileRead { tag, modified, body: Some(body) })
+}
+
+// Attempts to retrieve the keys from the issuer, trying the keys endpoint
+// and then the fallback urls in order. The current keys are kept when the
+// url that supplied them reports them unchanged.
+pub(crate) async fn get(
+    issuer: &str,
+    config: &Config,
+    current: Option<&KeyState>,
+    discovery: &DiscoveryCache,
+) -> Result<(Jwks, FetchMetadata)> {
+    if let Some(path) = &config.keys_file {
+        return read_keys_file(path, config, current).await;
+    }
+    #[cfg(all(
+        feature = "cache-redis",
+        any(feature = "client-reqwest", feature = "client-surf")
+    ))]
+    if let Some(stored) = redis_cache::load(issuer, config, current).await {
+        return Ok(stored);
+    }
+    let mut endpoints = keys_urls(issuer, config)?;
+    let Some(ttl) = config.discovery else {
+        return get_any(issuer, config, current, &endpoints).await;
+    };
+    let (info, fresh) = discovery.resolve(issuer, config, ttl).await?;
+    endpoints[0] = info.keys_url(config);
+    let result = get_any(issuer, config, current, &endpoints).await;
+    let from_jwks_uri = matches!(
+        &result,
+        Ok((_, fetch)) if fetch.source == endpoints[0]
+    );
+    if from_jwks_uri || fresh {
+        return result;
+    }
+    // The jwks_uri may have moved since the document was retrieved
+    match discovery.reload(issuer, config).await {
+        Ok(info) if info.keys_url(config) != endpoints[0] => {
+            logging::info!("The jwks_uri moved to {}", info.jwks_uri);
+            endpoints[0] = info.keys_url(config);
+            get_any(issuer, config, current, &endpoints).await
+        }
+        Ok(_) => result,
+        Err(e) => {
+            logging::warn!("Unable to retrieve the discovery document: {e:#}");
+            result
+        }
+    }
+}
+
+// Tries the given keys urls in order until one returns a usable key set
+async fn get_any(
+    issuer: &str,
+    config: &Config,
+    current: Option<&KeyState>,
+    endpoints: &[String],
+) -> Result<(Jwks, FetchMetadata)> {
+    let mut failures: Vec<(String, anyhow::Error)> = Vec::new();
+    for url in endpoints.iter().cloned() {
+        match get_from(issuer, &url, config, current).await {
+            Ok((jwks, fetch)) => {
+                retain(issuer, config, current, &jwks, &fetch).await;
+                return Ok((jwks, fetch));
+            }
+            // The other urls would wait in the same queue
+            Err(e) if queue::turned_away(&e) => return Err(e),
+            Err(e) => failures.push((url, e)),
+        }
+    }
+    if failures.len() > 1 {
+        let attempts = failures
+            .iter()
+            .map(|(url, e)| {
+                // Failures outside of the crate's errors come from the
+                // settings of the client rather than the keys endpoint, and
+                // won't go away by retrying
+                let error =
+                    e.downcast_ref::<Error>().cloned().unwrap_or_else(|| {
+                        Error::InvalidKeysEndpoint {
+                            endpoint: url.clone(),
+                            reason: format!("{e:#}"),
+                        }
+                    });
+                (url.clone(), error)
+            })
+            .collect();
+        bail!(Error::KeysEndpointsFailed { attempts })
+    }
+    match failures.pop() {
+        Some((_, e)) => Err(e),
+        None => bail!("No keys url configured!"),
+    }
+}
+
+// Keeps retrieved keys in the snapshot and the shared store, if configured.
+// The snapshot is only written when the keys differ from the current ones.
+pub(crate) async fn retain(
+    issuer: &str,
+    config: &Config,
+    current: Option<&KeyState>,
+    jwks: &Jwks,
+    fetch: &FetchMetadata,
+) {
+    let unchanged = current.is_some_and(|current| {
+        current.fetch.is_some() && !current.stale && current.jwks == *jwks
+    });
+    if !unchanged {
+        snapshot::write(issuer, config, jwks, fetch).await;
+    }
+    #[cfg(all(
+        feature = "cache-redis",
+        any(feature = "client-reqwest", feature = "client-surf")
+    ))]
+    redis_cache::store(issuer, config, jwks, fetch).await;
+}
+
+// Attempts to retrieve the keys from a single url, with retries
+async fn get_from(
+    issuer: &str,
+    url: &str,
+    config: &Config,
+    current: Option<&KeyState>,
+) -> Result<(Jwks, FetchMetadata)> {
+    let previous = current
+        .and_then(|state| Some((&state.jwks, state.fetch.as_ref()?)))
+        .filter(|(_, fetch)| fetch.source == url && !CACHING);
+    let validators =
+        previous.map_or_else(Validators::default, |(_, f)| Validators::of(f));
+    let limit = Some(config.max_keys_bytes);
+    let fetch = || queued_fetch(url, Some(issuer), config, limit, &validators);
+    let fetched = config.fetch_retry.run(config.classifier(), fetch).await?;
+    let (keys, max_age, validators) = match previous {
+        // A 304 only answers a request that sent validators
+        Some((jwks, fetch)) if fetched.not_modified => {
+            logging::debug!("The keys at {url} are unchanged");
+            let Validators { etag, last_modified } = fetched.validators;
+            let validators = Validators {
+                etag: etag.or_else(|| fetch.etag.clone()),
+                last_modified: last_modified
+                    .or_else(|| fetch.last_modified.clone()),
+            };
+            (jwks.clone(), fetched.max_age.or(fetch.max_age), validators)
+        }
+        _ => {
+            let keys = key_set_of(url, &fetched, config)?;
+            persist::persist(config, &fetched.body).await;
+            (keys, fetched.max_age, fetched.validators)
+        }
+    };
+    let fetch = FetchMetadata {
+        source: url.to_string(),
+        fetched_at: SystemTime::now(),
+        max_age,
+        etag: validators.etag,
+        last_modified: validators.last_modified,
+    };
+    Ok((keys, fetch))
+}
+
+// The keys of a response of the keys endpoint
+pub(crate) fn key_set_of(
+    url: &str,
+    fetched: &Fetched,
+    config: &Config,
+) -> Result<Jwks> {
+    if config.require_json_content_type {
+        let media_type = fetched
+            .content_type
+            .as_deref()
+            .and_then(|value| value.split(';').next())
+            .map(str::trim);
+        let is_json = media_type.is_some_and(|media_type| {
+            JSON_MEDIA_TYPES
+                .iter()
+                .any(|json| media_type.eq_ignore_ascii_case(json))
+        });
+        if !is_json {
+            let reason = match media_type {
+                Some(media_type) => {
+                    format!("{url} answered with the content type {media_type}")
+                }
+                None => format!("{url} answered without a content type"),
+            };
+            bail!(Error::InvalidKeySet { reason })
+        }
+    }
+    parse_key_set(&fetched.body, config)
+}
+
+// Builds the surf client config from the given config
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+fn build_surf_config(config: &Config) -> Result<surf::Config> {
+    if config.proxy.is_some() {
+        bail!("Proxies are not supported by the client-surf feature!")
+    }
+    // The fetch timeout is applied by remote_fetch instead, so that it's
+    // reported as such rather than as a transport failure
+    Ok(surf::Config::new().set_timeout(None))
+}
+
+// Builds the underlying surf client from the given config, surf only
+// follows redirects with its middleware
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+fn build_surf_base(config: &Config) -> Result<surf::Client> {
+    let client: surf::Client = match build_surf_config(config)?.try_into() {
+        Ok(client) => client,
+        Err(e) => bail!(e),
+    };
+    Ok(match config.max_redirects {
+        Some(max) if max > 0 => {
+            let attempts = u8::try_from(max).unwrap_or(u8::MAX);
+            client.with(surf::middleware::Redirect::new(attempts))
+        }
+        _ => client,
+    })
+}
+
+// Builds a default surf client
+#[cfg(all(
+    feature = "client-surf",
+    not(feature = "client-reqwest"),
+    not(feature = "cache-surf")
+))]
+fn build_surf_client(config: &Config, _: Option<&str>) -> Result<surf::Client> {
+    build_surf_base(config)
+}
+
+// Builds a surf client configured to use a disk cache
+#[cfg(all(feature = "cache-surf", not(feature = "client-reqwest")))]
+fn build_surf_client(
+    config: &Config,
+    issuer: Option<&str>,
+) -> Result<surf::Client> {
+    let cache = cache::http_cache(config, issuer);
+    Ok(build_surf_base(config)?.with(Cache(cache)))
+}
+
+// Whether the cache of a cache feature is used, which revalidates the keys
+// on its own
+pub(crate) const CACHING: bool = cfg!(any(
+    feature = "cache-reqwest",
+    all(feature = "cache-surf", not(feature = "client-reqwest"))
+));
+
+// A successful response of a remote fetch, or else a 304 answering a
+// conditional request, which has no body
+pub(crate) struct Fetched {
+    pub(crate) body: Vec<u8>,
+    pub(crate) max_age: Option<Duration>,
+    pub(crate) validators: Validators,
+    pub(crate) not_modified: bool,
+    pub(crate) content_type: Option<String>,
+}
+
+// The validators of a response, sent back in a conditional request so that
+// unchanged keys aren't transferred again
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Validators {
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+}
+
+impl Validators {
+    pub(crate) fn of(fetch: &FetchMetadata) -> Self {
+        Self {
+            etag: fetch.etag.clone(),
+            last_modified: fetch.last_modified.clone(),
+        }
+    }
+
+    #[cfg_attr(
+        not(any(feature = "client-surf", feature = "client-reqwest")),
+        allow(dead_code)
+    )]
+    fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+// The max-age directive of a Cache-Control header
+#[cfg_attr(
+    not(any(feature = "client-surf", feature = "client-reqwest")),
+    allow(dead_code)
+)]
+fn max_age(cache_control: &str) -> Option<Duration> {
+    cache_control.split(',').find_map(|directive| {
+        let (name, value) = directive.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("max-age") {
+            return None;
+        }
+        value.trim().trim_matches('"').parse().ok().map(Duration::from_secs)
+    })
+}
+
+// Requests the url, giving up once the fetch timeout elapses or the body
+// exceeds the limit. Racing the whole request covers reading the body as
+// well, and clients that don't report timeouts as such.
+pub(crate) async fn remote_fetch(
+    url: &str,
+    issuer: Option<&str>,
+    config: &Config,
+    limit: Option<usize>,
+) -> Result<Fetched> {
+    conditional_fetch(url, issuer, config, limit, &Validators::default()).await
+}
+
+// Like remote_fetch, answering with not_modified set when the response
+// matches the validators
+pub(crate) async fn conditional_fetch(
+    url: &str,
+    issuer: Option<&str>,
+    config: &Config,
+    limit: Option<usize>,
+    validators: &Validators,
+) -> Result<Fetched> {
+    let fetch = fetch_url(url, issuer, config, limit, validators);
+    let Some(timeout) = config.fetch_timeout else {
+        return fetch.await;
+    };
+    match runtime::timeout(timeout, fetch).await {
+        Some(result) => result,
+        None => bail!(Error::KeysTimeout { url: url.to_string(), timeout }),
+    }
+}
+
+// Like conditional_fetch, taking a slot in the fetch_queue for the duration
+// of the request
+pub(crate) async fn queued_fetch(
+    url: &str,
+    issuer: Option<&str>,
+    config: &Config,
+    limit: Option<usize>,
+    validators: &Validators,
+) -> Result<Fetched> {
+    let _slot = config.fetch_queue.enter(config, url).await?;
+    conditional_fetch(url, issuer, config, limit, validators).await
+}
+
+// The start of an error response, on a single line
+#[cfg_attr(
+    not(any(feature = "client-surf", feature = "client-reqwest")),
+    allow(dead_code)
+)]
+fn body_snippet(body: &[u8]) -> String {
+    let body = &body[..body.len().min(ERROR_BODY_SNIPPET_BYTES)];
+    let body = String::from_utf8_lossy(body);
+    body.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Fails once a body grows beyond the limit
+pub(crate) fn check_size(
+    url: &str,
+    len: usize,
+    limit: Option<usize>,
+) -> Result<()> {
+    match limit {
+        Some(max) if len > max => {
+            bail!(Error::ResponseTooLarge { url: url.to_string(), max })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+async fn fetch_url(
+    url: &str,
+    issuer: Option<&str>,
+    config: &Config,
+    limit: Option<usize>,
+    validators: &Validators,
+) -> Result<Fetched> {
+    use async_std::io::ReadExt;
+
+    let mut req = surf::get(url);
+    if let Some(etag) = &validators.etag {
+        req = req.header("If-None-Match", etag.as_str());
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        req = req.header("If-Modified-Since", last_modified.as_str());
+    }
+    let client = build_surf_client(config, issuer)?;
+    let mut res = match client.send(req).await {
+        Ok(r) => r,
+        Err(e) => bail!(Error::KeysUnreachable {
+            url: url.to_string(),
+            reason: e.to_string(),
+        }),
+    };
+    if res.status() == surf::StatusCode::TooManyRequests {
+        let header = |name| res.header(name).map(|value| value.as_str());
+        bail!(Error::KeysRateLimited {
+            url: url.to_string(),
+            retry_at: retry::rate_limit_reset(
+                header("Retry-After"),
+                header("X-Rate-Limit-Reset"),
+            ),
+        })
+    }
+    let max_age =
+        res.header("Cache-Control").and_then(|value| max_age(value.as_str()));
+    let value_of = |name| res.header(name).map(|value| value.as_str().into());
+    let response_validators = Validators {
+        etag: value_of("ETag"),
+        last_modified: value_of("Last-Modified"),
+    };
+    let content_type = value_of("Content-Type");
+    if res.status() == surf::StatusCode::NotModified && !validators.is_empty() {
+        return Ok(Fetched {
+            body: Vec::new(),
+            max_age,
+            validators: response_validators,
+            not_modified: true,
+            content_type: None,
+        });
+    }
+    if !res.status().is_success() {
+        let mut body = Vec::new();
+        let snippet = ERROR_BODY_SNIPPET_BYTES as u64;
+        let _ = res.take_body().take(snippet).read_to_end(&mut body).await;
+        bail!(Error::KeysStatus {
+            status: res.status().into(),
+            url: url.into(),
+            body: body_snippet(&body),
+        })
+    }
+    if let Some(len) = res.len() {
+        check_size(url, len, limit)?;
+    }
+    // Reads one byte past the limit to tell whether it was exceeded
+    let mut body = Vec::new();
+    let read_limit = limit.map_or(u64::MAX, |max| max as u64 + 1);
+    res.take_body().take(read_limit).read_to_end(&mut body).await?;
+    check_size(url, body.len(), limit)?;
+    Ok(Fetched {
+        body,
+        max_age,
+        validators: response_validators,
+        not_modified: false,
+        content_type,
+    })
+}
+
+// Builds the underlying reqwest client from the given config
+#[cfg(feature = "client-reqwest")]
+fn build_reqwest_base(config: &Config) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(timeout) = config.fetch_timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = config.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    match config.max_redirects {
+        Some(0) => {
+            builder = builder.redirect(reqwest::redirect::Policy::none())
+        }
+        Some(max) => {
+            builder = builder.redirect(reqwest::redirect::Policy::limited(max))
+        }
+        None => {}
+    }
+    Ok(builder.build()?)
+}
+
+// Builds a default reqwest client
+#[cfg(all(feature = "client-reqwest", not(feature = "cache-reqwest")))]
+fn build_reqwest_client(
+    config: &Config,
+    _: Option<&str>,
+) -> Result<reqwest_middleware::ClientWithMiddleware> {
+    Ok(reqwest_middleware::ClientBuilder::new(build_reqwest_base(config)?)
+        .build())
+}
+
+// Builds a reqwest client configured to use a disk cache
+#[cfg(all(feature = "client-reqwest", feature = "cache-reqwest"))]
+fn build_reqwest_client(
+    config: &Config,
+    issuer: Option<&str>,
+) -> Result<reqwest_middleware::ClientWithMiddleware> {
+    Ok(reqwest_middleware::ClientBuilder::new(build_reqwest_base(config)?)
+        .with(Cache(cache::http_cache(config, issuer)))
+        .build())
+}
+
+#[cfg(feature = "client-reqwest")]
+async fn fetch_url(
+    url: &str,
+    issuer: Option<&str>,
+    config: &Config,
+    limit: Option<usize>,
+    validators: &Validators,
+) -> Result<Fetched> {
+    use reqwest::header;
+
+    let client = build_reqwest_client(config, issuer)?;
+    let timed_out = |timeout: Option<Duration>| Error::KeysTimeout {
+        url: url.to_string(),
+        timeout: timeout.unwrap_or_default(),
+    };
+    let mut req = client.get(url);
+    if let Some(etag) = &validators.etag {
+        req = req.header(header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+    }
+    let mut res = match req.send().await {
+        Ok(r) => r,
+        Err(reqwest_middleware::Error::Reqwest(e)) if e.is_timeout() => {
+            bail!(timed_out(config.fetch_timeout))
+        }
+        Err(e) => bail!(Error::KeysUnreachable {
+            url: url.to_string(),
+            reason: e.to_string(),
+        }),
+    };
+    if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        let header =
+            |name| res.headers().get(name).and_then(|v| v.to_str().ok());
+        bail!(Error::KeysRateLimited {
+            url: url.to_string(),
+            retry_at: retry::rate_limit_reset(
+                header("Retry-After"),
+                header("X-Rate-Limit-Reset"),
+            ),
+        })
+    }
+    let max_age = res
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(max_age);
+    let value_of = |name| {
+        let value = res.headers().get(name)?.to_str().ok()?;
+        Some(value.to_string())
+    };
+    let response_validators = Validators {
+        etag: value_of(header::ETAG),
+        last_modified: value_of(header::LAST_MODIFIED),
+    };
+    let content_type = value_of(header::CONTENT_TYPE);
+    if res.status() == reqwest::StatusCode::NOT_MODIFIED
+        && !validators.is_empty()
+    {
+        return Ok(Fetched {
+            body: Vec::new(),
+            max_age,
+            validators: response_validators,
+            not_modified: true,
+            content_type: None,
+        });
+    }
+    if !res.status().is_success() {
+        let (status, url) = (res.status().as_u16(), res.url().to_string());
+        let mut body = Vec::new();
+        while body.len() < ERROR_BODY_SNIPPET_BYTES {
+            match res.chunk().await {
+                Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+                _ => break,
+            }
+        }
+        bail!(Error::KeysStatus { status, url, body: body_snippet(&body) })
+    }
+    if let Some(len) = res.content_length() {
+        check_size(url, usize::try_from(len).unwrap_or(usize::MAX), limit)?;
+    }
+    let mut body = Vec::new();
+    loop {
+        match res.chunk().await {
+            Ok(Some(chunk)) => {
+                check_size(url, body.len() + chunk.len(), limit)?;
+                body.extend_from_slice(&chunk);
+            }
+            Ok(None) => break,
+            Err(e) if e.is_timeout() => bail!(timed_out(config.fetch_timeout)),
+            Err(e) => bail!(e),
+        }
+    }
+    Ok(Fetched {
+        body,
+        max_age,
+        validators: response_validators,
+        not_modified: false,
+        content_type,
+    })
+}
+
+// Without a client only the keys file and keys provided by the
+// application are available
+#[cfg(not(any(feature = "client-surf", feature = "client-reqwest")))]
+async fn fetch_url(
+    url: &str,
+    _: Option<&str>,
+    _: &Config,
+    _: Option<usize>,
+    _: &Validators,
+) -> Result<Fetched> {
+    bail!(Error::KeysUnreachable {
+        url: url.to_string(),
+        reason: "no HTTP client is enabled, see the client-reqwest and \
+                 client-surf features"
+            .into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{Config, DefaultClaims, FetchRetry, Verifier, ORG_ENDPOINT};
+
+    #[test]
+    fn reads_the_max_age_directive() {
+        let secs = |value| max_age(value).map(|age| age.as_secs());
+        assert_eq!(secs("max-age=60"), Some(60));
+        assert_eq!(
+            secs("public, Max-Age = \"120\", must-revalidate"),
+            Some(120)
+        );
+        assert_eq!(secs("no-cache"), None);
+        assert_eq!(secs("max-age=soon"), None);
+    }
+
+    #[async_test]
+    async fn falls_back_when_keys_endpoint_fails() -> Result<()> {
+        let mut primary = mockito::Server::new_async().await;
+        let mut mirror = mockito::Server::new_async().await;
+        let p = primary
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(503)
+            .expect(3)
+            .create();
+        let m = mirror
+            .mock("GET", "/mirror/keys")
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .create();
+        let mirror_url = format!("{}/mirror/keys", mirror.url());
+        let config = Config {
+            fallback_keys_urls: vec![mirror_url.clone()],
+            ..Config::default()
+        };
+        let verifier =
+            Verifier::new_with_config(&primary.url(), config).await?;
+        p.assert();
+        m.assert();
+        assert_eq!(verifier.fetch_metadata().unwrap().source, mirror_url);
+        verifier.verify::<DefaultClaims>(&token(&primary.url())).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn falls_back_when_keys_endpoint_is_unreachable() -> Result<()> {
+        let mut mirror = mockito::Server::new_async().await;
+        mock_keys(&mut mirror, vec![jwk()]);
+        let mirror_url = format!("{}{ORG_ENDPOINT}", mirror.url());
+        let config = Config {
+            fallback_keys_urls: vec![mirror_url.clone()],
+            ..Config::default()
+        };
+        let verifier =
+            Verifier::new_with_config("http://127.0.0.1:1", config).await?;
+        assert_eq!(verifier.fetch_metadata().unwrap().source, mirror_url);
+        Ok(())
+    }
+
+    // The disk cache revalidates on its own
+    #[cfg(not(any(
+        feature = "cache-reqwest",
+        all(feature = "cache-surf", not(feature = "client-reqwest"))
+    )))]
+    #[async_test]
+    async fn revalidates_the_keys_with_conditional_requests() -> Result<()> {
+        use mockito::Matcher;
+
+        let mut server = mockito::Server::new_async().await;
+        let modified = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let first = server
+            .mock("GET", ORG_ENDPOINT)
+            .match_header("if-none-match", Matcher::Missing)
+            .match_header("if-modified-since", Matcher::Missing)
+            .with_status(200)
+            .with_header("ETag", r#""v1""#)
+            .with_header("Last-Modified", modified)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let verifier = Verifier::new(&server.url()).await?;
+        let fetch = verifier.fetch_metadata().unwrap();
+        assert_eq!(fetch.etag.as_deref(), Some(r#""v1""#));
+        assert_eq!(fetch.last_modified.as_deref(), Some(modified));
+        first.assert();
+
+        let unchanged = server
+            .mock("GET", ORG_ENDPOINT)
+            .match_header("if-none-match", r#""v1""#)
+            .match_header("if-modified-since", modified)
+            .with_status(304)
+            .with_header("Cache-Control", "max-age=60")
+            .expect(2)
+            .create();
+        let generation = verifier.key_generation();
+        for _ in 0..2 {
+            assert!(!verifier.refresh_keys().await?.changed());
+        }
+        unchanged.assert();
+        assert_eq!(verifier.key_generation(), generation);
+        let fetch = verifier.fetch_metadata().unwrap();
+        assert_eq!(fetch.etag.as_deref(), Some(r#""v1""#));
+        assert_eq!(fetch.max_age, Some(Duration::from_secs(60)));
+        assert_eq!(verifier.stats().key_count, 1);
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        unchanged.remove();
+        first.remove();
+
+        let rotated = server
+            .mock("GET", ORG_ENDPOINT)
+            .match_header("if-none-match", r#""v1""#)
+            .with_status(200)
+            .with_header("ETag", r#""v2""#)
+            .with_body(keys_body(vec![jwk(), rotated_jwk()]))
+            .expect(1)
+            .create();
+        assert!(verifier.refresh_keys().await?.changed());
+        let fetch = verifier.fetch_metadata().unwrap();
+        assert_eq!(fetch.etag.as_deref(), Some(r#""v2""#));
+        assert_eq!(fetch.last_modified, None);
+        rotated.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn tries_the_keys_endpoints_in_order() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let mut mirror = mockito::Server::new_async().await;
+        let missing = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(404)
+            .expect(1)
+            .create();
+        let empty = server
+            .mock("GET", "/empty/keys")
+            .with_status(200)
+            .with_body(keys_body(vec![]))
+            .expect(1)
+            .create();
+        let up = mirror
+            .mock("GET", "/keys")
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let mirror_url = format!("{}/keys", mirror.url());
+        let config = Config {
+            fallback_keys_urls: vec!["/empty/keys".into(), mirror_url.clone()],
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        assert_eq!(verifier.fetch_metadata().unwrap().source, mirror_url);
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        missing.assert();
+        empty.assert();
+        up.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn reports_the_failure_of_every_keys_url() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", ORG_ENDPOINT).with_status(404).create();
+        let down = server.mock("GET", "/down/keys").with_status(503).create();
+        let fallback = server
+            .mock("GET", "/fallback/keys")
+            .with_status(502)
+            .expect(2)
+            .create();
+        let issuer = server.url();
+        let url = |path: &str| format!("{issuer}{path}");
+        let config = || Config {
+            fallback_keys_urls: vec![
+                "/down/keys".into(),
+                url("/fallback/keys"),
+            ],
+            fetch_retry: FetchRetry::disabled(),
+            ..Config::default()
+        };
+        let err =
+            Verifier::new_with_config(&issuer, config()).await.unwrap_err();
+        let Some(Error::KeysEndpointsFailed { attempts }) =
+            err.downcast_ref::<Error>()
+        else {
+            panic!("unexpected error {err}")
+        };
+        let tried: Vec<_> = attempts
+            .iter()
+            .map(|(url, e)| match e {
+                Error::KeysStatus { status, .. } => (url.clone(), *status),
+                e => panic!("unexpected error {e}"),
+            })
+            .collect();
+        assert_eq!(
+            tried,
+            [
+                (url(ORG_ENDPOINT), 404),
+                (url("/down/keys"), 503),
+                (url("/fallback/keys"), 502)
+            ]
+        );
+        // The last url tried decides whether it's worth retrying
+        assert!(err.downcast_ref::<Error>().is_some_and(Error::is_retryable));
+        assert!(err.to_string().starts_with(&format!(
+            "Retrieving the keys failed at every url, {}: ",
+            url(ORG_ENDPOINT)
+        )));
+        down.remove();
+
+        // A failure that retrying won't fix moves on to the next url too
+        server
+            .mock("GET", "/down/keys")
+            .with_status(200)
+            .with_body("not json")
+            .create();
+        let err =
+            Verifier::new_with_config(&issuer, config()).await.unwrap_err();
+        let Some(Error::KeysEndpointsFailed { attempts }) =
+            err.downcast_ref::<Error>()
+        else {
+            panic!("unexpected error {err}")
+        };
+        assert_eq!(attempts.len(), 3);
+        assert!(matches!(attempts[1].1, Error::InvalidKeySet { .. }));
+        assert!(matches!(attempts[2].1, Error::KeysStatus { status: 502, .. }));
+        fallback.assert();
+        Ok(())
+    }
+
+    #[cfg(all(feature = "client-reqwest", not(feature = "cache-reqwest")))]
+    #[async_test]
+    async fn reports_client_failures_of_every_url_as_not_retryable() {
+        let config = Config {
+            proxy: Some("not a proxy".into()),
+            fallback_keys_urls: vec!["/mirror/keys".into()],
+            fetch_retry: FetchRetry::disabled(),
+            ..Config::default()
+        };
+        let err = Verifier::new_with_config("https://your.okta.com", config)
+            .await
+            .unwrap_err();
+        let Some(Error::KeysEndpointsFailed { attempts }) =
+            err.downcast_ref::<Error>()
+        else {
+            panic!("unexpected error {err}")
+        };
+        assert!(attempts
+            .iter()
+            .all(|(_, e)| matches!(e, Error::InvalidKeysEndpoint { .. })));
+        assert!(!err.downcast_ref::<Error>().is_some_and(Error::is_retryable));
+    }
+
+    #[test]
+    fn rejects_invalid_fallback_keys_urls() {
+        let config = Config {
+            fallback_keys_urls: vec!["keys".to_string()],
+            ..Config::default()
+        };
+        let err = Verifier::with_keys_and_config(
+            "https://your.domain/oauth2/default",
+            &keys_body(vec![jwk()]),
+            config,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidKeysEndpoint { .. })
+        ));
+    }
+
+    #[async_test]
+    async fn reports_the_status_and_start_of_error_responses() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let config = || Config {
+            fetch_retry: FetchRetry::disabled(),
+            ..Config::default()
+        };
+        let okta_error = r#"{"errorCode":"E0000007",
+            "errorSummary":"Not found: Resource not found: default (AuthorizationServer)"}"#;
+        let m = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(404)
+            .with_body(okta_error)
+            .create();
+        let err = Verifier::new_with_config(&server.url(), config())
+            .await
+            .unwrap_err();
+        let url = format!("{}{ORG_ENDPOINT}", server.url());
+        let body = okta_error.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::KeysStatus { status: 404, url: url.clone(), body })
+        );
+        assert!(err.to_string().contains("failed with status 404: {"));
+        m.remove();
+
+        let page = format!("<html>{}</html>", "x".repeat(10_000));
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(403)
+            .with_body(&page)
+            .create();
+        let err = Verifier::new_with_config(&server.url(), config())
+            .await
+            .unwrap_err();
+        let Some(Error::KeysStatus { status: 403, body, .. }) =
+            err.downcast_ref::<Error>()
+        else {
+            panic!("unexpected error {err}")
+        };
+        assert_eq!(body.as_str(), &page[..ERROR_BODY_SNIPPET_BYTES]);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn falls_back_on_client_errors_too() -> Result<()> {
+        let mut primary = mockito::Server::new_async().await;
+        let mut mirror = mockito::Server::new_async().await;
+        primary.mock("GET", ORG_ENDPOINT).with_status(404).create();
+        let m = mirror
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let mirror_url = format!("{}{ORG_ENDPOINT}", mirror.url());
+        let config = Config {
+            fallback_keys_urls: vec![mirror_url.clone()],
+            ..Config::default()
+        };
+        let verifier =
+            Verifier::new_with_config(&primary.url(), config).await?;
+        m.assert();
+        assert_eq!(verifier.fetch_metadata().unwrap().source, mirror_url);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn reads_the_keys_from_the_keys_file() -> Result<()> {
+        use std::time::{Duration, SystemTime};
+
+        let issuer = "https://keys-file.example";
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), keys_body(vec![jwk()]))?;
+        let config = Config {
+            keys_file: Some(file.path().to_path_buf()),
+            ..Config::default()
+        };
+        let verifier =
+            Verifier::new_with_config(issuer, config.clone()).await?;
+        verifier.verify::<DefaultClaims>(&token(issuer)).await?;
+        let rotated = sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(issuer));
+        assert!(verifier.verify::<DefaultClaims>(&rotated).await.is_err());
+
+        // The file is read again once its modification time changes, even
+        // within the same second
+        assert!(!verifier.refresh_keys().await?.changed());
+        let modified = std::fs::metadata(file.path())?.modified()?;
+        let set_modified = |modified: SystemTime| {
+            std::fs::File::options()
+                .write(true)
+                .open(file.path())?
+                .set_modified(modified)
+        };
+        std::fs::write(file.path(), keys_body(vec![rotated_jwk()]))?;
+        set_modified(modified + Duration::from_millis(1))?;
+        let refresh = verifier.refresh_keys().await?;
+        assert_eq!((refresh.added, refresh.removed), (1, 1));
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+
+        // Or its size does
+        let keys = keys_body(vec![jwk(), rotated_jwk()]);
+        std::fs::write(file.path(), keys)?;
+        set_modified(modified + Duration::from_millis(1))?;
+        assert_eq!(verifier.refresh_keys().await?.added, 1);
+
+        // An oversized file is rejected before it's read
+        let small = Config { max_keys_bytes: 16, ..config.clone() };
+        let e = Verifier::new_with_config(issuer, small).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(Error::ResponseTooLarge { max: 16, .. })
+        ));
+
+        std::fs::write(file.path(), "{")?;
+        let e = Verifier::new_with_config(issuer, config).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::InvalidKeySet { .. })));
+
+        let missing = file.path().with_extension("missing");
+        let config = Config { keys_file: Some(missing), ..Config::default() };
+        let e = Verifier::new_with_config(issuer, config).await.unwrap_err();
+        assert!(matches!(
+            e.root_cause().downcast_ref(),
+            Some(Error::KeysFileUnreadable { .. })
+        ));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn rejects_an_oversized_keys_response() -> Result<()> {
+        let body = keys_body(vec![jwk()]);
+        let config = |max| Config {
+            max_keys_bytes: max,
+            fetch_retry: FetchRetry::disabled(),
+            ..Config::default()
+        };
+        let mut server = mockito::Server::new_async().await;
+        let sized = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(&body)
+            .create();
+        Verifier::new_with_config(&server.url(), config(body.len())).await?;
+        let err = Verifier::new_with_config(&server.url(), config(100))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ResponseTooLarge { max: 100, .. })
+        ));
+
+        // Without a content length the limit applies while reading
+        sized.remove();
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_chunked_body(|w| {
+                for _ in 0..1024 {
+                    w.write_all(&[b' '; 1024])?;
+                }
+                Ok(())
+            })
+            .create();
+        let err = Verifier::new_with_config(&server.url(), config(64 * 1024))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::ResponseTooLarge { .. })
+        ));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn times_out_a_stalled_keys_request() -> Result<()> {
+        assert_eq!(
+            Config::default().fetch_timeout,
+            Some(Duration::from_secs(10))
+        );
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body_from_request(|_| {
+                std::thread::sleep(Duration::from_secs(1));
+                keys_body(vec![jwk()]).into_bytes()
+            })
+            .create();
+        let timeout = Duration::from_millis(200);
+        let config = Config {
+            fetch_timeout: Some(timeout),
+            fetch_retry: FetchRetry::disabled(),
+            ..Config::default()
+        };
+        let err =
+            Verifier::new_with_config(&server.url(), config).await.unwrap_err();
+        let error = err.downcast_ref::<Error>();
+        assert!(
+            matches!(error, Some(Error::KeysTimeout { timeout: t, .. }) if *t == timeout),
+            "{err:?}"
+        );
+        assert_eq!(error.map(Error::code), Some("keys_timeout"));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn passes_the_client_id_to_the_keys_endpoint() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", ORG_ENDPOINT)
+            .match_query(mockito::Matcher::UrlEncoded(
+                "client_id".into(),
+                "0oa1app".into(),
+            ))
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .create();
+        let config = Config {
+            keys_client_id: Some("0oa1app".to_string()),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        m.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn retrieves_the_keys_from_an_absolute_keys_endpoint() -> Result<()> {
+        let mut mirror = mockito::Server::new_async().await;
+        let m = mirror
+            .mock("GET", "/mirrored/keys")
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .create();
+        let issuer = "https://your.domain/oauth2/default";
+        let config = Config {
+            keys_endpoint: Some(format!("{}/mirrored/keys", mirror.url())),
+            allow_absolute_keys_endpoint: true,
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(issuer, config).await?;
+        assert_eq!(
+            verifier.keys_url()?,
+            format!("{}/mirrored/keys", mirror.url())
+        );
+        verifier.verify::<DefaultClaims>(&token(issuer)).await?;
+        // The issuer of tokens is still the configured one
+        let err = verifier
+            .verify::<DefaultClaims>(&token(&mirror.url()))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidToken { reason }) if reason == "InvalidIssuer"
+        ));
+        m.assert();
+        Ok(())
+    }
+}
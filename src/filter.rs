@@ -0,0 +1,262 @@
+/// Selects claims by name, e.g. the claims [`Verifier::verify_for_forwarding`](
+/// crate::Verifier::verify_for_forwarding) turns into headers or the
+/// claims a [`RedactionPolicy`](crate::RedactionPolicy) shows verbatim.
+///
+/// Patterns are either claim names, matched exactly, or JSON pointers to
+/// nested claims such as `/org/id`, and may end with `*` to match any
+/// name starting with what precedes it, e.g. `app_*` or `/org/team_*`. A
+/// pattern also matches everything nested below what it names, so `org`
+/// covers `/org/id`. A claim is selected when an allow pattern matches it
+/// and no deny pattern does, deny taking precedence whatever the order the
+/// patterns were added in.
+///
+/// By default every claim is selected.
+///
+/// ```
+/// use okta_jwt_verifier::ClaimFilter;
+///
+/// let filter = ClaimFilter::new(&["app_*", "dept", "/org/id"])
+///     .deny("email")
+///     .deny("internal_*");
+/// assert!(filter.allows("app_role"));
+/// assert!(filter.allows_pointer("/org/id"));
+/// assert!(!filter.allows_pointer("/org/name"));
+/// assert!(!filter.allows("email"));
+///```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClaimFilter {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl Default for ClaimFilter {
+    fn default() -> Self {
+        Self::new(&["*"])
+    }
+}
+
+impl ClaimFilter {
+    /// `new` constructs an instance of ClaimFilter that only allows the
+    /// claims matching the given patterns, none when there are none.
+    pub fn new(allow: &[&str]) -> Self {
+        Self {
+            allow: allow.iter().map(|p| Pattern::parse(p)).collect(),
+            deny: Vec::new(),
+        }
+    }
+
+    /// `allow` adds a pattern of claims that are selected unless denied.
+    pub fn allow(mut self, pattern: &str) -> Self {
+        self.allow.push(Pattern::parse(pattern));
+        self
+    }
+
+    /// `deny` adds a pattern of claims that are never selected.
+    pub fn deny(mut self, pattern: &str) -> Self {
+        self.deny.push(Pattern::parse(pattern));
+        self
+    }
+
+    /// `allows` reports whether the top level claim is selected.
+    pub fn allows(&self, claim: &str) -> bool {
+        self.allows_path(&[claim])
+    }
+
+    /// `allows_pointer` reports whether the claim the JSON pointer refers
+    /// to is selected, e.g. `/org/id`. Pointers not starting with `/`
+    /// name a top level claim.
+    pub fn allows_pointer(&self, pointer: &str) -> bool {
+        let path = Pattern::pointer(pointer).segments;
+        self.allows_path(&path.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    // Denies an exact claim name, as opposed to a pattern
+    pub(crate) fn deny_exact(mut self, claim: &str) -> Self {
+        self.deny.push(Pattern::exact(claim));
+        self
+    }
+
+    pub(crate) fn allows_path(&self, path: &[&str]) -> bool {
+        self.allow.iter().any(|p| p.matches(path))
+            && !self.deny.iter().any(|p| p.matches(path))
+    }
+
+    pub(crate) fn denies_path(&self, path: &[&str]) -> bool {
+        self.deny.iter().any(|p| p.matches(path))
+    }
+
+    // Whether a pattern names something below the path, in which case the
+    // claims nested in it are decided one by one
+    pub(crate) fn reaches_below(&self, path: &[&str]) -> bool {
+        self.allow.iter().chain(&self.deny).any(|p| p.is_below(path))
+    }
+}
+
+// A parsed pattern, a path of claim names whose last one may be a prefix
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    segments: Vec<String>,
+    prefix: bool,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        match pattern.strip_suffix('*') {
+            Some(start) => Self { prefix: true, ..Self::pointer(start) },
+            None => Self::pointer(pattern),
+        }
+    }
+
+    fn exact(claim: &str) -> Self {
+        Self { segments: vec![claim.to_string()], prefix: false }
+    }
+
+    // Splits a JSON pointer into its unescaped reference tokens, see
+    // RFC 6901
+    fn pointer(pointer: &str) -> Self {
+        let Some(tokens) = pointer.strip_prefix('/') else {
+            return Self::exact(pointer);
+        };
+        let segments = tokens
+            .split('/')
+            .map(|token| token.replace("~1", "/").replace("~0", "~"))
+            .collect();
+        Self { segments, prefix: false }
+    }
+
+    fn matches(&self, path: &[&str]) -> bool {
+        let Some((last, parents)) = self.segments.split_last() else {
+            return false;
+        };
+        if path.len() < self.segments.len() {
+            return false;
+        }
+        let name = path[parents.len()];
+        parents.iter().zip(path).all(|(segment, name)| segment == name)
+            && if self.prefix {
+                name.starts_with(last.as_str())
+            } else {
+                name == last
+            }
+    }
+
+    fn is_below(&self, path: &[&str]) -> bool {
+        self.segments.len() > path.len()
+            && self
+                .segments
+                .iter()
+                .zip(path)
+                .all(|(segment, name)| segment == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Claim names seen in Okta tokens along with the ones of the gateway
+    const CORPUS: [&str; 20] = [
+        "sub",
+        "email",
+        "email_verified",
+        "dept",
+        "department",
+        "app_",
+        "app_role",
+        "app_tenant",
+        "application",
+        "internal_",
+        "internal_id",
+        "app_internal_flag",
+        "internal",
+        "groups",
+        "",
+        "*",
+        "App_role",
+        "dept*",
+        "/dept",
+        "org",
+    ];
+
+    fn gateway() -> ClaimFilter {
+        ClaimFilter::new(&["app_*", "dept"]).deny("email").deny("internal_*")
+    }
+
+    #[test]
+    fn decides_like_the_patterns_say() {
+        let filter = gateway();
+        for claim in CORPUS {
+            let allowed = claim.starts_with("app_") || claim == "dept";
+            let denied = claim == "email" || claim.starts_with("internal_");
+            assert_eq!(filter.allows(claim), allowed && !denied, "{claim}");
+        }
+    }
+
+    #[test]
+    fn deny_takes_precedence_in_any_order() {
+        let patterns = ["app_*", "email", "internal_*", "app_internal_*", "*"];
+        for (i, denied) in patterns.iter().enumerate() {
+            // The same patterns added in two orders, one of them denied
+            let allowed = patterns.iter().filter(|p| *p != denied);
+            let forward = allowed
+                .clone()
+                .fold(ClaimFilter::new(&[]), |f, p| f.allow(p))
+                .deny(denied);
+            let backward = allowed
+                .rev()
+                .fold(ClaimFilter::new(&[]).deny(denied), |f, p| f.allow(p))
+                .allow(denied);
+            for claim in CORPUS {
+                let pattern = Pattern::parse(denied);
+                assert!(
+                    !pattern.matches(&[claim]) || !forward.allows(claim),
+                    "{i} {claim}"
+                );
+                assert_eq!(forward.allows(claim), backward.allows(claim));
+            }
+        }
+    }
+
+    #[test]
+    fn defaults_to_every_claim_and_new_to_none() {
+        let all = ClaimFilter::default();
+        let none = ClaimFilter::new(&[]);
+        for claim in CORPUS {
+            assert!(all.allows(claim), "{claim}");
+            assert!(!none.allows(claim), "{claim}");
+        }
+    }
+
+    #[test]
+    fn matches_nested_claims_by_pointer() {
+        let filter = ClaimFilter::new(&["/org/id", "/org/team_*", "profile"])
+            .deny("/profile/ssn");
+        assert!(filter.allows_pointer("/org/id"));
+        assert!(filter.allows_pointer("/org/id/0"));
+        assert!(filter.allows_pointer("/org/team_a"));
+        assert!(!filter.allows_pointer("/org/name"));
+        assert!(!filter.allows("org"));
+        assert!(filter.allows("profile"));
+        assert!(filter.allows_pointer("/profile/name"));
+        assert!(!filter.allows_pointer("/profile/ssn"));
+        assert!(filter.reaches_below(&["org"]));
+        assert!(filter.reaches_below(&["profile"]));
+        assert!(!filter.reaches_below(&["org", "id"]));
+
+        // Escaped reference tokens, and top level claims named by pointer
+        let filter = ClaimFilter::new(&["/https:~1~1example.com~1roles"]);
+        assert!(filter.allows("https://example.com/roles"));
+        assert!(filter.allows_pointer("/https:~1~1example.com~1roles"));
+        assert!(ClaimFilter::new(&["/a~0b"]).allows("a~b"));
+    }
+
+    #[test]
+    fn exact_names_are_not_patterns() {
+        let filter = ClaimFilter::default().deny_exact("app_*");
+        assert!(!filter.allows("app_*"));
+        assert!(filter.allows("app_role"));
+        let filter = ClaimFilter::default().deny_exact("/x");
+        assert!(!filter.allows("/x"));
+        assert!(filter.allows("x"));
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::Value;
+
+use crate::{logging, token_scopes, ClaimFilter, RedactionPolicy, Verifier};
+
+/// Describes how array claims are turned into headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArrayJoin {
+    /// A single header with the elements joined by the separator.
+    Separator(String),
+    /// One header per element.
+    Repeat,
+}
+
+impl Default for ArrayJoin {
+    fn default() -> Self {
+        Self::Separator(",".to_string())
+    }
+}
+
+/// Describes which claims [`Verifier::verify_for_forwarding`] turns into
+/// headers and how
+#[derive(Debug, Clone)]
+pub struct ForwardingConfig {
+    /// The top level claims to forward, by default all of them. Narrows
+    /// the claims allowed by `filter`.
+    pub include: Option<Vec<String>>,
+    /// The top level claims never forwarded, on top of those denied by
+    /// `filter`.
+    pub exclude: Vec<String>,
+    /// Selects the claims to forward by pattern, including nested ones,
+    /// by default all of them. A nested claim selected on its own is
+    /// forwarded under the name of its path, e.g. `x-auth-org-id` for
+    /// `/org/id`.
+    pub filter: ClaimFilter,
+    /// The prefix of every header name, by default `x-auth-`. It must not
+    /// be empty, as [`ForwardedIdentity::apply_to`] removes every header
+    /// starting with it.
+    pub prefix: String,
+    /// How array claims are turned into headers, by default joined
+    /// with a comma.
+    pub array_join: ArrayJoin,
+    /// Replaces the values of claims outside the allowlist of the policy
+    /// before forwarding them. By default values are forwarded verbatim.
+    pub redaction: Option<RedactionPolicy>,
+}
+
+impl ForwardingConfig {
+    // The filter along with the excluded claims
+    fn claim_filter(&self) -> ClaimFilter {
+        self.exclude
+            .iter()
+            .fold(self.filter.clone(), |filter, claim| filter.deny_exact(claim))
+    }
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        Self {
+            include: None,
+            exclude: Vec::new(),
+            filter: ClaimFilter::default(),
+            prefix: "x-auth-".to_string(),
+            array_join: ArrayJoin::default(),
+            redaction: None,
+        }
+    }
+}
+
+/// The identity of a verified token, as returned by
+/// [`Verifier::verify_for_forwarding`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ForwardedIdentity {
+    /// The sub claim.
+    pub sub: Option<String>,
+    /// The scopes granted by the scp or scope claim.
+    pub scopes: Vec<String>,
+    /// The cid claim, or client_id for tokens following RFC 9068.
+    pub cid: Option<String>,
+    /// The headers carrying the forwarded claims, in claim name order.
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    prefix: String,
+}
+
+impl ForwardedIdentity {
+    /// `apply_to` inserts the headers into a header map, after removing
+    /// any header with the same prefix so that a client can't pass off
+    /// its own claims as verified ones.
+    pub fn apply_to(&self, headers: &mut HeaderMap) {
+        let spoofed: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| name.as_str().starts_with(&self.prefix))
+            .cloned()
+            .collect();
+        for name in spoofed {
+            headers.remove(name);
+        }
+        for (name, value) in &self.headers {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+}
+
+impl Verifier {
+    /// `verify_for_forwarding` verifies the token and turns its claims into
+    /// headers, e.g. for a gateway passing the identity on to upstream
+    /// services. Nested claims are flattened into one header per value,
+    /// so a `{"org": {"id": 7}}` claim becomes `x-auth-org-id: 7`. Values
+    /// that can't be carried by a header, such as those with line breaks,
+    /// are left out. Tokens holding claims that map to the same header,
+    /// such as `org_id` and `{"org": {"id": 7}}`, are rejected so that one
+    /// claim can't pass for another, as are configs with an empty
+    /// `prefix`.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{ForwardingConfig, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///     let config = ForwardingConfig {
+    ///         exclude: vec!["exp".to_string(), "iat".to_string()],
+    ///         ..ForwardingConfig::default()
+    ///     };
+    ///
+    ///     let identity = Verifier::new(&issuer)
+    ///         .await?
+    ///         .verify_for_forwarding(&token, &config)
+    ///         .await?;
+    ///     let mut upstream = http::HeaderMap::new();
+    ///     identity.apply_to(&mut upstream);
+    ///     Ok(())
+    /// }
+    ///```
+    pub async fn verify_for_forwarding(
+        &self,
+        token: &str,
+        config: &ForwardingConfig,
+    ) -> Result<ForwardedIdentity> {
+        if config.prefix.is_empty() {
+            bail!("The forwarding header prefix must not be empty!")
+        }
+        let claims = self.verify::<Value>(token).await?.claims;
+        let string = |claim: &str| {
+            claims.get(claim).and_then(Value::as_str).map(str::to_string)
+        };
+        let forwarded = match &config.redaction {
+            Some(policy) => policy.redact_claims(&claims),
+            None => claims.clone(),
+        };
+        let filter = config.claim_filter();
+        let mut headers = Headers::default();
+        if let Value::Object(forwarded) = &forwarded {
+            let included = forwarded.iter().filter(|(claim, _)| {
+                config
+                    .include
+                    .as_ref()
+                    .map_or(true, |include| include.contains(claim))
+            });
+            for (claim, value) in included {
+                let name =
+                    format!("{}{}", config.prefix, header_segment(claim));
+                let mut path = vec![claim.as_str()];
+                flatten(
+                    &name,
+                    value,
+                    &mut path,
+                    &filter,
+                    config,
+                    &mut headers,
+                )?;
+            }
+        }
+        Ok(ForwardedIdentity {
+            sub: string("sub"),
+            scopes: token_scopes(&claims)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            cid: string("cid").or_else(|| string("client_id")),
+            headers: headers.list,
+            prefix: config.prefix.to_ascii_lowercase(),
+        })
+    }
+}
+
+// The headers produced so far, along with the path of the claim each
+// name was produced for
+#[derive(Default)]
+struct Headers {
+    list: Vec<(HeaderName, HeaderValue)>,
+    claims: HashMap<HeaderName, String>,
+}
+
+// Adds a header for every value below the given name that the filter
+// selects, the path being the one of the value within the claims
+fn flatten<'a>(
+    name: &str,
+    value: &'a Value,
+    path: &mut Vec<&'a str>,
+    filter: &ClaimFilter,
+    config: &ForwardingConfig,
+    headers: &mut Headers,
+) -> Result<()> {
+    if let Value::Object(claims) = value {
+        if filter.denies_path(path)
+            || !filter.allows_path(path) && !filter.reaches_below(path)
+        {
+            return Ok(());
+        }
+        for (claim, value) in claims {
+            let name = format!("{name}-{}", header_segment(claim));
+            path.push(claim);
+            let flattened =
+                flatten(&name, value, path, filter, config, headers);
+            path.pop();
+            flattened?;
+        }
+        return Ok(());
+    }
+    if !filter.allows_path(path) {
+        return Ok(());
+    }
+    match value {
+        Value::Null | Value::Object(_) => {}
+        Value::Array(values) => match &config.array_join {
+            ArrayJoin::Separator(separator) => {
+                let joined: Vec<String> =
+                    values.iter().filter_map(scalar).collect();
+                push(name, &joined.join(separator), path, headers)?;
+            }
+            ArrayJoin::Repeat => {
+                for value in values.iter().filter_map(scalar) {
+                    push(name, &value, path, headers)?;
+                }
+            }
+        },
+        value => {
+            if let Some(value) = scalar(value) {
+                push(name, &value, path, headers)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Nested values within arrays are kept as JSON
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+// Lowercases the claim name, replacing anything but letters and digits
+// with single dashes
+fn header_segment(claim: &str) -> String {
+    claim
+        .to_ascii_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// Rejects a header whose name was already produced for another claim, as
+// the sanitized names of different claims can coincide
+fn push(
+    name: &str,
+    value: &str,
+    path: &[&str],
+    headers: &mut Headers,
+) -> Result<()> {
+    let Ok(header) = HeaderName::from_bytes(name.as_bytes()) else {
+        bail!("Invalid forwarding header name {name}!")
+    };
+    let claim = format!("/{}", path.join("/"));
+    match headers.claims.get(&header) {
+        Some(other) if *other != claim => {
+            bail!("Claims {other} and {claim} are both forwarded as {name}!")
+        }
+        Some(_) => {}
+        None => {
+            headers.claims.insert(header.clone(), claim);
+        }
+    }
+    match HeaderValue::from_bytes(value.as_bytes()) {
+        Ok(value) => headers.list.push((header, value)),
+        Err(_) => logging::warn!("Not forwarding {name}, the value is invalid"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+
+    use jwt_simple::prelude::*;
+    use serde_json::json;
+
+    fn forwarded(identity: &ForwardedIdentity) -> Vec<(&str, &str)> {
+        identity
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect()
+    }
+
+    async fn verifier(server: &mut mockito::Server) -> Result<Verifier> {
+        mock_keys(server, vec![jwk()]);
+        Verifier::new(&server.url()).await
+    }
+
+    fn nested(issuer: &str) -> String {
+        let custom = json!({
+            "cid": "client",
+            "scp": ["read", "write"],
+            "org": {"id": 7, "name": "Acme", "teams": ["a", "b"]},
+            "https://example.com/roles": [{"admin": true}],
+            "note": "line\nbreak",
+            "empty": null,
+        });
+        sign(
+            Claims::with_custom_claims(custom, Duration::from_hours(2))
+                .with_issuer(issuer)
+                .with_subject("jane"),
+        )
+    }
+
+    #[async_test]
+    async fn flattens_nested_claims_into_headers() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let verifier = verifier(&mut server).await?;
+        let token = nested(&server.url());
+        let config = ForwardingConfig {
+            exclude: ["exp", "iat", "nbf", "iss"].map(String::from).to_vec(),
+            ..ForwardingConfig::default()
+        };
+
+        let identity = verifier.verify_for_forwarding(&token, &config).await?;
+        assert_eq!(identity.sub.as_deref(), Some("jane"));
+        assert_eq!(identity.scopes, ["read", "write"]);
+        assert_eq!(identity.cid.as_deref(), Some("client"));
+        assert_eq!(
+            forwarded(&identity),
+            [
+                ("x-auth-cid", "client"),
+                ("x-auth-https-example-com-roles", r#"{"admin":true}"#),
+                ("x-auth-org-id", "7"),
+                ("x-auth-org-name", "Acme"),
+                ("x-auth-org-teams", "a,b"),
+                ("x-auth-scp", "read,write"),
+                ("x-auth-sub", "jane"),
+            ]
+        );
+
+        let config = ForwardingConfig {
+            include: Some(vec!["scp".to_string(), "org".to_string()]),
+            exclude: vec!["org".to_string()],
+            prefix: "X-User-".to_string(),
+            array_join: ArrayJoin::Repeat,
+            ..ForwardingConfig::default()
+        };
+        let identity = verifier.verify_for_forwarding(&token, &config).await?;
+        assert_eq!(
+            forwarded(&identity),
+            [("x-user-scp", "read"), ("x-user-scp", "write")]
+        );
+        Ok(())
+    }
+
+    #[async_test]
+    async fn forwards_the_claims_the_filter_selects() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let verifier = verifier(&mut server).await?;
+        let custom = json!({
+            "app_role": "admin",
+            "app_internal": true,
+            "internal_id": 42,
+            "dept": "sales",
+            "email": "jane@example.com",
+            "org": {"id": 7, "name": "Acme", "team_a": "x", "team_b": "y"},
+        });
+        let token = sign(
+            Claims::with_custom_claims(custom, Duration::from_hours(2))
+                .with_issuer(server.url())
+                .with_subject("jane"),
+        );
+        let config = ForwardingConfig {
+            exclude: vec!["app_internal".to_string()],
+            filter: ClaimFilter::new(&["app_*", "dept", "/org/team_*", "*_id"])
+                .deny("email")
+                .deny("internal_*")
+                .deny("/org/team_b"),
+            ..ForwardingConfig::default()
+        };
+        let identity = verifier.verify_for_forwarding(&token, &config).await?;
+        assert_eq!(
+            forwarded(&identity),
+            [
+                ("x-auth-app-role", "admin"),
+                ("x-auth-dept", "sales"),
+                ("x-auth-org-team-a", "x"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[async_test]
+    async fn redacts_and_replaces_spoofed_headers() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let verifier = verifier(&mut server).await?;
+        let token = nested(&server.url());
+        let config = ForwardingConfig {
+            include: Some(vec!["sub".to_string(), "cid".to_string()]),
+            redaction: Some(RedactionPolicy::new(&["cid"])),
+            ..ForwardingConfig::default()
+        };
+        let identity = verifier.verify_for_forwarding(&token, &config).await?;
+        assert_eq!(
+            forwarded(&identity),
+            [("x-auth-cid", "client"), ("x-auth-sub", "<redacted>")]
+        );
+        assert_eq!(identity.sub.as_deref(), Some("jane"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-auth-admin", HeaderValue::from_static("true"));
+        headers.insert("x-auth-sub", HeaderValue::from_static("mallory"));
+        headers.insert("accept", HeaderValue::from_static("*/*"));
+        identity.apply_to(&mut headers);
+        assert!(headers.get("x-auth-admin").is_none());
+        assert_eq!(headers.get_all("x-auth-sub").iter().count(), 1);
+        assert_eq!(headers["x-auth-sub"], "<redacted>");
+        assert_eq!(headers["accept"], "*/*");
+        Ok(())
+    }
+
+    #[async_test]
+    async fn rejects_claims_forwarded_under_the_same_name() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let verifier = verifier(&mut server).await?;
+        let spoofing = [
+            json!({ "Sub": "mallory" }),
+            json!({ "sub_": "mallory" }),
+            json!({ "org_id": 1, "org": { "id": 7 } }),
+        ];
+        for custom in spoofing {
+            let token = sign(
+                Claims::with_custom_claims(custom, Duration::from_hours(2))
+                    .with_issuer(server.url())
+                    .with_subject("jane"),
+            );
+            let err = verifier
+                .verify_for_forwarding(&token, &ForwardingConfig::default())
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("both forwarded"), "{err}");
+        }
+
+        let token = nested(&server.url());
+        let config =
+            ForwardingConfig { prefix: String::new(), ..Default::default() };
+        let err =
+            verifier.verify_for_forwarding(&token, &config).await.unwrap_err();
+        assert!(err.to_string().contains("prefix"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn sanitizes_claim_names() {
+        assert_eq!(header_segment("given_name"), "given-name");
+        assert_eq!(header_segment("https://x.io/Roles"), "https-x-io-roles");
+    }
+}
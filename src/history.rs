@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::scope::token_audiences;
+use crate::verify::check_token_shape;
+use crate::{
+    parse_header, unverified_claims, Error, RedactionPolicy, Verifier,
+    DEFAULT_MAX_TOKEN_BYTES,
+};
+
+/// Describes a failed verification, as kept by the failure history
+/// enabled with [`Config::failure_history`](crate::Config::failure_history).
+///
+/// The values are read from the token without verifying it, so they are
+/// only hints, and are shown as far as the redaction policy permits.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct FailureSummary {
+    /// When the verification failed.
+    pub at: SystemTime,
+    /// The kind of failure, see [`Error::code`], or `unclassified`
+    /// for failures that aren't described by an [`Error`].
+    pub code: &'static str,
+    /// The kid presented in the token header.
+    pub kid: Option<String>,
+    /// The iss claim presented in the token.
+    pub issuer: Option<String>,
+    /// The aud claim presented in the token.
+    pub audience: Option<Vec<String>>,
+}
+
+// Keeps the most recent failures, the oldest being evicted first. The
+// lock is only held to push or copy entries.
+#[derive(Debug)]
+pub(crate) struct FailureHistory {
+    capacity: usize,
+    entries: Mutex<VecDeque<FailureSummary>>,
+}
+
+impl FailureHistory {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self { capacity, entries: Mutex::new(VecDeque::new()) }
+    }
+
+    fn push(&self, summary: FailureSummary) {
+        let mut entries =
+            self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(summary);
+    }
+
+    fn entries(&self) -> Vec<FailureSummary> {
+        let entries =
+            self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.iter().cloned().collect()
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+impl FailureSummary {
+    fn new(
+        token: &str,
+        error: &anyhow::Error,
+        policy: &RedactionPolicy,
+    ) -> Self {
+        let code =
+            error.downcast_ref::<Error>().map_or("unclassified", Error::code);
+        // Nothing is parsed out of tokens that were rejected for their shape
+        // or are larger than tokens are by default
+        let readable =
+            check_token_shape(token, DEFAULT_MAX_TOKEN_BYTES).is_ok();
+        let kid = readable
+            .then(|| parse_header(token).ok()?.kid)
+            .flatten()
+            .map(|kid| policy.redact("kid", &kid));
+        let claims = readable
+            .then(|| unverified_claims(token).ok())
+            .flatten()
+            .unwrap_or(Value::Null);
+        let issuer = claims
+            .get("iss")
+            .and_then(Value::as_str)
+            .map(|iss| policy.redact("iss", iss));
+        let audience = claims.get("aud").map(|_| {
+            token_audiences(&claims)
+                .into_iter()
+                .map(|aud| policy.redact("aud", aud))
+                .collect()
+        });
+        Self { at: SystemTime::now(), code, kid, issuer, audience }
+    }
+}
+
+impl Verifier {
+    // Keeps a summary of the failure when the history is enabled
+    pub(crate) fn record_failure(&self, token: &str, error: &anyhow::Error) {
+        if let Some(history) = &self.failures {
+            history.push(FailureSummary::new(
+                token,
+                error,
+                &self.config.redaction,
+            ));
+        }
+    }
+
+    /// `recent_failures` returns the most recent failed verifications of
+    /// this Verifier and its clones, oldest first. Always empty unless
+    /// enabled with [`Config::failure_history`](crate::Config::failure_history).
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{Config, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///     let config = Config { failure_history: 50, ..Config::default() };
+    ///
+    ///     let verifier = Verifier::new_with_config(&issuer, config).await?;
+    ///     for failure in verifier.recent_failures() {
+    ///         println!("{} {:?}", failure.code, failure.kid);
+    ///     }
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn recent_failures(&self) -> Vec<FailureSummary> {
+        self.failures.as_ref().map(|h| h.entries()).unwrap_or_default()
+    }
+
+    /// `clear_failures` empties the failure history.
+    pub fn clear_failures(&self) {
+        if let Some(history) = &self.failures {
+            history.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{Config, DefaultClaims};
+
+    async fn verifier(
+        server: &mut mockito::Server,
+        config: Config,
+    ) -> anyhow::Result<Verifier> {
+        mock_keys(server, vec![jwk()]);
+        Verifier::new_with_config(&server.url(), config).await
+    }
+
+    #[async_test]
+    async fn keeps_the_most_recent_failures() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let config = Config { failure_history: 3, ..Config::default() };
+        let verifier = verifier(&mut server, config).await?;
+        let clone = verifier.clone();
+        for i in 0..5 {
+            let token = sign(
+                claims(&format!("https://issuer-{i}.example"))
+                    .with_audience("api://default"),
+            );
+            assert!(verifier.verify::<DefaultClaims>(&token).await.is_err());
+        }
+        assert!(verifier
+            .verify::<DefaultClaims>(&token(&server.url()))
+            .await
+            .is_ok());
+        let failures = clone.recent_failures();
+        let issuers: Vec<_> =
+            failures.iter().filter_map(|f| f.issuer.as_deref()).collect();
+        assert_eq!(
+            issuers,
+            [
+                "https://issuer-2.example",
+                "https://issuer-3.example",
+                "https://issuer-4.example"
+            ]
+        );
+        assert!(failures.windows(2).all(|w| w[0].at <= w[1].at));
+        assert!(failures.iter().all(|f| f.code == "invalid_token"));
+        assert_eq!(failures[0].kid.as_deref(), Some(KEY_ID));
+        assert_eq!(
+            failures[0].audience,
+            Some(vec!["api://default".to_string()])
+        );
+
+        let err = verifier.verify::<DefaultClaims>("a.b").await.unwrap_err();
+        assert!(err.downcast_ref::<Error>().is_some());
+        let latest = verifier.recent_failures().pop().unwrap();
+        assert_eq!(latest.code, "malformed_token");
+        assert_eq!(latest.issuer, None);
+
+        clone.clear_failures();
+        assert!(verifier.recent_failures().is_empty());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn applies_the_redaction_policy() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let config = Config {
+            failure_history: 10,
+            redaction: RedactionPolicy::new(&["kid"]),
+            ..Config::default()
+        };
+        let verifier = verifier(&mut server, config).await?;
+        let token = sign(
+            claims("https://secret.example").with_audience("api://secret"),
+        );
+        assert!(verifier.verify::<DefaultClaims>(&token).await.is_err());
+        let failures = verifier.recent_failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].kid.as_deref(), Some(KEY_ID));
+        assert_eq!(failures[0].issuer.as_deref(), Some("<redacted>"));
+        assert_eq!(failures[0].audience, Some(vec!["<redacted>".to_string()]));
+        let output = format!("{failures:?}");
+        assert!(!output.contains("secret"));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn is_disabled_by_default() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let verifier = verifier(&mut server, Config::default()).await?;
+        assert!(verifier.verify::<DefaultClaims>("a.b").await.is_err());
+        assert!(verifier.recent_failures().is_empty());
+        Ok(())
+    }
+}
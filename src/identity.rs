@@ -0,0 +1,181 @@
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+use crate::{token_scopes, Error};
+
+// Claims normalized into dedicated fields, left out of the extras
+const NORMALIZED: [&str; 6] =
+    ["sub", "cid", "client_id", "scp", "scope", "exp"];
+
+// Describe the introspection response rather than the token
+const RESPONSE_ONLY: [&str; 2] = ["active", "token_type"];
+
+/// Describes how a [`VerifiedIdentity`] was established
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IdentitySource {
+    /// The signature and claims of a JWT were validated locally.
+    LocalJwt,
+    /// The token was reported active by an introspection endpoint.
+    Introspection,
+}
+
+/// The identity behind a token, independent of whether it was verified
+/// locally or introspected. Okta uses an `scp` array and a `cid` claim in
+/// its tokens, while introspection responses use a space separated
+/// `scope` and `client_id`, both are normalized into the same fields.
+/// Inserted into the request extensions by [`Verifier::authenticate`](crate::Verifier::authenticate).
+///
+/// ```
+/// use okta_jwt_verifier::{IdentitySource, VerifiedIdentity};
+/// use serde_json::json;
+///
+/// let identity = VerifiedIdentity::from_introspection(&json!({
+///     "active": true,
+///     "sub": "user@example.com",
+///     "client_id": "0oa1client",
+///     "scope": "openid orders:read",
+///     "exp": 1700000000,
+/// }))?;
+/// assert_eq!(identity.source, IdentitySource::Introspection);
+/// assert_eq!(identity.scopes, ["openid", "orders:read"]);
+/// # Ok::<(), anyhow::Error>(())
+///```
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct VerifiedIdentity {
+    /// How the identity was established.
+    pub source: IdentitySource,
+    /// The subject of the token.
+    pub sub: Option<String>,
+    /// The client the token was issued to, from `cid` or `client_id`.
+    pub client_id: Option<String>,
+    /// The scopes granted to the token.
+    pub scopes: Vec<String>,
+    /// The time the token expires, in Unix time (seconds).
+    pub expires_at: Option<u64>,
+    /// Any other claims, such as `iss`, `aud`, or custom claims.
+    pub extra: Map<String, Value>,
+}
+
+impl VerifiedIdentity {
+    /// `from_claims` describes the claims of a token verified locally,
+    /// e.g. by [`Verifier::verify`](crate::Verifier::verify) into a
+    /// [`Value`].
+    pub fn from_claims(claims: &Value) -> Self {
+        Self::new(IdentitySource::LocalJwt, claims, &[])
+    }
+
+    /// `from_introspection` describes an RFC 7662 introspection response.
+    /// Responses for inactive tokens are rejected with
+    /// [`Error::InvalidToken`].
+    pub fn from_introspection(response: &Value) -> Result<Self> {
+        if response.get("active").and_then(Value::as_bool) != Some(true) {
+            bail!(Error::InvalidToken { reason: "inactive".to_string() })
+        }
+        Ok(Self::new(IdentitySource::Introspection, response, &RESPONSE_ONLY))
+    }
+
+    fn new(source: IdentitySource, claims: &Value, skip: &[&str]) -> Self {
+        let string = |name: &str| {
+            claims.get(name).and_then(Value::as_str).map(str::to_string)
+        };
+        let extra = claims
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(name, _)| {
+                !NORMALIZED.contains(&name.as_str())
+                    && !skip.contains(&name.as_str())
+            })
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        Self {
+            source,
+            sub: string("sub"),
+            client_id: string("cid").or_else(|| string("client_id")),
+            scopes: token_scopes(claims)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            expires_at: claims.get("exp").and_then(Value::as_u64),
+            extra,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::Verifier;
+
+    use jwt_simple::prelude::*;
+    use serde_json::json;
+
+    #[async_test]
+    async fn local_and_introspected_identities_agree() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let issuer = server.url();
+        let custom = json!({
+            "cid": "0oa1client",
+            "scp": ["openid", "orders:read"],
+            "uid": "00u1user",
+        });
+        let claims =
+            Claims::with_custom_claims(custom, Duration::from_hours(2))
+                .with_issuer(&issuer)
+                .with_audience("api://default")
+                .with_subject("user@example.com");
+        let verifier =
+            Verifier::new(&issuer).await?.add_audience("api://default");
+        let local = verifier.verify::<Value>(&sign(claims)).await?.claims;
+        let local = VerifiedIdentity::from_claims(&local);
+
+        let introspected = VerifiedIdentity::from_introspection(&json!({
+            "active": true,
+            "token_type": "Bearer",
+            "scope": "openid orders:read",
+            "client_id": "0oa1client",
+            "uid": "00u1user",
+            "sub": "user@example.com",
+            "iss": issuer,
+            "aud": "api://default",
+            "iat": local.extra["iat"],
+            "nbf": local.extra["nbf"],
+            "exp": local.expires_at,
+        }))?;
+
+        assert_eq!(local.source, IdentitySource::LocalJwt);
+        assert_eq!(introspected.source, IdentitySource::Introspection);
+        assert_eq!(local.sub, introspected.sub);
+        assert_eq!(local.client_id.as_deref(), Some("0oa1client"));
+        assert_eq!(local.client_id, introspected.client_id);
+        assert_eq!(local.scopes, ["openid", "orders:read"]);
+        assert_eq!(local.scopes, introspected.scopes);
+        assert!(local.expires_at.is_some());
+        assert_eq!(local.expires_at, introspected.expires_at);
+        assert_eq!(local.extra, introspected.extra);
+        assert_eq!(
+            VerifiedIdentity {
+                source: IdentitySource::LocalJwt,
+                ..introspected
+            },
+            local
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_inactive_introspection_responses() {
+        for response in [json!({ "active": false }), json!({})] {
+            let err =
+                VerifiedIdentity::from_introspection(&response).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::InvalidToken { reason }) if reason == "inactive"
+            ));
+        }
+    }
+}
@@ -0,0 +1,344 @@
+//! Decodes tokens without verifying them, e.g. for log scrubbers and
+//! debugging scripts. Nothing here checks the signature or any claim, so
+//! the results must never be used to make authorization decisions.
+//!
+//! ```
+//! use okta_jwt_verifier::inspect;
+//!
+//! let token = "eyJhbGciOiJSUzI1NiIsImtpZCI6ImsxIn0.eyJpc3MiOiJodHRwczovL3lvdXIuZG9tYWluIiwic3ViIjoiamFuZSJ9.c2ln";
+//! let summary = inspect::summary(token)?;
+//! assert_eq!(summary.kid.as_deref(), Some("k1"));
+//! assert_eq!(summary.sub_redacted.as_deref(), Some("<redacted>"));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use anyhow::Result;
+use jsonwebtoken::Algorithm;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::verify::check_token_shape;
+use crate::{decode_segment, unverified_claims, DEFAULT_MAX_TOKEN_BYTES};
+use crate::{Error, RedactionPolicy};
+
+/// Describes the header of a token, holding every header parameter
+/// jsonwebtoken knows, see [`DecodedToken`](crate::DecodedToken)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenHeader {
+    /// The signature algorithm.
+    pub alg: String,
+    /// The id of the signing key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    /// The media type of the token, e.g. `JWT` or `at+jwt`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    /// The SHA-1 thumbprint of the signing certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x5t: Option<String>,
+    /// The SHA-256 thumbprint of the signing certificate.
+    #[serde(
+        default,
+        rename = "x5t#S256",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub x5t_s256: Option<String>,
+    /// The media type of the claims, when the token nests another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cty: Option<String>,
+    /// The url of the JWKS document holding the signing key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jku: Option<String>,
+    /// The signing key embedded in the header. It's never trusted, only
+    /// the keys of the issuer are.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jwk: Option<jsonwebtoken::jwk::Jwk>,
+    /// The url of the signing certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x5u: Option<String>,
+    /// The chain of the signing certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub x5c: Option<Vec<String>>,
+}
+
+impl TokenHeader {
+    // The algorithm as understood by jsonwebtoken, tokens signed with
+    // an algorithm it doesn't know are malformed
+    pub(crate) fn algorithm(&self) -> Result<Algorithm> {
+        self.alg
+            .parse()
+            .map_err(|e| anyhow::Error::new(e).context(Error::MalformedToken))
+    }
+}
+
+// The alg value of the algorithm, the inverse of TokenHeader::algorithm
+pub(crate) fn alg_name(alg: Algorithm) -> &'static str {
+    match alg {
+        Algorithm::HS256 => "HS256",
+        Algorithm::HS384 => "HS384",
+        Algorithm::HS512 => "HS512",
+        Algorithm::ES256 => "ES256",
+        Algorithm::ES384 => "ES384",
+        Algorithm::RS256 => "RS256",
+        Algorithm::RS384 => "RS384",
+        Algorithm::RS512 => "RS512",
+        Algorithm::PS256 => "PS256",
+        Algorithm::PS384 => "PS384",
+        Algorithm::PS512 => "PS512",
+        Algorithm::EdDSA => "EdDSA",
+    }
+}
+
+/// Describes a token with only the values that are safe to log, see
+/// [`summary`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct TokenSummary {
+    /// The id of the signing key.
+    pub kid: Option<String>,
+    /// The signature algorithm.
+    pub alg: String,
+    /// The iss claim.
+    pub iss: Option<String>,
+    /// The sub claim after applying the default [`RedactionPolicy`].
+    pub sub_redacted: Option<String>,
+    /// The exp claim, in Unix time (seconds).
+    pub exp: Option<u64>,
+    /// The iat claim, in Unix time (seconds).
+    pub iat: Option<u64>,
+    /// The aud claim, a single audience becoming a list of one.
+    pub aud: Vec<String>,
+}
+
+/// `header` decodes the header of a token, rejecting tokens that aren't
+/// made up of three segments with [`Error::MalformedToken`].
+pub fn header(token: &str) -> Result<TokenHeader> {
+    check_token_shape(token, DEFAULT_MAX_TOKEN_BYTES)?;
+    parse_header(token)
+}
+
+// The single place headers are parsed, every failure is reported as
+// Error::MalformedToken with the cause attached. The shape of the token is
+// checked by the callers, against the size they accept
+pub(crate) fn parse_header(token: &str) -> Result<TokenHeader> {
+    let header = decode_segment(token, 0)?;
+    serde_json::from_slice(&header)
+        .map_err(|e| anyhow::Error::new(e).context(Error::MalformedToken))
+}
+
+/// `claims_unverified` decodes the claims of a token without verifying
+/// its signature.
+pub fn claims_unverified(token: &str) -> Result<Value> {
+    check_token_shape(token, DEFAULT_MAX_TOKEN_BYTES)?;
+    unverified_claims(token)
+}
+
+/// `summary` describes a token for logging, the sub claim is redacted
+/// as configured by [`RedactionPolicy::default`].
+pub fn summary(token: &str) -> Result<TokenSummary> {
+    let header = header(token)?;
+    let claims = claims_unverified(token)?;
+    let string = |name: &str| claims.get(name).and_then(Value::as_str);
+    let aud = match claims.get("aud") {
+        Some(Value::String(aud)) => vec![aud.clone()],
+        Some(Value::Array(auds)) => {
+            auds.iter().filter_map(Value::as_str).map(str::to_string).collect()
+        }
+        _ => Vec::new(),
+    };
+    Ok(TokenSummary {
+        kid: header.kid,
+        alg: header.alg,
+        iss: string("iss").map(str::to_string),
+        sub_redacted: string("sub")
+            .map(|sub| RedactionPolicy::default().redact("sub", sub)),
+        exp: claims.get("exp").and_then(Value::as_u64),
+        iat: claims.get("iat").and_then(Value::as_u64),
+        aud,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    fn encode(value: &Value) -> String {
+        URL_SAFE_NO_PAD.encode(value.to_string())
+    }
+
+    #[test]
+    fn names_every_algorithm_by_its_alg_value() -> Result<()> {
+        for name in [
+            "HS256", "HS384", "HS512", "ES256", "ES384", "RS256", "RS384",
+            "RS512", "PS256", "PS384", "PS512", "EdDSA",
+        ] {
+            let alg: Algorithm = name.parse()?;
+            assert_eq!(alg_name(alg), name);
+            assert_eq!(serde_json::to_value(alg)?, name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn inspects_a_signed_token() -> Result<()> {
+        let issuer = "https://your.domain/oauth2/default";
+        let token = sign(claims(issuer).with_audience("api://default"));
+        let header = header(&token)?;
+        assert_eq!(header.alg, "RS256");
+        assert_eq!(header.kid.as_deref(), Some(KEY_ID));
+
+        let claims = claims_unverified(&token)?;
+        assert_eq!(claims["sub"], "test");
+
+        let summary = summary(&token)?;
+        assert_eq!(summary.kid.as_deref(), Some(KEY_ID));
+        assert_eq!(summary.alg, "RS256");
+        assert_eq!(
+            summary.iss.as_deref(),
+            Some("https://your.domain/oauth2/default")
+        );
+        assert_eq!(summary.sub_redacted.as_deref(), Some("<redacted>"));
+        assert_eq!(summary.exp, claims["exp"].as_u64());
+        assert_eq!(summary.iat, claims["iat"].as_u64());
+        assert_eq!(summary.aud, ["api://default"]);
+        Ok(())
+    }
+
+    #[test]
+    fn tolerates_missing_optional_fields() -> Result<()> {
+        let token = format!(
+            "{}.{}.sig",
+            encode(&json!({ "alg": "ES256" })),
+            encode(&json!({ "aud": ["a", "b"] }))
+        );
+        let header = header(&token)?;
+        assert_eq!(
+            header,
+            TokenHeader {
+                alg: "ES256".to_string(),
+                kid: None,
+                typ: None,
+                x5t: None,
+                x5t_s256: None,
+                ..TokenHeader::default()
+            }
+        );
+        let summary = summary(&token)?;
+        assert_eq!(summary.kid, None);
+        assert_eq!(summary.iss, None);
+        assert_eq!(summary.sub_redacted, None);
+        assert_eq!(summary.exp, None);
+        assert_eq!(summary.aud, ["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let header = encode(&json!({ "alg": "RS256" }));
+        let claims = encode(&json!({ "sub": "jane" }));
+        let malformed = [
+            String::new(),
+            "not a token".to_string(),
+            format!("{header}.{claims}"),
+            format!("{header}.{claims}.sig.extra"),
+            format!("{header}.!!!.sig"),
+            format!("{header}.{}.sig", URL_SAFE_NO_PAD.encode("not json")),
+            format!("{}.{claims}.sig", encode(&json!({ "kid": "k1" }))),
+        ];
+        for token in &malformed {
+            let err = summary(token).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<Error>(),
+                Some(&Error::MalformedToken),
+                "{token}"
+            );
+        }
+    }
+
+    // Arbitrary JSON, biased towards objects using the header names
+    fn json() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<f64>().prop_map(Value::from),
+            ".*".prop_map(Value::from),
+        ];
+        leaf.prop_recursive(3, 32, 8, |inner| {
+            let name = prop_oneof![
+                Just("alg".to_string()),
+                Just("kid".to_string()),
+                Just("typ".to_string()),
+                Just("x5t".to_string()),
+                Just("x5t#S256".to_string()),
+                Just("crit".to_string()),
+                ".*",
+            ];
+            prop_oneof![
+                prop::collection::vec(inner.clone(), 0..4)
+                    .prop_map(Value::from),
+                prop::collection::btree_map(name, inner, 0..6)
+                    .prop_map(|map| Value::Object(map.into_iter().collect())),
+            ]
+        })
+    }
+
+    // Every failure to parse a header is reported the same way
+    fn assert_parsed_or_malformed(token: &str) {
+        match parse_header(token) {
+            Ok(header) => {
+                if let Err(err) = header.algorithm() {
+                    assert_eq!(
+                        err.downcast_ref::<Error>(),
+                        Some(&Error::MalformedToken)
+                    );
+                }
+            }
+            Err(err) => assert_eq!(
+                err.downcast_ref::<Error>(),
+                Some(&Error::MalformedToken)
+            ),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn parses_any_header_json_without_panicking(header in json()) {
+            assert_parsed_or_malformed(&format!("{}.e30.sig", encode(&header)));
+        }
+
+        #[test]
+        fn parses_any_header_bytes_without_panicking(
+            header in prop::collection::vec(any::<u8>(), 0..256)
+        ) {
+            let header = URL_SAFE_NO_PAD.encode(header);
+            assert_parsed_or_malformed(&format!("{header}.e30.sig"));
+        }
+
+        #[test]
+        fn keeps_the_values_of_well_formed_headers(
+            kid in prop::option::of(".*"),
+            typ in prop::option::of(".*"),
+            x5t in prop::option::of(".*"),
+            x5t_s256 in prop::option::of(".*"),
+        ) {
+            let expected = TokenHeader {
+                alg: "RS256".to_string(),
+                kid,
+                typ,
+                x5t,
+                x5t_s256,
+                ..TokenHeader::default()
+            };
+            let header = serde_json::to_value(&expected).unwrap();
+            let token = format!("{}.e30.sig", encode(&header));
+            prop_assert_eq!(parse_header(&token).unwrap(), expected);
+        }
+    }
+}
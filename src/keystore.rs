@@ -0,0 +1,1256 @@
+// The keys shared by a Verifier and its clones.
+//
+// The store goes through the following states, each transition to other
+// keys bumping the generation so that callers can tell whether the keys
+// they used were replaced in the meantime:
+//
+//   pending  -- retrieval succeeded --> fetched
+//   pending  -- retrieval failed, fallback keys configured --> stale
+//   fetched  -- retrieval succeeded --> fetched
+//   stale    -- retrieval succeeded --> fetched
+//
+// Leaving the fetched or stale state for different keys notifies the
+// rotation callbacks, along with the final usage counts of the removed
+// keys whose counters are dropped. A failed retrieval leaves the keys as
+// they were. Retrievals are serialized by the refresh lock and each one
+// that finishes bumps the attempt counter, so a caller that waited on the
+// lock finds out whether a retrieval completed in the meantime, and if it
+// failed, shares its error rather than requesting the keys again.
+//
+// The std primitives are swapped for loom's when building with
+// `RUSTFLAGS="--cfg okta_loom"`, rather than `loom` which dependencies
+// with loom support of their own would pick up as well. Run the models with
+// `cargo test --release --lib keystore::loom_tests`. The regular tests
+// below avoid the network so that they also run under miri.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, PoisonError, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+#[cfg(okta_loom)]
+use loom::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, RwLock,
+};
+#[cfg(not(okta_loom))]
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, RwLock,
+};
+
+use crate::breaker::BreakerState;
+use crate::discovery::DiscoveryCache;
+use crate::refresh::{RefreshContext, RefreshTrigger};
+use crate::retry::{self, RetryDecision};
+use crate::rotation::{self, KeyRotation, RotationHook};
+use crate::usage::{self, KeyUsage, UsageCounters, UsageMap};
+use crate::{
+    get, keys_url, logging, parse_key_set, parse_keys, persist, queue, runtime,
+    snapshot, Config, Error, FetchMetadata, Jwks,
+};
+
+// Serializes retrievals, see lock_refresh
+#[cfg(not(okta_loom))]
+type RefreshLock = async_lock::Mutex<()>;
+#[cfg(okta_loom)]
+type RefreshLock = loom_lock::SpinLock;
+
+// Loom doesn't model async locks and the guard of its mutex isn't Send, so
+// the models take the refresh lock by spinning on a loom atomic instead
+#[cfg(okta_loom)]
+mod loom_lock {
+    use loom::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Debug)]
+    pub(super) struct SpinLock(AtomicBool);
+
+    pub(super) struct SpinGuard<'a>(&'a AtomicBool);
+
+    impl SpinLock {
+        pub(super) fn new(_: ()) -> Self {
+            Self(AtomicBool::new(false))
+        }
+
+        pub(super) fn lock(&self) -> SpinGuard<'_> {
+            while self
+                .0
+                .compare_exchange(
+                    false,
+                    true,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+            {
+                loom::thread::yield_now();
+            }
+            SpinGuard(&self.0)
+        }
+    }
+
+    impl Drop for SpinGuard<'_> {
+        fn drop(&mut self) {
+            self.0.store(false, Ordering::Release);
+        }
+    }
+}
+
+// Describes the keys currently trusted and where they came from
+#[derive(Debug)]
+pub(crate) struct KeyState {
+    pub(crate) jwks: Jwks,
+    pub(crate) fetch: Option<FetchMetadata>,
+    // Set while the keys are the fallback set
+    pub(crate) stale: bool,
+}
+
+impl KeyState {
+    // Keys that were just retrieved from upstream
+    pub(crate) fn fetched(jwks: Jwks, fetch: FetchMetadata) -> Self {
+        Self { jwks, fetch: Some(fetch), stale: false }
+    }
+
+    // No keys yet, they are retrieved on first use
+    pub(crate) fn pending() -> Self {
+        Self { jwks: Jwks::from_keys(Vec::new()), fetch: None, stale: false }
+    }
+
+    pub(crate) fn is_pending(&self) -> bool {
+        self.jwks.keys.is_empty() && self.fetch.is_none() && !self.stale
+    }
+
+    // The keys of the snapshot, the keys loader or the fallback keys, if
+    // configured, preferring those provided at runtime over the embedded
+    // ones. Invalid loaded keys are only reported when there are no
+    // fallback keys.
+    pub(crate) fn fallback(
+        issuer: &str,
+        config: &Config,
+    ) -> Option<Result<Self>> {
+        if let Some(state) = snapshot::load(issuer, config) {
+            return Some(Ok(state));
+        }
+        let stale = |jwks| Self { jwks, fetch: None, stale: true };
+        let loaded = persist::load(config).map(|body| {
+            parse_key_set(body.as_bytes(), config)
+                .context("Invalid loaded JWKS!")
+        });
+        let body =
+            config.fallback_keys.as_deref().or(config.embedded_fallback_jwks);
+        match (loaded, body) {
+            (Some(Ok(jwks)), _) => Some(Ok(stale(jwks))),
+            (Some(Err(e)), None) => Some(Err(e)),
+            (loaded, Some(body)) => {
+                if let Some(Err(e)) = loaded {
+                    logging::warn!("Skipping the loaded keys: {e:#}");
+                }
+                Some(
+                    parse_keys(body.as_bytes())
+                        .context("Invalid fallback JWKS!")
+                        .map(stale),
+                )
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+// Holds the current keys behind a lock so they can be shared between
+// clones and threads, the lock is only held long enough to swap an Arc.
+// Retrievals are serialized by a separate async lock so that concurrent
+// callers share a single request to the keys endpoint.
+#[derive(Debug)]
+pub(crate) struct KeyStore {
+    state: RwLock<Arc<KeyState>>,
+    // Bumped every time new keys are stored
+    generation: AtomicU64,
+    refresh: RefreshLock,
+    // When the keys were last retrieved for an unknown kid
+    kid_miss_refresh: Mutex<Option<Instant>>,
+    // Bumped every time a retrieval finished, along with the error of the
+    // last one if it failed, so that callers waiting for it share it
+    attempts: AtomicU64,
+    failure: Mutex<Option<anyhow::Error>>,
+    // When the last retrieval failed, cleared once one succeeds
+    failed_at: Mutex<Option<Instant>>,
+    // Only updated with the refresh lock held, see Config::circuit_breaker
+    breaker: Mutex<BreakerState>,
+    // Kids that weren't found even with fresh keys, and when they were
+    // last looked for, forgotten once new keys are added
+    unknown_kids: Mutex<HashMap<String, Instant>>,
+    // Invoked when stored keys replace others, as long as a Verifier holds
+    // on to them, see Verifier::on_key_rotation
+    rotation_hooks: Mutex<Vec<Weak<Vec<RotationHook>>>>,
+    // The verification counters of the current kids, only replaced along
+    // with the keys, see Verifier::key_usage
+    usage: RwLock<UsageMap>,
+    // The discovery document the keys are retrieved with, see
+    // Config::discovery
+    discovery: DiscoveryCache,
+}
+
+impl KeyStore {
+    pub(crate) fn new(state: KeyState) -> Self {
+        let (usage, _) =
+            usage::carry_over(&UsageMap::new(), &state.jwks, &state.jwks);
+        Self {
+            state: RwLock::new(Arc::new(state)),
+            generation: AtomicU64::new(0),
+            refresh: RefreshLock::new(()),
+            kid_miss_refresh: Mutex::new(None),
+            attempts: AtomicU64::new(0),
+            failure: Mutex::new(None),
+            failed_at: Mutex::new(None),
+            breaker: Mutex::new(BreakerState::default()),
+            unknown_kids: Mutex::new(HashMap::new()),
+            rotation_hooks: Mutex::new(Vec::new()),
+            usage: RwLock::new(usage),
+            discovery: DiscoveryCache::default(),
+        }
+    }
+
+    // Keeps the discovery document retrieved along with the initial keys
+    pub(crate) fn with_discovery(mut self, discovery: DiscoveryCache) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    pub(crate) fn discovery(&self) -> &DiscoveryCache {
+        &self.discovery
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    // The lock only guards an Arc swap, so a poisoned lock
+    // still holds a consistent state and can be recovered
+    pub(crate) fn load(&self) -> Arc<KeyState> {
+        self.state.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    // The generation is bumped once the keys are in place, so a caller that
+    // observed a generation always loads keys at least that recent. Storing
+    // the same keys again, e.g. after a 304, only updates the fetch metadata.
+    pub(crate) fn store(&self, state: KeyState) {
+        let state = Arc::new(state);
+        let mut current =
+            self.state.write().unwrap_or_else(PoisonError::into_inner);
+        let mut counters =
+            self.usage.write().unwrap_or_else(PoisonError::into_inner);
+        let (carried, removed_usage) =
+            usage::carry_over(&counters, &current.jwks, &state.jwks);
+        *counters = carried;
+        drop(counters);
+        let before = std::mem::replace(&mut *current, state.clone());
+        drop(current);
+        if before.jwks == state.jwks && before.stale == state.stale {
+            return;
+        }
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        if state
+            .jwks
+            .keys
+            .iter()
+            .any(|key| !before.jwks.keys.iter().any(|k| k.kid == key.kid))
+        {
+            self.unknown_kids
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+        }
+        // Retrieving the keys of a lazy verifier isn't a rotation
+        if before.is_pending() {
+            return;
+        }
+        let hooks = self.rotation_hooks();
+        if hooks.is_empty() {
+            return;
+        }
+        if let Some(rotation) =
+            KeyRotation::between(&before, &state, removed_usage)
+        {
+            rotation::notify(hooks, rotation);
+        }
+    }
+
+    // Runs the given recording on the counters of the kid, unless the kid
+    // was removed in the meantime
+    pub(crate) fn record_usage(&self, kid: &str, record: fn(&UsageCounters)) {
+        let usage = self.usage.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(counters) = usage.get(kid) {
+            record(counters);
+        }
+    }
+
+    pub(crate) fn usage(&self) -> Vec<(String, KeyUsage)> {
+        let keys = self.load();
+        let usage = self.usage.read().unwrap_or_else(PoisonError::into_inner);
+        usage::snapshot(&usage, &keys.jwks)
+    }
+
+    pub(crate) fn on_rotation(&self, hooks: &Arc<Vec<RotationHook>>) {
+        let mut registered =
+            self.rotation_hooks.lock().unwrap_or_else(PoisonError::into_inner);
+        registered.retain(|hooks| hooks.strong_count() > 0);
+        registered.push(Arc::downgrade(hooks));
+    }
+
+    // The callbacks of the Verifiers still around, each once although a
+    // Verifier and its later clones with more callbacks share some
+    fn rotation_hooks(&self) -> Vec<RotationHook> {
+        let registered =
+            self.rotation_hooks.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut hooks: Vec<RotationHook> = Vec::new();
+        for registered in registered.iter().filter_map(Weak::upgrade) {
+            for hook in registered.iter() {
+                if !hooks.iter().any(|seen| Arc::ptr_eq(&seen.0, &hook.0)) {
+                    hooks.push(hook.clone());
+                }
+            }
+        }
+        hooks
+    }
+
+    // Describes the keys and past retrievals for the refresh policy
+    pub(crate) fn refresh_context(
+        &self,
+        trigger: RefreshTrigger,
+    ) -> RefreshContext {
+        let keys = self.load();
+        let fetch = keys.fetch.as_ref();
+        let since = |at: &Mutex<Option<Instant>>| {
+            at.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .map(|at| at.elapsed())
+        };
+        let mut context = RefreshContext::new(trigger);
+        context.key_age =
+            fetch.and_then(|fetch| fetch.fetched_at.elapsed().ok());
+        context.max_age = fetch.and_then(|fetch| fetch.max_age);
+        context.last_failure = since(&self.failed_at);
+        context.last_kid_miss_refresh = since(&self.kid_miss_refresh);
+        context
+    }
+
+    pub(crate) fn kid_miss_refreshed(&self) {
+        *self.kid_miss_refresh.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(Instant::now());
+    }
+
+    // Whether the kid was remembered as unknown within the ttl
+    pub(crate) fn is_unknown_kid(&self, kid: &str, ttl: Duration) -> bool {
+        let unknown =
+            self.unknown_kids.lock().unwrap_or_else(PoisonError::into_inner);
+        unknown.get(kid).is_some_and(|seen| seen.elapsed() < ttl)
+    }
+
+    pub(crate) fn remember_unknown_kid(
+        &self,
+        kid: &str,
+        ttl: Duration,
+        capacity: usize,
+    ) {
+        if ttl.is_zero() || capacity == 0 {
+            return;
+        }
+        let mut unknown =
+            self.unknown_kids.lock().unwrap_or_else(PoisonError::into_inner);
+        if unknown.len() >= capacity && !unknown.contains_key(kid) {
+            unknown.retain(|_, seen| seen.elapsed() < ttl);
+        }
+        if unknown.len() >= capacity && !unknown.contains_key(kid) {
+            let oldest = unknown
+                .iter()
+                .min_by_key(|(_, seen)| **seen)
+                .map(|(kid, _)| kid.clone());
+            if let Some(oldest) = oldest {
+                unknown.remove(&oldest);
+            }
+        }
+        unknown.insert(kid.to_string(), Instant::now());
+    }
+
+    // Retrieves the keys unless they were replaced since the given
+    // generation, e.g. by a refresh that was in progress
+    pub(crate) async fn refresh_since(
+        &self,
+        issuer: &str,
+        config: &Config,
+        seen: u64,
+    ) -> Result<()> {
+        let url = keys_url(issuer, config)?;
+        self.refresh_with(config, &url, seen, || async {
+            get(issuer, config, Some(&self.load()), &self.discovery).await
+        })
+        .await
+    }
+
+    // The protocol of refresh_since with the retrieval passed in, so that
+    // the loom models run it without the network
+    async fn refresh_with<F>(
+        &self,
+        config: &Config,
+        url: &str,
+        seen: u64,
+        retrieve: impl FnOnce() -> F,
+    ) -> Result<()>
+    where
+        F: Future<Output = Result<(Jwks, FetchMetadata)>>,
+    {
+        let attempt = self.attempt();
+        let _guard = self.lock_refresh(config.wait_timeout, url).await?;
+        if let Some(result) = self.settled(seen, attempt) {
+            return result;
+        }
+        self.check_breaker(config)?;
+        let retrieval = logging::instrument!(retrieve(), "retrieve_keys", url);
+        let result = match retrieval.await {
+            // Turned away by the fetch queue without asking the issuer
+            Err(e) if queue::turned_away(&e) => return Err(e),
+            result => result,
+        };
+        self.record_breaker(config, &result);
+        self.finish(result.map(|(jwks, fetch)| KeyState::fetched(jwks, fetch)))
+    }
+
+    // Asks the keys url whether the keys changed with the refresh lock held
+    // and through the circuit breaker, like a refresh, sharing the outcome
+    // of a refresh that finished while waiting. The probe answers with the
+    // keys only when they changed, which are stored if `replace` is set.
+    pub(crate) async fn probe_with<F>(
+        &self,
+        config: &Config,
+        url: &str,
+        seen: u64,
+        replace: bool,
+        probe: impl FnOnce(Arc<KeyState>) -> F,
+    ) -> Result<bool>
+    where
+        F: Future<Output = Result<Option<(Jwks, FetchMetadata)>>>,
+    {
+        let attempt = self.attempt();
+        let _guard = self.lock_refresh(config.wait_timeout, url).await?;
+        if let Some(result) = self.settled(seen, attempt) {
+            return result.map(|()| false);
+        }
+        self.check_breaker(config)?;
+        let probe = logging::instrument!(probe(self.load()), "probe_keys", url);
+        let result = match probe.await {
+            Err(e) if queue::turned_away(&e) => return Err(e),
+            result => result,
+        };
+        self.record_breaker(config, &result);
+        match result {
+            Ok(Some((jwks, fetch))) if replace => {
+                self.finish(Ok(KeyState::fetched(jwks, fetch)))?;
+                Ok(true)
+            }
+            Err(e) if replace => self.finish(Err(e)).map(|()| false),
+            result => result.map(|probed| probed.is_some()),
+        }
+    }
+
+    // Fails right away while the circuit is open
+    fn check_breaker(&self, config: &Config) -> Result<()> {
+        if config.circuit_breaker.is_none() {
+            return Ok(());
+        }
+        self.breaker.lock().unwrap_or_else(PoisonError::into_inner).check()
+    }
+
+    // Fatal failures aren't down to the availability of the keys endpoint
+    // and leave the circuit as it is
+    fn record_breaker<T>(&self, config: &Config, result: &Result<T>) {
+        let Some(breaker) = config.circuit_breaker.as_ref() else {
+            return;
+        };
+        let fatal = result.as_ref().is_err_and(|e| {
+            retry::classify(config.classifier(), e) == RetryDecision::Fatal
+        });
+        if !fatal {
+            self.breaker
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record(breaker, result.is_ok());
+        }
+    }
+
+    #[cfg(not(okta_loom))]
+    async fn lock_refresh(
+        &self,
+        wait: Option<Duration>,
+        url: &str,
+    ) -> Result<async_lock::MutexGuard<'_, ()>> {
+        lock_within(&self.refresh, wait, url).await
+    }
+
+    // The models leave Config::wait_timeout unset
+    #[cfg(okta_loom)]
+    async fn lock_refresh(
+        &self,
+        _wait: Option<Duration>,
+        _url: &str,
+    ) -> Result<loom_lock::SpinGuard<'_>> {
+        Ok(self.refresh.lock())
+    }
+
+    // Read before waiting on the refresh lock, see settled
+    fn attempt(&self) -> u64 {
+        self.attempts.load(Ordering::Acquire)
+    }
+
+    // Called with the refresh lock held, decides whether a retrieval that
+    // finished while waiting makes another one unnecessary
+    fn settled(&self, seen: u64, attempt: u64) -> Option<Result<()>> {
+        if self.generation() != seen {
+            return Some(Ok(()));
+        }
+        if self.attempts.load(Ordering::Acquire) == attempt {
+            return None;
+        }
+        // A retrieval that finished while waiting either failed or found
+        // the keys unchanged
+        let failure =
+            self.failure.lock().unwrap_or_else(PoisonError::into_inner);
+        Some(failure.as_ref().map_or(Ok(()), |f| Err(shared_error(f))))
+    }
+
+    // Called with the refresh lock held, records the outcome of a retrieval
+    fn finish(&self, result: Result<KeyState>) -> Result<()> {
+        let mut failure =
+            self.failure.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = match result {
+            Ok(state) => {
+                *failure = None;
+                *self
+                    .failed_at
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) = None;
+                self.store(state);
+                Ok(())
+            }
+            Err(e) => {
+                *failure = Some(shared_error(&e));
+                *self
+                    .failed_at
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) =
+                    Some(Instant::now());
+                Err(e)
+            }
+        };
+        self.attempts.fetch_add(1, Ordering::AcqRel);
+        result
+    }
+}
+
+// A copy of an error shared by several callers, keeping the typed error so
+// it can still be recovered with downcast_ref
+fn shared_error(error: &anyhow::Error) -> anyhow::Error {
+    match error.downcast_ref::<Error>() {
+        Some(typed) => anyhow::Error::new(typed.clone()),
+        None => anyhow::anyhow!("{error:#}"),
+    }
+}
+
+// Waits for the async lock, giving up once the timeout elapses
+pub(crate) async fn lock_within<'a>(
+    lock: &'a async_lock::Mutex<()>,
+    wait: Option<Duration>,
+    url: &str,
+) -> Result<async_lock::MutexGuard<'a, ()>> {
+    let guard = match wait {
+        Some(wait) => runtime::timeout(wait, lock.lock()).await,
+        None => Some(lock.lock().await),
+    };
+    match guard {
+        Some(guard) => Ok(guard),
+        None => bail!(Error::WaitTimedOut { url: url.to_string() }),
+    }
+}
+
+#[cfg(all(test, not(okta_loom)))]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use crate::test_support::*;
+    use crate::{Config, DefaultClaims, Jwk, Verifier, ORG_ENDPOINT};
+
+    fn state(keys: usize) -> KeyState {
+        KeyState {
+            jwks: Jwks::from_keys(vec![jwk(); keys]),
+            fetch: None,
+            stale: false,
+        }
+    }
+
+    #[test]
+    fn stores_from_several_threads_bump_the_generation() {
+        let store = Arc::new(KeyStore::new(KeyState::pending()));
+        let handles: Vec<_> = (1..=4)
+            .map(|keys| {
+                let store = store.clone();
+                thread::spawn(move || store.store(state(keys)))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(store.generation(), 4);
+        assert!(!store.load().is_pending());
+    }
+
+    #[test]
+    fn remembers_a_bounded_number_of_unknown_kids() {
+        let store = KeyStore::new(state(1));
+        let ttl = Duration::from_secs(60);
+        for kid in ["a", "b", "c"] {
+            store.remember_unknown_kid(kid, ttl, 2);
+        }
+        assert!(!store.is_unknown_kid("a", ttl));
+        assert!(store.is_unknown_kid("b", ttl));
+        assert!(store.is_unknown_kid("c", ttl));
+        assert!(!store.is_unknown_kid("c", Duration::ZERO));
+
+        // Keys under a new kid forget them all
+        let mut rotated = state(1);
+        rotated.jwks.keys[0].kid = "rotated".to_string();
+        store.store(rotated);
+        assert!(!store.is_unknown_kid("c", ttl));
+    }
+
+    #[test]
+    fn rotation_callbacks_run_outside_of_a_runtime() {
+        let store = KeyStore::new(state(1));
+        let (sender, received) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+        let hooks: Arc<Vec<RotationHook>> =
+            Arc::new(vec![crate::Hook(Arc::new(move |_: &KeyRotation| {
+                sender.lock().unwrap().send(()).unwrap();
+            }))]);
+        store.on_rotation(&hooks);
+
+        let mut rotated = state(1);
+        rotated.jwks.keys[0].kid = "rotated".to_string();
+        store.store(rotated);
+        received.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn a_finished_retrieval_settles_the_callers_that_waited() {
+        let store = KeyStore::new(KeyState::pending());
+        let (seen, attempt) = (store.generation(), store.attempt());
+        assert!(store.settled(seen, attempt).is_none());
+
+        let failed = Error::KeysUnreachable {
+            url: "keys".into(),
+            reason: "down".into(),
+        };
+        assert!(store.finish(Err(failed.clone().into())).is_err());
+        let shared = store.settled(seen, attempt).unwrap().unwrap_err();
+        assert_eq!(shared.downcast_ref::<Error>(), Some(&failed));
+
+        // Callers arriving after the failure retrieve the keys again
+        let attempt = store.attempt();
+        assert!(store.settled(seen, attempt).is_none());
+        store.finish(Ok(state(1))).unwrap();
+        assert!(store.settled(seen, attempt).unwrap().is_ok());
+        assert_eq!(store.load().jwks.keys.len(), 1);
+
+        // As does one that found the keys unchanged
+        let (seen, attempt) = (store.generation(), store.attempt());
+        store.finish(Ok(state(1))).unwrap();
+        assert_eq!(store.generation(), seen);
+        assert!(store.settled(seen, attempt).unwrap().is_ok());
+    }
+
+    #[async_test]
+    async fn retries_once_when_keys_are_replaced_mid_verification() -> Result<()>
+    {
+        let mut server = mockito::Server::new_async().await;
+        let replaced = Jwk { kid: KEY_ID.to_string(), ..rotated_jwk() };
+        mock_keys(&mut server, vec![replaced]);
+        let verifier = Verifier::new(&server.url()).await?;
+        let generation = verifier.key_generation();
+        // Swaps the keys after the lookup but before the first decode
+        let keys = Arc::clone(&verifier.keys);
+        let swaps = Arc::new(AtomicU64::new(0));
+        let counted = Arc::clone(&swaps);
+        let verifier = verifier.with_validation_hook(move |_| {
+            if counted.fetch_add(1, Ordering::SeqCst) == 0 {
+                keys.store(KeyState {
+                    jwks: Jwks::from_keys(vec![jwk()]),
+                    fetch: None,
+                    stale: false,
+                });
+            }
+        });
+        let verified = verifier
+            .verify_detailed::<DefaultClaims>(&token(&server.url()))
+            .await?;
+        assert_eq!(verified.kid, KEY_ID);
+        assert_eq!(swaps.load(Ordering::SeqCst), 2);
+        assert_eq!(verifier.key_generation(), generation + 1);
+
+        // Without a replacement the signature failure is final
+        let rotated = sign_with(ROTATED_KP_PEM, KEY_ID, claims(&server.url()));
+        let err = verifier.verify::<DefaultClaims>(&rotated).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidToken { .. })
+        ));
+        assert_eq!(swaps.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn refetches_once_for_an_unknown_kid() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let before = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let verifier = Verifier::new(&server.url()).await?;
+        before.assert();
+        before.remove();
+        let rotated_keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk(), rotated_jwk()]))
+            .expect(1)
+            .create();
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        let verified =
+            verifier.verify_detailed::<DefaultClaims>(&rotated).await?;
+        assert_eq!(verified.kid, ROTATED_KEY_ID);
+        assert_eq!(verifier.key_generation(), 1);
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+
+        // Forged kids fail right away during the cooldown
+        for _ in 0..2 {
+            let forged =
+                sign_with(ROTATED_KP_PEM, "forged", claims(&server.url()));
+            let err =
+                verifier.verify::<DefaultClaims>(&forged).await.unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<Error>(),
+                Some(&Error::NoMatchingKey)
+            );
+        }
+        rotated_keys.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn clones_share_the_keys_without_blocking_on_retrievals() -> Result<()>
+    {
+        let mut server = mockito::Server::new_async().await;
+        let before = mock_keys(&mut server, vec![jwk()]);
+        let verifier = Arc::new(Verifier::new(&server.url()).await?);
+        let clone = (*verifier).clone();
+        before.remove();
+        let sent = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let body = keys_body(vec![jwk(), rotated_jwk()]);
+        let flag = sent.clone();
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_chunked_body(move |w| {
+                thread::sleep(Duration::from_millis(500));
+                flag.store(true, Ordering::SeqCst);
+                w.write_all(body.as_bytes())
+            })
+            .create();
+
+        // Verifications keep using the current keys while the keys are
+        // retrieved, the lock isn't held across the request
+        let token = token(&server.url());
+        let (refresh, verified) = futures::join!(clone.refresh_keys(), async {
+            sleep(Duration::from_millis(100)).await;
+            let verified = verifier.verify::<DefaultClaims>(&token).await;
+            (verified, sent.load(Ordering::SeqCst))
+        });
+        assert!(refresh?.changed());
+        assert!(verified.0.is_ok());
+        assert!(!verified.1);
+
+        // Keys retrieved through a clone are used by all of them
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+        assert_eq!(verifier.key_generation(), 1);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn unknown_kids_refetch_again_after_the_cooldown() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(3)
+            .create();
+        let config = Config {
+            kid_miss_cooldown: Duration::from_millis(200),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        let forged = sign_with(ROTATED_KP_PEM, "forged", claims(&server.url()));
+        let clones = (0..20).map(|_| verifier.clone());
+        let misses = clones.map(|verifier| {
+            let forged = forged.clone();
+            async move { verifier.verify::<DefaultClaims>(&forged).await }
+        });
+        for result in futures::future::join_all(misses).await {
+            assert_eq!(
+                result.unwrap_err().downcast_ref::<Error>(),
+                Some(&Error::NoMatchingKey)
+            );
+        }
+        assert!(verifier.verify::<DefaultClaims>(&forged).await.is_err());
+
+        // The forged kid itself is remembered as unknown, another one
+        // retrieves the keys again
+        sleep(Duration::from_millis(250)).await;
+        assert!(verifier.verify::<DefaultClaims>(&forged).await.is_err());
+        let other = sign_with(ROTATED_KP_PEM, "other", claims(&server.url()));
+        assert!(verifier.verify::<DefaultClaims>(&other).await.is_err());
+        keys.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn kids_missed_during_the_cooldown_are_not_remembered() -> Result<()>
+    {
+        let mut server = mockito::Server::new_async().await;
+        let before = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(2)
+            .create();
+        let config = Config {
+            kid_miss_cooldown: Duration::from_millis(200),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        let forged = sign_with(ROTATED_KP_PEM, "forged", claims(&server.url()));
+        assert!(verifier.verify::<DefaultClaims>(&forged).await.is_err());
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        let err = verifier.verify::<DefaultClaims>(&rotated).await.unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Error::NoMatchingKey));
+        before.assert();
+        before.remove();
+
+        // The rotated kid is looked for again once the cooldown is over
+        mock_keys(&mut server, vec![jwk(), rotated_jwk()]);
+        sleep(Duration::from_millis(250)).await;
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn unknown_kids_are_remembered_until_new_keys_arrive() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(3)
+            .create();
+        let config =
+            Config { kid_miss_cooldown: Duration::ZERO, ..Config::default() };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        let token =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        for _ in 0..3 {
+            let err =
+                verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+            assert_eq!(err.downcast_ref(), Some(&Error::NoMatchingKey));
+        }
+        // Retrieving the same keys again doesn't forget the kid
+        verifier.refresh_keys().await?;
+        assert!(verifier.verify::<DefaultClaims>(&token).await.is_err());
+        keys.assert();
+        keys.remove();
+
+        mock_keys(&mut server, vec![jwk(), rotated_jwk()]);
+        verifier.refresh_keys().await?;
+        verifier.verify::<DefaultClaims>(&token).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn refetching_for_an_unknown_kid_can_be_disabled() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let config = Config { refetch_on_kid_miss: false, ..Config::default() };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        let err = verifier.verify::<DefaultClaims>(&rotated).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NoMatchingKey));
+        keys.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn a_failed_refetch_reports_the_outage() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        keys.remove();
+        server.mock("GET", ORG_ENDPOINT).with_status(503).create();
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        let err = verifier.verify::<DefaultClaims>(&rotated).await.unwrap_err();
+        let err = err.downcast_ref::<Error>().unwrap();
+        assert_eq!(
+            err.status_hint(),
+            http::StatusCode::SERVICE_UNAVAILABLE,
+            "{err}"
+        );
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn persists_the_keys_off_the_async_task() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let thread = Arc::new(Mutex::new(None));
+        let persisted = thread.clone();
+        let config = Config {
+            on_keys_persist: Some(Arc::new(move |_: &str| {
+                *persisted.lock().unwrap() = Some(thread::current().id());
+            })),
+            ..Config::default()
+        };
+        Verifier::new_with_config(&server.url(), config).await?;
+        let persisted = thread.lock().unwrap().expect("the keys persisted");
+        assert_ne!(persisted, thread::current().id());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn round_trips_the_keys_through_the_persistence_hooks() -> Result<()>
+    {
+        let mut server = mockito::Server::new_async().await;
+        let up = mock_keys(&mut server, vec![jwk()]);
+        let store = Arc::new(Mutex::new(None::<String>));
+        let persisted = store.clone();
+        let loaded = store.clone();
+        let config = Config {
+            on_keys_persist: Some(Arc::new(move |jwks: &str| {
+                *persisted.lock().unwrap() = Some(jwks.to_string());
+            })),
+            keys_loader: Some(Arc::new(move || loaded.lock().unwrap().clone())),
+            ..Config::default()
+        };
+        let verifier =
+            Verifier::new_with_config(&server.url(), config.clone()).await?;
+        assert!(!verifier.stats().stale);
+        assert_eq!(
+            store.lock().unwrap().as_deref(),
+            Some(keys_body(vec![jwk()]).as_str())
+        );
+
+        up.remove();
+        server.mock("GET", ORG_ENDPOINT).with_status(503).create();
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        assert!(verifier.stats().stale);
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn validates_the_loaded_keys() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", ORG_ENDPOINT).with_status(503).create();
+        let config = Config {
+            keys_loader: Some(Arc::new(|| Some("not a jwks".to_string()))),
+            ..Config::default()
+        };
+        let err = Verifier::new_with_config(&server.url(), config.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidKeySet { .. })
+        ));
+
+        let config =
+            Config { fallback_keys: Some(keys_body(vec![jwk()])), ..config };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn concurrent_refreshes_share_one_request() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body_from_request(|_| {
+                thread::sleep(Duration::from_millis(200));
+                keys_body(vec![jwk()]).into()
+            })
+            .expect(2)
+            .create();
+        let verifier = Verifier::new(&server.url()).await?;
+        let refreshes = (0..50).map(|_| verifier.refresh_keys());
+        for result in futures::future::join_all(refreshes).await {
+            result?;
+        }
+        m.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn concurrent_refreshes_share_a_failure() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        keys.remove();
+        let down = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(503)
+            .with_body_from_request(|_| {
+                thread::sleep(Duration::from_millis(200));
+                Vec::new()
+            })
+            // Retried by the one request shared by all callers
+            .expect(3)
+            .create();
+        let clones: Vec<_> = (0..50).map(|_| verifier.clone()).collect();
+        let refreshes = clones.iter().map(|verifier| verifier.refresh_keys());
+        for result in futures::future::join_all(refreshes).await {
+            assert!(matches!(
+                result.unwrap_err().downcast_ref::<Error>(),
+                Some(Error::KeysStatus { status: 503, .. })
+            ));
+        }
+        down.assert();
+
+        // The failure isn't handed to callers arriving afterwards
+        down.remove();
+        let up = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![rotated_jwk()]))
+            .expect(1)
+            .create();
+        let refresh = verifier.refresh_keys().await?;
+        assert_eq!(refresh.added, 1);
+        up.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn waiting_for_a_refresh_can_time_out() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body_from_request(|_| {
+                thread::sleep(Duration::from_millis(300));
+                keys_body(vec![jwk()]).into()
+            })
+            .create();
+        let config = Config {
+            wait_timeout: Some(Duration::from_millis(50)),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        let (first, second) = futures::future::join(
+            verifier.refresh_keys(),
+            verifier.refresh_keys(),
+        )
+        .await;
+        first?;
+        assert!(matches!(
+            second.unwrap_err().downcast_ref::<Error>(),
+            Some(Error::WaitTimedOut { .. })
+        ));
+        Ok(())
+    }
+}
+
+// Models the interactions between verifications, which load the keys and
+// compare generations, and refreshes, which run refresh_since with the
+// retrieval swapped for a given outcome.
+#[cfg(all(test, okta_loom))]
+mod loom_tests {
+    use super::*;
+
+    use std::time::SystemTime;
+
+    use loom::sync::atomic::AtomicUsize;
+    use loom::thread;
+
+    use crate::test_support::jwk;
+
+    // The number of keys tells the stored states apart
+    fn state(keys: usize) -> KeyState {
+        KeyState {
+            jwks: Jwks::from_keys(vec![jwk(); keys]),
+            fetch: None,
+            stale: false,
+        }
+    }
+
+    // Runs refresh_since with the given number of keys or error as the
+    // outcome of the retrieval, returning whether it retrieved the keys
+    fn refresh(
+        store: &KeyStore,
+        seen: u64,
+        outcome: Result<usize>,
+    ) -> (bool, Result<()>) {
+        let mut fetched = false;
+        let retrieve = || {
+            fetched = true;
+            let fetch = FetchMetadata {
+                source: "keys".into(),
+                fetched_at: SystemTime::UNIX_EPOCH,
+                max_age: None,
+                etag: None,
+                last_modified: None,
+            };
+            let outcome =
+                outcome.map(|keys| (Jwks::from_keys(vec![jwk(); keys]), fetch));
+            async move { outcome }
+        };
+        let result = loom::future::block_on(store.refresh_with(
+            &Config::default(),
+            "keys",
+            seen,
+            retrieve,
+        ));
+        (fetched, result)
+    }
+
+    fn unreachable() -> anyhow::Error {
+        anyhow::Error::new(Error::KeysUnreachable {
+            url: "keys".into(),
+            reason: "down".into(),
+        })
+    }
+
+    #[test]
+    fn verifications_never_see_keys_older_than_the_generation() {
+        loom::model(|| {
+            let store = Arc::new(KeyStore::new(state(0)));
+            let writer = {
+                let store = store.clone();
+                thread::spawn(move || {
+                    store.store(state(1));
+                    store.store(state(2));
+                })
+            };
+            let generation = store.generation();
+            let keys = store.load().jwks.keys.len() as u64;
+            assert!(keys >= generation);
+            writer.join().unwrap();
+            assert_eq!(store.generation(), 2);
+            assert_eq!(store.load().jwks.keys.len(), 2);
+        });
+    }
+
+    #[test]
+    fn concurrent_refreshes_retrieve_the_keys_once() {
+        loom::model(|| {
+            let store = Arc::new(KeyStore::new(state(0)));
+            let fetches = Arc::new(AtomicUsize::new(0));
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let (store, fetches) = (store.clone(), fetches.clone());
+                    thread::spawn(move || {
+                        let (fetched, result) = refresh(&store, 0, Ok(1));
+                        if fetched {
+                            fetches.fetch_add(1, Ordering::SeqCst);
+                        }
+                        result.unwrap();
+                    })
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap();
+            }
+            // Both callers saw generation 0, the second one to take the
+            // lock always finds the keys replaced
+            assert_eq!(fetches.load(Ordering::SeqCst), 1);
+            assert_eq!(store.generation(), 1);
+        });
+    }
+
+    #[test]
+    fn callers_waiting_on_a_failed_refresh_share_its_error() {
+        loom::model(|| {
+            let store = Arc::new(KeyStore::new(state(0)));
+            let handles: Vec<_> = (0..2)
+                .map(|_| {
+                    let store = store.clone();
+                    thread::spawn(move || {
+                        refresh(&store, 0, Err(unreachable()))
+                    })
+                })
+                .collect();
+            let mut fetches = 0;
+            for handle in handles {
+                let (fetched, result) = handle.join().unwrap();
+                fetches += usize::from(fetched);
+                let e = result.unwrap_err();
+                assert!(e.downcast_ref::<Error>().is_some());
+            }
+            // A caller only retrieves the keys again if it started waiting
+            // after the first retrieval had finished
+            assert_eq!(store.attempt(), fetches as u64);
+            assert_eq!(store.generation(), 0);
+            assert!(store.load().jwks.keys.is_empty());
+        });
+    }
+
+    #[test]
+    fn a_failure_after_a_success_is_not_shared_with_later_callers() {
+        loom::model(|| {
+            let store = Arc::new(KeyStore::new(state(0)));
+            let failing = {
+                let store = store.clone();
+                thread::spawn(move || refresh(&store, 0, Err(unreachable())))
+            };
+            let seen = store.generation();
+            let (_, result) = refresh(&store, seen, Ok(1));
+            // Either this retrieval stored the keys, or it waited on the
+            // failed one and shares its error, the keys are never lost
+            if result.is_ok() {
+                assert_eq!(store.load().jwks.keys.len(), 1);
+            }
+            // Likewise a caller that waited on the successful retrieval
+            // finds the keys replaced rather than failing
+            let (_, result) = failing.join().unwrap();
+            if result.is_ok() {
+                assert_eq!(store.generation(), 1);
+            }
+            let after = store.generation();
+            let (_, result) = refresh(&store, after, Ok(1));
+            result.unwrap();
+        });
+    }
+}
@@ -0,0 +1,57 @@
+// The diagnostics of the crate go through tracing with the tracing feature,
+// or else through log with the log feature, and nowhere without either.
+// The arguments are still evaluated then, so that nothing goes unused.
+// With the tracing feature verifications and retrievals of the keys also
+// run in their own spans, see instrument.
+
+macro_rules! emit {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        ::tracing::$level!($($arg)+);
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        ::log::$level!($($arg)+);
+        #[cfg(not(any(feature = "log", feature = "tracing")))]
+        {
+            let _ = ::std::format_args!($($arg)+);
+        }
+    }};
+}
+
+macro_rules! warning {
+    ($($arg:tt)+) => {
+        $crate::logging::emit!(warn, $($arg)+)
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::logging::emit!(info, $($arg)+)
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::logging::emit!(debug, $($arg)+)
+    };
+}
+
+// Runs the future within a debug span with the given name and fields
+#[cfg(feature = "tracing")]
+macro_rules! instrument {
+    ($future:expr, $name:literal $(, $($field:tt)+)?) => {
+        ::tracing::Instrument::instrument(
+            $future,
+            ::tracing::debug_span!($name $(, $($field)+)?),
+        )
+    };
+}
+
+// Runs the future as is without the tracing feature
+#[cfg(not(feature = "tracing"))]
+macro_rules! instrument {
+    ($future:expr, $name:literal $(, $($field:tt)+)?) => {
+        $future
+    };
+}
+
+pub(crate) use {debug, emit, info, instrument, warning as warn};
@@ -0,0 +1,285 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess};
+use serde_json::{Map, Value};
+
+use crate::Error;
+
+// The timestamps the crate checks itself or has jsonwebtoken check, which
+// other parsers may read differently unless written as plain integers
+const TIMESTAMP_CLAIMS: [&str; 3] = ["exp", "iat", "nbf"];
+
+// Rejects claims that serde_json would accept but read in a way other
+// parsers may not, see Config::strict_payload_parsing. Anything that isn't
+// JSON at all is left to the decoding of the token to report.
+pub(crate) fn check_payload(token: &str) -> Result<()> {
+    let Some(Ok(bytes)) =
+        token.split('.').nth(1).map(|payload| URL_SAFE_NO_PAD.decode(payload))
+    else {
+        return Ok(());
+    };
+    let Ok(json) = std::str::from_utf8(&bytes) else {
+        bail!(Error::PayloadNotUtf8)
+    };
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let Ok(Strict { value, duplicate }) =
+        Strict::deserialize(&mut deserializer)
+    else {
+        return Ok(());
+    };
+    if let Some(claim) = duplicate {
+        bail!(Error::DuplicateClaim { claim })
+    }
+    for claim in TIMESTAMP_CLAIMS {
+        // A negative integer is written canonically, but isn't a timestamp
+        match value.get(claim) {
+            Some(Value::Number(number))
+                if number.as_i64().is_some_and(|n| n < 0) =>
+            {
+                bail!(Error::InvalidToken {
+                    reason: format!("{claim} claim is negative"),
+                })
+            }
+            Some(Value::Number(number)) if !number.is_u64() => {
+                bail!(Error::NonCanonicalNumber { claim: claim.to_string() })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// A JSON value along with the first key repeated within one of its
+// objects, which serde_json would otherwise replace silently
+struct Strict {
+    value: Value,
+    duplicate: Option<String>,
+}
+
+impl From<Value> for Strict {
+    fn from(value: Value) -> Self {
+        Self { value, duplicate: None }
+    }
+}
+
+impl<'de> Deserialize<'de> for Strict {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(StrictVisitor)
+    }
+}
+
+struct StrictVisitor;
+
+impl<'de> de::Visitor<'de> for StrictVisitor {
+    type Value = Strict;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a JSON value")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<Strict, E> {
+        Ok(Value::Bool(value).into())
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Strict, E> {
+        Ok(Value::from(value).into())
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Strict, E> {
+        Ok(Value::from(value).into())
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<Strict, E> {
+        Ok(Value::from(value).into())
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Strict, E> {
+        Ok(Value::from(value).into())
+    }
+
+    fn visit_string<E>(self, value: String) -> Result<Strict, E> {
+        Ok(Value::from(value).into())
+    }
+
+    fn visit_unit<E>(self) -> Result<Strict, E> {
+        Ok(Value::Null.into())
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Strict, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut values = Vec::new();
+        let mut duplicate = None;
+        while let Some(element) = seq.next_element::<Strict>()? {
+            duplicate = duplicate.or(element.duplicate);
+            values.push(element.value);
+        }
+        Ok(Strict { value: Value::Array(values), duplicate })
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Strict, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut values = Map::new();
+        let mut duplicate = None;
+        while let Some(key) = map.next_key::<String>()? {
+            let element = map.next_value::<Strict>()?;
+            if duplicate.is_none() && values.contains_key(&key) {
+                duplicate = Some(key.clone());
+            }
+            duplicate = duplicate.or(element.duplicate);
+            values.insert(key, element.value);
+        }
+        Ok(Strict { value: Value::Object(values), duplicate })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use crate::test_support::*;
+    use crate::{Config, DefaultClaims, Verifier};
+
+    const ISSUER: &str = "https://your.okta.com";
+
+    // Signs the payload as is, e.g. with a repeated claim that no claims
+    // type would serialize
+    fn sign_raw(payload: &[u8]) -> String {
+        let mut header = Header::new(jsonwebtoken::Algorithm::RS256);
+        header.kid = Some(KEY_ID.to_string());
+        let header =
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap());
+        let message = format!("{header}.{}", URL_SAFE_NO_PAD.encode(payload));
+        let key = EncodingKey::from_rsa_pem(RSA_KP_PEM.as_bytes()).unwrap();
+        let signature = jsonwebtoken::crypto::sign(
+            message.as_bytes(),
+            &key,
+            jsonwebtoken::Algorithm::RS256,
+        )
+        .unwrap();
+        format!("{message}.{signature}")
+    }
+
+    fn now() -> u64 {
+        jwt_simple::prelude::Clock::now_since_epoch().as_secs()
+    }
+
+    fn error(token: &str) -> Option<Error> {
+        check_payload(token).err()?.downcast_ref().cloned()
+    }
+
+    fn verifier(strict: bool) -> Verifier {
+        let config =
+            Config { strict_payload_parsing: strict, ..Config::default() };
+        Verifier::with_keys_and_config(ISSUER, &keys_body(vec![jwk()]), config)
+            .unwrap()
+    }
+
+    #[async_test]
+    async fn rejects_a_repeated_exp_when_strict() -> Result<()> {
+        let (expired, valid) = (now() - 3600, now() + 3600);
+        let payload = format!(
+            r#"{{"iss":"{ISSUER}","sub":"test","exp":{expired},"exp":{valid}}}"#
+        );
+        let token = sign_raw(payload.as_bytes());
+        // jsonwebtoken happens to refuse a repeated exp, as an invalid token
+        let e = verifier(false).verify::<Value>(&token).await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(Error::InvalidToken { .. })));
+        let e =
+            verifier(true).verify::<DefaultClaims>(&token).await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&Error::DuplicateClaim { claim: "exp".into() })
+        );
+        assert_eq!(e.downcast_ref::<Error>().unwrap().status_hint(), 401);
+
+        // Other claims are silently replaced by their last occurrence
+        let payload = format!(
+            r#"{{"iss":"{ISSUER}","role":"user","role":"admin","exp":{valid}}}"#
+        );
+        let token = sign_raw(payload.as_bytes());
+        let claims = verifier(false).verify::<Value>(&token).await?;
+        assert_eq!(claims.claims["role"], "admin");
+        let e = verifier(true).verify::<Value>(&token).await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&Error::DuplicateClaim { claim: "role".into() })
+        );
+        Ok(())
+    }
+
+    #[async_test]
+    async fn rejects_invalid_utf8_when_strict() -> Result<()> {
+        let payload = format!(
+            r#"{{"iss":"{ISSUER}","sub":"test","exp":{},"name":"#,
+            now() + 3600
+        );
+        let mut payload = payload.into_bytes();
+        payload.extend_from_slice(b"\"\xff\xfe\"}");
+        let token = sign_raw(&payload);
+        let e =
+            verifier(true).verify::<DefaultClaims>(&token).await.unwrap_err();
+        assert_eq!(e.downcast_ref(), Some(&Error::PayloadNotUtf8));
+        // Not accepted either way, but reported as an invalid token
+        let e =
+            verifier(false).verify::<DefaultClaims>(&token).await.unwrap_err();
+        assert_ne!(e.downcast_ref(), Some(&Error::PayloadNotUtf8));
+        Ok(())
+    }
+
+    #[test]
+    fn finds_duplicates_at_any_depth() {
+        let token = |payload: &str| {
+            format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(payload))
+        };
+        assert_eq!(error(&token(r#"{"sub":"a","exp":1,"iat":0}"#)), None);
+        assert_eq!(
+            error(&token(r#"{"org":{"id":1,"id":2}}"#)),
+            Some(Error::DuplicateClaim { claim: "id".into() })
+        );
+        assert_eq!(
+            error(&token(r#"{"roles":[{"a":1},{"b":1,"b":2}]}"#)),
+            Some(Error::DuplicateClaim { claim: "b".into() })
+        );
+        // The same key in sibling objects is fine
+        assert_eq!(error(&token(r#"{"a":{"id":1},"b":{"id":1}}"#)), None);
+    }
+
+    #[test]
+    fn requires_plain_integer_timestamps() {
+        let token = |payload: &str| {
+            format!("e30.{}.sig", URL_SAFE_NO_PAD.encode(payload))
+        };
+        for exp in ["1.7e9", "1700000000.0", "-1.0", "1E9"] {
+            assert_eq!(
+                error(&token(&format!(r#"{{"exp":{exp}}}"#))),
+                Some(Error::NonCanonicalNumber { claim: "exp".into() }),
+                "{exp}"
+            );
+        }
+        assert_eq!(
+            error(&token(r#"{"iat":1.5}"#)),
+            Some(Error::NonCanonicalNumber { claim: "iat".into() })
+        );
+        assert_eq!(
+            error(&token(r#"{"nbf":-1}"#)),
+            Some(Error::InvalidToken {
+                reason: "nbf claim is negative".into()
+            })
+        );
+        // Only the timestamps, and nothing that isn't JSON
+        assert_eq!(error(&token(r#"{"exp":1700000000,"ratio":0.5}"#)), None);
+        assert_eq!(error(&token("not json")), None);
+        assert_eq!(error("e30.!!!.sig"), None);
+    }
+}
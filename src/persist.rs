@@ -0,0 +1,71 @@
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::{logging, runtime, Config};
+
+/// Receives every key set retrieved from the keys endpoint as the JWKS
+/// document that was received, e.g. to write it to a custom storage
+/// backend, see [`Config::on_keys_persist`]. It's called on a thread that
+/// may block, e.g. to write a file, and the retrieval waits for it to
+/// return. Implemented for closures taking a `&str`.
+pub trait KeysPersist: Send + Sync {
+    /// Stores the given JWKS document.
+    fn persist(&self, jwks: &str);
+}
+
+impl<F: Fn(&str) + Send + Sync> KeysPersist for F {
+    fn persist(&self, jwks: &str) {
+        self(jwks)
+    }
+}
+
+impl fmt::Debug for dyn KeysPersist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeysPersist")
+    }
+}
+
+/// Provides a JWKS document, e.g. one stored by [`KeysPersist`], used
+/// when the keys can't be retrieved, see [`Config::keys_loader`].
+/// Implemented for closures returning an `Option<String>`.
+pub trait KeysLoader: Send + Sync {
+    /// Returns the stored JWKS document, if there is one.
+    fn load(&self) -> Option<String>;
+}
+
+impl<F: Fn() -> Option<String> + Send + Sync> KeysLoader for F {
+    fn load(&self) -> Option<String> {
+        self()
+    }
+}
+
+impl fmt::Debug for dyn KeysLoader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeysLoader")
+    }
+}
+
+// Hands a retrieved key set to the persist hook off the async task, a
+// panicking hook doesn't affect the retrieval
+pub(crate) async fn persist(config: &Config, body: &[u8]) {
+    let Some(hook) = config.on_keys_persist.clone() else {
+        return;
+    };
+    let jwks = String::from_utf8_lossy(body).into_owned();
+    runtime::unblock(move || {
+        if catch_unwind(AssertUnwindSafe(|| hook.persist(&jwks))).is_err() {
+            logging::warn!("The keys persist hook panicked");
+        }
+    })
+    .await;
+}
+
+// Asks the loader for a stored key set, a panicking loader counts as
+// having none
+pub(crate) fn load(config: &Config) -> Option<String> {
+    let loader = config.keys_loader.as_ref()?;
+    catch_unwind(AssertUnwindSafe(|| loader.load())).unwrap_or_else(|_| {
+        logging::warn!("The keys loader panicked");
+        None
+    })
+}
@@ -0,0 +1,416 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::inspect::alg_name;
+use crate::{Error, ExpPolicy, Rule, ScopePolicy, TokenHeader, Verifier};
+
+// Claims RFC 9068 requires in every JWT access token, with Okta's cid in
+// place of client_id
+const RFC9068_CLAIMS: [&str; 7] =
+    ["iss", "exp", "aud", "sub", "cid", "iat", "jti"];
+
+/// Describes the checks a [`Verifier`] applies to every token, after
+/// everything that influences them has been merged.
+///
+/// Settings are resolved in the following order, later steps overriding
+/// earlier ones:
+///
+/// 1. the defaults, e.g. RS256 and a leeway of 120 seconds,
+/// 2. the [`Config`](crate::Config) the Verifier was created with, with
+///    [`Config::discovery`](crate::Config::discovery) the algorithms
+///    narrowed to those the discovery document lists,
+/// 3. presets such as [`Verifier::strict`] and builder calls, in the
+///    order they were made,
+/// 4. the hook registered with [`Verifier::with_validation_hook`].
+///
+/// Per-call [`VerifyOptions`](crate::VerifyOptions) aren't included.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct ValidationPolicy {
+    /// The signature algorithms accepted.
+    pub algorithms: Vec<String>,
+    /// The accepted iss values.
+    pub issuers: Vec<String>,
+    /// The accepted aud values, any audience is accepted when absent.
+    pub audiences: Option<Vec<String>>,
+    /// The number of accepted aud values a token has to hold, see
+    /// [`Verifier::audience_threshold`].
+    pub audience_threshold: usize,
+    /// Whether the aud claim is validated.
+    pub validate_aud: bool,
+    /// Whether the exp claim is validated.
+    pub validate_exp: bool,
+    /// How tokens without an exp claim are treated.
+    pub exp_policy: ExpPolicy,
+    /// Whether the nbf claim is validated.
+    pub validate_nbf: bool,
+    /// Whether tokens issued further in the future than the leeway are
+    /// rejected.
+    pub reject_future_iat: bool,
+    /// The leeway in seconds applied to exp and nbf.
+    pub leeway: u64,
+    /// The claims every token must contain.
+    pub required_claims: Vec<String>,
+    /// The accepted typ header values, the header isn't checked
+    /// when absent.
+    pub accepted_typ: Option<Vec<String>>,
+    /// The required cid claim.
+    pub client_id: Option<String>,
+    /// Whether the client id is required in place of the audience, with
+    /// the azp claim accepted when cid is absent.
+    pub client_id_only: bool,
+    /// The scopes every token must grant, unless each of its audiences is
+    /// listed in `audience_policies`.
+    pub required_scopes: Option<Vec<String>>,
+    /// The authorization rules every token must satisfy.
+    pub rules: Vec<Rule>,
+    /// The scopes required from tokens holding the audience.
+    pub audience_policies: BTreeMap<String, ScopePolicy>,
+    /// The accepted sub values, any subject is accepted when absent.
+    pub allowed_subjects: Option<Vec<String>>,
+    /// The accepted idp values, any identity provider is accepted when
+    /// absent.
+    pub allowed_idps: Option<Vec<String>>,
+    /// Whether tokens are checked against a jti denylist.
+    pub jti_denylist: bool,
+    /// Whether questionable settings are treated as errors.
+    pub strict: bool,
+}
+
+impl Verifier {
+    /// `effective_policy` reports the checks applied to every token, e.g.
+    /// so that an audit can assert the configuration of a running service.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verifier = Verifier::new(&issuer)
+    ///         .await?
+    ///         .add_audience("api://default");
+    ///     println!("{}", serde_json::to_string(&verifier.effective_policy())?);
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn effective_policy(&self) -> ValidationPolicy {
+        let validation = self.validation();
+        let sorted = |values: &std::collections::HashSet<String>| {
+            values
+                .iter()
+                .cloned()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect()
+        };
+        let mut required_claims: BTreeSet<String> =
+            validation.required_spec_claims.iter().cloned().collect();
+        required_claims.extend(self.required_claims.iter().cloned());
+        if self.denylist.is_some() && self.reject_missing_jti {
+            required_claims.insert("jti".to_string());
+        }
+        ValidationPolicy {
+            algorithms: validation
+                .algorithms
+                .iter()
+                .map(|&alg| alg_name(alg).to_string())
+                .collect(),
+            issuers: validation.iss.as_ref().map(sorted).unwrap_or_default(),
+            audiences: validation.aud.as_ref().map(sorted),
+            audience_threshold: self.audience_threshold,
+            validate_aud: validation.validate_aud,
+            validate_exp: validation.validate_exp,
+            exp_policy: self.exp_policy,
+            validate_nbf: validation.validate_nbf,
+            reject_future_iat: self.reject_future_iat,
+            leeway: validation.leeway,
+            required_claims: required_claims.into_iter().collect(),
+            accepted_typ: self.accepted_typ.clone(),
+            client_id: self.cid.clone(),
+            client_id_only: self.client_id_only,
+            required_scopes: self.required_scopes.clone(),
+            rules: self.rules.clone(),
+            audience_policies: self.audience_policies.iter().cloned().collect(),
+            allowed_subjects: self.allowed_subjects.as_ref().map(sorted),
+            allowed_idps: self.allowed_idps.as_ref().map(sorted),
+            jti_denylist: self.denylist.is_some(),
+            strict: self.config.strict,
+        }
+    }
+
+    /// `strict` is a preset that treats questionable settings as errors,
+    /// see [`Config::strict`](crate::Config::strict), validates the exp,
+    /// nbf, and aud claims, and requires an iat claim.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{Verifier, DefaultClaims};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     Verifier::new(&issuer)
+    ///         .await?
+    ///         .strict()
+    ///         .build()?
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn strict(mut self) -> Self {
+        self.config.strict = true;
+        self.validate_aud = true;
+        self.validate_exp = true;
+        self.validate_nbf = true;
+        self.settings_changed();
+        self.require_claims(&["iat"])
+    }
+
+    /// `rfc9068` is a preset for the JWT access token profile of RFC 9068,
+    /// it requires an `at+jwt` typ header along with the iss, exp, aud,
+    /// sub, cid, iat, and jti claims. Okta names the client_id claim of
+    /// the profile cid, and only issues such tokens from custom
+    /// authorization servers configured for it.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{Verifier, DefaultClaims};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     Verifier::new(&issuer)
+    ///         .await?
+    ///         .rfc9068()
+    ///         .add_audience("api://default")
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn rfc9068(mut self) -> Self {
+        self.validate_aud = true;
+        self.settings_changed();
+        self.accepted_typ(&["at+jwt"]).require_claims(&RFC9068_CLAIMS)
+    }
+
+    // Adds claims every token must contain, tokens missing any of them are
+    // rejected with Error::MissingClaim
+    pub(crate) fn require_claims(mut self, claims: &[&str]) -> Self {
+        for claim in claims {
+            if !self.required_claims.iter().any(|c| c == claim) {
+                self.required_claims.push(claim.to_string());
+            }
+        }
+        self
+    }
+
+    // Rejects tokens whose typ header isn't one of the given media types,
+    // by default the header isn't checked
+    pub(crate) fn accepted_typ(mut self, typ: &[&str]) -> Self {
+        self.accepted_typ = Some(typ.iter().map(|t| t.to_string()).collect());
+        self
+    }
+
+    // Rejects tokens with a typ header outside the accepted ones
+    pub(crate) fn check_typ(&self, header: &TokenHeader) -> Result<()> {
+        let Some(accepted) = &self.accepted_typ else {
+            return Ok(());
+        };
+        let typ = header.typ.as_deref().map(media_type);
+        if !accepted.iter().any(|a| typ.as_deref() == Some(&media_type(a))) {
+            bail!(Error::InvalidToken {
+                reason: "Token type is not accepted".into()
+            })
+        }
+        Ok(())
+    }
+
+    // Rejects tokens missing any of the required claims
+    pub(crate) fn check_required_claims(&self, claims: &Value) -> Result<()> {
+        for claim in &self.required_claims {
+            if claims.get(claim).map_or(true, Value::is_null) {
+                bail!(Error::MissingClaim { claim: claim.clone() })
+            }
+        }
+        Ok(())
+    }
+}
+
+// Media types are case insensitive and may omit the application/
+// prefix in typ headers, see RFC 7515
+fn media_type(typ: &str) -> String {
+    let typ = typ.to_ascii_lowercase();
+    match typ.strip_prefix("application/") {
+        Some(subtype) => subtype.to_string(),
+        None => typ,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{Config, KeyState, KeyStore};
+
+    const ISSUER: &str = "https://your.domain/oauth2/default";
+
+    fn verifier(config: Config) -> Verifier {
+        let state = KeyState {
+            jwks: crate::Jwks::from_keys(vec![jwk()]),
+            fetch: None,
+            stale: false,
+        };
+        Verifier::with_store(ISSUER, config, KeyStore::new(state))
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn reports_the_defaults() {
+        let policy = verifier(Config::default()).effective_policy();
+        assert_eq!(
+            policy,
+            ValidationPolicy {
+                algorithms: strings(&["RS256"]),
+                issuers: strings(&[ISSUER, &format!("{ISSUER}/")]),
+                audiences: None,
+                audience_threshold: 1,
+                validate_aud: true,
+                validate_exp: true,
+                exp_policy: ExpPolicy::Require,
+                validate_nbf: false,
+                reject_future_iat: true,
+                leeway: 120,
+                required_claims: strings(&["exp"]),
+                accepted_typ: None,
+                client_id: None,
+                client_id_only: false,
+                required_scopes: None,
+                rules: Vec::new(),
+                audience_policies: BTreeMap::new(),
+                allowed_subjects: None,
+                allowed_idps: None,
+                jti_denylist: false,
+                strict: false,
+            }
+        );
+    }
+
+    #[test]
+    fn later_builder_calls_and_the_hook_take_precedence() {
+        let config = Config { strict: true, ..Config::default() };
+        let policy = verifier(config)
+            .strict()
+            .validate_nbf(false)
+            .leeway(30)
+            .add_audience("api://b")
+            .add_audience("api://a")
+            .client_id("client")
+            .with_validation_hook(|validation| {
+                validation.leeway = 5;
+            })
+            .effective_policy();
+        assert!(policy.strict);
+        assert!(!policy.validate_nbf);
+        assert_eq!(policy.leeway, 5);
+        assert_eq!(policy.audiences, Some(strings(&["api://a", "api://b"])));
+        assert_eq!(policy.required_claims, strings(&["exp", "iat"]));
+        assert_eq!(policy.client_id.as_deref(), Some("client"));
+    }
+
+    #[test]
+    fn rfc9068_requires_the_profile_claims_and_typ() {
+        let policy = verifier(Config::default())
+            .validate_aud(false)
+            .rfc9068()
+            .require_claims(&["uid", "jti"])
+            .effective_policy();
+        assert!(policy.validate_aud);
+        assert_eq!(policy.accepted_typ, Some(strings(&["at+jwt"])));
+        assert_eq!(
+            policy.required_claims,
+            strings(&["aud", "cid", "exp", "iat", "iss", "jti", "sub", "uid"])
+        );
+        let serialized = serde_json::to_value(&policy).unwrap();
+        assert_eq!(serialized["accepted_typ"][0], "at+jwt");
+    }
+
+    #[test]
+    fn rfc9068_accepts_okta_access_token_claims() {
+        let verifier = verifier(Config::default()).rfc9068();
+        let mut claims = serde_json::json!({
+            "iss": ISSUER,
+            "exp": 1_700_003_600,
+            "aud": "api://default",
+            "sub": "user@example.com",
+            "cid": "0oa1client",
+            "iat": 1_700_000_000,
+            "jti": "AT.abc",
+        });
+        assert!(verifier.check_required_claims(&claims).is_ok());
+        claims.as_object_mut().unwrap().remove("cid");
+        let err = verifier.check_required_claims(&claims).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::MissingClaim { claim: "cid".into() })
+        );
+    }
+
+    #[test]
+    fn checks_the_typ_header() {
+        let verifier = verifier(Config::default()).rfc9068();
+        let header = |typ: Option<&str>| TokenHeader {
+            alg: "RS256".to_string(),
+            kid: None,
+            typ: typ.map(str::to_string),
+            x5t: None,
+            x5t_s256: None,
+            ..TokenHeader::default()
+        };
+        assert!(verifier.check_typ(&header(Some("at+jwt"))).is_ok());
+        assert!(verifier
+            .check_typ(&header(Some("application/AT+JWT")))
+            .is_ok());
+        for typ in [Some("JWT"), None] {
+            let err = verifier.check_typ(&header(typ)).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::InvalidToken { .. })
+            ));
+        }
+    }
+
+    #[async_test]
+    async fn rejects_tokens_missing_required_claims() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        verifier
+            .clone()
+            .require_claims(&["sub", "iat"])
+            .verify::<crate::DefaultClaims>(&token(&server.url()))
+            .await?;
+        let err = verifier
+            .require_claims(&["uid"])
+            .verify::<crate::DefaultClaims>(&token(&server.url()))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::MissingClaim { claim: "uid".into() })
+        );
+        Ok(())
+    }
+}
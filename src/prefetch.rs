@@ -0,0 +1,169 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::{anyhow, Result};
+
+use crate::{runtime, Config, Verifier};
+
+// The number of issuers whose keys are retrieved at the same time
+const PREFETCH_CONCURRENCY: usize = 8;
+
+/// Describes the outcome of [`Verifier::prefetch`] for one issuer
+#[derive(Debug)]
+pub struct Prefetched {
+    /// The issuer as given.
+    pub issuer: String,
+    /// The Verifier of the issuer, or why it couldn't be constructed.
+    pub result: Result<Verifier>,
+}
+
+/// Describes the outcome of [`Verifier::prefetch`], with one entry per
+/// issuer in the order they were given
+#[derive(Debug)]
+pub struct PrefetchReport {
+    /// The outcome for each issuer.
+    pub issuers: Vec<Prefetched>,
+}
+
+impl PrefetchReport {
+    /// `is_complete` tells whether a Verifier was constructed for every
+    /// issuer.
+    pub fn is_complete(&self) -> bool {
+        self.issuers.iter().all(|prefetched| prefetched.result.is_ok())
+    }
+
+    /// `failures` lists the issuers whose Verifier couldn't be
+    /// constructed, along with the error.
+    pub fn failures(&self) -> impl Iterator<Item = (&str, &anyhow::Error)> {
+        self.issuers.iter().filter_map(|prefetched| {
+            let error = prefetched.result.as_ref().err()?;
+            Some((prefetched.issuer.as_str(), error))
+        })
+    }
+
+    /// `into_verifiers` returns the Verifier of every issuer in the order
+    /// they were given, failing with the error of the first issuer whose
+    /// Verifier couldn't be constructed.
+    pub fn into_verifiers(self) -> Result<Vec<Verifier>> {
+        self.issuers
+            .into_iter()
+            .map(|prefetched| {
+                let issuer = prefetched.issuer;
+                prefetched.result.map_err(|e| {
+                    e.context(format!(
+                        "Prefetching the keys of {issuer} failed!"
+                    ))
+                })
+            })
+            .collect()
+    }
+}
+
+impl Verifier {
+    /// `prefetch` constructs a Verifier for each of the given issuers with
+    /// the same config, retrieving their keys concurrently, e.g. to warm
+    /// them up before reporting ready. At most 8 issuers are retrieved at
+    /// the same time. A failure only affects its own issuer and is
+    /// reported in the [`PrefetchReport`], the caller decides whether it
+    /// should block readiness. A Verifier that fell back to the
+    /// `fallback_keys` is reported as constructed, see
+    /// [`Stats::stale`](crate::Stats::stale). With the `client-reqwest`
+    /// feature this has to be called within a tokio runtime.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{Config, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuers = [
+    ///         "https://your.domain/oauth2/default",
+    ///         "https://your.domain/oauth2/partners",
+    ///     ];
+    ///     let report = Verifier::prefetch(&issuers, &Config::default()).await;
+    ///     for (issuer, error) in report.failures() {
+    ///         eprintln!("{issuer} isn't ready: {error:#}");
+    ///     }
+    ///     let verifiers = report.into_verifiers()?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub async fn prefetch(issuers: &[&str], config: &Config) -> PrefetchReport {
+        let results: Arc<Mutex<Vec<Option<Result<Verifier>>>>> =
+            Arc::new(Mutex::new(issuers.iter().map(|_| None).collect()));
+        // Each retrieval holds a permit until it finished, so once all of
+        // them have been acquired again every retrieval is done
+        let permits =
+            Arc::new(async_lock::Semaphore::new(PREFETCH_CONCURRENCY));
+        for (index, issuer) in issuers.iter().enumerate() {
+            let permit = permits.acquire_arc().await;
+            let (issuer, config) = (issuer.to_string(), config.clone());
+            let results = results.clone();
+            runtime::spawn(async move {
+                let verifier = Verifier::new_with_config(&issuer, config).await;
+                results.lock().unwrap_or_else(PoisonError::into_inner)[index] =
+                    Some(verifier);
+                drop(permit);
+            });
+        }
+        let mut finished = Vec::with_capacity(PREFETCH_CONCURRENCY);
+        for _ in 0..PREFETCH_CONCURRENCY {
+            finished.push(permits.acquire().await);
+        }
+        let mut results =
+            results.lock().unwrap_or_else(PoisonError::into_inner);
+        let issuers = issuers
+            .iter()
+            .zip(results.drain(..))
+            .map(|(issuer, result)| Prefetched {
+                issuer: issuer.to_string(),
+                result: result.unwrap_or_else(|| {
+                    Err(anyhow!("Prefetching the keys of {issuer} panicked!"))
+                }),
+            })
+            .collect();
+        PrefetchReport { issuers }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{DefaultClaims, Error, ORG_ENDPOINT};
+
+    #[async_test]
+    async fn reports_each_issuer_on_its_own() -> Result<()> {
+        let mut up = mockito::Server::new_async().await;
+        let keys = up
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(10)
+            .create();
+        let mut down = mockito::Server::new_async().await;
+        down.mock("GET", ORG_ENDPOINT).with_status(404).create();
+        let (up, down) = (up.url(), down.url());
+
+        // More issuers than are retrieved at the same time
+        let mut issuers = vec![up.as_str(); 9];
+        issuers.insert(4, &down);
+        issuers.push(&up);
+        let report = Verifier::prefetch(&issuers, &Config::default()).await;
+        keys.assert();
+        assert!(!report.is_complete());
+        assert_eq!(report.issuers.len(), 11);
+        let failures: Vec<_> = report.failures().collect();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, down);
+        assert!(matches!(
+            failures[0].1.downcast_ref::<Error>(),
+            Some(Error::KeysStatus { status: 404, .. })
+        ));
+        let verifier = report.issuers[10].result.as_ref().unwrap();
+        verifier.verify::<DefaultClaims>(&token(&up)).await?;
+
+        let err = report.into_verifiers().unwrap_err();
+        assert!(err.to_string().contains(&down));
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+// Limits how many retrievals of the keys run at the same time across the
+// issuers sharing a FetchQueue, see Config::max_concurrent_fetches.
+// Retrievals of the same issuer are already serialized by its refresh lock,
+// so only one of them per issuer ever waits here. A slot is held for a
+// single request, not while backing off or discovering the keys url.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{bail, Result};
+
+use crate::{logging, runtime, Config, Error};
+
+/// Queues the retrievals of the keys beyond
+/// [`Config::max_concurrent_fetches`](crate::Config::max_concurrent_fetches),
+/// e.g. when the keys of many issuers are retrieved at once.
+///
+/// A queue is shared by every clone of the [`Config`] holding it, and so
+/// by every Verifier constructed with them, such as the Verifiers of a
+/// [`DynamicVerifier`](crate::DynamicVerifier). Configs constructed
+/// separately only share a queue when it's passed to each of them.
+#[derive(Debug, Clone, Default)]
+pub struct FetchQueue {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // Created by the first retrieval, with the limit of its Config
+    slots: OnceLock<(usize, async_lock::Semaphore)>,
+    queued: AtomicUsize,
+    // Whether a Config with another limit was reported
+    mismatched: AtomicBool,
+}
+
+impl FetchQueue {
+    /// `new` constructs an instance of FetchQueue that isn't shared with
+    /// any Config yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `queued` returns the number of retrievals waiting for another one
+    /// to finish.
+    pub fn queued(&self) -> usize {
+        self.inner.queued.load(Ordering::Relaxed)
+    }
+
+    // Waits for a slot among the concurrent requests, which is held until
+    // the guard is dropped. Fails right away when the queue is full, and
+    // once the fetch_queue_timeout elapses.
+    pub(crate) async fn enter(
+        &self,
+        config: &Config,
+        url: &str,
+    ) -> Result<async_lock::SemaphoreGuard<'_>> {
+        let limit = config.max_concurrent_fetches.max(1);
+        let (first, slots) = self
+            .inner
+            .slots
+            .get_or_init(|| (limit, async_lock::Semaphore::new(limit)));
+        if *first != limit
+            && !self.inner.mismatched.swap(true, Ordering::Relaxed)
+        {
+            logging::warn!(
+                "Ignoring max_concurrent_fetches {limit} of a Config sharing \
+                 a fetch_queue limited to {first}"
+            );
+        }
+        if let Some(guard) = slots.try_acquire() {
+            return Ok(guard);
+        }
+        let Some(queued) =
+            Queued::new(&self.inner.queued, config.fetch_queue_capacity)
+        else {
+            bail!(Error::FetchQueueFull {
+                url: url.to_string(),
+                capacity: config.fetch_queue_capacity.unwrap_or_default(),
+            })
+        };
+        let guard = match config.fetch_queue_timeout {
+            Some(wait) => runtime::timeout(wait, slots.acquire()).await,
+            None => Some(slots.acquire().await),
+        };
+        drop(queued);
+        match guard {
+            Some(guard) => Ok(guard),
+            None => bail!(Error::FetchQueueTimedOut {
+                url: url.to_string(),
+                timeout: config.fetch_queue_timeout.unwrap_or_default(),
+            }),
+        }
+    }
+}
+
+// Whether the request was turned away by the queue rather than failing
+// at the keys url, which isn't down to the issuer
+pub(crate) fn turned_away(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref(),
+        Some(Error::FetchQueueTimedOut { .. } | Error::FetchQueueFull { .. })
+    )
+}
+
+// Counts a retrieval as queued until dropped, including when the caller
+// gives up on it
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Queued<'a> {
+    // None when as many retrievals as the capacity are already queued
+    fn new(queued: &'a AtomicUsize, capacity: Option<usize>) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                match capacity {
+                    Some(capacity) if queued >= capacity => None,
+                    _ => Some(queued + 1),
+                }
+            })
+            .ok()?;
+        Some(Self(queued))
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use crate::test_support::*;
+    use crate::{
+        CircuitBreaker, DefaultClaims, DynamicVerifier, FetchRetry, Verifier,
+        ORG_ENDPOINT,
+    };
+
+    // Keys endpoints answering slowly, recording how many requests they
+    // were handling at the same time at most
+    async fn slow_issuers(
+        count: usize,
+        active: &Arc<AtomicUsize>,
+        peak: &Arc<AtomicUsize>,
+    ) -> Vec<mockito::ServerGuard> {
+        let mut servers = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut server = mockito::Server::new_async().await;
+            let (active, peak) = (active.clone(), peak.clone());
+            server
+                .mock("GET", ORG_ENDPOINT)
+                .with_status(200)
+                .with_body_from_request(move |_| {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(200));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    keys_body(vec![jwk()]).into()
+                })
+                .expect(1)
+                .create();
+            servers.push(server);
+        }
+        servers
+    }
+
+    #[async_test]
+    async fn limits_the_retrievals_across_issuers() -> Result<()> {
+        let (active, peak) = (Arc::default(), Arc::default());
+        let servers = slow_issuers(8, &active, &peak).await;
+        let config = Config { max_concurrent_fetches: 2, ..Config::default() };
+        let queue = config.fetch_queue.clone();
+        let verifier = DynamicVerifier::new(|_| async { Ok(true) }).factory(
+            move |issuer: String| {
+                let config = config.clone();
+                async move { Verifier::new_with_config(&issuer, config).await }
+            },
+        );
+        let tokens: Vec<_> = servers.iter().map(|s| token(&s.url())).collect();
+        let started = Instant::now();
+        let verifies =
+            tokens.iter().map(|t| verifier.verify::<DefaultClaims>(t));
+        let (results, queued) =
+            futures::join!(futures::future::join_all(verifies), async {
+                runtime::sleep(Duration::from_millis(100)).await;
+                queue.queued()
+            });
+        for result in results {
+            result?;
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        // Four rounds of two retrievals
+        assert!(started.elapsed() >= Duration::from_millis(800));
+        assert_eq!(queued, 6);
+        assert_eq!(queue.queued(), 0);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn fails_retrievals_waiting_too_long() -> Result<()> {
+        let (active, peak) = (Arc::default(), Arc::default());
+        let servers = slow_issuers(2, &active, &peak).await;
+        let config = Config {
+            max_concurrent_fetches: 1,
+            fetch_queue_timeout: Some(Duration::from_millis(50)),
+            fetch_retry: FetchRetry::disabled(),
+            circuit_breaker: Some(CircuitBreaker {
+                failure_threshold: 1,
+                open_for: Duration::from_secs(60),
+            }),
+            ..Config::default()
+        };
+        let first =
+            Verifier::lazy_with_config(&servers[0].url(), config.clone())?;
+        let second =
+            Verifier::lazy_with_config(&servers[1].url(), config.clone())?;
+        let results =
+            futures::join!(first.refresh_keys(), second.refresh_keys());
+        // Whichever came second gave up on the queue
+        let (e, waited) = match results {
+            (Ok(_), Err(e)) => (e, second),
+            (Err(e), Ok(_)) => (e, first),
+            _ => panic!("expected a single retrieval to give up"),
+        };
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(Error::FetchQueueTimedOut { timeout, .. })
+                if *timeout == Duration::from_millis(50)
+        ));
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+        assert_eq!(config.fetch_queue.queued(), 0);
+        // Neither the circuit breaker nor the keys url saw the first try
+        waited.refresh_keys().await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn turns_away_retrievals_beyond_the_capacity() -> Result<()> {
+        let (active, peak) = (Arc::default(), Arc::default());
+        let servers = slow_issuers(3, &active, &peak).await;
+        let config = Config {
+            max_concurrent_fetches: 1,
+            fetch_queue_capacity: Some(1),
+            ..Config::default()
+        };
+        let verifiers = servers
+            .iter()
+            .map(|s| Verifier::lazy_with_config(&s.url(), config.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let refreshes = verifiers.iter().map(Verifier::refresh_keys);
+        let results = futures::future::join_all(refreshes).await;
+        let full: Vec<_> =
+            results.iter().filter_map(|result| result.as_ref().err()).collect();
+        assert_eq!(full.len(), 1);
+        assert!(matches!(
+            full[0].downcast_ref(),
+            Some(Error::FetchQueueFull { capacity: 1, .. })
+        ));
+        assert_eq!(config.fetch_queue.queued(), 0);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn releases_the_slot_while_backing_off() -> Result<()> {
+        let mut down = mockito::Server::new_async().await;
+        let m =
+            down.mock("GET", ORG_ENDPOINT).with_status(503).expect(2).create();
+        let (active, peak) = (Arc::default(), Arc::default());
+        let servers = slow_issuers(1, &active, &peak).await;
+        let config = Config {
+            max_concurrent_fetches: 1,
+            fetch_retry: FetchRetry {
+                initial_delay: Duration::from_secs(1),
+                max_attempts: 2,
+                ..FetchRetry::default()
+            },
+            ..Config::default()
+        };
+        let retrying = Verifier::lazy_with_config(&down.url(), config.clone())?;
+        let waiting =
+            Verifier::lazy_with_config(&servers[0].url(), config.clone())?;
+        let started = Instant::now();
+        let (retried, waited) =
+            futures::join!(retrying.refresh_keys(), async {
+                waiting.refresh_keys().await?;
+                Ok::<_, anyhow::Error>(started.elapsed())
+            });
+        assert!(retried.is_err());
+        // Served between the attempts of the other issuer
+        assert!(waited? < Duration::from_secs(1));
+        m.assert();
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::ClaimFilter;
+
+// Claims that identify the token rather than the user
+const DEFAULT_ALLOWED: [&str; 6] = ["iss", "aud", "cid", "kid", "exp", "iat"];
+
+const PLACEHOLDER: &str = "<redacted>";
+
+/// Describes how claim values outside the allowlist are replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Redaction {
+    /// Replaced by `<redacted>`.
+    #[default]
+    Placeholder,
+    /// Replaced by a truncated SHA-256 of the value, which allows
+    /// correlating occurrences without revealing the value.
+    Hash,
+}
+
+/// Decides which claim values may appear verbatim in anything the crate
+/// logs or embeds in errors, such as verbose errors, and in the
+/// [`AuditClaims`](crate::AuditClaims).
+///
+/// By default everything except iss, aud, cid, kid, exp, and iat is
+/// replaced by `<redacted>`. The claims shown verbatim are selected by a
+/// [`ClaimFilter`], so patterns such as `app_*` or `/org/id` are accepted
+/// too, and denied claims are redacted even where a pattern allows them.
+///
+/// ```
+/// use okta_jwt_verifier::{Config, Redaction, RedactionPolicy};
+///
+/// let config = Config {
+///     redaction: RedactionPolicy::default()
+///         .allow("sub")
+///         .redaction(Redaction::Hash),
+///     ..Config::default()
+/// };
+/// assert_eq!(config.redaction.redact("sub", "jane"), "jane");
+/// assert_ne!(config.redaction.redact("email", "jane@example.com"), "jane@example.com");
+///```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedactionPolicy {
+    allowed: ClaimFilter,
+    redaction: Redaction,
+}
+
+impl Default for RedactionPolicy {
+    fn default() -> Self {
+        Self::new(&DEFAULT_ALLOWED)
+    }
+}
+
+impl RedactionPolicy {
+    /// `new` constructs an instance of RedactionPolicy that only allows
+    /// the claims matching the given patterns to appear verbatim.
+    pub fn new(allowed: &[&str]) -> Self {
+        Self::with_filter(ClaimFilter::new(allowed))
+    }
+
+    /// `with_filter` constructs an instance of RedactionPolicy that only
+    /// allows the claims selected by the filter to appear verbatim.
+    pub fn with_filter(allowed: ClaimFilter) -> Self {
+        Self { allowed, redaction: Redaction::default() }
+    }
+
+    /// `allow` adds a pattern of claims that may appear verbatim.
+    pub fn allow(mut self, pattern: &str) -> Self {
+        self.allowed = self.allowed.allow(pattern);
+        self
+    }
+
+    /// `deny` adds a pattern of claims that are always redacted.
+    pub fn deny(mut self, pattern: &str) -> Self {
+        self.allowed = self.allowed.deny(pattern);
+        self
+    }
+
+    /// `redaction` is for overriding how values are replaced,
+    /// by default with `<redacted>`.
+    pub fn redaction(mut self, redaction: Redaction) -> Self {
+        self.redaction = redaction;
+        self
+    }
+
+    /// `is_allowed` reports whether the claim may appear verbatim.
+    pub fn is_allowed(&self, claim: &str) -> bool {
+        self.allowed.allows(claim)
+    }
+
+    /// `redact` returns the value of the claim as it may be shown.
+    pub fn redact(&self, claim: &str, value: &str) -> String {
+        if self.is_allowed(claim) {
+            return value.to_string();
+        }
+        self.replace(value)
+    }
+
+    /// `redact_claims` returns a copy of a claims object with the values
+    /// of every claim outside the allowlist replaced, e.g. for audit logs.
+    /// Nested claims named by a pattern are decided one by one.
+    pub fn redact_claims(&self, claims: &Value) -> Value {
+        let Value::Object(claims) = claims else {
+            return Value::String(PLACEHOLDER.to_string());
+        };
+        Value::Object(self.redact_object(claims, &mut Vec::new()))
+    }
+
+    /// `allowed_claims` returns the claims of a claims object that may
+    /// appear verbatim, leaving out the others rather than replacing them,
+    /// so that it holds no more than the allowlist selects.
+    pub fn allowed_claims(&self, claims: &Value) -> Value {
+        let Value::Object(claims) = claims else {
+            return Value::Object(Map::new());
+        };
+        Value::Object(self.allowed_object(claims, &mut Vec::new()))
+    }
+
+    fn allowed_object<'a>(
+        &self,
+        claims: &'a Map<String, Value>,
+        path: &mut Vec<&'a str>,
+    ) -> Map<String, Value> {
+        claims
+            .iter()
+            .filter_map(|(claim, value)| {
+                path.push(claim);
+                let value = match value {
+                    Value::Object(nested)
+                        if !self.allowed.denies_path(path)
+                            && self.allowed.reaches_below(path) =>
+                    {
+                        let nested = self.allowed_object(nested, path);
+                        (!nested.is_empty()).then_some(Value::Object(nested))
+                    }
+                    value if self.allowed.allows_path(path) => {
+                        Some(value.clone())
+                    }
+                    _ => None,
+                };
+                path.pop();
+                Some((claim.clone(), value?))
+            })
+            .collect()
+    }
+
+    fn redact_object<'a>(
+        &self,
+        claims: &'a Map<String, Value>,
+        path: &mut Vec<&'a str>,
+    ) -> Map<String, Value> {
+        claims
+            .iter()
+            .map(|(claim, value)| {
+                path.push(claim);
+                let value = match value {
+                    Value::Object(nested)
+                        if !self.allowed.denies_path(path)
+                            && self.allowed.reaches_below(path) =>
+                    {
+                        Value::Object(self.redact_object(nested, path))
+                    }
+                    value if self.allowed.allows_path(path) => value.clone(),
+                    value => Value::String(self.replace(&value.to_string())),
+                };
+                path.pop();
+                (claim.clone(), value)
+            })
+            .collect()
+    }
+
+    fn replace(&self, value: &str) -> String {
+        match self.redaction {
+            Redaction::Placeholder => PLACEHOLDER.to_string(),
+            Redaction::Hash => hash(value),
+        }
+    }
+}
+
+// Short enough to read, long enough to tell values apart
+fn hash(value: &str) -> String {
+    let digest = Sha256::digest(value.as_bytes());
+    let hex: String =
+        digest.iter().take(8).map(|byte| format!("{byte:02x}")).collect();
+    format!("sha256:{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn redacts_everything_outside_the_allowlist() {
+        let policy = RedactionPolicy::default();
+        let claims = json!({
+            "iss": "https://your.domain/oauth2/default",
+            "exp": 1,
+            "email": "jane@example.com",
+            "sub": "jane",
+        });
+        let redacted = policy.redact_claims(&claims);
+        assert_eq!(
+            redacted,
+            json!({
+                "iss": "https://your.domain/oauth2/default",
+                "exp": 1,
+                "email": "<redacted>",
+                "sub": "<redacted>",
+            })
+        );
+        let output = format!("{redacted} {redacted:?}");
+        assert!(!output.contains("jane"));
+    }
+
+    #[test]
+    fn keeps_only_the_allowed_claims() {
+        let policy = RedactionPolicy::default().allow("/org/id");
+        let groups: Vec<String> =
+            (0..1000).map(|i| format!("group-{i}")).collect();
+        let claims = json!({
+            "iss": "https://your.domain/oauth2/default",
+            "exp": 1,
+            "sub": "jane",
+            "groups": groups,
+            "org": {"id": 7, "name": "Acme"},
+            "profile": {"name": "Jane"},
+        });
+        assert_eq!(
+            policy.allowed_claims(&claims),
+            json!({
+                "iss": "https://your.domain/oauth2/default",
+                "exp": 1,
+                "org": {"id": 7},
+            })
+        );
+        assert_eq!(policy.allowed_claims(&json!("jane")), json!({}));
+    }
+
+    #[test]
+    fn hashes_consistently() {
+        let policy = RedactionPolicy::new(&[]).redaction(Redaction::Hash);
+        let hashed = policy.redact("email", "jane@example.com");
+        assert!(hashed.starts_with("sha256:"));
+        assert_eq!(hashed.len(), "sha256:".len() + 16);
+        assert_eq!(hashed, policy.redact("sub", "jane@example.com"));
+        assert_ne!(hashed, policy.redact("email", "john@example.com"));
+    }
+
+    #[test]
+    fn redacts_by_pattern_with_deny_first() {
+        let policy = RedactionPolicy::new(&["app_*", "/org/id", "profile"])
+            .deny("app_secret")
+            .deny("/profile/ssn");
+        let claims = json!({
+            "app_role": "admin",
+            "app_secret": "s3cr3t",
+            "org": {"id": 7, "name": "Acme"},
+            "profile": {"name": "Jane", "ssn": "123-45-6789"},
+            "email": "jane@example.com",
+        });
+        assert_eq!(
+            policy.redact_claims(&claims),
+            json!({
+                "app_role": "admin",
+                "app_secret": "<redacted>",
+                "org": {"id": 7, "name": "<redacted>"},
+                "profile": {"name": "Jane", "ssn": "<redacted>"},
+                "email": "<redacted>",
+            })
+        );
+        assert_eq!(policy.redact("app_role", "admin"), "admin");
+        assert_eq!(policy.redact("app_secret", "s3cr3t"), "<redacted>");
+        assert_eq!(
+            RedactionPolicy::with_filter(ClaimFilter::default()),
+            RedactionPolicy::new(&["*"])
+        );
+    }
+}
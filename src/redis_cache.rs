@@ -0,0 +1,423 @@
+// Shares the retrieved keys between the replicas of a deployment through
+// Redis, see Config::redis_url. Redis is only ever a shortcut, whenever it
+// can't be used the keys are retrieved directly.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{bail, Result};
+use redis::aio::MultiplexedConnection;
+use redis::FromRedisValue;
+
+use crate::keystore::KeyState;
+use crate::{
+    keys_url, logging, runtime, snapshot, Config, FetchMetadata, Jwks,
+};
+
+// How long Redis gets to answer before the keys are retrieved directly
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// How long Redis is left alone after a failure
+const BACKOFF: Duration = Duration::from_secs(30);
+
+// The connection to each configured Redis, shared by every Verifier, or
+// when it last failed. There are as many entries as distinct redis_url
+// values, which come from the configuration.
+static CONNECTIONS: Mutex<BTreeMap<String, Connection>> =
+    Mutex::new(BTreeMap::new());
+
+#[derive(Clone)]
+enum Connection {
+    Open(MultiplexedConnection),
+    Failed(Instant),
+}
+
+// The key the keys of an issuer are stored under, telling apart the keys
+// urls, and with them the keys endpoints and client ids, of an issuer
+pub(crate) fn key(issuer: &str, config: &Config) -> Result<String> {
+    Ok(format!("okta-jwt-verifier:jwks:{issuer}:{}", keys_url(issuer, config)?))
+}
+
+// The keys stored for the issuer, unless they are the ones already held,
+// in which case the caller is after newer keys, e.g. for an unknown kid.
+// Redis expires the keys along with their max-age, so any stored keys are
+// fresh.
+pub(crate) async fn load(
+    issuer: &str,
+    config: &Config,
+    current: Option<&KeyState>,
+) -> Option<(Jwks, FetchMetadata)> {
+    let url = config.redis_url.as_deref()?;
+    let loaded = async {
+        let key = key(issuer, config)?;
+        let body: Option<Vec<u8>> =
+            query(url, redis::cmd("GET").arg(&key).clone()).await?;
+        Ok::<_, anyhow::Error>(body.map(|body| (key, body)))
+    };
+    let (key, body) = match loaded.await {
+        Ok(loaded) => loaded?,
+        Err(e) => {
+            logging::warn!("Unable to read the keys from Redis: {e:#}");
+            return None;
+        }
+    };
+    let source = format!("redis:{key}");
+    match snapshot::decode(&source, &body, issuer, config) {
+        Ok((jwks, _)) if current.is_some_and(|c| c.jwks == jwks) => None,
+        Ok(found) => Some(found),
+        Err(e) => {
+            logging::warn!("Skipping the keys stored in Redis: {e:#}");
+            None
+        }
+    }
+}
+
+// Stores the retrieved keys for as long as their max-age allows, keys
+// that may not be cached aren't stored. Failing to store them doesn't
+// affect the retrieval.
+pub(crate) async fn store(
+    issuer: &str,
+    config: &Config,
+    jwks: &Jwks,
+    fetch: &FetchMetadata,
+) {
+    let Some(url) = config.redis_url.as_deref() else {
+        return;
+    };
+    let Some(ttl) = time_to_live(fetch) else {
+        return;
+    };
+    let stored = async {
+        let key = key(issuer, config)?;
+        let body = snapshot::encode(issuer, jwks, fetch)?;
+        let set =
+            redis::cmd("SET").arg(key).arg(body).arg("EX").arg(ttl).clone();
+        query::<()>(url, set).await
+    };
+    if let Err(e) = stored.await {
+        logging::warn!("Unable to store the keys in Redis: {e:#}");
+    }
+}
+
+// Deletes the keys stored for the issuer, if configured, so that no
+// replica takes them from Redis anymore
+pub(crate) async fn clear(issuer: &str, config: &Config) -> Result<()> {
+    let Some(url) = config.redis_url.as_deref() else {
+        return Ok(());
+    };
+    let key = key(issuer, config)?;
+    query::<()>(url, redis::cmd("DEL").arg(key).clone()).await
+}
+
+// What's left of the max-age of the keys, in whole seconds since that's
+// what Redis expires keys by
+fn time_to_live(fetch: &FetchMetadata) -> Option<u64> {
+    let age =
+        SystemTime::now().duration_since(fetch.fetched_at).unwrap_or_default();
+    let ttl = fetch.max_age?.checked_sub(age)?.as_secs();
+    (ttl > 0).then_some(ttl)
+}
+
+// Runs the command on the shared connection, giving up once the timeout
+// elapses. A failure drops the connection, and Redis isn't tried again
+// until the backoff elapsed.
+async fn query<T: FromRedisValue>(url: &str, cmd: redis::Cmd) -> Result<T> {
+    let connection = match connection(url) {
+        Some(Connection::Failed(at)) if at.elapsed() < BACKOFF => {
+            bail!("Redis failed {}s ago", at.elapsed().as_secs())
+        }
+        Some(Connection::Open(connection)) => Some(connection),
+        _ => None,
+    };
+    let run = async {
+        let mut connection = match connection {
+            Some(connection) => connection,
+            None => {
+                let client = redis::Client::open(url)?;
+                let connection =
+                    client.get_multiplexed_async_connection().await?;
+                set_connection(url, Connection::Open(connection.clone()));
+                connection
+            }
+        };
+        Ok(cmd.query_async(&mut connection).await?)
+    };
+    let result = runtime::timeout(TIMEOUT, run).await.unwrap_or_else(|| {
+        bail!("Redis didn't answer within {}s", TIMEOUT.as_secs())
+    });
+    if result.is_err() {
+        set_connection(url, Connection::Failed(Instant::now()));
+    }
+    result
+}
+
+fn connection(url: &str) -> Option<Connection> {
+    let connections =
+        CONNECTIONS.lock().unwrap_or_else(PoisonError::into_inner);
+    connections.get(url).cloned()
+}
+
+fn set_connection(url: &str, connection: Connection) {
+    CONNECTIONS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(url.to_string(), connection);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use crate::test_support::*;
+    use crate::{DefaultClaims, Verifier, ORG_ENDPOINT};
+
+    // The values stored by the fake Redis, along with the expiry they
+    // were stored with
+    type Store = Arc<Mutex<HashMap<Vec<u8>, (Vec<u8>, Option<u64>)>>>;
+
+    // Speaks just enough RESP to answer GET, SET and DEL, every other command
+    // is acknowledged, counting the connections it accepted
+    fn fake_redis() -> (String, Store, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let store = Store::default();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let (shared, counted) = (store.clone(), accepted.clone());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                counted.fetch_add(1, Ordering::SeqCst);
+                let store = shared.clone();
+                std::thread::spawn(move || serve(stream, store));
+            }
+        });
+        (url, store, accepted)
+    }
+
+    fn serve(stream: TcpStream, store: Store) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        while let Some(command) = read_command(&mut reader) {
+            let name = String::from_utf8_lossy(&command[0]).to_uppercase();
+            let reply = match (name.as_str(), &command[1..]) {
+                ("GET", [key]) => match store.lock().unwrap().get(key) {
+                    Some((value, _)) => {
+                        let mut reply =
+                            format!("${}\r\n", value.len()).into_bytes();
+                        reply.extend_from_slice(value);
+                        reply.extend_from_slice(b"\r\n");
+                        reply
+                    }
+                    None => b"$-1\r\n".to_vec(),
+                },
+                ("SET", [key, value, ex, seconds])
+                    if ex.eq_ignore_ascii_case(b"EX") =>
+                {
+                    let seconds = std::str::from_utf8(seconds)
+                        .ok()
+                        .and_then(|s| s.parse().ok());
+                    store
+                        .lock()
+                        .unwrap()
+                        .insert(key.clone(), (value.clone(), seconds));
+                    b"+OK\r\n".to_vec()
+                }
+                ("DEL", [key]) => {
+                    let removed = store.lock().unwrap().remove(key);
+                    format!(":{}\r\n", u8::from(removed.is_some())).into_bytes()
+                }
+                _ => b"+OK\r\n".to_vec(),
+            };
+            if writer.write_all(&reply).is_err() {
+                return;
+            }
+        }
+    }
+
+    fn read_line(reader: &mut impl BufRead) -> Option<String> {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end().to_string()),
+        }
+    }
+
+    fn read_command(reader: &mut impl BufRead) -> Option<Vec<Vec<u8>>> {
+        let count: usize =
+            read_line(reader)?.strip_prefix('*')?.parse().ok()?;
+        let mut command = Vec::with_capacity(count);
+        for _ in 0..count {
+            let len: usize =
+                read_line(reader)?.strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).ok()?;
+            arg.truncate(len);
+            command.push(arg);
+        }
+        Some(command)
+    }
+
+    #[async_test]
+    async fn shares_the_keys_between_verifiers() -> Result<()> {
+        let (url, store, accepted) = fake_redis();
+        let config =
+            Config { redis_url: Some(url.clone()), ..Config::default() };
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_header("cache-control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let issuer = server.url();
+        Verifier::new_with_config(&issuer, config.clone()).await?;
+        let stored = store
+            .lock()
+            .unwrap()
+            .get(key(&issuer, &config)?.as_bytes())
+            .cloned();
+        let (_, expiry) = stored.expect("keys stored in Redis");
+        assert!(matches!(expiry, Some(299 | 300)), "{expiry:?}");
+
+        // Another replica takes the keys from Redis, over the connection
+        // already open
+        let verifier = Verifier::new_with_config(&issuer, config).await?;
+        keys.assert();
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        verifier.verify::<DefaultClaims>(&token(&issuer)).await?;
+        let fetch = verifier.fetch_metadata().expect("fetch metadata");
+        assert_eq!(fetch.max_age, Some(Duration::from_secs(300)));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn clears_the_keys_along_with_the_cache() -> Result<()> {
+        let (url, store, _) = fake_redis();
+        let config = Config { redis_url: Some(url), ..Config::default() };
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_header("cache-control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .create();
+        let issuer = server.url();
+        let verifier =
+            Verifier::new_with_config(&issuer, config.clone()).await?;
+        let key = key(&issuer, &config)?;
+        assert!(store.lock().unwrap().contains_key(key.as_bytes()));
+
+        verifier.clear_cache().await?;
+        assert!(!store.lock().unwrap().contains_key(key.as_bytes()));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn retrieves_the_keys_directly_without_redis() -> Result<()> {
+        // Nothing listens on port 1
+        let config = Config {
+            redis_url: Some("redis://127.0.0.1:1".into()),
+            ..Config::default()
+        };
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_header("cache-control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(2)
+            .create();
+        let issuer = server.url();
+        for _ in 0..2 {
+            Verifier::new_with_config(&issuer, config.clone())
+                .await?
+                .verify::<DefaultClaims>(&token(&issuer))
+                .await?;
+        }
+        keys.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn stores_only_keys_that_may_be_cached() -> Result<()> {
+        let (url, store, _) = fake_redis();
+        let config = Config { redis_url: Some(url), ..Config::default() };
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_header("cache-control", "no-store")
+            .with_body(keys_body(vec![jwk()]))
+            .create();
+        Verifier::new_with_config(&server.url(), config).await?;
+        assert!(store.lock().unwrap().is_empty());
+        Ok(())
+    }
+    #[async_test]
+    async fn backs_off_after_a_failure() -> Result<()> {
+        // Accepts connections and closes them right away
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("redis://{}", listener.local_addr()?);
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counted = accepted.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                counted.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+        let config = Config { redis_url: Some(url), ..Config::default() };
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_header("cache-control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(2)
+            .create();
+        for _ in 0..2 {
+            Verifier::new_with_config(&server.url(), config.clone()).await?;
+        }
+        keys.assert();
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn keeps_the_keys_of_each_keys_url_apart() -> Result<()> {
+        let (url, store, _) = fake_redis();
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .match_query(mockito::Matcher::Any)
+            .with_status(200)
+            .with_header("cache-control", "max-age=300")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(2)
+            .create();
+        let config = |client_id: &str| Config {
+            redis_url: Some(url.clone()),
+            keys_client_id: Some(client_id.into()),
+            ..Config::default()
+        };
+        let issuer = server.url();
+        for client_id in ["first", "second"] {
+            Verifier::new_with_config(&issuer, config(client_id)).await?;
+        }
+        keys.assert();
+        let store = store.lock().unwrap();
+        for client_id in ["first", "second"] {
+            let key = key(&issuer, &config(client_id))?;
+            assert!(key.ends_with(&format!("?client_id={client_id}")));
+            assert!(store.contains_key(key.as_bytes()));
+        }
+        Ok(())
+    }
+}
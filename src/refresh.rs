@@ -0,0 +1,620 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::fetch::{
+    key_set_of, queued_fetch, read_keys_file, retain, Validators, CACHING,
+};
+use crate::keystore::KeyState;
+use crate::{
+    persist, Config, FetchMetadata, Jwks, Verifier, DEFAULT_KID_MISS_COOLDOWN,
+};
+
+/// Describes how [`Verifier::refresh_keys`] changed the keys, e.g. for
+/// logging rotations. A key whose material changed while its kid stayed
+/// the same counts as both removed and added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct KeyRefresh {
+    /// The number of keys that weren't held before.
+    pub added: usize,
+    /// The number of keys that are no longer held.
+    pub removed: usize,
+    /// The number of keys held after the refresh.
+    pub total: usize,
+}
+
+impl KeyRefresh {
+    fn new(before: &Jwks, after: &Jwks) -> Self {
+        let diff = before.diff(after);
+        Self {
+            added: diff.added.len() + diff.changed.len(),
+            removed: diff.removed.len() + diff.changed.len(),
+            total: after.keys.len(),
+        }
+    }
+
+    /// Whether any key was added or removed.
+    pub fn changed(&self) -> bool {
+        self.added > 0 || self.removed > 0
+    }
+}
+
+/// What asks for the keys to be retrieved again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RefreshTrigger {
+    /// A token named a kid that isn't among the current keys.
+    KidMiss,
+    /// The interval of [`Config::background_refresh`] elapsed, or a failed
+    /// background retrieval is retried.
+    Periodic,
+    /// [`Verifier::refresh_keys`] was called.
+    Manual,
+}
+
+/// Describes the keys and past retrievals when a [`RefreshPolicy`] is asked
+/// whether to retrieve the keys again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RefreshContext {
+    /// What asks for the refresh.
+    pub trigger: RefreshTrigger,
+    /// How long ago the current keys were retrieved, none when they weren't
+    /// retrieved, e.g. the fallback keys or those passed to
+    /// [`Verifier::with_keys`].
+    pub key_age: Option<Duration>,
+    /// The max-age of the Cache-Control header sent along with the
+    /// current keys.
+    pub max_age: Option<Duration>,
+    /// How long ago the last retrieval failed, none when it succeeded.
+    pub last_failure: Option<Duration>,
+    /// How long ago the keys were last retrieved for an unknown kid.
+    pub last_kid_miss_refresh: Option<Duration>,
+}
+
+impl RefreshContext {
+    /// `new` describes a refresh asked for by the trigger with nothing
+    /// known about the keys, e.g. for testing a policy.
+    pub fn new(trigger: RefreshTrigger) -> Self {
+        Self {
+            trigger,
+            key_age: None,
+            max_age: None,
+            last_failure: None,
+            last_kid_miss_refresh: None,
+        }
+    }
+}
+
+/// Whether the keys are retrieved again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RefreshDecision {
+    /// Retrieve the keys.
+    Refresh,
+    /// Keep the current keys, an unknown kid fails right away and
+    /// [`Verifier::refresh_keys`] reports no change.
+    Skip,
+}
+
+/// Decides when the keys are retrieved again, see
+/// [`Config::refresh_policy`]. Consulted before every retrieval except the
+/// first one, retrievals already in progress are shared regardless.
+pub trait RefreshPolicy: fmt::Debug + Send + Sync {
+    /// `should_refresh` decides whether the keys are retrieved for the
+    /// given context.
+    fn should_refresh(&self, context: RefreshContext) -> RefreshDecision;
+}
+
+/// Retrieves the keys whenever asked to, as recommended by Okta, except
+/// for unknown kids within a cooldown after the last retrieval for one,
+/// so that tokens with made up kids can't flood the keys endpoint. Used
+/// by default with [`Config::kid_miss_cooldown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OktaRecommended {
+    /// The time after retrieving the keys for an unknown kid during which
+    /// further unknown kids don't retrieve them again.
+    pub kid_miss_cooldown: Duration,
+}
+
+impl Default for OktaRecommended {
+    fn default() -> Self {
+        Self { kid_miss_cooldown: DEFAULT_KID_MISS_COOLDOWN }
+    }
+}
+
+impl RefreshPolicy for OktaRecommended {
+    fn should_refresh(&self, context: RefreshContext) -> RefreshDecision {
+        let cooling_down = context
+            .last_kid_miss_refresh
+            .is_some_and(|last| last < self.kid_miss_cooldown);
+        if context.trigger == RefreshTrigger::KidMiss && cooling_down {
+            return RefreshDecision::Skip;
+        }
+        RefreshDecision::Refresh
+    }
+}
+
+/// Only retrieves the keys once they are older than the max-age the keys
+/// endpoint sent along with them, or when they weren't retrieved or came
+/// without a max-age. [`Verifier::refresh_keys`] always retrieves them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MaxAgeStrict;
+
+impl RefreshPolicy for MaxAgeStrict {
+    fn should_refresh(&self, context: RefreshContext) -> RefreshDecision {
+        let fresh = match (context.key_age, context.max_age) {
+            (Some(age), Some(max_age)) => age < max_age,
+            _ => false,
+        };
+        if fresh && context.trigger != RefreshTrigger::Manual {
+            return RefreshDecision::Skip;
+        }
+        RefreshDecision::Refresh
+    }
+}
+
+/// Keeps the keys until [`Verifier::refresh_keys`] is called, e.g. for keys
+/// that never change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Never;
+
+impl RefreshPolicy for Never {
+    fn should_refresh(&self, context: RefreshContext) -> RefreshDecision {
+        match context.trigger {
+            RefreshTrigger::Manual => RefreshDecision::Refresh,
+            _ => RefreshDecision::Skip,
+        }
+    }
+}
+
+// Consults the configured policy, or the recommended one honoring the
+// older kid miss settings
+pub(crate) fn decide(
+    config: &Config,
+    context: RefreshContext,
+) -> RefreshDecision {
+    match &config.refresh_policy {
+        Some(policy) => policy.should_refresh(context),
+        None if context.trigger == RefreshTrigger::KidMiss
+            && !config.refetch_on_kid_miss =>
+        {
+            RefreshDecision::Skip
+        }
+        None => OktaRecommended { kid_miss_cooldown: config.kid_miss_cooldown }
+            .should_refresh(context),
+    }
+}
+
+impl Verifier {
+    /// `refresh_keys` retrieves the keys from the issuer again and replaces
+    /// the current keys on success, for this Verifier and all of its clones,
+    /// returning how many keys were added and removed. On failure the
+    /// current keys are kept and the error is returned. Concurrent calls
+    /// share a single request, callers that had to wait for another
+    /// refresh to finish use its keys instead of requesting them again,
+    /// see [`Config::wait_timeout`]. Nothing changes when the
+    /// [`Config::refresh_policy`] skips the refresh.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verifier = Verifier::new(&issuer).await?;
+    ///     let refresh = verifier.refresh_keys().await?;
+    ///     if refresh.changed() {
+    ///         println!("keys rotated: +{} -{}", refresh.added, refresh.removed);
+    ///     }
+    ///     Ok(())
+    /// }
+    ///```
+    pub async fn refresh_keys(&self) -> Result<KeyRefresh> {
+        let seen = self.keys.generation();
+        let before = self.keys.load();
+        if self.refresh_decision(RefreshTrigger::Manual)
+            == RefreshDecision::Refresh
+        {
+            self.refresh_since(seen).await?;
+        }
+        let after = self.keys.load();
+        Ok(KeyRefresh::new(&before.jwks, &after.jwks))
+    }
+
+    /// `keys_changed` asks the url that supplied the current keys whether
+    /// they changed, without replacing them. The request is a conditional
+    /// GET with the ETag and Last-Modified of the current keys rather than
+    /// a HEAD, which not every endpoint supports, so unchanged keys are
+    /// usually answered with a 304 and no body. Without a 304 the keys in
+    /// the response are compared with the current ones. Like a refresh it
+    /// fails while the [`Config::circuit_breaker`] is open and waits for a
+    /// refresh in progress, sharing its outcome.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verifier = Verifier::new(&issuer).await?;
+    ///     if verifier.keys_changed().await? {
+    ///         verifier.refresh_keys().await?;
+    ///     }
+    ///     Ok(())
+    /// }
+    ///```
+    pub async fn keys_changed(&self) -> Result<bool> {
+        self.probe_keys(false).await
+    }
+
+    /// `refresh_keys_if_changed` behaves like [`Verifier::refresh_keys`]
+    /// when [`Verifier::keys_changed`] finds the keys changed, storing the
+    /// keys of that response rather than retrieving them again, and
+    /// otherwise leaves the current keys in place, e.g. for refreshes on
+    /// a long schedule.
+    pub async fn refresh_keys_if_changed(&self) -> Result<KeyRefresh> {
+        let before = self.keys.load();
+        if self.refresh_decision(RefreshTrigger::Manual)
+            == RefreshDecision::Refresh
+        {
+            self.probe_keys(true).await?;
+        }
+        let after = self.keys.load();
+        Ok(KeyRefresh::new(&before.jwks, &after.jwks))
+    }
+
+    // Runs keys_changed through the key store, storing the changed keys
+    // when `replace` is set
+    async fn probe_keys(&self, replace: bool) -> Result<bool> {
+        let seen = self.keys.generation();
+        let url = self.keys_url()?;
+        let probe = |current| self.probe(current, replace);
+        self.keys.probe_with(&self.config, &url, seen, replace, probe).await
+    }
+
+    // The keys at the url that supplied the current ones, if they changed
+    async fn probe(
+        &self,
+        current: Arc<KeyState>,
+        replace: bool,
+    ) -> Result<Option<(Jwks, FetchMetadata)>> {
+        if let Some(path) = &self.config.keys_file {
+            let (jwks, fetch) =
+                read_keys_file(path, &self.config, Some(&current)).await?;
+            let changed = !current.jwks.diff(&jwks).is_empty();
+            return Ok(changed.then_some((jwks, fetch)));
+        }
+        let (url, validators) = match &current.fetch {
+            Some(fetch) if !CACHING => {
+                (fetch.source.clone(), Validators::of(fetch))
+            }
+            Some(fetch) => (fetch.source.clone(), Validators::default()),
+            None => (self.keys_url()?, Validators::default()),
+        };
+        let limit = Some(self.config.max_keys_bytes);
+        let fetch = || {
+            queued_fetch(
+                &url,
+                Some(&self.issuer),
+                &self.config,
+                limit,
+                &validators,
+            )
+        };
+        let classifier = self.config.classifier();
+        let fetched = self.config.fetch_retry.run(classifier, fetch).await?;
+        if fetched.not_modified {
+            return Ok(None);
+        }
+        let jwks = key_set_of(&url, &fetched, &self.config)?;
+        if current.jwks.diff(&jwks).is_empty() {
+            return Ok(None);
+        }
+        let fetch = FetchMetadata {
+            source: url,
+            fetched_at: SystemTime::now(),
+            max_age: fetched.max_age,
+            etag: fetched.validators.etag,
+            last_modified: fetched.validators.last_modified,
+        };
+        if replace {
+            persist::persist(&self.config, &fetched.body).await;
+            retain(&self.issuer, &self.config, Some(&current), &jwks, &fetch)
+                .await;
+        }
+        Ok(Some((jwks, fetch)))
+    }
+
+    pub(crate) fn refresh_decision(
+        &self,
+        trigger: RefreshTrigger,
+    ) -> RefreshDecision {
+        decide(&self.config, self.keys.refresh_context(trigger))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{
+        CircuitBreaker, DefaultClaims, Error, FetchRetry, ORG_ENDPOINT,
+    };
+
+    use RefreshDecision::{Refresh, Skip};
+    use RefreshTrigger::{KidMiss, Manual, Periodic};
+
+    fn context(
+        trigger: RefreshTrigger,
+        key_age: Option<u64>,
+        max_age: Option<u64>,
+        last_kid_miss_refresh: Option<u64>,
+    ) -> RefreshContext {
+        let mut context = RefreshContext::new(trigger);
+        context.key_age = key_age.map(Duration::from_secs);
+        context.max_age = max_age.map(Duration::from_secs);
+        context.last_kid_miss_refresh =
+            last_kid_miss_refresh.map(Duration::from_secs);
+        context
+    }
+
+    #[test]
+    fn okta_recommended_cools_down_kid_misses() {
+        let policy = OktaRecommended::default();
+        let cases = [
+            (context(KidMiss, Some(10), None, None), Refresh),
+            (context(KidMiss, Some(10), None, Some(10)), Skip),
+            (context(KidMiss, Some(90), None, Some(90)), Refresh),
+            (context(Periodic, Some(10), Some(3600), Some(10)), Refresh),
+            (context(Manual, Some(10), None, Some(10)), Refresh),
+        ];
+        for (context, decision) in cases {
+            assert_eq!(policy.should_refresh(context), decision, "{context:?}");
+        }
+    }
+
+    #[test]
+    fn max_age_strict_keeps_fresh_keys() {
+        let policy = MaxAgeStrict;
+        let cases = [
+            (context(KidMiss, Some(10), Some(60), None), Skip),
+            (context(Periodic, Some(10), Some(60), None), Skip),
+            (context(Periodic, Some(60), Some(60), None), Refresh),
+            (context(KidMiss, Some(10), None, None), Refresh),
+            (context(KidMiss, None, Some(60), None), Refresh),
+            (context(Manual, Some(10), Some(60), None), Refresh),
+        ];
+        for (context, decision) in cases {
+            assert_eq!(policy.should_refresh(context), decision, "{context:?}");
+        }
+    }
+
+    #[test]
+    fn never_only_refreshes_when_asked_to() {
+        for (trigger, decision) in
+            [(KidMiss, Skip), (Periodic, Skip), (Manual, Refresh)]
+        {
+            let context = context(trigger, Some(u64::MAX), Some(0), None);
+            assert_eq!(Never.should_refresh(context), decision);
+        }
+    }
+
+    #[test]
+    fn the_default_honors_the_kid_miss_settings() {
+        let miss = context(KidMiss, Some(10), None, Some(10));
+        assert_eq!(decide(&Config::default(), miss), Skip);
+        let config =
+            Config { kid_miss_cooldown: Duration::ZERO, ..Config::default() };
+        assert_eq!(decide(&config, miss), Refresh);
+        let config = Config { refetch_on_kid_miss: false, ..config };
+        assert_eq!(decide(&config, miss), Skip);
+        assert_eq!(decide(&config, context(Manual, None, None, None)), Refresh);
+        let config = Config { refresh_policy: Some(Arc::new(Never)), ..config };
+        assert_eq!(decide(&config, context(Periodic, None, None, None)), Skip);
+    }
+
+    #[async_test]
+    async fn max_age_strict_follows_the_cache_control_header() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_header("Cache-Control", "public, max-age=3600")
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let config = Config {
+            refresh_policy: Some(Arc::new(MaxAgeStrict)),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        let fetch = verifier.fetch_metadata().expect("retrieved keys");
+        assert_eq!(fetch.max_age, Some(Duration::from_secs(3600)));
+
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        let err = verifier.verify::<DefaultClaims>(&rotated).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NoMatchingKey));
+        m.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn never_keeps_the_keys_until_refreshed() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk(), rotated_jwk()]))
+            .expect(1)
+            .create();
+        let config = Config {
+            refresh_policy: Some(Arc::new(Never)),
+            ..Config::default()
+        };
+        let verifier = Verifier::with_keys_and_config(
+            &server.url(),
+            &keys_body(vec![jwk()]),
+            config,
+        )?;
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        assert!(verifier.verify::<DefaultClaims>(&rotated).await.is_err());
+
+        let refresh = verifier.refresh_keys().await?;
+        assert_eq!(refresh.added, 1);
+        m.assert();
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn reports_added_and_removed_keys() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+
+        let refresh = verifier.refresh_keys().await?;
+        assert_eq!(refresh, KeyRefresh { added: 0, removed: 0, total: 1 });
+        assert!(!refresh.changed());
+
+        m.remove();
+        let m = mock_keys(&mut server, vec![jwk(), rotated_jwk()]);
+        let refresh = verifier.refresh_keys().await?;
+        assert_eq!(refresh, KeyRefresh { added: 1, removed: 0, total: 2 });
+
+        m.remove();
+        mock_keys(&mut server, vec![rotated_jwk()]);
+        let refresh = verifier.refresh_keys().await?;
+        assert_eq!(refresh, KeyRefresh { added: 0, removed: 1, total: 1 });
+        assert!(refresh.changed());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn keeps_the_keys_when_the_refresh_fails() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk()]);
+        let verifier = Verifier::new(&server.url()).await?;
+        let generation = verifier.key_generation();
+
+        m.remove();
+        let m = server.mock("GET", ORG_ENDPOINT).with_status(503).create();
+        let err = verifier.refresh_keys().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::KeysStatus { status: 503, .. })
+        ));
+
+        m.remove();
+        server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body("not json")
+            .create();
+        assert!(verifier.refresh_keys().await.is_err());
+
+        assert_eq!(verifier.key_generation(), generation);
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        Ok(())
+    }
+
+    #[cfg(not(any(
+        feature = "cache-reqwest",
+        all(feature = "cache-surf", not(feature = "client-reqwest"))
+    )))]
+    #[async_test]
+    async fn probes_for_changed_keys_without_replacing_them() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_header("ETag", "v1")
+            .with_body(keys_body(vec![jwk()]))
+            .create();
+        let verifier = Verifier::new(&server.url()).await?;
+        let generation = verifier.key_generation();
+        m.remove();
+
+        let unchanged = server
+            .mock("GET", ORG_ENDPOINT)
+            .match_header("if-none-match", "v1")
+            .with_status(304)
+            .expect(2)
+            .create();
+        assert!(!verifier.keys_changed().await?);
+        assert!(!verifier.refresh_keys_if_changed().await?.changed());
+        assert_eq!(verifier.key_generation(), generation);
+        unchanged.assert();
+        unchanged.remove();
+
+        // Without support for conditional requests the keys are compared
+        let same = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        assert!(!verifier.keys_changed().await?);
+        same.assert();
+        same.remove();
+
+        let rotated = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk(), rotated_jwk()]))
+            .expect(2)
+            .create();
+        assert!(verifier.keys_changed().await?);
+        assert_eq!(verifier.key_generation(), generation);
+        assert_eq!(verifier.stats().key_count, 1);
+        let refresh = verifier.refresh_keys_if_changed().await?;
+        assert_eq!(refresh.added, 1);
+        assert_eq!(verifier.stats().key_count, 2);
+        rotated.assert();
+        Ok(())
+    }
+    #[async_test]
+    async fn probes_through_the_circuit_breaker() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = mock_keys(&mut server, vec![jwk()]);
+        let config = Config {
+            fetch_retry: FetchRetry::disabled(),
+            circuit_breaker: Some(CircuitBreaker {
+                failure_threshold: 1,
+                open_for: Duration::from_secs(60),
+            }),
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config).await?;
+        m.remove();
+
+        let down = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(503)
+            .expect(1)
+            .create();
+        assert!(verifier.keys_changed().await.is_err());
+        // The failed probe opened the circuit for probes and refreshes
+        for err in [
+            verifier.keys_changed().await.unwrap_err(),
+            verifier.refresh_keys_if_changed().await.unwrap_err(),
+            verifier.refresh_keys().await.unwrap_err(),
+        ] {
+            assert!(matches!(
+                err.downcast_ref::<Error>(),
+                Some(Error::KeySourceUnavailable { failures: 1, .. })
+            ));
+        }
+        down.assert();
+        Ok(())
+    }
+}
@@ -0,0 +1,344 @@
+use std::time::{Duration, SystemTime};
+
+use http::header::{CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use http::{HeaderMap, HeaderValue, Response, StatusCode};
+use serde_json::Value;
+
+use crate::Error;
+
+/// Describes the HTTP response for a failed verification, shared by
+/// framework integrations so they classify errors the same way.
+///
+/// Errors from this crate are mapped with [`Error::status_hint`], tokens
+/// that are malformed, invalid, or expired result in a 401 and missing
+/// scopes in a 403, both with a `WWW-Authenticate` header, while failures
+/// to reach the keys endpoint result in a 503 so that an outage isn't
+/// mistaken for bad tokens, with a `Retry-After` header when it's known
+/// when the keys are retrieved again. Claims that don't deserialize into
+/// the requested type are reported as [`Error::InvalidToken`], any error
+/// that isn't one of this crate's results in a 500.
+///
+/// ```
+/// use okta_jwt_verifier::{Error, ErrorResponse};
+///
+/// let error = anyhow::Error::new(Error::TokenExpired);
+/// let response = ErrorResponse::new(&error, Some("api")).into_response(());
+/// assert_eq!(response.status(), 401);
+/// assert!(response.headers().contains_key("www-authenticate"));
+///```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorResponse {
+    /// The status of the response.
+    pub status: StatusCode,
+    /// The value of the `WWW-Authenticate` header, if one should be sent.
+    pub www_authenticate: Option<String>,
+    /// How long to wait before retrying, sent as the `Retry-After` header
+    /// in whole seconds, when the keys are rate limited or the circuit
+    /// breaker is open.
+    pub retry_after: Option<Duration>,
+}
+
+impl ErrorResponse {
+    /// `new` classifies the error returned by a verification.
+    pub fn new(error: &anyhow::Error, realm: Option<&str>) -> Self {
+        // An error of a hook or of reading a file isn't down to the token
+        let Some(error) = error.downcast_ref::<Error>() else {
+            return Self {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                www_authenticate: None,
+                retry_after: None,
+            };
+        };
+        let status = error.status_hint();
+        let www_authenticate = match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Some(error.to_www_authenticate(realm))
+            }
+            _ => None,
+        };
+        Self { status, www_authenticate, retry_after: retry_after(error) }
+    }
+
+    /// `missing_token` describes the response for a request without a
+    /// token, a 401 that only announces the Bearer scheme.
+    pub fn missing_token(realm: Option<&str>) -> Self {
+        Self::new(&Error::MissingToken.into(), realm)
+    }
+
+    /// `into_response` builds an [`http::Response`] with the given body.
+    pub fn into_response<B>(self, body: B) -> Response<B> {
+        let mut response = Response::new(body);
+        *response.status_mut() = self.status;
+        self.insert_headers(response.headers_mut());
+        response
+    }
+
+    fn insert_headers(self, headers: &mut HeaderMap) {
+        if let Some(value) = self.www_authenticate {
+            // The value is built from printable ASCII only
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(WWW_AUTHENTICATE, value);
+            }
+        }
+        if let Some(delay) = self.retry_after {
+            // Rounded up so that clients don't retry too early
+            let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+            headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+        }
+    }
+}
+
+// How long until the keys are retrieved again, if that's known
+fn retry_after(error: &Error) -> Option<Duration> {
+    let at = match error {
+        Error::KeysRateLimited { retry_at, .. } => (*retry_at)?,
+        Error::KeySourceUnavailable { retry_at, .. } => *retry_at,
+        Error::KeysEndpointsFailed { attempts } => {
+            return retry_after(&attempts.last()?.1)
+        }
+        _ => return None,
+    };
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Decides how framework integrations answer requests, implemented once
+/// and shared by all of them. Every method has a default, the defaults
+/// pass on requests with a verified token and answer the others as
+/// described by [`ErrorResponse`], with a small JSON body.
+///
+/// ```
+/// use okta_jwt_verifier::{DenialResponse, Error, ResponseMapper};
+///
+/// struct Teapot;
+///
+/// impl ResponseMapper for Teapot {
+///     fn on_failure(&self, error: &anyhow::Error) -> DenialResponse {
+///         match error.downcast_ref::<Error>() {
+///             Some(Error::TokenExpired) => DenialResponse::new(418),
+///             _ => DenialResponse::for_error(error, self.realm()),
+///         }
+///     }
+/// }
+///
+/// let error = anyhow::Error::new(Error::TokenExpired);
+/// assert_eq!(Teapot.on_failure(&error).status, 418);
+///```
+pub trait ResponseMapper: Send + Sync {
+    /// `realm` is announced in the `WWW-Authenticate` header of the
+    /// default responses, none by default.
+    fn realm(&self) -> Option<&str> {
+        None
+    }
+
+    /// `on_success` decides whether a request whose token was verified is
+    /// passed on, given the claims of the token.
+    fn on_success(&self, claims: &Value) -> Decision {
+        let _ = claims;
+        Decision::Allow
+    }
+
+    /// `on_failure` builds the response to a request whose token was
+    /// rejected, or missing in which case the error is
+    /// [`Error::MissingToken`].
+    fn on_failure(&self, error: &anyhow::Error) -> DenialResponse {
+        DenialResponse::for_error(error, self.realm())
+    }
+}
+
+/// The [`ResponseMapper`] used unless another one is registered,
+/// answering as described by [`ErrorResponse`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DefaultResponseMapper {
+    realm: Option<String>,
+}
+
+impl DefaultResponseMapper {
+    /// `new` announces the realm in the `WWW-Authenticate` header.
+    pub fn new(realm: Option<&str>) -> Self {
+        Self { realm: realm.map(str::to_string) }
+    }
+}
+
+impl ResponseMapper for DefaultResponseMapper {
+    fn realm(&self) -> Option<&str> {
+        self.realm.as_deref()
+    }
+}
+
+/// What a [`ResponseMapper`] decided for a request whose token was
+/// verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Decision {
+    /// The request is passed on.
+    Allow,
+    /// The request is answered with the given response.
+    Deny(DenialResponse),
+}
+
+/// Describes the response to a request that was denied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DenialResponse {
+    /// The status of the response.
+    pub status: StatusCode,
+    /// The headers of the response.
+    pub headers: HeaderMap,
+    /// The body of the response.
+    pub body: Vec<u8>,
+}
+
+impl DenialResponse {
+    /// `new` describes a response with the given status, e.g. 418, and
+    /// neither headers nor a body. Invalid statuses result in a 500.
+    pub fn new(status: u16) -> Self {
+        Self {
+            status: StatusCode::from_u16(status)
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// `for_error` describes the default response to a failed
+    /// verification, the status and the `WWW-Authenticate` and
+    /// `Retry-After` headers from [`ErrorResponse`] with a JSON body
+    /// holding the reason phrase.
+    pub fn for_error(error: &anyhow::Error, realm: Option<&str>) -> Self {
+        let response = ErrorResponse::new(error, realm);
+        let status = response.status;
+        let mut headers = HeaderMap::new();
+        response.insert_headers(&mut headers);
+        headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let body = serde_json::json!({ "message": status.canonical_reason() });
+        Self { status, headers, body: body.to_string().into_bytes() }
+    }
+
+    /// `into_response` builds an [`http::Response`].
+    pub fn into_response(self) -> Response<Vec<u8>> {
+        let mut response = Response::new(self.body);
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn respond(error: anyhow::Error) -> Response<()> {
+        ErrorResponse::new(&error, Some("api")).into_response(())
+    }
+
+    fn www_authenticate(response: &Response<()>) -> Option<&str> {
+        response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn invalid_tokens_are_unauthorized() {
+        let response = respond(Error::MalformedToken.into());
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            www_authenticate(&response),
+            Some(
+                r#"Bearer realm="api", error="invalid_token", error_description="The access token is malformed""#
+            )
+        );
+    }
+
+    #[test]
+    fn other_errors_are_internal() {
+        let response = respond(anyhow::anyhow!("hook failed"));
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(www_authenticate(&response), None);
+        assert!(response.headers().get(RETRY_AFTER).is_none());
+    }
+
+    #[test]
+    fn missing_tokens_are_unauthorized() {
+        let response =
+            ErrorResponse::missing_token(Some("api")).into_response(());
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(www_authenticate(&response), Some(r#"Bearer realm="api""#));
+    }
+
+    #[test]
+    fn insufficient_scope_is_forbidden() {
+        let error = Error::InsufficientScope {
+            required: vec!["a".into()],
+            audience: None,
+        };
+        let response = respond(error.into());
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(www_authenticate(&response)
+            .unwrap()
+            .contains(r#"error="insufficient_scope""#));
+    }
+
+    #[test]
+    fn unreachable_keys_are_unavailable() {
+        let error = Error::KeysStatus {
+            status: 502,
+            url: "url".into(),
+            body: String::new(),
+        };
+        assert!(error.is_retryable());
+        let response = respond(error.into());
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(www_authenticate(&response), None);
+        assert!(!Error::TokenExpired.is_retryable());
+    }
+
+    #[test]
+    fn known_retry_times_are_announced() {
+        let retry_at = SystemTime::now() + Duration::from_millis(29_500);
+        let limited = Error::KeysRateLimited {
+            url: "url".into(),
+            retry_at: Some(retry_at),
+        };
+        let response = respond(limited.clone().into());
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+        let open = Error::KeySourceUnavailable { failures: 5, retry_at };
+        let denial = DenialResponse::for_error(&open.into(), None);
+        assert_eq!(denial.headers[RETRY_AFTER], "30");
+        let attempts = vec![("url".to_string(), limited)];
+        let failed = Error::KeysEndpointsFailed { attempts };
+        assert_eq!(respond(failed.into()).headers()[RETRY_AFTER], "30");
+
+        let unknown =
+            Error::KeysRateLimited { url: "url".into(), retry_at: None };
+        assert!(!respond(unknown.into()).headers().contains_key(RETRY_AFTER));
+        let past = Error::KeySourceUnavailable {
+            failures: 5,
+            retry_at: SystemTime::UNIX_EPOCH,
+        };
+        assert_eq!(respond(past.into()).headers()[RETRY_AFTER], "0");
+    }
+
+    #[test]
+    fn the_default_mapper_follows_rfc_6750() {
+        let mapper = DefaultResponseMapper::new(Some("api"));
+        assert_eq!(mapper.on_success(&Value::Null), Decision::Allow);
+        let error = Error::TokenExpired.into();
+        let response = mapper.on_failure(&error).into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[WWW_AUTHENTICATE],
+            respond(error).headers()[WWW_AUTHENTICATE]
+        );
+        assert_eq!(response.body(), br#"{"message":"Unauthorized"}"#);
+    }
+
+    #[test]
+    fn configuration_errors_are_internal() {
+        let error = Error::LeewayTooLarge { leeway: 2, threshold: 1 };
+        let response = respond(error.into());
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(www_authenticate(&response), None);
+    }
+}
@@ -0,0 +1,464 @@
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use crate::{logging, queue, runtime, Config, Error};
+
+/// Describes how often a request to the keys endpoint, or to one of the
+/// fallback urls, is attempted before giving up, see
+/// [`Config::fetch_retry`](crate::Config::fetch_retry). Which failures
+/// are retried is decided by the
+/// [`Config::retry_classifier`](crate::Config::retry_classifier), by
+/// default only connection errors, timeouts, server errors, and rate
+/// limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FetchRetry {
+    /// The number of attempts including the first one, 1 disables
+    /// retrying. By default 3.
+    pub max_attempts: u32,
+    /// The delay before the first retry, by default 200 milliseconds.
+    pub initial_delay: Duration,
+    /// The factor the delay grows by with every further retry, by
+    /// default 2.
+    pub multiplier: u32,
+    /// The longest delay between two attempts, by default 2 seconds.
+    pub max_delay: Duration,
+    /// The longest time waited for a rate limit to reset before retrying,
+    /// a later reset fails right away with [`Error::KeysRateLimited`]
+    /// so that the caller can retry once it passed. A rate limit without
+    /// a reset time is retried like a server error. By default 2 seconds.
+    pub max_rate_limit_wait: Duration,
+}
+
+impl Default for FetchRetry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(200),
+            multiplier: 2,
+            max_delay: Duration::from_secs(2),
+            max_rate_limit_wait: Duration::from_secs(2),
+        }
+    }
+}
+
+impl FetchRetry {
+    /// `disabled` attempts every request only once.
+    pub fn disabled() -> Self {
+        Self { max_attempts: 1, ..Self::default() }
+    }
+
+    // The delay before the given retry, counting from zero
+    fn delay(&self, retry: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(self.multiplier.saturating_pow(retry))
+            .min(self.max_delay)
+    }
+
+    // Runs the request until it succeeds, fails in a way that isn't worth
+    // retrying, or runs out of attempts
+    pub(crate) async fn run<T, F, Fut>(
+        &self,
+        classifier: &dyn RetryClassifier,
+        mut request: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut retry = 0;
+        loop {
+            let e = match request().await {
+                Err(e) if retry + 1 < self.max_attempts => e,
+                result => return result,
+            };
+            let delay = match classify(classifier, &e) {
+                RetryDecision::Fatal => return Err(e),
+                RetryDecision::RateLimited(wait)
+                    if wait > self.max_rate_limit_wait =>
+                {
+                    return Err(e)
+                }
+                RetryDecision::RateLimited(wait) => wait,
+                RetryDecision::Retryable => self.delay(retry),
+            };
+            logging::debug!("Retrying the keys request in {delay:?}: {e}");
+            runtime::sleep(delay).await;
+            retry += 1;
+        }
+    }
+}
+
+/// How a failed request for the keys is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// The failure may go away on its own, the request is retried with
+    /// a backoff and the failure counts towards opening the circuit
+    /// breaker.
+    Retryable,
+    /// The failure points at a misconfiguration, it's reported right
+    /// away and doesn't count towards opening the circuit breaker.
+    Fatal,
+    /// The request is retried once the given duration passed, unless it
+    /// exceeds [`FetchRetry::max_rate_limit_wait`]. Counts towards
+    /// opening the circuit breaker.
+    RateLimited(Duration),
+}
+
+/// Decides which failed requests for the keys are retried, see
+/// [`Config::retry_classifier`]. Consulted by both the [`FetchRetry`]
+/// and the [`CircuitBreaker`](crate::CircuitBreaker), and only for
+/// failures described by an [`Error`], any other failure is fatal. After
+/// several urls were tried only the failure of the last one is
+/// classified, never [`Error::KeysEndpointsFailed`] itself.
+pub trait RetryClassifier: fmt::Debug + Send + Sync {
+    /// `classify` decides how the failure is handled.
+    fn classify(&self, error: &Error) -> RetryDecision;
+}
+
+/// Retries connection errors, timeouts, server errors, and rate limits,
+/// waiting for the reset of a rate limit when the response names one. An
+/// unreadable keys file counts as transient, e.g. while it's being synced.
+/// Used by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DefaultClassifier;
+
+impl RetryClassifier for DefaultClassifier {
+    fn classify(&self, error: &Error) -> RetryDecision {
+        match error {
+            Error::KeysRateLimited { retry_at: Some(at), .. } => {
+                RetryDecision::RateLimited(
+                    at.duration_since(SystemTime::now()).unwrap_or_default(),
+                )
+            }
+            Error::KeysUnreachable { .. }
+            | Error::KeysFileUnreadable { .. }
+            | Error::KeysTimeout { .. }
+            | Error::KeysStatus { status: 500..=599, .. }
+            | Error::KeysRateLimited { .. } => RetryDecision::Retryable,
+            _ => RetryDecision::Fatal,
+        }
+    }
+}
+
+impl Config {
+    // The configured classifier or else the default one
+    pub(crate) fn classifier(&self) -> &dyn RetryClassifier {
+        self.retry_classifier.as_deref().unwrap_or(&DefaultClassifier)
+    }
+}
+
+// Consults the classifier about failures described by the crate's error
+pub(crate) fn classify(
+    classifier: &dyn RetryClassifier,
+    error: &anyhow::Error,
+) -> RetryDecision {
+    if queue::turned_away(error) {
+        // Retrying would only add to the queue
+        return RetryDecision::Fatal;
+    }
+    match error.downcast_ref::<Error>() {
+        Some(Error::KeysEndpointsFailed { attempts }) => attempts
+            .last()
+            .map_or(RetryDecision::Fatal, |(_, e)| classifier.classify(e)),
+        Some(e) => classifier.classify(e),
+        None => RetryDecision::Fatal,
+    }
+}
+
+// How long until the rate limit or open circuit breaker that failed the
+// request resets
+pub(crate) fn until_reset(error: &anyhow::Error) -> Option<Duration> {
+    error.downcast_ref::<Error>().and_then(reset_of)
+}
+
+fn reset_of(error: &Error) -> Option<Duration> {
+    let at = match error {
+        Error::KeysRateLimited { retry_at: Some(at), .. }
+        | Error::KeySourceUnavailable { retry_at: at, .. } => at,
+        Error::KeysEndpointsFailed { attempts } => {
+            return reset_of(&attempts.last()?.1)
+        }
+        _ => return None,
+    };
+    Some(at.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+// When a rate limit resets according to the Retry-After header, either
+// seconds or an HTTP date, or else Okta's X-Rate-Limit-Reset header in
+// seconds since the epoch
+#[cfg_attr(
+    not(any(feature = "client-surf", feature = "client-reqwest")),
+    allow(dead_code)
+)]
+pub(crate) fn rate_limit_reset(
+    retry_after: Option<&str>,
+    reset: Option<&str>,
+) -> Option<SystemTime> {
+    let parse = |value: &str| match value.parse() {
+        Ok(secs) => SystemTime::now().checked_add(Duration::from_secs(secs)),
+        Err(_) => httpdate::parse_http_date(value).ok(),
+    };
+    let retry_after = retry_after.map(str::trim).and_then(parse);
+    retry_after.or_else(|| {
+        let secs = reset?.trim().parse().ok()?;
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use crate::test_support::*;
+    use crate::{Config, Verifier, ORG_ENDPOINT};
+
+    // Retries 418 on top of the failures retried by default
+    #[derive(Debug)]
+    struct Teapot;
+
+    impl RetryClassifier for Teapot {
+        fn classify(&self, error: &Error) -> RetryDecision {
+            match error {
+                Error::KeysStatus { status: 418, .. } => {
+                    RetryDecision::Retryable
+                }
+                _ => DefaultClassifier.classify(error),
+            }
+        }
+    }
+
+    fn unreachable() -> anyhow::Error {
+        Error::KeysUnreachable { url: "url".into(), reason: "dns".into() }
+            .into()
+    }
+
+    #[test]
+    fn backs_off_up_to_the_cap() {
+        let retry = FetchRetry::default();
+        let delays: Vec<_> = (0..6).map(|n| retry.delay(n)).collect();
+        let millis = |ms| Duration::from_millis(ms);
+        assert_eq!(
+            delays,
+            [200, 400, 800, 1600, 2000, 2000].map(millis).to_vec()
+        );
+        assert_eq!(retry.delay(u32::MAX), retry.max_delay);
+    }
+
+    #[async_test]
+    async fn only_retries_transient_errors() {
+        let retry = FetchRetry {
+            initial_delay: Duration::from_millis(1),
+            ..FetchRetry::default()
+        };
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = retry
+            .run(&DefaultClassifier, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(unreachable())
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+
+        let result: Result<()> = retry
+            .run(&DefaultClassifier, || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(Error::KeysStatus {
+                    status: 404,
+                    url: "url".into(),
+                    body: String::new(),
+                }
+                .into())
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 1);
+
+        let result = retry
+            .run(&DefaultClassifier, || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(unreachable()),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(result.ok(), Some(1));
+    }
+
+    #[test]
+    fn reads_the_rate_limit_reset() {
+        let in_secs = |at: Option<SystemTime>| {
+            at.map(|at| {
+                at.duration_since(SystemTime::now()).unwrap_or_default()
+            })
+            .map(|wait| wait.as_secs_f64().round() as u64)
+        };
+        assert_eq!(in_secs(rate_limit_reset(Some(" 30 "), None)), Some(30));
+        let date = httpdate::fmt_http_date(
+            SystemTime::now() + Duration::from_secs(120),
+        );
+        // HTTP dates only have a resolution of seconds
+        let wait = in_secs(rate_limit_reset(Some(&date), Some("1")));
+        assert!(matches!(wait, Some(119..=120)), "{wait:?}");
+        assert_eq!(
+            rate_limit_reset(None, Some("1700000000")),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert_eq!(rate_limit_reset(Some("soon"), None), None);
+        assert_eq!(rate_limit_reset(None, None), None);
+        // Times past what SystemTime holds are no reset at all
+        let never = u64::MAX.to_string();
+        assert_eq!(rate_limit_reset(Some(&never), None), None);
+        assert_eq!(rate_limit_reset(None, Some(&never)), None);
+    }
+
+    #[async_test]
+    async fn waits_for_a_rate_limit_within_the_ceiling() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let limited = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(429)
+            .with_header("Retry-After", "1")
+            .expect(1)
+            .create();
+        let up = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        Verifier::new(&server.url()).await?;
+        limited.assert();
+        up.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn reports_a_rate_limit_beyond_the_ceiling() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let limited = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(429)
+            .with_header("Retry-After", "3600")
+            .expect(1)
+            .create();
+        let err = Verifier::new(&server.url()).await.unwrap_err();
+        let Some(Error::KeysRateLimited { retry_at: Some(at), .. }) =
+            err.downcast_ref::<Error>()
+        else {
+            panic!("not rate limited: {err}");
+        };
+        let wait = at.duration_since(SystemTime::now())?;
+        assert!(wait > Duration::from_secs(3500), "{wait:?}");
+        assert!(err.downcast_ref::<Error>().is_some_and(Error::is_retryable));
+        limited.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn retries_a_server_error_of_the_keys_endpoint() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let down = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(502)
+            .expect(1)
+            .create();
+        let up = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            .expect(1)
+            .create();
+        let config = Config {
+            fetch_retry: FetchRetry {
+                initial_delay: Duration::from_millis(1),
+                ..FetchRetry::default()
+            },
+            ..Config::default()
+        };
+        Verifier::new_with_config(&server.url(), config).await?;
+        down.assert();
+        up.assert();
+
+        let mut server = mockito::Server::new_async().await;
+        let missing = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(404)
+            .expect(1)
+            .create();
+        let err = Verifier::new(&server.url()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::KeysStatus { status: 404, .. })
+        ));
+        missing.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn retries_what_the_classifier_deems_retryable() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let teapot = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(418)
+            .expect(3)
+            .create();
+        let fetch_retry = FetchRetry {
+            initial_delay: Duration::from_millis(1),
+            ..FetchRetry::default()
+        };
+        let config = Config {
+            fetch_retry,
+            retry_classifier: Some(Arc::new(Teapot)),
+            ..Config::default()
+        };
+        let err =
+            Verifier::new_with_config(&server.url(), config).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::KeysStatus { status: 418, .. })
+        ));
+        teapot.assert();
+        teapot.remove();
+
+        // The default classifier gives up right away
+        let teapot = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(418)
+            .expect(1)
+            .create();
+        let config = Config { fetch_retry, ..Config::default() };
+        assert!(Verifier::new_with_config(&server.url(), config)
+            .await
+            .is_err());
+        teapot.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn classifies_failures_by_default() {
+        let status = |status| Error::KeysStatus {
+            status,
+            url: "url".into(),
+            body: String::new(),
+        };
+        let classify = |error| DefaultClassifier.classify(&error);
+        assert_eq!(classify(status(503)), RetryDecision::Retryable);
+        assert_eq!(classify(status(410)), RetryDecision::Fatal);
+        assert_eq!(classify(Error::NoMatchingKey), RetryDecision::Fatal);
+        let rate_limited =
+            |retry_at| Error::KeysRateLimited { url: "url".into(), retry_at };
+        assert_eq!(classify(rate_limited(None)), RetryDecision::Retryable);
+        let at = SystemTime::now() + Duration::from_secs(60);
+        assert!(matches!(
+            classify(rate_limited(Some(at))),
+            RetryDecision::RateLimited(wait) if wait > Duration::from_secs(50)
+        ));
+    }
+}
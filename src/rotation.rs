@@ -0,0 +1,214 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::keystore::KeyState;
+use crate::{logging, runtime, Hook, KeyInfo, KeyUsage, Verifier};
+
+pub(crate) type RotationHook = Hook<dyn Fn(&KeyRotation) + Send + Sync>;
+
+/// Describes a change of the keys held by a Verifier, reported to the
+/// callbacks registered with [`Verifier::on_key_rotation`]. A key whose
+/// material changed while its kid stayed the same is listed as both
+/// removed and added.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct KeyRotation {
+    /// The kids of the keys that weren't held before.
+    pub added: Vec<String>,
+    /// The kids of the keys that are no longer held.
+    pub removed: Vec<String>,
+    /// The final usage counts of the removed kids, see
+    /// [`Verifier::key_usage`]. They're only reported here, the counters
+    /// are dropped along with the keys.
+    pub removed_usage: Vec<(String, KeyUsage)>,
+    /// When the new keys were retrieved.
+    pub fetched_at: SystemTime,
+}
+
+impl KeyRotation {
+    // None when the same keys were retrieved again
+    pub(crate) fn between(
+        before: &KeyState,
+        after: &KeyState,
+        removed_usage: Vec<(String, KeyUsage)>,
+    ) -> Option<Self> {
+        let diff = before.jwks.diff(&after.jwks);
+        if diff.is_empty() {
+            return None;
+        }
+        let kids = |keys: &[KeyInfo]| -> Vec<String> {
+            keys.iter().map(|key| key.kid.clone()).collect()
+        };
+        let mut added = kids(&diff.added);
+        let mut removed = kids(&diff.removed);
+        for (old, new) in diff.changed {
+            removed.push(old.kid);
+            added.push(new.kid);
+        }
+        let fetched_at = after
+            .fetch
+            .as_ref()
+            .map_or_else(SystemTime::now, |fetch| fetch.fetched_at);
+        Some(Self { added, removed, removed_usage, fetched_at })
+    }
+}
+
+// Runs the callbacks on a blocking thread so that a slow callback doesn't
+// hold up the verification that retrieved the keys, and a panicking one
+// neither affects the others nor the verification
+pub(crate) fn notify(hooks: Vec<RotationHook>, rotation: KeyRotation) {
+    runtime::spawn_blocking(move || {
+        for Hook(hook) in hooks {
+            if catch_unwind(AssertUnwindSafe(|| hook(&rotation))).is_err() {
+                logging::warn!("A key rotation callback panicked");
+            }
+        }
+    });
+}
+
+impl Verifier {
+    /// `on_key_rotation` registers a callback invoked whenever new keys
+    /// replace the current ones, e.g. to alert on rotations. It covers
+    /// [`Verifier::refresh_keys`], retrievals for unknown kids, and the
+    /// background refresh, but not the first retrieval of the keys. The
+    /// callbacks are shared with the clones made afterwards, not with the
+    /// ones made earlier, and stop once all of them are dropped.
+    ///
+    /// Callbacks run on a separate thread once the keys are replaced, so
+    /// they don't delay verifications, and a panic is logged rather than
+    /// propagated.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verifier = Verifier::new(&issuer)
+    ///         .await?
+    ///         .on_key_rotation(|rotation| {
+    ///             println!("keys rotated: {:?}", rotation.added);
+    ///         });
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn on_key_rotation(
+        mut self,
+        callback: impl Fn(&KeyRotation) + Send + Sync + 'static,
+    ) -> Self {
+        let mut hooks = self.rotation_hooks.as_ref().clone();
+        hooks.push(Hook(Arc::new(callback)));
+        self.rotation_hooks = Arc::new(hooks);
+        self.keys.on_rotation(&self.rotation_hooks);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use anyhow::Result;
+
+    use crate::test_support::*;
+    use crate::DefaultClaims;
+
+    // Callbacks run on another thread, waits for the expected number
+    async fn wait_for(seen: &Mutex<Vec<KeyRotation>>, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while seen.lock().unwrap().len() < count && Instant::now() < deadline {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[async_test]
+    async fn reports_the_added_and_removed_kids() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let before = mock_keys(&mut server, vec![jwk()]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let verifier = Verifier::new(&server.url()).await?.on_key_rotation(
+            move |rotation| recorded.lock().unwrap().push(rotation.clone()),
+        );
+        // The same keys retrieved again aren't a rotation
+        verifier.refresh_keys().await?;
+        before.remove();
+        mock_keys(&mut server, vec![rotated_jwk()]);
+        verifier.refresh_keys().await?;
+
+        wait_for(&seen, 1).await;
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].added, vec![ROTATED_KEY_ID.to_string()]);
+        assert_eq!(seen[0].removed, vec![KEY_ID.to_string()]);
+        assert_eq!(
+            Some(seen[0].fetched_at),
+            verifier.fetch_metadata().map(|fetch| fetch.fetched_at)
+        );
+        Ok(())
+    }
+
+    #[async_test]
+    async fn callbacks_stay_with_the_verifier_they_were_added_to() -> Result<()>
+    {
+        let mut server = mockito::Server::new_async().await;
+        let before = mock_keys(&mut server, vec![jwk()]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let earlier = Verifier::new(&server.url()).await?;
+        let later = earlier.clone().on_key_rotation(move |rotation| {
+            recorded.lock().unwrap().push(rotation.clone())
+        });
+        before.remove();
+        let rotated = mock_keys(&mut server, vec![rotated_jwk()]);
+        // The keys are shared, so rotations through the earlier clone reach
+        // the callback as long as the later one is around
+        earlier.refresh_keys().await?;
+        wait_for(&seen, 1).await;
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        drop(later);
+        rotated.remove();
+        mock_keys(&mut server, vec![jwk()]);
+        earlier.refresh_keys().await?;
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn callbacks_are_isolated_from_verifications() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let before = mock_keys(&mut server, vec![jwk()]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let verifier = Verifier::new(&server.url())
+            .await?
+            .on_key_rotation(|_| panic!("callback failed"))
+            .on_key_rotation(|_| std::thread::sleep(Duration::from_secs(2)))
+            .on_key_rotation(move |rotation| {
+                recorded.lock().unwrap().push(rotation.clone())
+            });
+        before.remove();
+        mock_keys(&mut server, vec![jwk(), rotated_jwk()]);
+
+        // The unknown kid retrieves the keys, which invokes the callbacks
+        let started = Instant::now();
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&server.url()));
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+
+        wait_for(&seen, 1).await;
+        assert_eq!(seen.lock().unwrap()[0].added, vec![ROTATED_KEY_ID]);
+        Ok(())
+    }
+}
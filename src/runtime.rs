@@ -0,0 +1,425 @@
+// Helpers over the async runtime that goes with the enabled client, tokio
+// for `client-reqwest` and async-std for `client-surf`. With both clients
+// `client-reqwest` is used. Without a client there is no runtime to rely on
+// and plain threads stand in for one, see fallback.
+
+use std::future::Future;
+use std::time::Duration;
+
+// Runs the future to completion unless the timeout elapses first
+#[cfg(feature = "client-reqwest")]
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    tokio::time::timeout(duration, future).await.ok()
+}
+
+// Runs the future to completion unless the timeout elapses first
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    async_std::future::timeout(duration, future).await.ok()
+}
+
+// Runs the future to completion unless the timeout elapses first
+#[cfg(not(any(feature = "client-surf", feature = "client-reqwest")))]
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    fallback::timeout(duration, future).await
+}
+
+// Runs the future on its own task without waiting for it
+#[cfg(feature = "client-reqwest")]
+pub(crate) fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(future);
+}
+
+// Runs the future on its own task without waiting for it
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+pub(crate) fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    async_std::task::spawn(future);
+}
+
+// Runs the future on its own thread without waiting for it
+#[cfg(not(any(feature = "client-surf", feature = "client-reqwest")))]
+pub(crate) fn spawn<F>(future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    std::thread::spawn(move || fallback::block_on(future));
+}
+
+// Runs the closure on a thread that may block without waiting for it.
+// Outside of a runtime the closure gets a thread of its own.
+#[cfg(feature = "client-reqwest")]
+pub(crate) fn spawn_blocking<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => drop(handle.spawn_blocking(f)),
+        Err(_) => drop(std::thread::spawn(f)),
+    }
+}
+
+// Runs the closure on a thread that may block without waiting for it
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+pub(crate) fn spawn_blocking<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    async_std::task::spawn_blocking(f);
+}
+
+// Runs the closure on a thread that may block without waiting for it
+#[cfg(not(any(feature = "client-surf", feature = "client-reqwest")))]
+pub(crate) fn spawn_blocking<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    std::thread::spawn(f);
+}
+
+// Runs the blocking closure off the task, e.g. file system calls, and
+// waits for its result. Outside of a runtime the closure runs right away.
+#[cfg(feature = "client-reqwest")]
+pub(crate) async fn unblock<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let Ok(handle) = tokio::runtime::Handle::try_current() else {
+        return f();
+    };
+    match handle.spawn_blocking(f).await {
+        Ok(output) => output,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+// Runs the blocking closure off the task, e.g. file system calls, and
+// waits for its result
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+pub(crate) async fn unblock<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    async_std::task::spawn_blocking(f).await
+}
+
+// Runs the blocking closure on a thread of its own and waits for its result
+#[cfg(not(any(feature = "client-surf", feature = "client-reqwest")))]
+pub(crate) async fn unblock<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    fallback::unblock(f).await
+}
+
+// Waits for the duration without blocking the thread
+#[cfg(feature = "client-reqwest")]
+pub(crate) async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+// Waits for the duration without blocking the thread
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+pub(crate) async fn sleep(duration: Duration) {
+    async_std::task::sleep(duration).await
+}
+
+// Waits for the duration without blocking the thread
+#[cfg(not(any(feature = "client-surf", feature = "client-reqwest")))]
+pub(crate) async fn sleep(duration: Duration) {
+    fallback::Delay::new(duration).await
+}
+
+// Timers and tasks on plain threads, working with whatever executor polls
+// them. Every timer is served by a single thread sleeping until the
+// earliest deadline, and a timer dropped before it elapsed, e.g. of a
+// timeout whose future finished first, is forgotten right away.
+#[cfg(not(any(feature = "client-surf", feature = "client-reqwest")))]
+mod fallback {
+    use std::collections::BTreeMap;
+    use std::future::{poll_fn, Future};
+    use std::panic::AssertUnwindSafe;
+    use std::pin::{pin, Pin};
+    use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use std::time::{Duration, Instant};
+
+    // Whether the timer elapsed, and the task to wake once it does
+    #[derive(Debug, Default)]
+    struct Timer {
+        elapsed: bool,
+        waker: Option<Waker>,
+    }
+
+    // The pending timers by deadline, then in the order they were created
+    type Deadline = (Instant, u64);
+
+    #[derive(Default)]
+    struct Schedule {
+        pending: BTreeMap<Deadline, Arc<Mutex<Timer>>>,
+        created: u64,
+    }
+
+    // The timers served by the timer thread, which is told about new ones
+    // through the condvar
+    #[derive(Default)]
+    struct Timers {
+        schedule: Mutex<Schedule>,
+        added: Condvar,
+    }
+
+    impl Timers {
+        // The timers, starting their thread the first time
+        fn get() -> &'static Self {
+            static TIMERS: OnceLock<Timers> = OnceLock::new();
+            let mut created = false;
+            let timers = TIMERS.get_or_init(|| {
+                created = true;
+                Self::default()
+            });
+            if created {
+                thread::spawn(|| timers.run());
+            }
+            timers
+        }
+
+        fn lock(&self) -> MutexGuard<'_, Schedule> {
+            self.schedule.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        fn add(&self, at: Instant, timer: Arc<Mutex<Timer>>) -> Deadline {
+            let mut schedule = self.lock();
+            schedule.created += 1;
+            let deadline = (at, schedule.created);
+            schedule.pending.insert(deadline, timer);
+            self.added.notify_one();
+            deadline
+        }
+
+        fn remove(&self, deadline: &Deadline) {
+            self.lock().pending.remove(deadline);
+        }
+
+        // Wakes the tasks of the elapsed timers, outside of the lock of
+        // the schedule
+        fn run(&self) {
+            let mut schedule = self.lock();
+            loop {
+                let now = Instant::now();
+                let later = schedule.pending.split_off(&(now, u64::MAX));
+                let elapsed = std::mem::replace(&mut schedule.pending, later);
+                if !elapsed.is_empty() {
+                    drop(schedule);
+                    for timer in elapsed.into_values() {
+                        let mut timer = timer
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner);
+                        timer.elapsed = true;
+                        if let Some(waker) = timer.waker.take() {
+                            waker.wake();
+                        }
+                    }
+                    schedule = self.lock();
+                    continue;
+                }
+                schedule = match schedule.pending.keys().next() {
+                    Some((at, _)) => {
+                        let wait = at.saturating_duration_since(now);
+                        self.added
+                            .wait_timeout(schedule, wait)
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .0
+                    }
+                    None => self
+                        .added
+                        .wait(schedule)
+                        .unwrap_or_else(PoisonError::into_inner),
+                };
+            }
+        }
+    }
+
+    // Completes once the duration elapsed, never when it's too long to
+    // tell when
+    #[derive(Debug)]
+    pub(super) struct Delay {
+        timer: Arc<Mutex<Timer>>,
+        deadline: Option<Deadline>,
+    }
+
+    impl Delay {
+        pub(super) fn new(duration: Duration) -> Self {
+            let timer = Arc::new(Mutex::new(Timer::default()));
+            let deadline = Instant::now()
+                .checked_add(duration)
+                .map(|at| Timers::get().add(at, timer.clone()));
+            Self { timer, deadline }
+        }
+    }
+
+    impl Future for Delay {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let mut timer =
+                self.timer.lock().unwrap_or_else(PoisonError::into_inner);
+            if timer.elapsed {
+                return Poll::Ready(());
+            }
+            timer.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Delay {
+        fn drop(&mut self) {
+            if let Some(deadline) = &self.deadline {
+                Timers::get().remove(deadline);
+            }
+        }
+    }
+
+    pub(super) async fn timeout<F: Future>(
+        duration: Duration,
+        future: F,
+    ) -> Option<F::Output> {
+        let mut future = pin!(future);
+        let mut delay = Delay::new(duration);
+        poll_fn(|cx| {
+            if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                return Poll::Ready(Some(output));
+            }
+            Pin::new(&mut delay).poll(cx).map(|_| None)
+        })
+        .await
+    }
+
+    // The result of a closure run on another thread, and the task to wake
+    // once it's there
+    struct Slot<T> {
+        output: Option<thread::Result<T>>,
+        waker: Option<Waker>,
+    }
+
+    pub(super) async fn unblock<T, F>(f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Slot { output: None, waker: None }));
+        let shared = slot.clone();
+        thread::spawn(move || {
+            let output = std::panic::catch_unwind(AssertUnwindSafe(f));
+            let mut slot =
+                shared.lock().unwrap_or_else(PoisonError::into_inner);
+            slot.output = Some(output);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake();
+            }
+        });
+        let output = poll_fn(|cx| {
+            let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+            match slot.output.take() {
+                Some(output) => Poll::Ready(output),
+                None => {
+                    slot.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+        output.unwrap_or_else(|e| std::panic::resume_unwind(e))
+    }
+
+    // Wakes the thread blocked on the future
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    pub(super) fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        use crate::runtime::{sleep, timeout};
+
+        #[test]
+        fn elapses_the_timers_in_order() {
+            let started = Instant::now();
+            let (first, second) = block_on(async {
+                let first = async {
+                    sleep(Duration::from_millis(60)).await;
+                    started.elapsed()
+                };
+                let second = async {
+                    sleep(Duration::from_millis(20)).await;
+                    started.elapsed()
+                };
+                futures::join!(first, second)
+            });
+            assert!(second >= Duration::from_millis(20));
+            assert!(first >= Duration::from_millis(60));
+            assert!(second < first);
+        }
+
+        #[test]
+        fn forgets_the_timers_of_finished_timeouts() {
+            let finished =
+                block_on(timeout(Duration::from_secs(60), async { 1 }));
+            assert_eq!(finished, Some(1));
+
+            let delay = Delay::new(Duration::from_secs(60));
+            let deadline = delay.deadline.unwrap();
+            assert!(Timers::get().lock().pending.contains_key(&deadline));
+            drop(delay);
+            assert!(!Timers::get().lock().pending.contains_key(&deadline));
+        }
+
+        #[test]
+        fn times_out_the_futures_taking_too_long() {
+            let started = Instant::now();
+            let timed_out = block_on(timeout(
+                Duration::from_millis(20),
+                sleep(Duration::from_secs(60)),
+            ));
+            assert_eq!(timed_out, None);
+            assert!(started.elapsed() < Duration::from_secs(60));
+            // Too far out to tell when, so it never elapses
+            let never = Delay::new(Duration::MAX);
+            assert!(never.deadline.is_none());
+        }
+    }
+}
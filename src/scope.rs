@@ -0,0 +1,283 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{token_scopes, Error, Verifier, VerifyOptions};
+
+/// Describes the scopes a token must grant, see
+/// [`Verifier::audience_policy`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScopePolicy {
+    /// Every one of the scopes is required.
+    All(Vec<String>),
+    /// At least one of the scopes is required.
+    Any(Vec<String>),
+}
+
+impl ScopePolicy {
+    /// `all` constructs a policy that requires every one of the scopes.
+    pub fn all(scopes: &[&str]) -> Self {
+        Self::All(scopes.iter().map(|s| s.to_string()).collect())
+    }
+
+    /// `any` constructs a policy that requires at least one of the scopes.
+    pub fn any(scopes: &[&str]) -> Self {
+        Self::Any(scopes.iter().map(|s| s.to_string()).collect())
+    }
+
+    /// The scopes named by the policy.
+    pub fn scopes(&self) -> &[String] {
+        match self {
+            Self::All(scopes) | Self::Any(scopes) => scopes,
+        }
+    }
+
+    // Whether the granted scopes satisfy the policy
+    fn allows(&self, granted: &[&str]) -> bool {
+        let granted = |scope: &String| granted.contains(&scope.as_str());
+        match self {
+            Self::All(scopes) => scopes.iter().all(granted),
+            Self::Any(scopes) => scopes.iter().any(granted),
+        }
+    }
+}
+
+impl Verifier {
+    /// `audience_policy` requires the given scopes from tokens issued for
+    /// the audience, which is accepted as if added with
+    /// [`Verifier::add_audience`]. The policy replaces the scopes set with
+    /// [`Verifier::required_scopes`] for tokens with this audience, tokens
+    /// also holding any audience without a policy remain subject to those.
+    /// When a token holds several audiences with a policy, every one of
+    /// these policies has to be satisfied. Per-call
+    /// [`VerifyOptions::required_scopes`] replace the policies as well.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{DefaultClaims, ScopePolicy, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     Verifier::new(&issuer)
+    ///         .await?
+    ///         .audience_policy("api://internal", ScopePolicy::all(&["internal:full"]))
+    ///         .audience_policy("api://partner", ScopePolicy::any(&["partner:read"]))
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn audience_policy(mut self, aud: &str, policy: ScopePolicy) -> Self {
+        self.audience_policies.retain(|(audience, _)| audience != aud);
+        self.audience_policies.push((aud.to_string(), policy));
+        self.add_audience(aud)
+    }
+
+    // Checks the granted scopes against the per-call requirements, or the
+    // policies of the audiences the token holds along with the global
+    // requirements unless every one of its audiences has a policy
+    pub(crate) fn check_scopes(
+        &self,
+        claims: &Value,
+        options: &VerifyOptions,
+    ) -> Result<()> {
+        let granted = token_scopes(claims);
+        if let Some(required) = &options.required_scopes {
+            return require_all(required, &granted, None);
+        }
+        let audiences = token_audiences(claims);
+        for (aud, policy) in &self.audience_policies {
+            if audiences.contains(&aud.as_str()) && !policy.allows(&granted) {
+                bail!(Error::InsufficientScope {
+                    required: policy.scopes().to_vec(),
+                    audience: Some(aud.clone()),
+                })
+            }
+        }
+        let has_policy = |aud: &&str| {
+            self.audience_policies.iter().any(|(policy, _)| policy == aud)
+        };
+        match &self.required_scopes {
+            Some(required)
+                if audiences.is_empty()
+                    || !audiences.iter().all(has_policy) =>
+            {
+                require_all(required, &granted, None)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn require_all(
+    required: &[String],
+    granted: &[&str],
+    audience: Option<String>,
+) -> Result<()> {
+    if !required.iter().all(|scope| granted.contains(&scope.as_str())) {
+        bail!(Error::InsufficientScope {
+            required: required.to_vec(),
+            audience
+        })
+    }
+    Ok(())
+}
+
+// The audiences of the token, the aud claim is either a string or an array
+pub(crate) fn token_audiences(claims: &Value) -> Vec<&str> {
+    match claims.get("aud") {
+        Some(Value::String(aud)) => vec![aud.as_str()],
+        Some(Value::Array(aud)) => {
+            aud.iter().filter_map(Value::as_str).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::DefaultClaims;
+
+    use jwt_simple::prelude::*;
+
+    fn scoped(issuer: &str, aud: &str, scopes: &[&str]) -> String {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Scopes {
+            scp: Vec<String>,
+        }
+        let scp = scopes.iter().map(|s| s.to_string()).collect();
+        sign(
+            Claims::with_custom_claims(Scopes { scp }, Duration::from_hours(2))
+                .with_issuer(issuer)
+                .with_subject("test")
+                .with_audience(aud),
+        )
+    }
+
+    fn insufficient(err: &anyhow::Error) -> Option<(&[String], Option<&str>)> {
+        match err.downcast_ref::<Error>() {
+            Some(Error::InsufficientScope { required, audience }) => {
+                Some((required, audience.as_deref()))
+            }
+            _ => None,
+        }
+    }
+
+    #[async_test]
+    async fn applies_the_policy_of_the_matched_audience() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let issuer = server.url();
+        let verifier = Verifier::new(&issuer)
+            .await?
+            .required_scopes(&["global"])
+            .add_audience("api://other")
+            .audience_policy(
+                "api://internal",
+                ScopePolicy::all(&["internal:full", "internal:audit"]),
+            )
+            .audience_policy(
+                "api://partner",
+                ScopePolicy::any(&["partner:read", "partner:write"]),
+            );
+
+        let token = scoped(
+            &issuer,
+            "api://internal",
+            &["internal:full", "internal:audit"],
+        );
+        verifier.verify::<DefaultClaims>(&token).await?;
+        let token =
+            scoped(&issuer, "api://internal", &["internal:full", "global"]);
+        let err = verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+        let (required, audience) = insufficient(&err).unwrap();
+        assert_eq!(required, ["internal:full", "internal:audit"]);
+        assert_eq!(audience, Some("api://internal"));
+        assert!(err.to_string().contains("api://internal"));
+
+        let token = scoped(&issuer, "api://partner", &["partner:write"]);
+        verifier.verify::<DefaultClaims>(&token).await?;
+        let token = scoped(&issuer, "api://partner", &["internal:full"]);
+        let err = verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+        assert_eq!(insufficient(&err).unwrap().1, Some("api://partner"));
+
+        // An audience without a policy falls back to the global scopes
+        let token = scoped(&issuer, "api://other", &["global"]);
+        verifier.verify::<DefaultClaims>(&token).await?;
+        let token = scoped(&issuer, "api://other", &["partner:read"]);
+        let err = verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+        assert_eq!(
+            insufficient(&err).unwrap(),
+            (&["global".to_string()][..], None)
+        );
+
+        // Matching none of the audiences fails before any scope is checked
+        let token = scoped(&issuer, "api://unknown", &["global"]);
+        let err = verifier.verify::<DefaultClaims>(&token).await.unwrap_err();
+        assert!(insufficient(&err).is_none());
+        Ok(())
+    }
+
+    #[async_test]
+    async fn applies_the_global_scopes_to_other_audiences() -> Result<()> {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Scopes {
+            scp: Vec<String>,
+        }
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let issuer = server.url();
+        let verifier = Verifier::new(&issuer)
+            .await?
+            .required_scopes(&["global"])
+            .add_audience("api://other")
+            .audience_policy("api://internal", ScopePolicy::all(&["internal"]));
+        let token = |scopes: &[&str]| {
+            let scp = scopes.iter().map(|s| s.to_string()).collect();
+            sign(
+                Claims::with_custom_claims(
+                    Scopes { scp },
+                    Duration::from_hours(2),
+                )
+                .with_issuer(&issuer)
+                .with_subject("test")
+                .with_audiences(HashSet::from_strings(&[
+                    "api://internal",
+                    "api://other",
+                ])),
+            )
+        };
+
+        let err = verifier
+            .verify::<DefaultClaims>(&token(&["internal"]))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            insufficient(&err).unwrap(),
+            (&["global".to_string()][..], None)
+        );
+        let err = verifier
+            .verify::<DefaultClaims>(&token(&["global"]))
+            .await
+            .unwrap_err();
+        assert_eq!(insufficient(&err).unwrap().1, Some("api://internal"));
+        verifier
+            .verify::<DefaultClaims>(&token(&["internal", "global"]))
+            .await?;
+        Ok(())
+    }
+
+    #[test]
+    fn policies_require_all_or_any() {
+        assert!(ScopePolicy::all(&["a", "b"]).allows(&["b", "a", "c"]));
+        assert!(!ScopePolicy::all(&["a", "b"]).allows(&["a"]));
+        assert!(ScopePolicy::any(&["a", "b"]).allows(&["b"]));
+        assert!(!ScopePolicy::any(&["a", "b"]).allows(&["c"]));
+    }
+}
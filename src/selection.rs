@@ -0,0 +1,229 @@
+use crate::{secure_compare, Jwk, Jwks, TokenHeader, Verifier};
+
+/// Describes how the key that validated a token was selected.
+///
+/// Keys are tried in the following order, and the first one whose
+/// signature checks out is used:
+///
+/// 1. keys whose `kid` and `alg` both match the token header,
+/// 2. keys whose `kid` matches regardless of `alg`,
+/// 3. keys whose `x5t#S256` or `x5t` certificate thumbprint matches
+///    the token header,
+/// 4. every remaining key, only when enabled with
+///    [`Verifier::try_all_keys`].
+///
+/// Within each step keys are tried in the order of the JWKS document,
+/// so the outcome doesn't depend on how the keys are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KeySelection {
+    /// The kid and algorithm of the key match the token header.
+    ExactKid,
+    /// The kid of the key matches the token header, its algorithm
+    /// differs or is unknown.
+    Kid,
+    /// A certificate thumbprint of the key matches the token header.
+    Thumbprint,
+    /// Nothing in the token header matched the key, it was found by
+    /// trying the remaining keys.
+    FallbackScan {
+        /// The number of keys tried by the scan, including the one
+        /// that validated the token.
+        attempts: usize,
+    },
+}
+
+impl KeySelection {
+    // Position of the step in the documented order
+    fn step(&self) -> u8 {
+        match self {
+            Self::ExactKid => 0,
+            Self::Kid => 1,
+            Self::Thumbprint => 2,
+            Self::FallbackScan { .. } => 3,
+        }
+    }
+}
+
+impl Jwks {
+    // Lists the keys to try for the token in the documented order,
+    // every key appears at most once
+    pub(crate) fn candidates(
+        &self,
+        header: &TokenHeader,
+        scan: bool,
+    ) -> Vec<(&Jwk, KeySelection)> {
+        let (mut matched, rest): (Vec<_>, Vec<_>) = self
+            .keys
+            .iter()
+            .map(|jwk| (jwk, matches(jwk, header)))
+            .partition(|(_, selection)| selection.is_some());
+        // The sort is stable, keeping the document order within a step
+        matched.sort_by_key(|(_, selection)| selection.map(|s| s.step()));
+        let mut candidates: Vec<(&Jwk, KeySelection)> = matched
+            .into_iter()
+            .filter_map(|(jwk, selection)| Some((jwk, selection?)))
+            .collect();
+        if scan {
+            candidates.extend(rest.into_iter().enumerate().map(
+                |(i, (jwk, _))| {
+                    (jwk, KeySelection::FallbackScan { attempts: i + 1 })
+                },
+            ));
+        }
+        candidates
+    }
+}
+
+// Whether the header identifies a key at all, without one only the
+// fallback scan can find a key
+pub(crate) fn identifies_key(header: &TokenHeader) -> bool {
+    header.kid.is_some() || header.x5t.is_some() || header.x5t_s256.is_some()
+}
+
+fn matches(jwk: &Jwk, header: &TokenHeader) -> Option<KeySelection> {
+    if header.kid.as_deref() == Some(jwk.kid.as_str()) {
+        if jwk.alg == header.alg {
+            return Some(KeySelection::ExactKid);
+        }
+        return Some(KeySelection::Kid);
+    }
+    let same = |a: &Option<String>, b: &Option<String>| match (a, b) {
+        (Some(a), Some(b)) => secure_compare(a, b),
+        _ => false,
+    };
+    if same(&jwk.x5t_s256, &header.x5t_s256) || same(&jwk.x5t, &header.x5t) {
+        return Some(KeySelection::Thumbprint);
+    }
+    None
+}
+
+impl Verifier {
+    /// `try_all_keys` is for trying every key when nothing in the token
+    /// header identifies one, e.g. for issuers that omit the kid. Each
+    /// attempt costs a signature check, so this is best reserved for
+    /// small key sets. By default this is set to false.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{DefaultClaims, KeySelection, Verifier};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verified = Verifier::new(&issuer)
+    ///         .await?
+    ///         .try_all_keys(true)
+    ///         .verify_detailed::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     if let KeySelection::FallbackScan { attempts } = verified.key_selection {
+    ///         println!("found key {} after {attempts} attempts", verified.kid);
+    ///     }
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn try_all_keys(mut self, try_all_keys: bool) -> Self {
+        self.try_all_keys = try_all_keys;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+
+    fn key(kid: &str, alg: &str) -> Jwk {
+        Jwk { kid: kid.to_string(), alg: alg.to_string(), ..jwk() }
+    }
+
+    fn header_for(kid: Option<&str>) -> TokenHeader {
+        TokenHeader {
+            alg: "RS256".to_string(),
+            kid: kid.map(str::to_string),
+            typ: None,
+            x5t: None,
+            x5t_s256: None,
+            ..TokenHeader::default()
+        }
+    }
+
+    fn order(
+        jwks: &Jwks,
+        header: &TokenHeader,
+        scan: bool,
+    ) -> Vec<(String, KeySelection)> {
+        jwks.candidates(header, scan)
+            .into_iter()
+            .map(|(jwk, selection)| (jwk.kid.clone(), selection))
+            .collect()
+    }
+
+    #[test]
+    fn follows_the_documented_order() {
+        let thumbprinted =
+            Jwk { x5t_s256: Some("thumb".to_string()), ..key("b", "RS256") };
+        let jwks = Jwks::from_keys(vec![
+            key("c", "RS256"),
+            key("a", "RS384"),
+            thumbprinted,
+            key("a", "RS256"),
+        ]);
+        let header = TokenHeader {
+            x5t_s256: Some("thumb".to_string()),
+            ..header_for(Some("a"))
+        };
+        assert_eq!(
+            order(&jwks, &header, true),
+            vec![
+                ("a".to_string(), KeySelection::ExactKid),
+                ("a".to_string(), KeySelection::Kid),
+                ("b".to_string(), KeySelection::Thumbprint),
+                ("c".to_string(), KeySelection::FallbackScan { attempts: 1 }),
+            ]
+        );
+        assert_eq!(order(&jwks, &header, false).len(), 3);
+        assert!(order(&jwks, &header_for(None), false).is_empty());
+    }
+
+    #[test]
+    fn order_is_independent_of_how_keys_are_stored() {
+        // Enough keys that any hashing of the kids would reorder them
+        for seed in 0..32usize {
+            let kids: Vec<String> = (0..64)
+                .map(|i| format!("kid-{}", (i * 37 + seed * 11) % 64))
+                .collect();
+            let mut keys: Vec<Jwk> = kids
+                .iter()
+                .map(|kid| {
+                    key(kid, if seed % 2 == 0 { "RS256" } else { "RS384" })
+                })
+                .collect();
+            keys.push(key(&kids[seed], "RS256"));
+            let jwks = Jwks::from_keys(keys);
+            let header = header_for(Some(&kids[seed]));
+            let candidates = order(&jwks, &header, true);
+            let (first, second) = if seed % 2 == 0 {
+                (KeySelection::ExactKid, KeySelection::ExactKid)
+            } else {
+                (KeySelection::ExactKid, KeySelection::Kid)
+            };
+            assert_eq!(candidates[0], (kids[seed].clone(), first));
+            assert_eq!(candidates[1], (kids[seed].clone(), second));
+            let scanned: Vec<String> =
+                candidates[2..].iter().map(|(kid, _)| kid.clone()).collect();
+            let expected: Vec<String> = kids
+                .iter()
+                .filter(|kid| **kid != kids[seed])
+                .cloned()
+                .collect();
+            assert_eq!(scanned, expected);
+            assert_eq!(
+                candidates.last().map(|(_, selection)| *selection),
+                Some(KeySelection::FallbackScan { attempts: 63 })
+            );
+        }
+    }
+}
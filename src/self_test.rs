@@ -0,0 +1,394 @@
+use std::time::SystemTime;
+
+use jsonwebtoken::errors::ErrorKind;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::fetch::remote_fetch;
+use crate::{keys_url, parse_keys, Error, Verifier, VerifyOptions};
+
+/// Describes a step of the verification checked by [`Verifier::self_test`],
+/// in the order they are performed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum SelfTestCheck {
+    /// The token is well formed.
+    Token,
+    /// A key matching the token is known.
+    Key,
+    /// The signature is valid.
+    Signature,
+    /// The token is neither expired nor used before its nbf.
+    Expiry,
+    /// The iss claim is accepted.
+    Issuer,
+    /// The aud claim is accepted.
+    Audience,
+    /// Everything else, such as the client id, scopes, required claims,
+    /// and validators.
+    Claims,
+}
+
+const CHECKS: [SelfTestCheck; 7] = [
+    SelfTestCheck::Token,
+    SelfTestCheck::Key,
+    SelfTestCheck::Signature,
+    SelfTestCheck::Expiry,
+    SelfTestCheck::Issuer,
+    SelfTestCheck::Audience,
+    SelfTestCheck::Claims,
+];
+
+/// Describes the outcome of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckOutcome {
+    /// The check passed.
+    Passed,
+    /// The check failed.
+    Failed,
+    /// The check wasn't reached because an earlier one failed.
+    Skipped,
+}
+
+/// Describes the outcome of a single check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckResult {
+    /// The step of the verification.
+    pub check: SelfTestCheck,
+    /// Whether it passed.
+    pub outcome: CheckOutcome,
+    /// Why it failed.
+    pub detail: Option<String>,
+}
+
+/// Describes the keys held when the self test ran
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct KeySetReport {
+    /// The number of keys available for verification.
+    pub count: usize,
+    /// Whether the keys are the fallback set.
+    pub stale: bool,
+    /// When the keys were retrieved.
+    pub fetched_at: Option<SystemTime>,
+    /// How long ago the keys were retrieved, in seconds.
+    pub age_secs: Option<u64>,
+}
+
+/// Describes whether the keys endpoint could be reached
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EndpointReport {
+    /// The url of the keys endpoint.
+    pub url: String,
+    /// Whether it responded with a valid key set.
+    pub reachable: bool,
+    /// Why it couldn't be reached.
+    pub error: Option<String>,
+}
+
+/// Describes the outcome of [`Verifier::self_test`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SelfTestReport {
+    /// Whether every check passed and the keys endpoint was reachable.
+    pub passed: bool,
+    /// The outcome of each step of the verification, in order.
+    pub checks: Vec<CheckResult>,
+    /// The keys held by the Verifier.
+    pub keys: KeySetReport,
+    /// The reachability of the keys endpoint.
+    pub endpoint: EndpointReport,
+}
+
+impl SelfTestReport {
+    /// The outcome of the given check.
+    pub fn outcome(&self, check: SelfTestCheck) -> CheckOutcome {
+        self.checks
+            .iter()
+            .find(|result| result.check == check)
+            .map_or(CheckOutcome::Skipped, |result| result.outcome)
+    }
+}
+
+impl Verifier {
+    /// `self_test` runs the full verification against a canary token,
+    /// e.g. one minted by a deploy pipeline, and reports which checks
+    /// passed along with the freshness of the keys and whether the keys
+    /// endpoint is reachable. Failures are part of the report rather than
+    /// returned as errors. Neither the held keys nor the failure history,
+    /// key usage, or unknown kids are touched, so a canary signed with an
+    /// unknown key fails the key check rather than retrieving the keys
+    /// again.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let canary = std::env::var("CANARY_TOKEN")?;
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verifier = Verifier::new(&issuer)
+    ///         .await?
+    ///         .add_audience("api://default");
+    ///     let report = verifier.self_test(&canary).await;
+    ///     println!("{}", serde_json::to_string(&report)?);
+    ///     assert!(report.passed);
+    ///     Ok(())
+    /// }
+    ///```
+    pub async fn self_test(&self, canary_token: &str) -> SelfTestReport {
+        let options = VerifyOptions::default();
+        let failure = self
+            .verify_within::<Value>(canary_token.trim(), &options, false)
+            .await
+            .err()
+            .map(|e| (failed_check(&e), e.to_string()));
+        let mut reached = true;
+        let checks = CHECKS
+            .iter()
+            .map(|&check| {
+                let (outcome, detail) = match &failure {
+                    _ if !reached => (CheckOutcome::Skipped, None),
+                    Some((failed, detail)) if *failed == check => {
+                        reached = false;
+                        (CheckOutcome::Failed, Some(detail.clone()))
+                    }
+                    _ => (CheckOutcome::Passed, None),
+                };
+                CheckResult { check, outcome, detail }
+            })
+            .collect();
+        let endpoint = self.check_endpoint().await;
+        SelfTestReport {
+            passed: failure.is_none() && endpoint.reachable,
+            checks,
+            keys: self.key_set_report(),
+            endpoint,
+        }
+    }
+
+    fn key_set_report(&self) -> KeySetReport {
+        let keys = self.keys.load();
+        let fetched_at = keys.fetch.as_ref().map(|fetch| fetch.fetched_at);
+        KeySetReport {
+            count: keys.jwks.keys.len(),
+            stale: keys.stale,
+            fetched_at,
+            age_secs: fetched_at.and_then(|fetched_at| {
+                Some(fetched_at.elapsed().ok()?.as_secs())
+            }),
+        }
+    }
+
+    // Only the keys endpoint itself is tried, a fallback url answering
+    // would hide that it's down
+    async fn check_endpoint(&self) -> EndpointReport {
+        let (url, result) = match keys_url(&self.issuer, &self.config) {
+            Ok(url) => {
+                let result = match remote_fetch(
+                    &url,
+                    Some(&self.issuer),
+                    &self.config,
+                    Some(self.config.max_keys_bytes),
+                )
+                .await
+                {
+                    Ok(fetched) => parse_keys(&fetched.body).map(|_| ()),
+                    Err(e) => Err(e),
+                };
+                (url, result)
+            }
+            Err(e) => (String::new(), Err(e)),
+        };
+        EndpointReport {
+            reachable: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+            url,
+        }
+    }
+}
+
+// The step of the verification that rejected the token
+fn failed_check(error: &anyhow::Error) -> SelfTestCheck {
+    if let Some(error) = error.downcast_ref::<jsonwebtoken::errors::Error>() {
+        return match error.kind() {
+            ErrorKind::InvalidSignature
+            | ErrorKind::InvalidRsaKey(_)
+            | ErrorKind::InvalidKeyFormat => SelfTestCheck::Signature,
+            ErrorKind::ExpiredSignature | ErrorKind::ImmatureSignature => {
+                SelfTestCheck::Expiry
+            }
+            ErrorKind::InvalidIssuer => SelfTestCheck::Issuer,
+            ErrorKind::InvalidAudience => SelfTestCheck::Audience,
+            ErrorKind::MissingRequiredClaim(claim) => match claim.as_str() {
+                "exp" | "nbf" => SelfTestCheck::Expiry,
+                "iss" => SelfTestCheck::Issuer,
+                "aud" => SelfTestCheck::Audience,
+                _ => SelfTestCheck::Claims,
+            },
+            ErrorKind::InvalidSubject => SelfTestCheck::Claims,
+            _ => SelfTestCheck::Token,
+        };
+    }
+    match error.downcast_ref::<Error>() {
+        Some(
+            Error::MissingToken
+            | Error::EmptyToken
+            | Error::TokenTooLarge { .. }
+            | Error::ClaimsTooLarge { .. }
+            | Error::MalformedToken
+            | Error::InvalidToken { .. },
+        ) => SelfTestCheck::Token,
+        Some(
+            Error::MissingKeyId
+            | Error::NoMatchingKey
+            | Error::KeysUnreachable { .. }
+            | Error::KeysStatus { .. }
+            | Error::Timeout { .. },
+        ) => SelfTestCheck::Key,
+        Some(Error::TokenExpired) => SelfTestCheck::Expiry,
+        _ => SelfTestCheck::Claims,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{Config, ORG_ENDPOINT};
+
+    use jwt_simple::prelude::*;
+
+    async fn verifier(
+        server: &mut mockito::Server,
+    ) -> anyhow::Result<Verifier> {
+        mock_keys(server, vec![jwk()]);
+        Ok(Verifier::new(&server.url()).await?.add_audience("api://default"))
+    }
+
+    fn outcomes(report: &SelfTestReport) -> Vec<CheckOutcome> {
+        report.checks.iter().map(|result| result.outcome).collect()
+    }
+
+    #[async_test]
+    async fn passes_a_valid_canary() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let verifier = verifier(&mut server).await?;
+        let canary = sign(claims(&server.url()).with_audience("api://default"));
+        let report = verifier.self_test(&canary).await;
+        assert!(report.passed);
+        assert!(outcomes(&report).iter().all(|o| *o == CheckOutcome::Passed));
+        assert_eq!(report.keys.count, 1);
+        assert!(!report.keys.stale);
+        assert!(report.keys.age_secs.is_some());
+        assert!(report.endpoint.reachable);
+        let serialized = serde_json::to_value(&report)?;
+        assert_eq!(serialized["checks"][0]["check"], "token");
+        assert_eq!(serialized["checks"][0]["outcome"], "passed");
+        Ok(())
+    }
+
+    #[async_test]
+    async fn reports_the_failing_check() -> anyhow::Result<()> {
+        use CheckOutcome::{Failed, Passed, Skipped};
+
+        let mut server = mockito::Server::new_async().await;
+        let verifier = verifier(&mut server).await?;
+
+        let wrong_issuer = sign(
+            claims("https://other.example").with_audience("api://default"),
+        );
+        let report = verifier.self_test(&wrong_issuer).await;
+        assert!(!report.passed);
+        assert_eq!(
+            outcomes(&report),
+            [Passed, Passed, Passed, Passed, Failed, Skipped, Skipped]
+        );
+        assert!(report.checks[4].detail.is_some());
+
+        let wrong_audience =
+            sign(claims(&server.url()).with_audience("api://other"));
+        let report = verifier.self_test(&wrong_audience).await;
+        assert_eq!(report.outcome(SelfTestCheck::Issuer), Passed);
+        assert_eq!(report.outcome(SelfTestCheck::Audience), Failed);
+
+        let mut expired = claims(&server.url()).with_audience("api://default");
+        expired.expires_at =
+            Some(Clock::now_since_epoch() - Duration::from_hours(1));
+        let expired = sign(expired);
+        let report = verifier.self_test(&expired).await;
+        assert_eq!(report.outcome(SelfTestCheck::Signature), Passed);
+        assert_eq!(report.outcome(SelfTestCheck::Expiry), Failed);
+
+        let forged = sign_with(
+            ROTATED_KP_PEM,
+            KEY_ID,
+            claims(&server.url()).with_audience("api://default"),
+        );
+        let report = verifier.self_test(&forged).await;
+        assert_eq!(report.outcome(SelfTestCheck::Key), Passed);
+        assert_eq!(report.outcome(SelfTestCheck::Signature), Failed);
+
+        let report = verifier.self_test("not.a.token").await;
+        assert_eq!(report.outcome(SelfTestCheck::Token), Failed);
+        assert_eq!(report.outcome(SelfTestCheck::Key), Skipped);
+        Ok(())
+    }
+
+    #[async_test]
+    async fn leaves_the_shared_state_untouched() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let keys = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk()]))
+            // Once on creation, once per endpoint check, and once for the
+            // unknown kid of the verification
+            .expect(4)
+            .create();
+        let config = Config {
+            failure_history: 10,
+            kid_miss_cooldown: std::time::Duration::ZERO,
+            ..Config::default()
+        };
+        let verifier = Verifier::new_with_config(&server.url(), config)
+            .await?
+            .add_audience("api://default");
+        let unknown = sign_with(
+            ROTATED_KP_PEM,
+            "unknown",
+            claims(&server.url()).with_audience("api://default"),
+        );
+        let before = verifier.stats();
+        let report = verifier.self_test(&unknown).await;
+        assert_eq!(report.outcome(SelfTestCheck::Key), CheckOutcome::Failed);
+        let report = verifier.self_test("").await;
+        assert_eq!(report.outcome(SelfTestCheck::Token), CheckOutcome::Failed);
+        assert!(verifier.recent_failures().is_empty());
+        assert_eq!(verifier.stats(), before);
+        // The kid wasn't remembered as unknown, so a verification
+        // retrieves the keys again for it
+        let err = verifier.verify::<Value>(&unknown).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::NoMatchingKey));
+        assert_eq!(verifier.recent_failures().len(), 1);
+        keys.assert();
+        Ok(())
+    }
+
+    #[async_test]
+    async fn reports_an_unreachable_endpoint() -> anyhow::Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let verifier = verifier(&mut server).await?;
+        server.reset();
+        server.mock("GET", ORG_ENDPOINT).with_status(503).create();
+        let canary = sign(claims(&server.url()).with_audience("api://default"));
+        let report = verifier.self_test(&canary).await;
+        assert!(!report.passed);
+        assert_eq!(report.outcome(SelfTestCheck::Claims), CheckOutcome::Passed);
+        assert!(!report.endpoint.reachable);
+        assert!(report.endpoint.error.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::fetch::check_size;
+use crate::{
+    logging, parse_key_set, runtime, Config, FetchMetadata, Jwks, KeyState,
+};
+
+// Bumped whenever the layout of the snapshot or of the keys in it changes,
+// older snapshots are then skipped rather than read into the new layout
+const SNAPSHOT_VERSION: u32 = 1;
+
+// Tells apart the temporary files of concurrent writers
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
+// The layout of the snapshots in Config::snapshot_dir
+#[derive(Serialize, Deserialize)]
+struct Snapshot<F, K> {
+    version: u32,
+    issuer: String,
+    fetch: F,
+    jwks: K,
+}
+
+// The snapshot of the issuer in the directory, named after a hash of the
+// issuer so that the issuers sharing a Config keep their own. The disk
+// cache names its entries the same way after their cache keys.
+pub(crate) fn path_of(dir: &Path, issuer: &str) -> PathBuf {
+    let digest = Sha256::digest(issuer.as_bytes());
+    let hex: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+    dir.join(format!("{hex}.json"))
+}
+
+// Writes the retrieved keys to the snapshot of the issuer off the async
+// task, if configured. The snapshot is written to a temporary file that
+// then replaces it, so a crash never leaves a partial one behind. Failing
+// to write it doesn't affect the retrieval.
+pub(crate) async fn write(
+    issuer: &str,
+    config: &Config,
+    jwks: &Jwks,
+    fetch: &FetchMetadata,
+) {
+    let Some(dir) = &config.snapshot_dir else {
+        return;
+    };
+    let path = path_of(dir, issuer);
+    let written = match encode(issuer, jwks, fetch) {
+        Ok(snapshot) => {
+            let path = path.clone();
+            runtime::unblock(move || replace(&path, &snapshot)).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        logging::warn!(
+            "Unable to write the keys snapshot {}: {e:#}",
+            path.display()
+        );
+    }
+}
+
+// Deletes the snapshot of the issuer, if configured, so that the keys
+// aren't restored from it anymore
+pub(crate) async fn clear(issuer: &str, config: &Config) -> Result<()> {
+    let Some(dir) = &config.snapshot_dir else {
+        return Ok(());
+    };
+    let path = path_of(dir, issuer);
+    runtime::unblock(move || match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    })
+    .await
+}
+
+// The keys along with their fetch metadata, in the layout of the snapshot,
+// which is shared with the Redis cache
+pub(crate) fn encode(
+    issuer: &str,
+    jwks: &Jwks,
+    fetch: &FetchMetadata,
+) -> Result<Vec<u8>> {
+    let snapshot = Snapshot {
+        version: SNAPSHOT_VERSION,
+        issuer: issuer.to_string(),
+        fetch,
+        jwks,
+    };
+    serde_json::to_vec(&snapshot).context("Unable to encode the keys snapshot!")
+}
+
+// The temporary file is synced before the rename, so that the snapshot
+// holds the whole content once it's in place
+pub(crate) fn replace(path: &Path, snapshot: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    let write = WRITES.fetch_add(1, Ordering::Relaxed);
+    temporary.push(format!(".{}.{write}.tmp", std::process::id()));
+    let temporary = PathBuf::from(temporary);
+    let written = std::fs::File::create(&temporary).and_then(|mut file| {
+        file.write_all(snapshot)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| std::fs::rename(&temporary, path)) {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+// The keys of the snapshot of the issuer, if configured and written. A
+// missing snapshot is expected on the first start, any other problem is
+// logged and the snapshot skipped.
+pub(crate) fn load(issuer: &str, config: &Config) -> Option<KeyState> {
+    let path = path_of(config.snapshot_dir.as_ref()?, issuer);
+    read(&path, issuer, config).unwrap_or_else(|e| {
+        logging::warn!("Skipping the keys snapshot {}: {e:#}", path.display());
+        None
+    })
+}
+
+fn read(
+    path: &Path,
+    issuer: &str,
+    config: &Config,
+) -> Result<Option<KeyState>> {
+    let body = match std::fs::read(path) {
+        Ok(body) => body,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (jwks, fetch) =
+        decode(&path.display().to_string(), &body, issuer, config)?;
+    Ok(Some(KeyState { jwks, fetch: Some(fetch), stale: true }))
+}
+
+// Reads back the keys written by encode for the issuer, the source naming
+// where they were read from
+pub(crate) fn decode(
+    source: &str,
+    body: &[u8],
+    issuer: &str,
+    config: &Config,
+) -> Result<(Jwks, FetchMetadata)> {
+    // Leaves room for the issuer and fetch metadata next to the keys
+    let max = config.max_keys_bytes.saturating_add(4 * 1024);
+    check_size(source, body.len(), Some(max))?;
+    let snapshot: Value =
+        serde_json::from_slice(body).context("Invalid keys snapshot!")?;
+    // The version is checked first, the rest may be laid out differently
+    let version = snapshot.get("version").and_then(Value::as_u64);
+    if version != Some(SNAPSHOT_VERSION.into()) {
+        let found = version.map_or("none".to_string(), |v| v.to_string());
+        bail!(
+            "Unsupported keys snapshot version {found}, expected {SNAPSHOT_VERSION}!"
+        )
+    }
+    let snapshot: Snapshot<FetchMetadata, Value> =
+        serde_json::from_value(snapshot).context("Invalid keys snapshot!")?;
+    if snapshot.issuer != issuer {
+        bail!("The keys snapshot is of another issuer, {}!", snapshot.issuer)
+    }
+    let jwks = parse_key_set(&serde_json::to_vec(&snapshot.jwks)?, config)?;
+    Ok((jwks, snapshot.fetch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::test_support::*;
+    use crate::{DefaultClaims, Error, Verifier, ORG_ENDPOINT};
+
+    #[async_test]
+    async fn restores_the_keys_when_they_cant_be_retrieved() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Config {
+            snapshot_dir: Some(dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut server = mockito::Server::new_async().await;
+        let keys = mock_keys(&mut server, vec![jwk()]);
+        let issuer = server.url();
+        let path = path_of(dir.path(), &issuer);
+        let fetched = Verifier::new_with_config(&issuer, config.clone())
+            .await?
+            .fetch_metadata();
+        let written: Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+        assert_eq!(written["version"], SNAPSHOT_VERSION);
+        assert_eq!(written["issuer"], issuer);
+        // Only the snapshot is left behind
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+        keys.remove();
+        server.mock("GET", ORG_ENDPOINT).with_status(404).create();
+        let verifier =
+            Verifier::new_with_config(&issuer, config.clone()).await?;
+        let stats = verifier.stats();
+        assert!(stats.stale);
+        assert_eq!(stats.fetch, fetched);
+        verifier.verify::<DefaultClaims>(&token(&issuer)).await?;
+
+        // Without a snapshot the failure is fatal
+        std::fs::remove_file(&path)?;
+        let e = Verifier::new_with_config(&issuer, config).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(Error::KeysStatus { status: 404, .. })
+        ));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn clears_the_snapshot_along_with_the_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Config {
+            snapshot_dir: Some(dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut server = mockito::Server::new_async().await;
+        mock_keys(&mut server, vec![jwk()]);
+        let issuer = server.url();
+        let verifier = Verifier::new_with_config(&issuer, config).await?;
+        let path = path_of(dir.path(), &issuer);
+        assert!(path.exists());
+
+        verifier.clear_cache().await?;
+        assert!(!path.exists());
+        // Nothing left to delete
+        verifier.clear_cache().await?;
+        Ok(())
+    }
+
+    #[async_test]
+    async fn skips_snapshots_it_cant_trust() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Config {
+            snapshot_dir: Some(dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", ORG_ENDPOINT).with_status(404).create();
+        let issuer = server.url();
+        let path = path_of(dir.path(), &issuer);
+        let fetch = json!({
+            "source": format!("{issuer}{ORG_ENDPOINT}"),
+            "fetched_at": { "secs_since_epoch": 0, "nanos_since_epoch": 0 },
+            "max_age": null,
+        });
+        let jwks: Value = serde_json::from_str(&keys_body(vec![jwk()]))?;
+        let snapshot = |version, issuer: &str| {
+            json!({
+                "version": version,
+                "issuer": issuer,
+                "fetch": fetch,
+                "jwks": jwks,
+            })
+        };
+        std::fs::write(&path, snapshot(1, &issuer).to_string())?;
+        Verifier::new_with_config(&issuer, config.clone()).await?;
+
+        let untrusted = [
+            snapshot(2, &issuer),
+            snapshot(1, "https://other.example"),
+            json!({ "issuer": issuer, "keys": jwks["keys"] }),
+            json!({ "version": 1, "issuer": issuer, "jwks": jwks }),
+        ];
+        for snapshot in untrusted {
+            std::fs::write(&path, snapshot.to_string())?;
+            let e = Verifier::new_with_config(&issuer, config.clone())
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                e.downcast_ref(),
+                Some(Error::KeysStatus { status: 404, .. })
+            ));
+        }
+        Ok(())
+    }
+    #[async_test]
+    async fn keeps_a_snapshot_per_issuer() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = Config {
+            snapshot_dir: Some(dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let mut servers = Vec::new();
+        let mut verifiers = Vec::new();
+        for _ in 0..2 {
+            let mut server = mockito::Server::new_async().await;
+            mock_keys(&mut server, vec![jwk()]);
+            let issuer = server.url();
+            verifiers.push(
+                Verifier::new_with_config(&issuer, config.clone()).await?,
+            );
+            servers.push(server);
+        }
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 2);
+
+        // Unchanged keys aren't written again
+        let path = path_of(dir.path(), &servers[0].url());
+        std::fs::remove_file(&path)?;
+        verifiers[0].refresh_keys().await?;
+        assert!(!path.exists());
+
+        // The other issuer restores its own keys
+        for server in &mut servers {
+            server.reset();
+            server.mock("GET", ORG_ENDPOINT).with_status(404).create();
+        }
+        let (first, second) = (servers[0].url(), servers[1].url());
+        assert!(Verifier::new_with_config(&first, config.clone())
+            .await
+            .is_err());
+        let verifier = Verifier::new_with_config(&second, config).await?;
+        assert!(verifier.stats().stale);
+        verifier.verify::<DefaultClaims>(&token(&second)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Config, Error, ExpPolicy, FetchMetadata, Jwk, Jwks, KeyState, KeyStore,
+    Rule, ScopePolicy, Verifier, MAX_LEEWAY_SECS,
+};
+
+// Bumped whenever the serialized layout of VerifierState changes
+const STATE_VERSION: u32 = 3;
+
+/// A serializable snapshot of a [`Verifier`], including its keys.
+///
+/// Created with [`Verifier::to_state`] and restored with
+/// [`Verifier::from_state`]. Anything that can't be serialized, such as
+/// validation hooks and the jti denylist, is left out and has to be
+/// registered again on the restored instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifierState {
+    version: u32,
+    issuer: String,
+    cid: Option<String>,
+    client_id_only: bool,
+    leeway: Option<u64>,
+    aud: Option<HashSet<String>>,
+    audience_threshold: usize,
+    allowed_subjects: Option<HashSet<String>>,
+    allowed_idps: Option<HashSet<String>>,
+    required_scopes: Option<Vec<String>>,
+    rules: Vec<Rule>,
+    audience_policies: Vec<(String, ScopePolicy)>,
+    required_claims: Vec<String>,
+    accepted_typ: Option<Vec<String>>,
+    verbose_errors: bool,
+    try_all_keys: bool,
+    validate_aud: bool,
+    validate_exp: bool,
+    exp_policy: ExpPolicy,
+    validate_nbf: bool,
+    reject_future_iat: bool,
+    verify_timeout: Option<Duration>,
+    keys: Vec<Jwk>,
+    fetch: Option<FetchMetadata>,
+    stale: bool,
+}
+
+impl VerifierState {
+    /// The version of the format this snapshot was written with.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub(crate) fn keys(&self) -> &[Jwk] {
+        &self.keys
+    }
+}
+
+impl Verifier {
+    /// `to_state` captures the settings and keys of this Verifier so it can
+    /// be stored, e.g. as JSON, and restored later without a network call.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verifier = Verifier::new(&issuer).await?.client_id("Bl3hStrINgiD");
+    ///     let snapshot = serde_json::to_string(&verifier.to_state())?;
+    ///     std::fs::write("verifier.json", snapshot)?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn to_state(&self) -> VerifierState {
+        let keys = self.keys.load();
+        VerifierState {
+            version: STATE_VERSION,
+            issuer: self.issuer.clone(),
+            cid: self.cid.clone(),
+            client_id_only: self.client_id_only,
+            leeway: Some(self.leeway),
+            aud: self.aud.clone(),
+            audience_threshold: self.audience_threshold,
+            allowed_subjects: self.allowed_subjects.clone(),
+            allowed_idps: self.allowed_idps.clone(),
+            required_scopes: self.required_scopes.clone(),
+            rules: self.rules.clone(),
+            audience_policies: self.audience_policies.clone(),
+            required_claims: self.required_claims.clone(),
+            accepted_typ: self.accepted_typ.clone(),
+            verbose_errors: self.verbose_errors,
+            try_all_keys: self.try_all_keys,
+            validate_aud: self.validate_aud,
+            validate_exp: self.validate_exp,
+            exp_policy: self.exp_policy,
+            validate_nbf: self.validate_nbf,
+            reject_future_iat: self.reject_future_iat,
+            verify_timeout: self.verify_timeout,
+            keys: keys.jwks.keys.clone(),
+            fetch: keys.fetch.clone(),
+            stale: keys.stale,
+        }
+    }
+
+    /// `from_state` restores a Verifier from a snapshot taken with
+    /// [`Verifier::to_state`] without retrieving the keys again.
+    /// Snapshots written by a different version of the format are
+    /// rejected with [`Error::UnsupportedStateVersion`]. The [`Config`]
+    /// isn't part of the snapshot, the restored instance uses the one
+    /// given here for any later retrieval.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::{Config, DefaultClaims, Verifier, VerifierState};
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let token = "token";
+    ///     let snapshot = std::fs::read_to_string("verifier.json")?;
+    ///     let state: VerifierState = serde_json::from_str(&snapshot)?;
+    ///
+    ///     Verifier::from_state(state, Config::default())?
+    ///         .verify::<DefaultClaims>(&token)
+    ///         .await?;
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn from_state(state: VerifierState, config: Config) -> Result<Self> {
+        if state.version != STATE_VERSION {
+            bail!(Error::UnsupportedStateVersion {
+                found: state.version,
+                supported: STATE_VERSION,
+            })
+        }
+        let keys = KeyStore::new(KeyState {
+            jwks: Jwks::from_keys(state.keys),
+            fetch: state.fetch,
+            stale: state.stale,
+        });
+        let mut verifier = Self::with_store(&state.issuer, config, keys);
+        verifier.cid = state.cid;
+        verifier.client_id_only = state.client_id_only;
+        verifier.leeway = state
+            .leeway
+            .map_or(verifier.leeway, |leeway| leeway.min(MAX_LEEWAY_SECS));
+        verifier.aud = state.aud;
+        verifier.audience_threshold = state.audience_threshold;
+        verifier.allowed_subjects = state.allowed_subjects;
+        verifier.allowed_idps = state.allowed_idps;
+        verifier.required_scopes = state.required_scopes;
+        verifier.rules = state.rules;
+        verifier.audience_policies = state.audience_policies;
+        verifier.required_claims = state.required_claims;
+        verifier.accepted_typ = state.accepted_typ;
+        verifier.verbose_errors = state.verbose_errors;
+        verifier.try_all_keys = state.try_all_keys;
+        verifier.validate_aud = state.validate_aud;
+        verifier.validate_exp = state.validate_exp;
+        verifier.exp_policy = state.exp_policy;
+        verifier.validate_nbf = state.validate_nbf;
+        verifier.reject_future_iat = state.reject_future_iat;
+        verifier.verify_timeout = state.verify_timeout;
+        verifier.settings_changed();
+        Ok(verifier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{DefaultClaims, ORG_ENDPOINT};
+
+    #[async_test]
+    async fn restored_verifier_verifies_without_fetching() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let m = server
+            .mock("GET", ORG_ENDPOINT)
+            .with_status(200)
+            .with_body(keys_body(vec![jwk(), rotated_jwk()]))
+            .expect(1)
+            .create();
+        let verifier = Verifier::new(&server.url())
+            .await?
+            .leeway(30)
+            .add_audience("api://default")
+            .add_audience("api://admin")
+            .audience_threshold(2)
+            .validate_aud(false)
+            .validate_nbf(true)
+            .exp_policy(ExpPolicy::AllowMissingWithMaxAge(Duration::from_secs(
+                3600,
+            )))
+            .verify_timeout(Duration::from_secs(5));
+        let state = verifier.to_state();
+        let json = serde_json::to_string(&state)?;
+        let restored: VerifierState = serde_json::from_str(&json)?;
+        assert_eq!(restored, state);
+
+        let verifier = Verifier::from_state(restored, Config::default())?;
+        assert_eq!(verifier.to_state(), state);
+        verifier.verify::<DefaultClaims>(&token(&server.url())).await?;
+        // Tokens without an exp claim are still accepted
+        let mut claims = claims(&server.url());
+        claims.expires_at = None;
+        verifier.verify::<serde_json::Value>(&sign(claims)).await?;
+        m.assert();
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_other_state_versions() -> Result<()> {
+        let keys = KeyStore::new(KeyState {
+            jwks: Jwks::from_keys(vec![jwk()]),
+            fetch: None,
+            stale: false,
+        });
+        let mut state =
+            Verifier::with_store("issuer", Config::default(), keys).to_state();
+        state.version = STATE_VERSION + 1;
+        let json = serde_json::to_string(&state)?;
+        let err = Verifier::from_state(
+            serde_json::from_str(&json)?,
+            Config::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::UnsupportedStateVersion {
+                found: STATE_VERSION + 1,
+                supported: STATE_VERSION,
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! Signs tokens for the tests of applications using this crate, only
+//! available with the `test-util` feature. The key pair is a fixed test
+//! fixture published along with the crate, so a Verifier must never trust
+//! it outside of tests.
+//!
+//! ```
+//! use okta_jwt_verifier::test_util::TestIssuer;
+//! use okta_jwt_verifier::DefaultClaims;
+//!
+//! # async_std::task::block_on(async {
+//! let issuer = TestIssuer::new("https://your.domain/oauth2/default");
+//! let token = issuer.token(&issuer.claims("jane"))?;
+//! let verified = issuer.verifier()?.verify::<DefaultClaims>(&token).await?;
+//! assert_eq!(verified.claims.sub, "jane");
+//! # Ok::<(), anyhow::Error>(())
+//! # })?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::Verifier;
+
+const RSA_KP_PEM: &str = include_str!("../tests/fixtures/rsa_key.pem");
+
+const JWKS: &str = include_str!("../tests/fixtures/jwks.json");
+
+/// The id of the key [`TestIssuer`] signs with.
+pub const KEY_ID: &str = "12345";
+
+/// An issuer signing tokens with a fixed RS256 key pair, whose public key
+/// [`TestIssuer::verifier`] trusts without any request to Okta.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestIssuer {
+    issuer: String,
+}
+
+impl TestIssuer {
+    /// `new` signs tokens for the given issuer.
+    pub fn new(issuer: &str) -> Self {
+        Self { issuer: issuer.to_string() }
+    }
+
+    /// `issuer` is the issuer the tokens are signed for.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// `keys` is the JWKS document holding the public key.
+    pub fn keys(&self) -> &'static str {
+        JWKS
+    }
+
+    /// `verifier` constructs a Verifier for the issuer trusting the
+    /// public key, see [`Verifier::with_keys`].
+    pub fn verifier(&self) -> Result<Verifier> {
+        Verifier::with_keys(&self.issuer, JWKS)
+    }
+
+    /// `claims` describes a token of the given subject for the issuer,
+    /// issued now and valid for an hour, to be amended before signing,
+    /// e.g. with an audience or scopes.
+    pub fn claims(&self, subject: &str) -> Value {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        json!({
+            "iss": self.issuer,
+            "sub": subject,
+            "iat": now,
+            "exp": now + 3600,
+        })
+    }
+
+    /// `token` signs the given claims with the key pair, naming
+    /// [`KEY_ID`] in the header.
+    pub fn token<C: Serialize>(&self, claims: &C) -> Result<String> {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(KEY_ID.to_string());
+        let key = EncodingKey::from_rsa_pem(RSA_KP_PEM.as_bytes())?;
+        Ok(jsonwebtoken::encode(&header, claims, &key)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::async_test;
+    use crate::{DefaultClaims, Error};
+
+    #[async_test]
+    async fn signs_tokens_the_verifier_accepts() -> Result<()> {
+        let issuer = TestIssuer::new("https://your.domain/oauth2/default");
+        let mut claims = issuer.claims("jane");
+        claims["scp"] = json!(["read"]);
+        let token = issuer.token(&claims)?;
+        let verifier = issuer.verifier()?.required_scopes(&["read"]);
+        let verified = verifier.verify::<DefaultClaims>(&token).await?;
+        assert_eq!(verified.claims.sub, "jane");
+        assert_eq!(verified.header.kid.as_deref(), Some(KEY_ID));
+        Ok(())
+    }
+
+    #[async_test]
+    async fn signs_for_the_given_issuer_only() -> Result<()> {
+        let issuer = TestIssuer::new("https://your.domain/oauth2/default");
+        let other = TestIssuer::new("https://other.domain/oauth2/default");
+        let token = other.token(&other.claims("jane"))?;
+        let err = issuer
+            .verifier()?
+            .verify::<DefaultClaims>(&token)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidToken { reason }) if reason == "InvalidIssuer"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+// The tide middleware, only compiled with the `tide` feature. tide requests
+// aren't http::Requests, so the token is read from the header values and
+// the extensions are inserted one by one.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::{
+    bearer_token, Authenticated, DefaultResponseMapper, DenialResponse,
+    ResponseMapper, Verifier,
+};
+
+/// A [tide](https://github.com/http-rs/tide) middleware verifying the
+/// bearer token of every request, only available with the `tide`
+/// feature.
+///
+/// Accepted requests carry the extensions [`Verifier::authenticate`]
+/// inserts, such as the claims as `T` along with the
+/// [`MatchedKey`](crate::MatchedKey). Rejected requests are answered by
+/// the [`ResponseMapper`], by default with the status and the
+/// `WWW-Authenticate` and `Retry-After` headers from
+/// [`ErrorResponse`](crate::ErrorResponse), so that an Okta outage isn't
+/// reported as a bad token.
+///
+/// ```no_run
+/// use okta_jwt_verifier::{DefaultClaims, TideAuthentication, Verifier};
+///
+/// #[async_std::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let issuer = "https://your.domain/oauth2/default";
+///     let verifier = Verifier::new(&issuer).await?;
+///     let mut app = tide::new();
+///     app.with(TideAuthentication::<DefaultClaims>::new(verifier));
+///     app.at("/").get(|req: tide::Request<()>| async move {
+///         let claims = req.ext::<DefaultClaims>().unwrap();
+///         Ok(claims.sub.clone())
+///     });
+///     app.listen("0.0.0.0:8080").await?;
+///     Ok(())
+/// }
+///```
+pub struct TideAuthentication<T> {
+    verifier: Verifier,
+    mapper: Arc<dyn ResponseMapper>,
+    claims: PhantomData<fn() -> T>,
+}
+
+impl<T> TideAuthentication<T> {
+    /// `new` verifies the tokens with the given Verifier and answers
+    /// rejected requests with the [`DefaultResponseMapper`].
+    pub fn new(verifier: Verifier) -> Self {
+        Self {
+            verifier,
+            mapper: Arc::new(DefaultResponseMapper::default()),
+            claims: PhantomData,
+        }
+    }
+
+    /// `with_mapper` answers requests with the given mapper instead.
+    pub fn with_mapper(
+        mut self,
+        mapper: impl ResponseMapper + 'static,
+    ) -> Self {
+        self.mapper = Arc::new(mapper);
+        self
+    }
+}
+
+impl<T> Clone for TideAuthentication<T> {
+    fn clone(&self) -> Self {
+        Self {
+            verifier: self.verifier.clone(),
+            mapper: self.mapper.clone(),
+            claims: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TideAuthentication<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TideAuthentication")
+            .field("verifier", &self.verifier)
+            .finish_non_exhaustive()
+    }
+}
+
+// Builds the tide response from the one the mapper decided on
+fn respond(denial: DenialResponse) -> tide::Result {
+    let mut response =
+        Response::new(StatusCode::try_from(denial.status.as_u16())?);
+    for (name, value) in &denial.headers {
+        response.insert_header(name.as_str(), value.to_str()?);
+    }
+    response.set_body(denial.body);
+    Ok(response)
+}
+
+// Inserts the extensions Verifier::authenticate would insert into an
+// http::Request
+fn insert<State, T>(req: &mut Request<State>, authenticated: Authenticated<T>)
+where
+    T: Send + Sync + 'static,
+{
+    req.set_ext(authenticated.decoded);
+    req.set_ext(authenticated.claims);
+    req.set_ext(authenticated.identity);
+    if let Some(raw) = authenticated.raw {
+        req.set_ext(raw);
+    }
+    req.set_ext(authenticated.audit);
+    req.set_ext(authenticated.kid);
+}
+
+#[tide::utils::async_trait]
+impl<State, T> Middleware<State> for TideAuthentication<T>
+where
+    State: Clone + Send + Sync + 'static,
+    T: DeserializeOwned + Clone + Send + Sync + 'static,
+{
+    async fn handle(
+        &self,
+        mut req: Request<State>,
+        next: Next<'_, State>,
+    ) -> tide::Result {
+        // Every Authorization header is passed on, so that a request
+        // carrying several is handled as configured rather than using the
+        // first
+        let values = req.header("Authorization").map(|values| {
+            values.iter().map(|value| value.as_str()).collect::<Vec<_>>()
+        });
+        let duplicates = self.verifier.config.duplicate_authorization;
+        let token = bearer_token(values.unwrap_or_default(), duplicates);
+        match self.verifier.admit::<T>(token, self.mapper.as_ref()).await {
+            Ok(authenticated) => {
+                insert(&mut req, authenticated);
+                Ok(next.run(req).await)
+            }
+            Err(denial) => respond(denial),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{Config, DefaultClaims, Error, MatchedKey, RawClaims};
+
+    use jwt_simple::prelude::*;
+    use serde_json::json;
+    use tide::http::{Method, Request as HttpRequest, Url};
+
+    const ISSUER: &str = "https://your.domain/oauth2/default";
+
+    const REALM: &str = "api";
+
+    // Answers expired tokens with a 418, anything else as by default
+    struct Teapot;
+
+    impl ResponseMapper for Teapot {
+        fn on_failure(&self, error: &anyhow::Error) -> DenialResponse {
+            match error.downcast_ref::<Error>() {
+                Some(Error::TokenExpired) => DenialResponse::new(418),
+                _ => DenialResponse::for_error(error, Some(REALM)),
+            }
+        }
+    }
+
+    fn verifier() -> Verifier {
+        Verifier::with_keys(ISSUER, &keys_body(vec![jwk()])).unwrap()
+    }
+
+    fn auth(verifier: Verifier) -> TideAuthentication<DefaultClaims> {
+        TideAuthentication::new(verifier)
+            .with_mapper(DefaultResponseMapper::new(Some(REALM)))
+    }
+
+    async fn protected(req: Request<()>) -> tide::Result {
+        let claims = req.ext::<DefaultClaims>();
+        let raw = req.ext::<RawClaims>();
+        let kid = req.ext::<MatchedKey>();
+        Ok(json!({
+            "sub": claims.map(|claims| claims.sub.clone()),
+            "scp": raw.map(|RawClaims(raw)| raw["scp"].clone()),
+            "kid": kid.map(|MatchedKey(kid)| kid.clone()),
+        })
+        .into())
+    }
+
+    fn app(auth: TideAuthentication<DefaultClaims>) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(auth);
+        app.at("/").get(protected);
+        app
+    }
+
+    fn expired_token() -> String {
+        let mut claims = claims(ISSUER);
+        claims.expires_at =
+            Some(Clock::now_since_epoch() - Duration::from_hours(1));
+        sign(claims)
+    }
+
+    // Sends one Authorization header per token
+    async fn respond_to(
+        app: &tide::Server<()>,
+        tokens: &[&str],
+    ) -> tide::http::Response {
+        let url = Url::parse("http://localhost/").unwrap();
+        let mut req = HttpRequest::new(Method::Get, url);
+        for token in tokens {
+            req.append_header("Authorization", format!("Bearer {token}"));
+        }
+        app.respond(req).await.unwrap()
+    }
+
+    async fn status_of(app: &tide::Server<()>, tokens: &[&str]) -> u16 {
+        respond_to(app, tokens).await.status().into()
+    }
+
+    async fn json_of(mut res: tide::http::Response) -> serde_json::Value {
+        res.body_json().await.unwrap()
+    }
+
+    #[derive(Clone, serde::Deserialize)]
+    struct Subject {
+        sub: String,
+    }
+
+    #[async_test]
+    async fn hands_the_typed_and_raw_claims_to_handlers() {
+        let app = app(auth(verifier()));
+        let res = respond_to(&app, &[&token(ISSUER)]).await;
+        assert_eq!(u16::from(res.status()), 200);
+        let body = json_of(res).await;
+        assert_eq!(body["sub"], "test");
+        assert_eq!(body["kid"], KEY_ID);
+
+        let config = Config { retain_raw_claims: true, ..Config::default() };
+        let keys = keys_body(vec![jwk()]);
+        let verifier =
+            Verifier::with_keys_and_config(ISSUER, &keys, config).unwrap();
+        let mut app = tide::new();
+        app.with(TideAuthentication::<Subject>::new(verifier));
+        app.at("/").get(|req: Request<()>| async move {
+            let subject = req.ext::<Subject>().unwrap();
+            let RawClaims(raw) = req.ext::<RawClaims>().unwrap();
+            assert!(req.ext::<DefaultClaims>().is_none());
+            Ok(json!({ "sub": subject.sub, "iss": raw["iss"] }))
+        });
+        let body = json_of(respond_to(&app, &[&token(ISSUER)]).await).await;
+        assert_eq!(body, json!({ "sub": "test", "iss": ISSUER }));
+    }
+
+    #[async_test]
+    async fn rejects_invalid_tokens_with_a_challenge() {
+        let app = app(auth(verifier()));
+        let res = respond_to(&app, &[&expired_token()]).await;
+        assert_eq!(u16::from(res.status()), 401);
+        let challenge = res.header("WWW-Authenticate").unwrap().as_str();
+        assert!(challenge.contains("invalid_token"), "{challenge}");
+        assert!(challenge.contains("realm=\"api\""), "{challenge}");
+    }
+
+    #[async_test]
+    async fn rejects_a_bearer_scheme_without_a_token_as_bad_request() {
+        let verifier = verifier();
+        let app = app(auth(verifier.clone()));
+        assert_eq!(status_of(&app, &[""]).await, 400);
+        assert_eq!(status_of(&app, &[]).await, 401);
+        assert_eq!(verifier.stats().empty_tokens, 1);
+    }
+
+    #[async_test]
+    async fn rejects_missing_scopes_as_forbidden() {
+        let verifier = verifier().required_scopes(&["admin"]);
+        let app = app(auth(verifier));
+        let res = respond_to(&app, &[&token(ISSUER)]).await;
+        assert_eq!(u16::from(res.status()), 403);
+        let challenge = res.header("WWW-Authenticate").unwrap().as_str();
+        assert!(challenge.contains("insufficient_scope"), "{challenge}");
+    }
+
+    #[async_test]
+    async fn reports_rate_limited_keys_as_unavailable() {
+        let mut server = mockito::Server::new_async().await;
+        let issuer = format!("{}/oauth2/default", server.url());
+        let limited = server
+            .mock("GET", "/oauth2/default/v1/keys")
+            .with_status(429)
+            .with_header("Retry-After", "3600")
+            .expect(2)
+            .create_async()
+            .await;
+        // A mapper falling back to the default response keeps Retry-After
+        for teapot in [false, true] {
+            let auth =
+                TideAuthentication::new(Verifier::lazy(&issuer).unwrap());
+            let app = app(if teapot { auth.with_mapper(Teapot) } else { auth });
+            let res = respond_to(&app, &[&token(&issuer)]).await;
+            assert_eq!(u16::from(res.status()), 503);
+            let retry_after: u64 =
+                res.header("Retry-After").unwrap().as_str().parse().unwrap();
+            assert!((3500..=3600).contains(&retry_after), "{retry_after}");
+        }
+        limited.assert_async().await;
+    }
+
+    #[async_test]
+    async fn honors_the_response_mapper() {
+        let token = expired_token();
+        let default = app(auth(verifier()));
+        assert_eq!(status_of(&default, &[&token]).await, 401);
+        let teapot = app(auth(verifier()).with_mapper(Teapot));
+        assert_eq!(status_of(&teapot, &[&token]).await, 418);
+        assert_eq!(status_of(&teapot, &[]).await, 401);
+    }
+
+    #[async_test]
+    async fn handles_duplicate_authorization_headers_as_configured() {
+        let reject = app(auth(verifier()));
+        assert_eq!(status_of(&reject, &["sidecar", "client"]).await, 400);
+
+        let config = Config {
+            duplicate_authorization: crate::DuplicateAuthorization::PreferLast,
+            ..Config::default()
+        };
+        let keys = keys_body(vec![jwk()]);
+        let verifier =
+            Verifier::with_keys_and_config(ISSUER, &keys, config).unwrap();
+        let last = app(auth(verifier));
+        let tokens = ["sidecar", &token(ISSUER)];
+        assert_eq!(status_of(&last, &tokens).await, 200);
+    }
+}
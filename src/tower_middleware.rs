@@ -0,0 +1,335 @@
+// The tower middleware, only compiled with the `tower` feature, for
+// services taking http::Requests such as those of axum, tonic or hyper.
+
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::{Request, Response};
+use serde::de::DeserializeOwned;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{DefaultResponseMapper, ResponseMapper, TokenExtractor, Verifier};
+
+/// A [tower](https://github.com/tower-rs/tower) layer verifying the token
+/// of every request, only available with the `tower` feature.
+///
+/// Accepted requests are passed on to the inner service carrying the
+/// extensions [`Verifier::authenticate`] inserts, such as the claims as
+/// `T` along with the [`MatchedKey`](crate::MatchedKey). Rejected requests
+/// are answered by the [`ResponseMapper`], by default with the status and
+/// the `WWW-Authenticate` and `Retry-After` headers from
+/// [`ErrorResponse`](crate::ErrorResponse), without reaching the inner
+/// service.
+///
+/// ```no_run
+/// use okta_jwt_verifier::{AuthenticationLayer, DefaultClaims, Verifier};
+/// use tower_layer::Layer;
+///
+/// # fn wrap<S>(service: S) -> anyhow::Result<()> {
+/// # async_std::task::block_on(async {
+/// let issuer = "https://your.domain/oauth2/default";
+/// let verifier = Verifier::new(&issuer).await?;
+/// let layer = AuthenticationLayer::<DefaultClaims>::new(verifier);
+/// let service = layer.layer(service);
+/// # Ok(())
+/// # })
+/// # }
+///```
+pub struct AuthenticationLayer<T> {
+    verifier: Verifier,
+    mapper: Arc<dyn ResponseMapper>,
+    extractor: Arc<TokenExtractor>,
+    claims: PhantomData<fn() -> T>,
+}
+
+impl<T> AuthenticationLayer<T> {
+    /// `new` verifies the tokens with the given Verifier, reading them
+    /// with the default [`TokenExtractor`] and answering rejected requests
+    /// with the [`DefaultResponseMapper`].
+    pub fn new(verifier: Verifier) -> Self {
+        Self {
+            verifier,
+            mapper: Arc::new(DefaultResponseMapper::default()),
+            extractor: Arc::new(TokenExtractor::default()),
+            claims: PhantomData,
+        }
+    }
+
+    /// `with_mapper` answers requests with the given mapper instead.
+    pub fn with_mapper(
+        mut self,
+        mapper: impl ResponseMapper + 'static,
+    ) -> Self {
+        self.mapper = Arc::new(mapper);
+        self
+    }
+
+    /// `with_extractor` reads the tokens with the given extractor instead.
+    pub fn with_extractor(mut self, extractor: TokenExtractor) -> Self {
+        self.extractor = Arc::new(extractor);
+        self
+    }
+}
+
+impl<T> Clone for AuthenticationLayer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            verifier: self.verifier.clone(),
+            mapper: self.mapper.clone(),
+            extractor: self.extractor.clone(),
+            claims: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for AuthenticationLayer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthenticationLayer")
+            .field("verifier", &self.verifier)
+            .field("extractor", &self.extractor)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, T> Layer<S> for AuthenticationLayer<T> {
+    type Service = AuthenticationService<S, T>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthenticationService { inner, layer: self.clone() }
+    }
+}
+
+/// The service built by an [`AuthenticationLayer`], passing on the
+/// requests whose token was verified to the inner service.
+pub struct AuthenticationService<S, T> {
+    inner: S,
+    layer: AuthenticationLayer<T>,
+}
+
+impl<S: Clone, T> Clone for AuthenticationService<S, T> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), layer: self.layer.clone() }
+    }
+}
+
+impl<S: fmt::Debug, T> fmt::Debug for AuthenticationService<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthenticationService")
+            .field("inner", &self.inner)
+            .field("layer", &self.layer)
+            .finish()
+    }
+}
+
+impl<S, T, B, ResBody> Service<Request<B>> for AuthenticationService<S, T>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    T: DeserializeOwned + Clone + Send + Sync + 'static,
+    B: Send + 'static,
+    ResBody: From<Vec<u8>>,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Pin<
+        Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        // The inner service that was polled ready handles the request,
+        // leaving a clone to be polled for the next one
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let AuthenticationLayer { verifier, mapper, extractor, .. } =
+            self.layer.clone();
+        Box::pin(async move {
+            let duplicates = verifier.config.duplicate_authorization;
+            let token = extractor.extract_with(&req, duplicates);
+            match verifier.admit::<T>(token, mapper.as_ref()).await {
+                Ok(authenticated) => {
+                    authenticated.insert_into(req.extensions_mut());
+                    inner.call(req).await
+                }
+                Err(denial) => {
+                    let (parts, body) = denial.into_response().into_parts();
+                    Ok(Response::from_parts(parts, body.into()))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_support::*;
+    use crate::{
+        Config, DefaultClaims, DenialResponse, Error, MatchedKey, RawClaims,
+        TokenSource,
+    };
+
+    use std::convert::Infallible;
+    use std::future::{poll_fn, Ready};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use jwt_simple::prelude::*;
+    use serde_json::Value;
+
+    const ISSUER: &str = "https://your.domain/oauth2/default";
+
+    // Answers with the subject and key id it was handed, counting the
+    // requests that reach it
+    #[derive(Clone, Default)]
+    struct Protected {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Service<Request<()>> for Protected {
+        type Response = Response<Vec<u8>>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(
+            &mut self,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<()>) -> Self::Future {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            let claims = req.extensions().get::<DefaultClaims>();
+            let kid = req.extensions().get::<MatchedKey>();
+            let body = serde_json::json!({
+                "sub": claims.map(|claims| claims.sub.clone()),
+                "kid": kid.map(|MatchedKey(kid)| kid.clone()),
+                "raw": req.extensions().get::<RawClaims>().is_some(),
+            });
+            std::future::ready(Ok(Response::new(body.to_string().into())))
+        }
+    }
+
+    // Answers expired tokens with a 418, anything else as by default
+    struct Teapot;
+
+    impl ResponseMapper for Teapot {
+        fn on_failure(&self, error: &anyhow::Error) -> DenialResponse {
+            match error.downcast_ref::<Error>() {
+                Some(Error::TokenExpired) => DenialResponse::new(418),
+                _ => DenialResponse::for_error(error, self.realm()),
+            }
+        }
+    }
+
+    fn verifier() -> Verifier {
+        Verifier::with_keys(ISSUER, &keys_body(vec![jwk()])).unwrap()
+    }
+
+    fn request(tokens: &[&str]) -> Request<()> {
+        let mut req = Request::builder();
+        for token in tokens {
+            req = req.header("Authorization", format!("Bearer {token}"));
+        }
+        req.body(()).unwrap()
+    }
+
+    async fn send<S>(service: &mut S, tokens: &[&str]) -> Response<Vec<u8>>
+    where
+        S: Service<Request<()>, Response = Response<Vec<u8>>>,
+        S::Error: fmt::Debug,
+    {
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        service.call(request(tokens)).await.unwrap()
+    }
+
+    fn json_of(res: Response<Vec<u8>>) -> Value {
+        serde_json::from_slice(res.body()).unwrap()
+    }
+
+    fn expired_token() -> String {
+        let mut claims = claims(ISSUER);
+        claims.expires_at =
+            Some(Clock::now_since_epoch() - Duration::from_hours(1));
+        sign(claims)
+    }
+
+    #[async_test]
+    async fn passes_on_verified_requests_with_the_claims() {
+        let inner = Protected::default();
+        let layer = AuthenticationLayer::<DefaultClaims>::new(verifier());
+        let mut service = layer.layer(inner.clone());
+        let res = send(&mut service, &[&token(ISSUER)]).await;
+        assert_eq!(res.status(), 200);
+        let body = json_of(res);
+        assert_eq!(body["sub"], "test");
+        assert_eq!(body["kid"], KEY_ID);
+        assert_eq!(body["raw"], false);
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 1);
+
+        let config = Config { retain_raw_claims: true, ..Config::default() };
+        let keys = keys_body(vec![jwk()]);
+        let verifier =
+            Verifier::with_keys_and_config(ISSUER, &keys, config).unwrap();
+        let layer = AuthenticationLayer::<DefaultClaims>::new(verifier);
+        let mut service = layer.layer(inner.clone());
+        let body = json_of(send(&mut service, &[&token(ISSUER)]).await);
+        assert_eq!(body["raw"], true);
+    }
+
+    #[async_test]
+    async fn answers_rejected_requests_without_the_inner_service() {
+        let inner = Protected::default();
+        let layer = AuthenticationLayer::<DefaultClaims>::new(verifier());
+        let mut service = layer.layer(inner.clone());
+        let res = send(&mut service, &[&expired_token()]).await;
+        assert_eq!(res.status(), 401);
+        let challenge = res.headers()["www-authenticate"].to_str().unwrap();
+        assert!(challenge.contains("invalid_token"), "{challenge}");
+        assert_eq!(send(&mut service, &[]).await.status(), 401);
+        assert_eq!(send(&mut service, &[""]).await.status(), 400);
+        let tokens = ["sidecar", "client"];
+        assert_eq!(send(&mut service, &tokens).await.status(), 400);
+        assert_eq!(inner.calls.load(Ordering::Relaxed), 0);
+    }
+
+    #[async_test]
+    async fn honors_the_response_mapper() {
+        let layer = AuthenticationLayer::<DefaultClaims>::new(verifier())
+            .with_mapper(Teapot);
+        let mut service = layer.layer(Protected::default());
+        let res = send(&mut service, &[&expired_token()]).await;
+        assert_eq!(res.status(), 418);
+        assert_eq!(send(&mut service, &[]).await.status(), 401);
+    }
+
+    #[async_test]
+    async fn reads_the_token_with_the_given_extractor() {
+        let source = TokenSource::Cookie("access_token".into());
+        let extractor = TokenExtractor::new(vec![source]);
+        let layer = AuthenticationLayer::<DefaultClaims>::new(verifier())
+            .with_extractor(extractor);
+        let mut service = layer.layer(Protected::default());
+        let mut req = request(&[]);
+        let cookie = format!("access_token={}", token(ISSUER));
+        req.headers_mut().insert("cookie", cookie.parse().unwrap());
+        poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+        let res = service.call(req).await.unwrap();
+        assert_eq!(json_of(res)["sub"], "test");
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::{Jwks, Verifier};
+
+/// Counts the verifications of one trusted key, see
+/// [`Verifier::key_usage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct KeyUsage {
+    /// The number of tokens whose signature the key verified.
+    pub successes: u64,
+    /// The number of tokens naming the key whose signature it didn't
+    /// verify. Keys merely tried by [`Verifier::try_all_keys`] aren't
+    /// counted.
+    pub signature_failures: u64,
+}
+
+// The counters of one kid, shared by the key states holding the same key
+#[derive(Debug, Default)]
+pub(crate) struct UsageCounters {
+    successes: AtomicU64,
+    signature_failures: AtomicU64,
+}
+
+impl UsageCounters {
+    pub(crate) fn success(&self) {
+        self.successes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn signature_failure(&self) {
+        self.signature_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> KeyUsage {
+        KeyUsage {
+            successes: self.successes.load(Ordering::Relaxed),
+            signature_failures: self.signature_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) type UsageMap = HashMap<String, Arc<UsageCounters>>;
+
+// The counters for the keys of `after`, carried over from `before` for the
+// kids whose key didn't change, along with the final counts of the kids
+// that were removed or whose key changed
+pub(crate) fn carry_over(
+    usage: &UsageMap,
+    before: &Jwks,
+    after: &Jwks,
+) -> (UsageMap, Vec<(String, KeyUsage)>) {
+    let diff = before.diff(after);
+    let dropped: HashSet<&str> = diff
+        .removed
+        .iter()
+        .chain(diff.changed.iter().map(|(old, _)| old))
+        .map(|key| key.kid.as_str())
+        .collect();
+    let carried = after
+        .keys
+        .iter()
+        .map(|jwk| {
+            let counters = usage
+                .get(&jwk.kid)
+                .filter(|_| !dropped.contains(jwk.kid.as_str()))
+                .cloned()
+                .unwrap_or_default();
+            (jwk.kid.clone(), counters)
+        })
+        .collect();
+    let mut removed: Vec<(String, KeyUsage)> = Vec::new();
+    for kid in before.keys.iter().map(|jwk| jwk.kid.as_str()) {
+        if !dropped.contains(kid) || removed.iter().any(|(k, _)| k == kid) {
+            continue;
+        }
+        let counts = usage.get(kid).map(|c| c.snapshot()).unwrap_or_default();
+        removed.push((kid.to_string(), counts));
+    }
+    (carried, removed)
+}
+
+// The counts of the given keys in the order of the JWKS document, once
+// per kid
+pub(crate) fn snapshot(
+    usage: &UsageMap,
+    jwks: &Jwks,
+) -> Vec<(String, KeyUsage)> {
+    let mut counts: Vec<(String, KeyUsage)> = Vec::new();
+    for jwk in &jwks.keys {
+        if counts.iter().any(|(kid, _)| *kid == jwk.kid) {
+            continue;
+        }
+        let usage =
+            usage.get(&jwk.kid).map(|c| c.snapshot()).unwrap_or_default();
+        counts.push((jwk.kid.clone(), usage));
+    }
+    counts
+}
+
+impl Verifier {
+    /// `key_usage` counts the verifications of each trusted key by kid, in
+    /// the order of the JWKS document, e.g. to watch the traffic move from
+    /// the old kid to the new one during a rotation. The counters are
+    /// shared with the clones of this Verifier and survive refreshes that
+    /// keep the key. A removed key, or one whose material changed, loses
+    /// its counters once its final counts were reported to the
+    /// [`Verifier::on_key_rotation`] callbacks.
+    ///
+    /// ```no_run
+    /// use okta_jwt_verifier::Verifier;
+    ///
+    /// #[async_std::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let issuer = "https://your.domain/oauth2/default";
+    ///
+    ///     let verifier = Verifier::new(&issuer).await?;
+    ///     for (kid, usage) in verifier.key_usage() {
+    ///         println!("{kid}: {} verified", usage.successes);
+    ///     }
+    ///     Ok(())
+    /// }
+    ///```
+    pub fn key_usage(&self) -> Vec<(String, KeyUsage)> {
+        self.keys.usage()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use anyhow::Result;
+
+    use crate::test_support::*;
+    use crate::{DefaultClaims, KeyRotation};
+
+    fn usage(successes: u64, signature_failures: u64) -> KeyUsage {
+        KeyUsage { successes, signature_failures }
+    }
+
+    #[async_test]
+    async fn counts_the_verifications_of_each_kid() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let both = mock_keys(&mut server, vec![jwk(), rotated_jwk()]);
+        let issuer = server.url();
+        let seen: Arc<Mutex<Vec<KeyRotation>>> = Arc::default();
+        let recorded = seen.clone();
+        let verifier =
+            Verifier::new(&issuer).await?.on_key_rotation(move |rotation| {
+                recorded.lock().unwrap().push(rotation.clone())
+            });
+        let rotated =
+            sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(&issuer));
+        let forged = sign_with(ROTATED_KP_PEM, KEY_ID, claims(&issuer));
+        verifier.verify::<DefaultClaims>(&token(&issuer)).await?;
+        verifier.clone().verify::<DefaultClaims>(&token(&issuer)).await?;
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+        assert!(verifier.verify::<DefaultClaims>(&forged).await.is_err());
+        let counts = vec![
+            (KEY_ID.to_string(), usage(2, 1)),
+            (ROTATED_KEY_ID.to_string(), usage(1, 0)),
+        ];
+        assert_eq!(verifier.key_usage(), counts);
+        assert_eq!(verifier.stats().key_usage, counts);
+
+        // The counters of the kept keys survive a refresh
+        verifier.refresh_keys().await?;
+        assert_eq!(verifier.key_usage(), counts);
+
+        // The removed kid is reported once and forgotten
+        both.remove();
+        mock_keys(&mut server, vec![rotated_jwk()]);
+        verifier.refresh_keys().await?;
+        verifier.verify::<DefaultClaims>(&rotated).await?;
+        assert_eq!(
+            verifier.key_usage(),
+            vec![(ROTATED_KEY_ID.to_string(), usage(2, 0))]
+        );
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while seen.lock().unwrap().is_empty() && Instant::now() < deadline {
+            sleep(Duration::from_millis(10)).await;
+        }
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(
+            seen[0].removed_usage,
+            vec![(KEY_ID.to_string(), usage(2, 1))]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,837 @@
+// The checks a token goes through once the keys are at hand, given the
+// settings of the Verifier and a key set. Nothing here retrieves keys or
+// awaits anything, so the whole path from a token to its checked claims
+// can be tested without a runtime or a network.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+
+use anyhow::{bail, Result};
+use jsonwebtoken::{Algorithm, TokenData, Validation};
+use serde_json::Value;
+
+use crate::inspect::{alg_name, parse_header, TokenHeader};
+use crate::payload::check_payload;
+use crate::usage::UsageCounters;
+use crate::{
+    logging, selection, Error, ExpPolicy, Hook, Jwk, Jwks, KeySelection,
+    Verifier, VerifyOptions,
+};
+
+// Counts a use of the key with the given kid, e.g. into the key store, so
+// the checks themselves hold no state
+pub(crate) type RecordUsage<'a> = dyn Fn(&str, fn(&UsageCounters)) + 'a;
+
+impl Verifier {
+    // Runs the checks that don't need the keys, returning the header of
+    // the token
+    pub(crate) fn precheck(&self, token: &str) -> Result<TokenHeader> {
+        self.checked_settings()?;
+        check_token_shape(token, self.config.max_token_bytes)?;
+        self.check_claims_size(token)?;
+        if self.config.strict_payload_parsing {
+            check_payload(token)?;
+        }
+        let header = parse_header(token)?;
+        header.algorithm()?;
+        self.check_typ(&header)?;
+        if !selection::identifies_key(&header) && !self.try_all_keys {
+            bail!(Error::MissingKeyId)
+        }
+        Ok(header)
+    }
+
+    // Forgets the outcome of checking the settings and the validation built
+    // from them, called by everything changing them
+    pub(crate) fn settings_changed(&mut self) {
+        self.settings = OnceLock::new();
+        self.validation = OnceLock::new();
+    }
+
+    // Checks the settings unless they were checked since they last changed
+    pub(crate) fn checked_settings(&self) -> Result<()> {
+        match self.settings.get_or_init(|| self.check_settings().err()) {
+            Some(e) => Err(e.clone().into()),
+            None => Ok(()),
+        }
+    }
+
+    // Rejects invalid settings, and questionable ones when strict mode is
+    // enabled, otherwise they're only logged and counted
+    fn check_settings(&self) -> std::result::Result<(), Error> {
+        let audiences = self.aud.as_ref().map_or(0, HashSet::len);
+        let threshold = self.audience_threshold;
+        if threshold == 0 || (threshold > 1 && threshold > audiences) {
+            return Err(Error::InvalidAudienceThreshold {
+                threshold,
+                audiences,
+            });
+        }
+        let (leeway, threshold) = (self.leeway, self.config.leeway_threshold);
+        if leeway <= threshold {
+            return Ok(());
+        }
+        if self.config.strict {
+            return Err(Error::LeewayTooLarge { leeway, threshold });
+        }
+        self.counters.leeway_warnings.fetch_add(1, Ordering::Relaxed);
+        logging::warn!(
+            "leeway of {leeway}s for {} exceeds the threshold of \
+             {threshold}s, expired tokens will be accepted for that long",
+            self.issuer
+        );
+        Ok(())
+    }
+
+    // Rejects claims larger than configured before they're decoded
+    fn check_claims_size(&self, token: &str) -> Result<()> {
+        let (size, max) = (claims_size(token), self.config.max_claims_bytes);
+        if size > max {
+            bail!(Error::ClaimsTooLarge { size, max })
+        }
+        Ok(())
+    }
+
+    // Tries the candidate keys in order until one validates the signature,
+    // any other failure is reported right away since it would recur with
+    // every key
+    pub(crate) fn select_and_decode(
+        &self,
+        token: &str,
+        header: &TokenHeader,
+        jwks: &Jwks,
+        record: &RecordUsage<'_>,
+    ) -> Result<(String, KeySelection, TokenData<Value>)> {
+        let mut rejected = None;
+        for (jwk, selection) in jwks.candidates(header, self.try_all_keys) {
+            match self.decode(token, jwk) {
+                Ok(token_data) => {
+                    record(&jwk.kid, UsageCounters::success);
+                    return Ok((jwk.kid.clone(), selection, token_data));
+                }
+                Err(e) if is_key_mismatch(&e) => {
+                    // Keys merely tried by the fallback scan aren't the
+                    // ones the token was signed with
+                    let scanned =
+                        matches!(selection, KeySelection::FallbackScan { .. });
+                    if !scanned && is_invalid_signature(&e) {
+                        record(&jwk.kid, UsageCounters::signature_failure);
+                    }
+                    rejected = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(rejected.unwrap_or_else(|| Error::NoMatchingKey.into()))
+    }
+
+    // Attempts to decode the passed token and validate the registered claims
+    fn decode(&self, token: &str, key_jwk: &Jwk) -> Result<TokenData<Value>> {
+        let decoding_key = jsonwebtoken::DecodingKey::from_rsa_components(
+            &key_jwk.n, &key_jwk.e,
+        )?;
+        jsonwebtoken::decode::<Value>(token, &decoding_key, &self.validation())
+            .map_err(token_error)
+    }
+
+    // The validation handed to jsonwebtoken, see ValidationPolicy for the
+    // order in which the settings are applied. Only the algorithms narrowed
+    // by discovery and the hook call for a copy per verification.
+    pub(crate) fn validation(&self) -> Cow<'_, Validation> {
+        let base = self.validation.get_or_init(|| self.base_validation());
+        let info = match self.config.discovery {
+            Some(_) => self.keys.discovery().info(),
+            None => None,
+        };
+        if info.is_none() && self.validation_hook.is_none() {
+            return Cow::Borrowed(base);
+        }
+        let mut validation = base.clone();
+        if let Some(info) = info {
+            validation.algorithms.retain(|&alg| info.signs_with(alg_name(alg)));
+        }
+        if let Some(Hook(hook)) = &self.validation_hook {
+            hook(&mut validation);
+        }
+        Cow::Owned(validation)
+    }
+
+    // The validation as far as the settings of the Verifier decide it
+    fn base_validation(&self) -> Validation {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.leeway = self.leeway;
+        validation.aud = self.aud.clone();
+        let mut iss = HashSet::new();
+        iss.insert(self.issuer.clone());
+        iss.insert(format!("{}/", self.issuer));
+        validation.iss = Some(iss);
+        validation.validate_aud = self.validate_aud && !self.client_id_only;
+        validation.validate_exp = self.validate_exp;
+        validation.validate_nbf = self.validate_nbf;
+        if self.exp_policy != ExpPolicy::Require {
+            validation.required_spec_claims.remove("exp");
+        }
+        validation
+    }
+
+    // Checks the claims the crate validates itself rather than jsonwebtoken
+    pub(crate) fn check_claims(
+        &self,
+        claims: &Value,
+        options: &VerifyOptions,
+    ) -> Result<()> {
+        self.check_required_claims(claims)?;
+        self.check_missing_exp(claims)?;
+        self.check_future_iat(claims)?;
+        self.check_audience_threshold(claims)?;
+        if let Some(cid) = &self.cid {
+            let mut claim = client_claim(claims, "cid")?;
+            if self.client_id_only {
+                if claim.is_none() {
+                    claim = client_claim(claims, "azp")?;
+                }
+                if claim.is_none() {
+                    bail!(Error::MissingClientIdClaim)
+                }
+            }
+            if claim != Some(cid) {
+                bail!(Error::ClientIdMismatch)
+            }
+        }
+        let allowed_subjects = options
+            .allowed_subjects
+            .as_ref()
+            .or(self.allowed_subjects.as_ref());
+        if let Some(allowed) = allowed_subjects {
+            let sub = claims.get("sub").and_then(Value::as_str);
+            if !sub.is_some_and(|sub| allowed.contains(sub)) {
+                bail!(self.subject_not_allowed(sub, allowed))
+            }
+        }
+        if let Some(allowed) = &self.allowed_idps {
+            let idp = claims.get("idp").and_then(Value::as_str);
+            if !idp.is_some_and(|idp| allowed.contains(idp)) {
+                bail!(Error::IdpNotAllowed { idp: idp.map(str::to_string) })
+            }
+        }
+        self.check_scopes(claims, options)?;
+        self.check_rules(claims)
+    }
+
+    // jsonwebtoken accepts a token sharing any audience with the configured
+    // ones, a threshold above 1 is checked on the raw claims
+    fn check_audience_threshold(&self, claims: &Value) -> Result<()> {
+        let (threshold, Some(audiences)) = (self.audience_threshold, &self.aud)
+        else {
+            return Ok(());
+        };
+        if threshold <= 1 || !self.validate_aud || self.client_id_only {
+            return Ok(());
+        }
+        let held: HashSet<&str> = match claims.get("aud") {
+            Some(Value::String(aud)) => HashSet::from([aud.as_str()]),
+            Some(Value::Array(auds)) => {
+                auds.iter().filter_map(Value::as_str).collect()
+            }
+            _ => HashSet::new(),
+        };
+        let matched =
+            audiences.iter().filter(|aud| held.contains(aud.as_str())).count();
+        if matched < threshold {
+            bail!(Error::InvalidToken {
+                reason: format!(
+                    "aud holds {matched} of the required {threshold} audiences"
+                ),
+            })
+        }
+        Ok(())
+    }
+
+    // Only names the subject and the allowlist when verbose errors are on,
+    // and even then only as far as the redaction policy permits
+    fn subject_not_allowed(
+        &self,
+        sub: Option<&str>,
+        allowed: &HashSet<String>,
+    ) -> Error {
+        if !self.verbose_errors {
+            return Error::SubjectNotAllowed { subject: None, allowed: None };
+        }
+        let policy = &self.config.redaction;
+        let mut allowed: Vec<String> =
+            allowed.iter().map(|s| policy.redact("sub", s)).collect();
+        allowed.sort();
+        Error::SubjectNotAllowed {
+            subject: sub.map(|s| policy.redact("sub", s)),
+            allowed: Some(allowed),
+        }
+    }
+}
+
+// A claim naming the client, null counts as absent while any other value
+// that isn't a string is rejected rather than reported as a mismatch
+fn client_claim<'a>(claims: &'a Value, name: &str) -> Result<Option<&'a str>> {
+    match claims.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => bail!(Error::InvalidToken {
+            reason: format!("{name} claim present but not a string"),
+        }),
+    }
+}
+
+// Measures the decoded payload from the length of its unpadded base64
+// encoding, so that oversized claims are rejected before decoding them
+pub(crate) fn claims_size(token: &str) -> usize {
+    let encoded = token.split('.').nth(1).map_or(0, str::len);
+    encoded / 4 * 3 + (encoded % 4).saturating_sub(1)
+}
+
+// Rejects tokens that are larger than the given size or not made up of
+// three segments before they are handed to any decoding
+pub(crate) fn check_token_shape(token: &str, max: usize) -> Result<()> {
+    if token.len() > max {
+        bail!(Error::TokenTooLarge { size: token.len(), max })
+    }
+    if token.split('.').count() != 3 {
+        bail!(Error::MalformedToken)
+    }
+    Ok(())
+}
+
+// Describes a failed validation with the crate's error, while keeping the
+// jsonwebtoken error available for downcasting
+fn token_error(error: jsonwebtoken::errors::Error) -> anyhow::Error {
+    let context = match error.kind() {
+        jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+            Error::TokenExpired
+        }
+        _ => Error::InvalidToken { reason: error.to_string() },
+    };
+    anyhow::Error::new(error).context(context)
+}
+
+pub(crate) fn is_unknown_key(error: &anyhow::Error) -> bool {
+    error.downcast_ref() == Some(&Error::NoMatchingKey)
+}
+
+// Whether the failure is down to the key rather than the token,
+// in which case another candidate key may still validate it
+pub(crate) fn is_key_mismatch(error: &anyhow::Error) -> bool {
+    use jsonwebtoken::errors::ErrorKind;
+    error.downcast_ref::<jsonwebtoken::errors::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            ErrorKind::InvalidSignature
+                | ErrorKind::InvalidRsaKey(_)
+                | ErrorKind::InvalidKeyFormat
+                | ErrorKind::Base64(_)
+        )
+    })
+}
+
+// Whether the key was valid but didn't verify the signature
+pub(crate) fn is_invalid_signature(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<jsonwebtoken::errors::Error>().is_some_and(|e| {
+        *e.kind() == jsonwebtoken::errors::ErrorKind::InvalidSignature
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jwt_simple::prelude::*;
+    use serde_json::json;
+
+    use crate::test_support::*;
+    use crate::{authz::Rule, Config, RedactionPolicy};
+
+    const ISSUER: &str = "https://your.okta.com";
+
+    fn verifier() -> Verifier {
+        Verifier::with_keys(ISSUER, &keys_body(vec![jwk()])).unwrap()
+    }
+
+    // Goes through the checks verify performs once the keys are loaded,
+    // without retrieving anything
+    fn check(verifier: &Verifier, token: &str) -> Result<Value> {
+        check_with(verifier, token, &VerifyOptions::default())
+    }
+
+    fn check_with(
+        verifier: &Verifier,
+        token: &str,
+        options: &VerifyOptions,
+    ) -> Result<Value> {
+        let header = verifier.precheck(token)?;
+        let jwks = verifier.export_keys();
+        let (_, _, token_data) =
+            verifier.select_and_decode(token, &header, &jwks, &|_, _| {})?;
+        verifier.check_claims(&token_data.claims, options)?;
+        Ok(token_data.claims)
+    }
+
+    fn error(result: Result<Value>) -> Error {
+        result.unwrap_err().downcast_ref::<Error>().cloned().unwrap()
+    }
+
+    fn with_claims(custom: Value) -> String {
+        let claims =
+            Claims::with_custom_claims(custom, Duration::from_hours(2))
+                .with_issuer(ISSUER)
+                .with_subject("test");
+        sign(claims)
+    }
+
+    #[test]
+    fn builds_the_validation_once_per_settings() {
+        let verifier = verifier();
+        let first = verifier.validation();
+        assert!(matches!(first, Cow::Borrowed(_)));
+        assert!(std::ptr::eq(&*first, &*verifier.validation()));
+        assert_eq!(first.leeway, 120);
+
+        let changed = verifier.clone().leeway(5).validate_nbf(true);
+        let validation = changed.validation();
+        assert_eq!(validation.leeway, 5);
+        assert!(validation.validate_nbf);
+        assert_eq!(verifier.validation().leeway, 120);
+        let audiences = changed.add_audience("api://default");
+        assert!(audiences.validation().aud.is_some());
+
+        let hooked = verifier.with_validation_hook(|v| v.leeway = 1);
+        assert!(matches!(hooked.validation(), Cow::Owned(v) if v.leeway == 1));
+    }
+
+    #[test]
+    fn accepts_a_valid_token() -> Result<()> {
+        let claims = check(&verifier(), &token(ISSUER))?;
+        assert_eq!(claims["sub"], "test");
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_oversized_and_malformed_tokens() {
+        let config = Config { max_token_bytes: 16, ..Config::default() };
+        let verifier = Verifier::with_keys_and_config(
+            ISSUER,
+            &keys_body(vec![jwk()]),
+            config,
+        )
+        .unwrap();
+        let token = token(ISSUER);
+        assert!(matches!(
+            error(check(&verifier, &token)),
+            Error::TokenTooLarge { max: 16, .. }
+        ));
+        assert_eq!(
+            error(check(&self::verifier(), "header.claims")),
+            Error::MalformedToken
+        );
+        assert_eq!(
+            error(check(&self::verifier(), &"a.".repeat(32 * 1024 + 1))),
+            Error::TokenTooLarge { size: 64 * 1024 + 2, max: 64 * 1024 }
+        );
+    }
+
+    #[test]
+    fn measures_the_claims_before_decoding() {
+        let token = token(ISSUER);
+        let payload = token.split('.').nth(1).unwrap();
+        let size = URL_SAFE_NO_PAD.decode(payload).unwrap().len();
+        assert_eq!(claims_size(&token), size);
+        let verifier = |max| {
+            let config = Config { max_claims_bytes: max, ..Config::default() };
+            Verifier::with_keys_and_config(
+                ISSUER,
+                &keys_body(vec![jwk()]),
+                config,
+            )
+            .unwrap()
+        };
+        check(&verifier(size), &token).unwrap();
+        assert_eq!(
+            error(check(&verifier(size - 1), &token)),
+            Error::ClaimsTooLarge { size, max: size - 1 }
+        );
+    }
+
+    #[test]
+    fn rejects_unusable_headers() {
+        let verifier = verifier();
+        // alg none
+        let unsigned = format!(
+            "{}.{}.",
+            "eyJhbGciOiJub25lIiwidHlwIjoiSldUIn0",
+            token(ISSUER).split('.').nth(1).unwrap()
+        );
+        assert!(check(&verifier, &unsigned).is_err());
+
+        let verifier = self::verifier().accepted_typ(&["at+jwt"]);
+        assert!(matches!(
+            error(check(&verifier, &token(ISSUER))),
+            Error::InvalidToken { .. }
+        ));
+    }
+
+    #[test]
+    fn requires_a_kid_unless_every_key_is_tried() -> Result<()> {
+        let key_pair = RS256KeyPair::from_pem(RSA_KP_PEM)?;
+        let token = key_pair.sign(claims(ISSUER))?;
+        assert_eq!(error(check(&verifier(), &token)), Error::MissingKeyId);
+        check(&verifier().try_all_keys(true), &token)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reports_unknown_keys_and_invalid_signatures() {
+        let token = sign_with(ROTATED_KP_PEM, ROTATED_KEY_ID, claims(ISSUER));
+        let result = check(&verifier(), &token);
+        assert!(is_unknown_key(result.as_ref().unwrap_err()));
+
+        // The kid is known, but names another key
+        let token = sign_with(ROTATED_KP_PEM, KEY_ID, claims(ISSUER));
+        let e = check(&verifier(), &token).unwrap_err();
+        assert!(is_key_mismatch(&e));
+        assert!(is_invalid_signature(&e));
+        assert!(matches!(e.downcast_ref(), Some(Error::InvalidToken { .. })));
+    }
+
+    #[test]
+    fn records_the_usage_of_the_named_key_only() -> Result<()> {
+        let verifier = verifier().try_all_keys(true);
+        let jwks = Jwks::from_json(&keys_body(vec![rotated_jwk(), jwk()]))?;
+        let recorded = RefCell::new(Vec::new());
+        let record = |kid: &str, _: fn(&UsageCounters)| {
+            recorded.borrow_mut().push(kid.to_string())
+        };
+
+        let token = token(ISSUER);
+        let header = verifier.precheck(&token)?;
+        verifier.select_and_decode(&token, &header, &jwks, &record)?;
+        assert_eq!(*recorded.borrow(), [KEY_ID]);
+
+        // Only the key named by the token counts a signature failure, not
+        // the ones tried along the way
+        recorded.borrow_mut().clear();
+        let token = sign_with(ROTATED_KP_PEM, KEY_ID, claims(ISSUER));
+        let header = verifier.precheck(&token)?;
+        let jwks = Jwks::from_json(&keys_body(vec![jwk()]))?;
+        assert!(verifier
+            .select_and_decode(&token, &header, &jwks, &record)
+            .is_err());
+        assert_eq!(*recorded.borrow(), [KEY_ID]);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_expired_tokens_and_other_issuers() {
+        let mut claims = claims(ISSUER);
+        claims.expires_at =
+            Some(Clock::now_since_epoch() - Duration::from_hours(1));
+        let err = check(&verifier(), &sign(claims)).unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&Error::TokenExpired));
+        assert_eq!(
+            err.downcast_ref::<jsonwebtoken::errors::Error>().map(|e| e.kind()),
+            Some(&jsonwebtoken::errors::ErrorKind::ExpiredSignature)
+        );
+
+        let token = token("https://other.okta.com");
+        assert!(matches!(
+            error(check(&verifier(), &token)),
+            Error::InvalidToken { .. }
+        ));
+    }
+
+    #[test]
+    fn checks_the_audience() {
+        let token = with_claims(json!({ "aud": ["api://a", "api://b"] }));
+        let string = with_claims(json!({ "aud": "api://a" }));
+        let other = with_claims(json!({ "aud": "api://b" }));
+        let one = verifier().add_audience("api://a");
+        let many = one.clone().add_audience("api://c");
+        for verifier in [&one, &many] {
+            check(verifier, &token).unwrap();
+            check(verifier, &string).unwrap();
+            assert!(matches!(
+                error(check(verifier, &other)),
+                Error::InvalidToken { reason } if reason == "InvalidAudience"
+            ));
+        }
+        assert!(matches!(
+            error(check(&verifier().add_audience("api://c"), &token)),
+            Error::InvalidToken { .. }
+        ));
+
+        let verifier = |threshold| many.clone().audience_threshold(threshold);
+        check(&verifier(1), &token).unwrap();
+        assert!(matches!(
+            error(check(&verifier(2), &token)),
+            Error::InvalidToken { reason }
+                if reason == "aud holds 1 of the required 2 audiences"
+        ));
+        let both = with_claims(json!({ "aud": ["api://a", "api://c"] }));
+        check(&verifier(2), &both).unwrap();
+        assert_eq!(verifier(2).effective_policy().audience_threshold, 2);
+        for threshold in [0, 3] {
+            let expected =
+                Error::InvalidAudienceThreshold { threshold, audiences: 2 };
+            assert_eq!(error(check(&verifier(threshold), &both)), expected);
+            let err = verifier(threshold).build().unwrap_err();
+            assert_eq!(err.downcast_ref(), Some(&expected));
+        }
+    }
+
+    #[test]
+    fn applies_the_leeway() -> Result<()> {
+        let mut claims = claims(ISSUER);
+        claims.expires_at =
+            Some(Clock::now_since_epoch() - Duration::from_secs(1));
+        let expired = sign(claims);
+        assert_eq!(verifier().effective_leeway(), 120);
+        check(&verifier(), &expired)?;
+
+        let verifier = verifier().leeway(0);
+        assert_eq!(verifier.effective_leeway(), 0);
+        assert_eq!(verifier.effective_policy().leeway, 0);
+        assert_eq!(error(check(&verifier, &expired)), Error::TokenExpired);
+        check(&verifier, &token(ISSUER))?;
+        let restored =
+            Verifier::from_state(verifier.to_state(), Config::default())?;
+        assert_eq!(restored.effective_leeway(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_mode_rejects_a_large_leeway() {
+        let verifier = verifier().strict().leeway(3600);
+        assert!(matches!(
+            error(check(&verifier, &token(ISSUER))),
+            Error::LeewayTooLarge { leeway: 3600, .. }
+        ));
+
+        let config =
+            Config { strict: true, leeway_threshold: 60, ..Config::default() };
+        let verifier = Verifier::with_keys_and_config(
+            ISSUER,
+            &keys_body(vec![jwk()]),
+            config,
+        )
+        .unwrap();
+        // The default leeway is lowered to the threshold
+        assert_eq!(verifier.effective_leeway(), 60);
+        verifier.clone().leeway(60).build().unwrap();
+        let verifier = verifier.leeway(61);
+        let expected = Error::LeewayTooLarge { leeway: 61, threshold: 60 };
+        let err = verifier.clone().build().unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&expected));
+        assert_eq!(error(check(&verifier, &token(ISSUER))), expected);
+        assert_eq!(verifier.stats().leeway_warnings, 0);
+    }
+
+    #[test]
+    fn checks_the_timestamps_crate_side() {
+        let token = token(ISSUER);
+        assert_eq!(
+            error(check(&verifier().require_claims(&["uid"]), &token)),
+            Error::MissingClaim { claim: "uid".into() }
+        );
+
+        // No exp, issued longer ago than the max age allows
+        let mut claims = claims(ISSUER);
+        claims.expires_at = None;
+        claims.issued_at =
+            Some(Clock::now_since_epoch() - Duration::from_hours(3));
+        claims.invalid_before = claims.issued_at;
+        let verifier =
+            verifier().exp_policy(ExpPolicy::AllowMissingWithMaxAge(
+                std::time::Duration::from_secs(3600),
+            ));
+        assert_eq!(error(check(&verifier, &sign(claims))), Error::TokenExpired);
+
+        let mut claims = self::claims(ISSUER);
+        let iat = Clock::now_since_epoch() + Duration::from_hours(1);
+        claims.issued_at = Some(iat);
+        assert!(matches!(
+            error(check(&self::verifier(), &sign(claims))),
+            Error::IssuedInFuture { .. }
+        ));
+    }
+
+    #[test]
+    fn checks_the_client_id() {
+        let cid = with_claims(json!({ "cid": "a-client" }));
+        check(&verifier().client_id("a-client"), &cid).unwrap();
+        let e = check(&verifier().client_id("another"), &cid).unwrap_err();
+        assert_eq!(e.to_string(), "client_id validation failed!");
+        assert_eq!(e.downcast_ref(), Some(&Error::ClientIdMismatch));
+        assert_eq!(Error::ClientIdMismatch.code(), "client_id_mismatch");
+        assert_eq!(Error::ClientIdMismatch.status_hint(), 401);
+
+        let azp = with_claims(json!({ "azp": "a-client" }));
+        check(&verifier().client_id_only("a-client"), &azp).unwrap();
+        assert_eq!(
+            error(check(
+                &verifier().client_id_only("a-client"),
+                &token(ISSUER)
+            )),
+            Error::MissingClientIdClaim
+        );
+
+        let not_a_string = |claim: &str| Error::InvalidToken {
+            reason: format!("{claim} claim present but not a string"),
+        };
+        for cid in [json!(42), json!(["a-client"])] {
+            let token = with_claims(json!({ "cid": cid }));
+            assert_eq!(
+                error(check(&verifier().client_id("a-client"), &token)),
+                not_a_string("cid")
+            );
+        }
+        // A null cid is treated as if it was absent
+        let null = with_claims(json!({ "cid": null }));
+        assert_eq!(
+            error(check(&verifier().client_id("a-client"), &null)),
+            Error::ClientIdMismatch
+        );
+        let only = verifier().client_id_only("a-client");
+        assert_eq!(error(check(&only, &null)), Error::MissingClientIdClaim);
+        let null_cid = with_claims(json!({ "cid": null, "azp": "a-client" }));
+        check(&only, &null_cid).unwrap();
+        let numeric_azp = with_claims(json!({ "cid": null, "azp": 7 }));
+        assert_eq!(error(check(&only, &numeric_azp)), not_a_string("azp"));
+    }
+
+    #[test]
+    fn client_id_only_skips_the_audience() {
+        let token = |client: Value| {
+            let claims =
+                Claims::with_custom_claims(client, Duration::from_hours(2))
+                    .with_issuer(ISSUER)
+                    .with_audience("api://unrelated");
+            sign(claims)
+        };
+        let verifier = verifier().client_id_only("a-client");
+        assert!(!verifier.effective_policy().validate_aud);
+        let with_cid = token(json!({ "cid": "a-client" }));
+        check(&verifier, &with_cid).unwrap();
+        check(&verifier, &token(json!({ "azp": "a-client" }))).unwrap();
+        assert_eq!(
+            error(check(&verifier, &token(json!({ "cid": "another" })))),
+            Error::ClientIdMismatch
+        );
+
+        // Later audience settings don't bring the aud validation back
+        let verifier = verifier.validate_aud(true).add_audience("api://other");
+        assert!(!verifier.effective_policy().validate_aud);
+        check(&verifier, &with_cid).unwrap();
+    }
+
+    #[test]
+    fn checks_the_subject_and_idp() {
+        let subjects = HashSet::from(["someone".to_string()]);
+        let verifier = |verbose| {
+            verifier()
+                .allowed_subjects(subjects.clone())
+                .verbose_errors(verbose)
+        };
+        let no_sub =
+            sign(Claims::create(Duration::from_hours(2)).with_issuer(ISSUER));
+        for token in [token(ISSUER), no_sub] {
+            assert_eq!(
+                error(check(&verifier(false), &token)),
+                Error::SubjectNotAllowed { subject: None, allowed: None }
+            );
+        }
+        // The redaction policy still applies to verbose errors
+        let err = check(&verifier(true), &token(ISSUER)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&Error::SubjectNotAllowed {
+                subject: Some("<redacted>".into()),
+                allowed: Some(vec!["<redacted>".into()]),
+            })
+        );
+        assert!(!format!("{err} {err:?} {err:#}").contains("someone"));
+
+        let config = Config {
+            redaction: RedactionPolicy::default().allow("sub"),
+            ..Config::default()
+        };
+        let verbose = Verifier::with_keys_and_config(
+            ISSUER,
+            &keys_body(vec![jwk()]),
+            config,
+        )
+        .unwrap()
+        .allowed_subjects(HashSet::from(["test".into(), "other".into()]))
+        .verbose_errors(true);
+        check(&verbose, &token(ISSUER)).unwrap();
+        let denied = sign(claims(ISSUER).with_subject("intruder"));
+        assert_eq!(
+            error(check(&verbose, &denied)),
+            Error::SubjectNotAllowed {
+                subject: Some("intruder".into()),
+                allowed: Some(vec!["other".into(), "test".into()]),
+            }
+        );
+
+        // Options given to the verification take precedence
+        let options = VerifyOptions {
+            allowed_subjects: Some(HashSet::from(["test".to_string()])),
+            ..VerifyOptions::default()
+        };
+        check_with(&verifier(false), &token(ISSUER), &options).unwrap();
+
+        let verifier = self::verifier().require_idp_any(&["0oa-okta"]);
+        let token = with_claims(json!({ "idp": "0oa-okta" }));
+        check(&verifier, &token).unwrap();
+        let token = with_claims(json!({ "idp": "0oa-other" }));
+        assert_eq!(
+            error(check(&verifier, &token)),
+            Error::IdpNotAllowed { idp: Some("0oa-other".into()) }
+        );
+        assert_eq!(
+            error(check(&verifier, &self::token(ISSUER))),
+            Error::IdpNotAllowed { idp: None }
+        );
+    }
+
+    #[test]
+    fn checks_the_scopes_and_rules() {
+        let token =
+            with_claims(json!({ "scp": ["read"], "groups": ["staff"] }));
+        let rfc9068 = with_claims(json!({ "scope": "read write" }));
+        check(&verifier().required_scopes(&["read"]), &token).unwrap();
+        check(&verifier().required_scopes(&["write", "read"]), &rfc9068)
+            .unwrap();
+        let scoped = || verifier().required_scopes(&["read", "write"]);
+        assert_eq!(
+            error(check(&scoped(), &token)),
+            Error::InsufficientScope {
+                required: vec!["read".into(), "write".into()],
+                audience: None,
+            }
+        );
+        let options = VerifyOptions {
+            required_scopes: Some(vec!["read".into()]),
+            ..VerifyOptions::default()
+        };
+        check_with(&scoped(), &token, &options).unwrap();
+
+        check(&verifier().require_rule(Rule::group("staff")), &token).unwrap();
+        assert!(matches!(
+            error(check(
+                &verifier().require_rule(Rule::group("admins")),
+                &token
+            )),
+            Error::RuleNotSatisfied { .. }
+        ));
+    }
+}
@@ -0,0 +1,86 @@
+// Builds a small program against the deprecated `key` and `token` modules
+// and `verify` function, both the functions built around `key::Keys` and the
+// items kept from the 0.3 releases, to make sure code that hasn't migrated
+// to the Verifier keeps compiling.
+#![cfg(all(
+    feature = "compat",
+    any(feature = "client-reqwest", feature = "client-surf")
+))]
+#![allow(deprecated)]
+
+use anyhow::Result;
+use jwt_simple::prelude::*;
+use okta_jwt_verifier::key::{Keys, JWK, JWKS};
+use okta_jwt_verifier::{key, token, verify, DefaultClaims};
+
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+use async_std::test as async_test;
+#[cfg(not(all(
+    feature = "client-surf",
+    not(feature = "client-reqwest")
+)))]
+use tokio::test as async_test;
+
+const RSA_KP_PEM: &str = include_str!("fixtures/rsa_key.pem");
+
+const KEY_ID: &str = "12345";
+
+const JWKS_BODY: &str = include_str!("fixtures/jwks.json");
+
+fn sign(issuer: &str) -> Result<String> {
+    let key_pair = RS256KeyPair::from_pem(RSA_KP_PEM)?.with_key_id(KEY_ID);
+    let claims = Claims::create(Duration::from_hours(2))
+        .with_issuer(issuer)
+        .with_subject("subject");
+    key_pair.sign(claims)
+}
+
+// The way callers of the module API verified tokens
+async fn verify_with_keys(issuer: &str, token: &str) -> Result<DefaultClaims> {
+    let keys: Keys = key::get(issuer, None).await?;
+    let token_data =
+        token::decode::<DefaultClaims>(token, issuer, &keys).await?;
+    Ok(token_data.claims)
+}
+
+// The way callers of the 0.3 releases looked up the key of a token
+fn find_key(keys: &Keys, token: &str) -> Result<Option<JWK>> {
+    let kid: String = token::key_id(token)?;
+    let jwks: JWKS = keys.into();
+    Ok(jwks.where_id(&kid).cloned())
+}
+
+#[async_test]
+async fn module_api_verifies_tokens() -> Result<()> {
+    let mut server = mockito::Server::new_async().await;
+    server
+        .mock("GET", "/v1/keys")
+        .with_status(200)
+        .with_header("Cache-Control", "max-age=3600")
+        .with_body(JWKS_BODY)
+        .create();
+    let issuer = server.url();
+
+    let claims = verify_with_keys(&issuer, &sign(&issuer)?).await?;
+    assert_eq!(claims.sub, "subject");
+    let token_data = verify::<DefaultClaims>(&issuer, &sign(&issuer)?).await?;
+    assert_eq!(token_data.claims.sub, "subject");
+
+    let keys = key::get(&issuer, Some("/v1/keys")).await?;
+    assert_eq!(keys.jwks.keys.len(), 1);
+    assert_eq!(keys.max_age, Some(std::time::Duration::from_secs(3600)));
+    let other = sign("https://other.issuer")?;
+    assert!(token::decode::<DefaultClaims>(&other, &issuer, &keys)
+        .await
+        .is_err());
+
+    let jwk = find_key(&keys, &sign(&issuer)?)?.unwrap();
+    assert_eq!((jwk.kty.as_str(), jwk.uses.as_str()), ("RSA", "sig"));
+    let other_key = RS256KeyPair::generate(2048)?.with_key_id("other");
+    let claims = Claims::create(Duration::from_hours(2)).with_issuer(&issuer);
+    let other = other_key.sign(claims)?;
+    assert!(find_key(&keys, &other)?.is_none());
+    let err = verify::<DefaultClaims>(&issuer, &other).await.unwrap_err();
+    assert_eq!(err.to_string(), "No matching key found!");
+    Ok(())
+}
@@ -0,0 +1,79 @@
+// Builds the crate with every feature on its own and along with every other
+// one, the way `cargo hack --feature-powerset --depth 2` would, making sure
+// the features stay additive. Every combination also runs tests/offline.rs.
+// A build per combination takes a while, so this is ignored by default:
+//
+//     cargo test --test feature_combinations -- --ignored
+
+use std::path::Path;
+use std::process::Command;
+
+// Every feature of Cargo.toml but the default one
+const FEATURES: [&str; 13] = [
+    "client-surf",
+    "client-reqwest",
+    "cache-surf",
+    "cache-reqwest",
+    "cache-memory",
+    "cache-redis",
+    "okta-config",
+    "compat",
+    "log",
+    "tracing",
+    "tide",
+    "tower",
+    "test-util",
+];
+
+// No feature, every feature on its own, and every pair of features
+fn combinations() -> Vec<Vec<&'static str>> {
+    let mut combinations = vec![Vec::new()];
+    for (i, first) in FEATURES.iter().enumerate() {
+        combinations.push(vec![*first]);
+        for second in &FEATURES[i + 1..] {
+            combinations.push(vec![*first, *second]);
+        }
+    }
+    combinations
+}
+
+fn cargo(args: &[&str], features: &[&str]) -> bool {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new(env!("CARGO"))
+        .args(args)
+        .args(["--no-default-features", "--features", &features.join(",")])
+        .current_dir(manifest)
+        // Kept apart so the regular builds aren't invalidated
+        .env("CARGO_TARGET_DIR", manifest.join("target/feature-combinations"))
+        .status()
+        .expect("cargo runs");
+    status.success()
+}
+
+#[test]
+fn covers_every_feature() {
+    let manifest = include_str!("../Cargo.toml");
+    let (_, features) = manifest.split_once("[features]").unwrap();
+    let declared: Vec<_> = features
+        .lines()
+        .take_while(|line| !line.starts_with('['))
+        .filter_map(|line| line.split_once(" = "))
+        .map(|(name, _)| name.trim())
+        .filter(|name| *name != "default")
+        .collect();
+    assert_eq!(declared, FEATURES);
+}
+
+#[test]
+#[ignore = "builds the crate once per combination of features"]
+fn builds_every_combination_of_features() {
+    let failed: Vec<_> = combinations()
+        .into_iter()
+        .filter(|features| {
+            !cargo(&["check", "--lib", "--tests"], features)
+                || !cargo(&["test", "--test", "offline"], features)
+        })
+        .map(|features| features.join(","))
+        .collect();
+    assert!(failed.is_empty(), "failing combinations: {failed:?}");
+}
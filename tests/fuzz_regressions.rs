@@ -0,0 +1,162 @@
+// Regression inputs promoted from the fuzz corpus, see the fuzz directory
+// for the targets that produced them.
+
+// The keys are retrieved from a mock server
+#![cfg(any(feature = "client-reqwest", feature = "client-surf"))]
+
+use anyhow::Result;
+use jwt_simple::prelude::*;
+use okta_jwt_verifier::{DefaultClaims, Error, Verifier};
+
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+use async_std::test as async_test;
+#[cfg(not(all(
+    feature = "client-surf",
+    not(feature = "client-reqwest")
+)))]
+use tokio::test as async_test;
+
+// The key pair signing the test tokens, and the JWKS holding its public key
+const RSA_KP_PEM: &str = include_str!("fixtures/rsa_key.pem");
+
+const KEY_ID: &str = "12345";
+
+const JWKS: &str = include_str!("fixtures/jwks.json");
+
+async fn verifier(server: &mut mockito::ServerGuard) -> Result<Verifier> {
+    server
+        .mock("GET", "/oauth2/v1/keys")
+        .with_status(200)
+        .with_body(JWKS)
+        .create();
+    Verifier::new(&server.url()).await
+}
+
+fn error_of(result: Result<impl std::fmt::Debug>) -> anyhow::Error {
+    result.expect_err("input should have been rejected")
+}
+
+#[async_test]
+async fn malformed_tokens_are_rejected() -> Result<()> {
+    let mut server = mockito::Server::new_async().await;
+    let verifier = verifier(&mut server).await?;
+    let inputs = [
+        "",
+        ".",
+        "..",
+        "...",
+        "a.b.c",
+        "a.b.c.d",
+        "eyJ.eyJ.",
+        "\u{0}.\u{0}.\u{0}",
+        "🦀.🦀.🦀",
+        // header is not JSON
+        "bm90IGpzb24.e30.c2ln",
+        // header is JSON but not an object
+        "WzFd.e30.c2ln",
+        // header declares the none algorithm
+        "eyJhbGciOiJub25lIiwia2lkIjoiMTIzNDUifQ.e30.",
+        // header segment with invalid base64 padding
+        "eyJhbGciOiJSUzI1NiJ9===.e30.c2ln",
+    ];
+    for input in inputs {
+        error_of(verifier.verify::<DefaultClaims>(input).await);
+    }
+    Ok(())
+}
+
+#[async_test]
+async fn token_without_key_id_is_rejected() -> Result<()> {
+    let mut server = mockito::Server::new_async().await;
+    let verifier = verifier(&mut server).await?;
+    // {"alg":"RS256","typ":"JWT"}
+    let token = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.e30.c2ln";
+    let err = error_of(verifier.verify::<DefaultClaims>(token).await);
+    assert_eq!(err.downcast_ref::<Error>(), Some(&Error::MissingKeyId));
+    Ok(())
+}
+
+#[async_test]
+async fn oversized_tokens_are_rejected() -> Result<()> {
+    let mut server = mockito::Server::new_async().await;
+    let verifier = verifier(&mut server).await?;
+    let token = format!("{}.e30.c2ln", "A".repeat(1024 * 1024));
+    let err = error_of(verifier.verify::<DefaultClaims>(&token).await);
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::TokenTooLarge { .. })
+    ));
+    Ok(())
+}
+
+#[async_test]
+async fn hostile_key_sets_are_rejected() -> Result<()> {
+    let deeply_nested = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+    let documents = [
+        "",
+        "[]",
+        "null",
+        r#"{"keys":null}"#,
+        r#"{"keys":[{"kid":1}]}"#,
+        r#"{"keys":[{"kty":"RSA","alg":"RS256","kid":"1","use":"sig","e":1e999,"n":""}]}"#,
+        &deeply_nested,
+    ];
+    for document in documents {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/oauth2/v1/keys")
+            .with_status(200)
+            .with_body(document)
+            .create();
+        error_of(Verifier::new(&server.url()).await);
+    }
+    Ok(())
+}
+
+#[async_test]
+async fn unusable_key_material_does_not_panic() -> Result<()> {
+    let key_pair = RS256KeyPair::from_pem(RSA_KP_PEM)?.with_key_id(KEY_ID);
+    let mut server = mockito::Server::new_async().await;
+    let claims = Claims::create(Duration::from_hours(2))
+        .with_issuer(server.url())
+        .with_subject("test");
+    let token = key_pair.sign(claims)?;
+    for (e, n) in [("", ""), ("AQAB", ""), ("", "AQAB"), ("!!", "??")] {
+        let document = format!(
+            r#"{{"keys":[{{"kty":"RSA","alg":"RS256","kid":"{KEY_ID}","use":"sig","e":"{e}","n":"{n}"}}]}}"#
+        );
+        let m = server
+            .mock("GET", "/oauth2/v1/keys")
+            .with_status(200)
+            .with_body(document)
+            .create();
+        // Keys that can't be decoded are skipped, leaving none at all
+        match Verifier::new(&server.url()).await {
+            Ok(verifier) => {
+                error_of(verifier.verify::<DefaultClaims>(&token).await);
+            }
+            Err(e) => assert!(matches!(
+                e.downcast_ref(),
+                Some(Error::NoUsableKeys { received: 1, .. })
+            )),
+        }
+        m.remove();
+    }
+    Ok(())
+}
+
+#[async_test]
+async fn huge_leeway_does_not_overflow() -> Result<()> {
+    let key_pair = RS256KeyPair::from_pem(RSA_KP_PEM)?.with_key_id(KEY_ID);
+    let mut server = mockito::Server::new_async().await;
+    let claims = Claims::create(Duration::from_hours(2))
+        .with_issuer(server.url())
+        .with_subject("test");
+    let token = key_pair.sign(claims)?;
+    verifier(&mut server)
+        .await?
+        .leeway(u64::MAX)
+        .verify::<DefaultClaims>(&token)
+        .await?;
+    Ok(())
+}
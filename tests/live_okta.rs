@@ -0,0 +1,170 @@
+// Exercises the Verifier against a real Okta org, catching behavior the
+// mockito tests can't, such as the headers Okta sends along with the
+// keys. Ignored by default, run with
+//
+//   OKTA_TEST_ISSUER=https://your.okta.com/oauth2/default \
+//   OKTA_TEST_CLIENT_ID=... OKTA_TEST_CLIENT_SECRET=... \
+//   cargo test --test live_okta -- --ignored
+//
+// The client needs the client credentials grant enabled on the issuer's
+// authorization server. OKTA_TEST_SCOPE names the scope to request, and
+// OKTA_TEST_AUDIENCE the expected audience, api://default by default.
+// Without the variables the tests only print that they were skipped.
+#![cfg(feature = "client-reqwest")]
+
+use std::env;
+
+use anyhow::{bail, Result};
+use okta_jwt_verifier::{DefaultClaims, Verifier};
+use serde_json::Value;
+
+struct Org {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    audience: String,
+}
+
+impl Org {
+    fn from_env() -> Option<Self> {
+        let var = |name| env::var(name).ok().filter(|v| !v.is_empty());
+        let org = Self {
+            issuer: var("OKTA_TEST_ISSUER")?,
+            client_id: var("OKTA_TEST_CLIENT_ID")?,
+            client_secret: var("OKTA_TEST_CLIENT_SECRET")?,
+            scope: var("OKTA_TEST_SCOPE"),
+            audience: var("OKTA_TEST_AUDIENCE")
+                .unwrap_or_else(|| "api://default".to_string()),
+        };
+        Some(org)
+    }
+
+    // The metadata of the authorization server, which names its endpoints
+    async fn metadata(&self) -> Result<Value> {
+        let url = format!(
+            "{}/.well-known/oauth-authorization-server",
+            self.issuer.trim_end_matches('/')
+        );
+        Ok(reqwest::get(url).await?.error_for_status()?.json().await?)
+    }
+
+    // Mints an access token with the client credentials grant
+    async fn token(&self) -> Result<String> {
+        let metadata = self.metadata().await?;
+        let Some(endpoint) = metadata["token_endpoint"].as_str() else {
+            bail!("the metadata doesn't name a token endpoint")
+        };
+        let mut form = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        let response: Value = reqwest::Client::new()
+            .post(endpoint)
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        match response["access_token"].as_str() {
+            Some(token) => Ok(token.to_string()),
+            None => bail!("the token response lacks an access token"),
+        }
+    }
+}
+
+macro_rules! org_or_skip {
+    () => {
+        match Org::from_env() {
+            Some(org) => org,
+            None => {
+                eprintln!("skipped, OKTA_TEST_* variables are not set");
+                return Ok(());
+            }
+        }
+    };
+}
+
+#[tokio::test]
+#[ignore = "requires an Okta org, see the OKTA_TEST_* variables"]
+async fn metadata_names_the_keys_endpoint() -> Result<()> {
+    let org = org_or_skip!();
+    let metadata = org.metadata().await?;
+    assert_eq!(metadata["issuer"].as_str(), Some(org.issuer.as_str()));
+    let verifier = Verifier::new(&org.issuer).await?;
+    assert_eq!(
+        metadata["jwks_uri"].as_str(),
+        Some(verifier.keys_url()?.as_str())
+    );
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires an Okta org, see the OKTA_TEST_* variables"]
+async fn retrieves_cacheable_keys() -> Result<()> {
+    let org = org_or_skip!();
+    let verifier = Verifier::new(&org.issuer).await?;
+    let stats = verifier.stats();
+    assert!(stats.key_count > 0);
+    assert!(!stats.stale);
+    let fetch = stats.fetch.expect("the keys were retrieved");
+    assert!(fetch.max_age.is_some(), "Okta sends a max-age");
+
+    // With a cache feature the second retrieval is served from the cache
+    let again = Verifier::new(&org.issuer).await?;
+    assert_eq!(again.stats().key_count, stats.key_count);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires an Okta org, see the OKTA_TEST_* variables"]
+async fn verifies_a_minted_token() -> Result<()> {
+    let org = org_or_skip!();
+    let token = org.token().await?;
+    let verifier = Verifier::new(&org.issuer)
+        .await?
+        .add_audience(&org.audience)
+        .client_id(&org.client_id);
+
+    let verified = verifier.verify_detailed::<Value>(&token).await?;
+    let claims = &verified.token_data.claims;
+    assert!(!verified.kid.is_empty());
+    assert_eq!(claims["iss"].as_str(), Some(org.issuer.as_str()));
+    assert_eq!(claims["cid"].as_str(), Some(org.client_id.as_str()));
+    assert!(claims["aud"].is_string() || claims["aud"].is_array());
+    assert!(claims["exp"].as_u64() > claims["iat"].as_u64());
+    assert!(claims["jti"].is_string());
+    if org.scope.is_some() {
+        assert!(claims["scp"].is_array());
+    }
+    // Tokens of the client credentials grant have the client as subject
+    let typed = verifier.verify::<DefaultClaims>(&token).await?.claims;
+    assert_eq!(typed.sub, org.client_id);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires an Okta org, see the OKTA_TEST_* variables"]
+async fn rejects_another_audience_or_client() -> Result<()> {
+    let org = org_or_skip!();
+    let token = org.token().await?;
+    let verifier = Verifier::new(&org.issuer).await?;
+
+    let other_audience = verifier.clone().add_audience("api://not-this-one");
+    assert!(other_audience.verify::<Value>(&token).await.is_err());
+    let other_client =
+        verifier.add_audience(&org.audience).client_id("not-this-client");
+    assert!(other_client.verify::<Value>(&token).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "requires an Okta org, see the OKTA_TEST_* variables"]
+async fn refreshes_without_changes() -> Result<()> {
+    let org = org_or_skip!();
+    let verifier = Verifier::new(&org.issuer).await?;
+    assert!(!verifier.refresh_keys().await?.changed());
+    Ok(())
+}
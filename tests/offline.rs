@@ -0,0 +1,74 @@
+// Verifies tokens with keys provided by the application, which works with
+// any combination of features, including none at all, see
+// tests/feature_combinations.rs.
+
+use anyhow::Result;
+use jwt_simple::prelude::*;
+use okta_jwt_verifier::{Config, DefaultClaims, Error, Verifier};
+
+#[cfg(all(feature = "client-surf", not(feature = "client-reqwest")))]
+use async_std::test as async_test;
+#[cfg(not(all(
+    feature = "client-surf",
+    not(feature = "client-reqwest")
+)))]
+use tokio::test as async_test;
+
+const RSA_KP_PEM: &str = include_str!("fixtures/rsa_key.pem");
+
+const KEY_ID: &str = "12345";
+
+const JWKS: &str = include_str!("fixtures/jwks.json");
+
+const ISSUER: &str = "https://your.okta.com";
+
+fn token() -> Result<String> {
+    let key_pair = RS256KeyPair::from_pem(RSA_KP_PEM)?.with_key_id(KEY_ID);
+    let claims = Claims::create(Duration::from_hours(2))
+        .with_issuer(ISSUER)
+        .with_subject("test");
+    key_pair.sign(claims)
+}
+
+#[async_test]
+async fn verifies_with_the_given_keys() -> Result<()> {
+    let claims = Verifier::with_keys(ISSUER, JWKS)?
+        .verify::<DefaultClaims>(&token()?)
+        .await?;
+    assert_eq!(claims.claims.sub, "test");
+    Ok(())
+}
+
+#[async_test]
+async fn verifies_with_the_keys_file() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("jwks.json");
+    std::fs::write(&path, JWKS)?;
+    let config = Config { keys_file: Some(path), ..Config::default() };
+    let verifier = Verifier::new_with_config(ISSUER, config).await?;
+    verifier.verify::<DefaultClaims>(&token()?).await?;
+    verifier.refresh_keys().await?;
+    Ok(())
+}
+
+#[cfg(not(any(feature = "client-reqwest", feature = "client-surf")))]
+#[async_test]
+async fn reports_that_keys_cant_be_retrieved() -> Result<()> {
+    let e = Verifier::new(ISSUER).await.unwrap_err();
+    assert!(matches!(
+        e.downcast_ref(),
+        Some(Error::KeysUnreachable { reason, .. })
+            if reason.starts_with("no HTTP client")
+    ));
+    Ok(())
+}
+
+#[async_test]
+async fn rejects_tokens_of_other_issuers() -> Result<()> {
+    let e = Verifier::with_keys("https://other.okta.com", JWKS)?
+        .verify::<DefaultClaims>(&token()?)
+        .await
+        .unwrap_err();
+    assert!(matches!(e.downcast_ref(), Some(Error::InvalidToken { .. })));
+    Ok(())
+}